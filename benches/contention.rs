@@ -0,0 +1,70 @@
+//! Writers on distinct keys against the memory backend with a single lock
+//! and with the default shards. Run with `cargo bench --bench contention`.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use hyper::body::Bytes;
+use microservice_rust_workshop::{KVDatabase, MemoryDatabase};
+
+const WRITES_PER_TASK: usize = 20_000;
+const ROUNDS: usize = 5;
+
+/// The fastest of `ROUNDS` runs of `tasks` writers, each on keys of its own
+fn run(runtime: &tokio::runtime::Runtime, db: fn() -> MemoryDatabase, tasks: usize) -> Duration {
+    let value = ("text/plain".to_string(), Bytes::from_static(&[0; 64]));
+    (0..ROUNDS)
+        .map(|_| {
+            let db = Arc::new(db());
+            runtime.block_on(async {
+                let started = Instant::now();
+                let writers: Vec<_> = (0..tasks)
+                    .map(|task| {
+                        let (db, value) = (Arc::clone(&db), value.clone());
+                        tokio::spawn(async move {
+                            for i in 0..WRITES_PER_TASK {
+                                let key = format!("{}/{}", task, i);
+                                db.insert(key, value.clone()).await.unwrap();
+                            }
+                        })
+                    })
+                    .collect();
+                for writer in writers {
+                    writer.await.unwrap();
+                }
+                started.elapsed()
+            })
+        })
+        .min()
+        .expect("At least one round")
+}
+
+fn main() {
+    let threads = std::thread::available_parallelism().map_or(4, |n| n.get());
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(threads)
+        .build()
+        .unwrap();
+    let single_lock = || MemoryDatabase::with_shards(1);
+    let baseline = run(&runtime, single_lock, 1);
+    let per_second =
+        |tasks: usize, elapsed: Duration| (tasks * WRITES_PER_TASK) as f64 / elapsed.as_secs_f64();
+    println!(
+        "1 writer: {:.0} writes/s, {} threads",
+        per_second(1, baseline),
+        threads
+    );
+    for tasks in [threads, 4 * threads] {
+        let single = run(&runtime, single_lock, tasks);
+        let sharded = run(&runtime, MemoryDatabase::default, tasks);
+        println!(
+            "{} writers: single lock {:.0} writes/s, sharded {:.0} writes/s ({:.1}x)",
+            tasks,
+            per_second(tasks, single),
+            per_second(tasks, sharded),
+            single.as_secs_f64() / sharded.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS kv (
+    key TEXT PRIMARY KEY,
+    content_type TEXT NOT NULL,
+    body BLOB NOT NULL
+);
@@ -0,0 +1,87 @@
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{kv_store::is_reserved, KVError, SharedState};
+
+use super::{Feature, LegalHolds};
+
+#[derive(Deserialize)]
+pub struct DebugQuery {
+    /// How many of the largest entries to list
+    #[serde(default = "default_top")]
+    top: usize,
+}
+
+fn default_top() -> usize {
+    10
+}
+
+#[derive(Serialize)]
+pub struct EntrySize {
+    key: String,
+    content_type: String,
+    bytes: usize,
+}
+
+#[derive(Serialize)]
+pub struct DebugState {
+    entries: usize,
+    largest: Vec<EntrySize>,
+    burn_after_read: usize,
+    expiring: usize,
+    redirect_counters: usize,
+    cached_tiles: usize,
+    flood_blocks: usize,
+    holds: LegalHolds,
+    disabled_features: Vec<Feature>,
+    read_only: bool,
+    maintenance: bool,
+}
+
+/// Snapshot of the internal state for incident debugging. Reads every
+/// entry to find the largest ones, so it is slow on big or remote stores.
+pub async fn debug_state(
+    State(state): State<SharedState>,
+    Query(query): Query<DebugQuery>,
+) -> Result<Json<DebugState>, KVError> {
+    let db = state.read()?.db.clone();
+    let mut keys = db.keys("").await?;
+    // The service's own state isn't an entry
+    keys.retain(|key| !is_reserved(key));
+    let mut largest = Vec::new();
+    for key in &keys {
+        if let Some((content_type, data)) = db.read(key).await? {
+            largest.push(EntrySize {
+                key: key.clone(),
+                content_type,
+                bytes: data.len(),
+            });
+        }
+    }
+    largest.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.key.cmp(&b.key)));
+    largest.truncate(query.top);
+
+    let state = state.read()?;
+    let now = state.clock.now();
+    let mut disabled_features: Vec<Feature> = state.disabled_features.iter().copied().collect();
+    disabled_features.sort();
+    Ok(Json(DebugState {
+        entries: keys.len(),
+        largest,
+        burn_after_read: state.burn_after_read.len(),
+        expiring: state.expiries.len(),
+        redirect_counters: state.redirects.len(),
+        cached_tiles: state.tiles.len(),
+        flood_blocks: state
+            .flood_guard
+            .as_ref()
+            .map_or(0, |guard| guard.blocks(now).len()),
+        holds: state.holds.clone(),
+        disabled_features,
+        read_only: state.read_only,
+        maintenance: state.maintenance.is_some(),
+    }))
+}
@@ -0,0 +1,153 @@
+use std::collections::BTreeMap;
+
+use axum::{
+    extract::{Path, State},
+    http::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use hyper::{body::Bytes, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::{KVError, SharedState, INTERNAL_NAMESPACE};
+
+/// Route groups that can be switched off at runtime. Disabled features are
+/// persisted as keys in the reserved namespace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Feature {
+    /// `/kv/:key/grayscale`, `/kv/:key/flatten`, `/kv/:key/preview` and the
+    /// like
+    Transforms,
+    /// `/transform/batch`, which is off along with `Transforms` too
+    Batch,
+    /// `/r/:key`
+    Links,
+    /// `/site/:namespace`
+    Sites,
+}
+
+impl Feature {
+    const ALL: [Feature; 4] = [
+        Feature::Transforms,
+        Feature::Batch,
+        Feature::Links,
+        Feature::Sites,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Feature::Transforms => "transforms",
+            Feature::Batch => "batch",
+            Feature::Links => "links",
+            Feature::Sites => "sites",
+        }
+    }
+
+    /// Another feature whose handlers this one runs
+    fn depends_on(self) -> Option<Feature> {
+        match self {
+            Feature::Batch => Some(Feature::Transforms),
+            _ => None,
+        }
+    }
+
+    /// The feature a request path belongs to. Works on the raw path, a `/`
+    /// inside a key is percent-encoded and can't fake a transform suffix.
+    pub(crate) fn of_path(path: &str) -> Option<Feature> {
+        if let Some(rest) = path.strip_prefix("/kv/") {
+            let tiles = rest.contains("/tiles/") || rest.ends_with("/tiles");
+            (tiles
+                || ["/grayscale", "/flatten", "/preview", "/thumbnail"]
+                    .iter()
+                    .any(|transform| rest.ends_with(transform)))
+            .then_some(Feature::Transforms)
+        } else if path.starts_with("/transform/") {
+            Some(Feature::Batch)
+        } else if path.starts_with("/r/") {
+            Some(Feature::Links)
+        } else if path.starts_with("/site/") {
+            Some(Feature::Sites)
+        } else {
+            None
+        }
+    }
+
+    fn storage_prefix() -> String {
+        format!("{}/features/", INTERNAL_NAMESPACE)
+    }
+
+    fn storage_key(self) -> String {
+        format!("{}{}", Self::storage_prefix(), self.name())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FeatureState {
+    enabled: bool,
+}
+
+/// Restores the disabled features persisted by an earlier run
+pub async fn load_features(state: &SharedState) -> Result<(), KVError> {
+    let db = state.read()?.db.clone();
+    let stored = db.keys(&Feature::storage_prefix()).await?;
+    let disabled = Feature::ALL
+        .into_iter()
+        .filter(|feature| stored.contains(&feature.storage_key()));
+    state.write()?.disabled_features.extend(disabled);
+    Ok(())
+}
+
+pub async fn list_features(State(state): State<SharedState>) -> Json<BTreeMap<Feature, bool>> {
+    let state = state.read().expect("What, an error here?");
+    Json(
+        Feature::ALL
+            .into_iter()
+            .map(|feature| (feature, !state.disabled_features.contains(&feature)))
+            .collect(),
+    )
+}
+
+pub async fn set_feature(
+    Path(feature): Path<Feature>,
+    State(state): State<SharedState>,
+    Json(feature_state): Json<FeatureState>,
+) -> Result<Json<FeatureState>, KVError> {
+    let db = state.read()?.db.clone();
+    if feature_state.enabled {
+        db.remove(&feature.storage_key()).await?;
+        state.write()?.disabled_features.remove(&feature);
+    } else {
+        db.insert(
+            feature.storage_key(),
+            ("text/plain".to_string(), Bytes::from_static(b"disabled")),
+        )
+        .await?;
+        state.write()?.disabled_features.insert(feature);
+    }
+    Ok(Json(feature_state))
+}
+
+/// Rejects requests to route groups that are switched off. Only requests
+/// that belong to a feature look at the state.
+pub async fn reject_disabled_features<B>(
+    State(state): State<SharedState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let disabled = Feature::of_path(request.uri().path()).and_then(|feature| {
+        let state = state.read().expect("What, an error here?");
+        std::iter::once(feature)
+            .chain(feature.depends_on())
+            .find(|feature| state.disabled_features.contains(feature))
+    });
+    match disabled {
+        Some(feature) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("The {} feature is disabled", feature.name()),
+        )
+            .into_response(),
+        None => next.run(request).await,
+    }
+}
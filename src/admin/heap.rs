@@ -0,0 +1,36 @@
+use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+use crate::KVError;
+use axum::response::IntoResponse;
+
+/// Dumps a jemalloc heap profile, to be read with `jeprof --svg <binary> <dump>`.
+/// Only works when the binary was built with the heap-profile feature,
+/// which switches to jemalloc and turns on sampling at startup.
+pub async fn heap_profile() -> Result<impl IntoResponse, KVError> {
+    let dump = tokio::task::spawn_blocking(dump_heap_profile)
+        .await
+        .map_err(KVError::internal)??;
+    Ok((
+        [
+            ("content-type", "application/octet-stream"),
+            ("content-disposition", "attachment; filename=\"kv.heap\""),
+        ],
+        dump,
+    ))
+}
+
+fn dump_heap_profile() -> Result<Vec<u8>, KVError> {
+    if !tikv_jemalloc_ctl::profiling::prof::read().map_err(KVError::internal)? {
+        return Err(KVError::Unavailable(
+            "Heap profiling is not enabled".to_string(),
+        ));
+    }
+    let path = std::env::temp_dir().join(format!("kv-{:016x}.heap", rand::random::<u64>()));
+    let c_path = CString::new(path.as_os_str().as_bytes()).map_err(KVError::internal)?;
+    // prof.dump takes a NUL terminated file name, which outlives the call
+    unsafe { tikv_jemalloc_ctl::raw::write(b"prof.dump\0", c_path.as_ptr()) }
+        .map_err(KVError::internal)?;
+    let dump = std::fs::read(&path);
+    let _ = std::fs::remove_file(&path);
+    dump.map_err(KVError::internal)
+}
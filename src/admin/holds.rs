@@ -0,0 +1,150 @@
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, RwLock},
+};
+
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use hyper::{body::Bytes, StatusCode};
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::{
+    kv_store::{namespace, Database},
+    KVError, SharedState, INTERNAL_NAMESPACE,
+};
+
+#[derive(Default, Serialize, Deserialize)]
+struct Held {
+    keys: BTreeSet<String>,
+    namespaces: BTreeSet<String>,
+}
+
+/// Keys and namespaces under legal hold. Held entries must survive
+/// deletion, expiry, eviction and restarts until the hold is lifted. Clones
+/// share the holds, so backends that evict on their own can be handed them.
+#[derive(Default, Clone)]
+pub struct LegalHolds(Arc<RwLock<Held>>);
+
+impl LegalHolds {
+    pub fn is_held(&self, key: &str) -> bool {
+        let held = self.0.read().expect("What, an error here?");
+        held.keys.contains(key) || namespace(key).is_some_and(|ns| held.namespaces.contains(ns))
+    }
+
+    pub fn hold_key(&self, key: impl Into<String>) {
+        self.0
+            .write()
+            .expect("What, an error here?")
+            .keys
+            .insert(key.into());
+    }
+
+    /// Whether `key` was held
+    pub fn release_key(&self, key: &str) -> bool {
+        self.0
+            .write()
+            .expect("What, an error here?")
+            .keys
+            .remove(key)
+    }
+
+    pub fn hold_namespace(&self, namespace: impl Into<String>) {
+        self.0
+            .write()
+            .expect("What, an error here?")
+            .namespaces
+            .insert(namespace.into());
+    }
+
+    /// Whether `namespace` was held
+    pub fn release_namespace(&self, namespace: &str) -> bool {
+        self.0
+            .write()
+            .expect("What, an error here?")
+            .namespaces
+            .remove(namespace)
+    }
+}
+
+impl Serialize for LegalHolds {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0
+            .read()
+            .expect("What, an error here?")
+            .serialize(serializer)
+    }
+}
+
+fn storage_key() -> String {
+    format!("{}/holds", INTERNAL_NAMESPACE)
+}
+
+/// Restores the holds persisted by an earlier run into `holds`
+pub(crate) async fn load_holds(db: &Database, holds: &LegalHolds) -> Result<(), KVError> {
+    let Some((_, stored)) = db.read(&storage_key()).await? else {
+        return Ok(());
+    };
+    let held = serde_json::from_slice(&stored).map_err(KVError::internal)?;
+    *holds.0.write()? = held;
+    Ok(())
+}
+
+/// Stores the holds as they are now, for `load_holds`
+async fn persist(state: &SharedState) -> Result<(), KVError> {
+    let (db, stored) = {
+        let state = state.read()?;
+        let stored = serde_json::to_vec(&state.holds).map_err(KVError::internal)?;
+        (state.db.clone(), stored)
+    };
+    db.insert(
+        storage_key(),
+        ("application/json".to_string(), Bytes::from(stored)),
+    )
+    .await
+}
+
+pub async fn list_holds(State(state): State<SharedState>) -> Json<LegalHolds> {
+    Json(state.read().expect("What, an error here?").holds.clone())
+}
+
+pub async fn hold_key(
+    Path(key): Path<String>,
+    State(state): State<SharedState>,
+) -> Result<StatusCode, KVError> {
+    state.read()?.holds.hold_key(key);
+    persist(&state).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn release_key(
+    Path(key): Path<String>,
+    State(state): State<SharedState>,
+) -> Result<StatusCode, KVError> {
+    if !state.read()?.holds.release_key(&key) {
+        return Err(KVError::NotFound);
+    }
+    persist(&state).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn hold_namespace(
+    Path(namespace): Path<String>,
+    State(state): State<SharedState>,
+) -> Result<StatusCode, KVError> {
+    state.read()?.holds.hold_namespace(namespace);
+    persist(&state).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn release_namespace(
+    Path(namespace): Path<String>,
+    State(state): State<SharedState>,
+) -> Result<StatusCode, KVError> {
+    if !state.read()?.holds.release_namespace(&namespace) {
+        return Err(KVError::NotFound);
+    }
+    persist(&state).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,108 @@
+use std::net::{IpAddr, SocketAddr};
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use hyper::{header::RETRY_AFTER, Method, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::SharedState;
+
+/// Maintenance announcement. Writes are rejected with `message`, except
+/// from the allowlisted client addresses.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Maintenance {
+    message: String,
+    #[serde(default)]
+    allow: Vec<IpAddr>,
+    #[serde(default)]
+    retry_after_secs: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct Unavailable<'a> {
+    error: &'static str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after_secs: Option<u64>,
+}
+
+pub async fn get_maintenance(State(state): State<SharedState>) -> Json<Option<Maintenance>> {
+    Json(
+        state
+            .read()
+            .expect("What, an error here?")
+            .maintenance
+            .clone(),
+    )
+}
+
+pub async fn start_maintenance(
+    State(state): State<SharedState>,
+    Json(maintenance): Json<Maintenance>,
+) -> Json<Maintenance> {
+    state.write().expect("What, an error here?").maintenance = Some(maintenance.clone());
+    Json(maintenance)
+}
+
+pub async fn end_maintenance(State(state): State<SharedState>) -> StatusCode {
+    state.write().expect("What, an error here?").maintenance = None;
+    StatusCode::NO_CONTENT
+}
+
+fn unavailable(error: &'static str, message: &str, retry_after_secs: Option<u64>) -> Response {
+    let body = Json(Unavailable {
+        error,
+        message,
+        retry_after_secs,
+    });
+    match retry_after_secs {
+        Some(secs) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(RETRY_AFTER, secs.to_string())],
+            body,
+        )
+            .into_response(),
+        None => (StatusCode::SERVICE_UNAVAILABLE, body).into_response(),
+    }
+}
+
+/// Rejects mutating requests in read-only or maintenance mode, reads keep working
+pub async fn reject_writes<B>(
+    State(state): State<SharedState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let mutating = !matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    );
+    if mutating {
+        let client = request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        let state = state.read().expect("What, an error here?");
+        if state.read_only {
+            return unavailable(
+                "read_only",
+                "The service is in read-only mode, writes are rejected until it is lifted",
+                None,
+            );
+        }
+        if let Some(maintenance) = &state.maintenance {
+            if !client.is_some_and(|client| maintenance.allow.contains(&client)) {
+                return unavailable(
+                    "maintenance",
+                    &maintenance.message,
+                    maintenance.retry_after_secs,
+                );
+            }
+        }
+    }
+    next.run(request).await
+}
@@ -0,0 +1,178 @@
+use std::collections::HashSet;
+
+use axum::{extract::State, Json};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    kv_store::{forget_hot_keys, local_key, overlaps_reserved, remove_record, validate_data_key},
+    BlockTarget, KVError, SharedState,
+};
+
+#[cfg(feature = "debug-state")]
+pub use debug::debug_state;
+pub use features::{list_features, load_features, reject_disabled_features, set_feature, Feature};
+#[cfg(feature = "heap-profile")]
+pub use heap::heap_profile;
+pub(crate) use holds::load_holds;
+pub use holds::{hold_key, hold_namespace, list_holds, release_key, release_namespace, LegalHolds};
+pub use maintenance::{
+    end_maintenance, get_maintenance, reject_writes, start_maintenance, Maintenance,
+};
+pub use mounts::{list_mounts, mount, unmount, Mount, MountSource};
+pub use read_only::{get_read_only, set_read_only};
+
+#[cfg(feature = "debug-state")]
+mod debug;
+mod features;
+#[cfg(feature = "heap-profile")]
+mod heap;
+mod holds;
+mod maintenance;
+mod mounts;
+mod read_only;
+
+/// Selects the entries a data subject erasure request applies to
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EraseRequest {
+    #[serde(default)]
+    keys: Vec<String>,
+    #[serde(default)]
+    prefixes: Vec<String>,
+}
+
+impl EraseRequest {
+    /// Erasure is for user data, the service's own state is not up for it
+    fn check(&self) -> Result<(), KVError> {
+        if self.prefixes.iter().any(String::is_empty) {
+            return Err(KVError::BadRequest(
+                "An empty prefix would erase every key".to_string(),
+            ));
+        }
+        for key in &self.keys {
+            validate_data_key(key)?;
+        }
+        if self.prefixes.iter().any(|prefix| overlaps_reserved(prefix)) {
+            return Err(KVError::Forbidden(
+                "Keys reserved for internal use can't be erased".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+pub struct ErasureReport {
+    erased: Vec<String>,
+    count: usize,
+    /// Matching keys kept because they are under legal hold
+    held: Vec<String>,
+}
+
+impl EraseRequest {
+    /// Whether `key` is named by the request, keys in buckets by the key
+    /// their client knows them as, too
+    fn matches(&self, key: &str) -> bool {
+        [key, local_key(key)].into_iter().any(|key| {
+            self.keys.iter().any(|erased| erased == key)
+                || self
+                    .prefixes
+                    .iter()
+                    .any(|prefix| key.starts_with(prefix.as_str()))
+        })
+    }
+}
+
+pub async fn erase(
+    State(state): State<SharedState>,
+    Json(request): Json<EraseRequest>,
+) -> Result<Json<ErasureReport>, KVError> {
+    request.check()?;
+    let db = state.read()?.db.clone();
+    let keys = db.keys("").await?;
+    let (mut held, mut erased): (Vec<String>, Vec<String>) = {
+        let state = state.read()?;
+        let mut matched: HashSet<String> = keys
+            .into_iter()
+            .filter(|key| request.matches(key))
+            .collect();
+        // What transforms made of an erased entry goes with it
+        loop {
+            let derived: Vec<String> = state
+                .metadata
+                .iter()
+                .filter(|(key, metadata)| {
+                    !matched.contains(*key)
+                        && metadata
+                            .derived_from
+                            .as_ref()
+                            .is_some_and(|source| matched.contains(source))
+                })
+                .map(|(key, _)| key.clone())
+                .collect();
+            if derived.is_empty() {
+                break;
+            }
+            matched.extend(derived);
+        }
+        matched
+            .into_iter()
+            .partition(|key| state.holds.is_held(key))
+    };
+    for key in &erased {
+        db.remove(key).await?;
+        // Only once removed, or a failure would leave values without metadata
+        {
+            let mut state = state.write()?;
+            state.forget(key);
+            state.tiles.forget(key);
+        }
+        remove_record(&db, key).await?;
+    }
+    forget_hot_keys(&db, &erased.iter().cloned().collect()).await?;
+    state.read()?.usage.forget_namespaces(|namespace| {
+        let namespace = format!("{}/", namespace);
+        request
+            .prefixes
+            .iter()
+            .any(|prefix| namespace.starts_with(prefix.as_str()))
+    });
+    erased.sort();
+    held.sort();
+    Ok(Json(ErasureReport {
+        count: erased.len(),
+        erased,
+        held,
+    }))
+}
+
+#[derive(Serialize)]
+pub struct Block {
+    target: BlockTarget,
+    remaining_secs: u64,
+}
+
+pub async fn list_blocks(State(state): State<SharedState>) -> Json<Vec<Block>> {
+    let state = state.read().expect("What, an error here?");
+    let now = state.clock.now();
+    let blocks = state.flood_guard.as_ref().map(|guard| guard.blocks(now));
+    Json(
+        blocks
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(target, remaining)| Block {
+                target,
+                remaining_secs: remaining.as_secs(),
+            })
+            .collect(),
+    )
+}
+
+pub async fn clear_blocks(State(state): State<SharedState>) -> StatusCode {
+    let mut state = state.write().expect("What, an error here?");
+    if let Some(guard) = state.flood_guard.as_mut() {
+        guard.clear();
+    }
+    StatusCode::NO_CONTENT
+}
@@ -0,0 +1,123 @@
+use std::path::PathBuf;
+
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    kv_store::Database, BundleDatabase, FsDatabase, KVError, SharedState, INTERNAL_NAMESPACE,
+};
+
+/// Where the entries of a mount come from
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum MountSource {
+    /// A file written by `export_bundle`
+    Bundle { path: PathBuf },
+    /// A directory the fs backend wrote, e.g. content shipped with the binary
+    Fs { root: PathBuf },
+}
+
+impl MountSource {
+    /// Opens the backend and checks it belongs under `namespace`
+    fn open(&self, namespace: &str) -> Result<Database, KVError> {
+        let cant_mount =
+            |error: std::io::Error| KVError::BadRequest(format!("Can't mount: {}", error));
+        match self {
+            Self::Bundle { path } => {
+                let bundle = BundleDatabase::open(path).map_err(cant_mount)?;
+                if bundle.namespace() != namespace {
+                    return Err(KVError::BadRequest(format!(
+                        "The bundle is of the namespace {}",
+                        bundle.namespace()
+                    )));
+                }
+                Ok(Database::new(bundle))
+            }
+            Self::Fs { root } => {
+                if !root.is_dir() {
+                    return Err(KVError::BadRequest(format!(
+                        "Can't mount: {} is not a directory",
+                        root.display()
+                    )));
+                }
+                Ok(Database::new(FsDatabase::open(root).map_err(cant_mount)?))
+            }
+        }
+    }
+}
+
+/// A read-only backend serving a namespace below the configured one
+#[derive(Clone)]
+pub struct Mount {
+    pub(crate) db: Database,
+    /// Unknown for backends mounted in code
+    pub(crate) source: Option<MountSource>,
+}
+
+#[derive(Serialize)]
+pub struct MountInfo {
+    namespace: String,
+    source: Option<MountSource>,
+}
+
+pub async fn list_mounts(
+    State(state): State<SharedState>,
+) -> Result<Json<Vec<MountInfo>>, KVError> {
+    let state = state.read()?;
+    Ok(Json(
+        state
+            .mounts
+            .iter()
+            .map(|(namespace, mount)| MountInfo {
+                namespace: namespace.clone(),
+                source: mount.source.clone(),
+            })
+            .collect(),
+    ))
+}
+
+/// Mounts `source` under `namespace`, replacing what was mounted there
+pub async fn mount(
+    Path(namespace): Path<String>,
+    State(state): State<SharedState>,
+    Json(source): Json<MountSource>,
+) -> Result<Json<MountSource>, KVError> {
+    if namespace.is_empty() || namespace.contains('/') || namespace == INTERNAL_NAMESPACE {
+        return Err(KVError::BadRequest(format!(
+            "Can't mount under {:?}",
+            namespace
+        )));
+    }
+    // Bundles are read whole to verify their checksum
+    let db = {
+        let (namespace, source) = (namespace.clone(), source.clone());
+        tokio::task::spawn_blocking(move || source.open(&namespace))
+            .await
+            .map_err(KVError::internal)??
+    };
+    let mut state = state.write()?;
+    state.mount(
+        namespace,
+        Mount {
+            db,
+            source: Some(source.clone()),
+        },
+    );
+    Ok(Json(source))
+}
+
+pub async fn unmount(
+    Path(namespace): Path<String>,
+    State(state): State<SharedState>,
+) -> Result<StatusCode, KVError> {
+    let mut state = state.write()?;
+    Ok(if state.unmount(&namespace) {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    })
+}
@@ -0,0 +1,22 @@
+use axum::{extract::State, Json};
+use serde::{Deserialize, Serialize};
+
+use crate::SharedState;
+
+#[derive(Serialize, Deserialize)]
+pub struct ReadOnly {
+    enabled: bool,
+}
+
+pub async fn get_read_only(State(state): State<SharedState>) -> Json<ReadOnly> {
+    let enabled = state.read().expect("What, an error here?").read_only;
+    Json(ReadOnly { enabled })
+}
+
+pub async fn set_read_only(
+    State(state): State<SharedState>,
+    Json(read_only): Json<ReadOnly>,
+) -> Json<ReadOnly> {
+    state.write().expect("What, an error here?").read_only = read_only.enabled;
+    Json(read_only)
+}
@@ -0,0 +1,186 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Bound,
+};
+
+use axum::{
+    extract::{Path, Query, State},
+    Json,
+};
+use hyper::{body::Bytes, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use super::{require_scope, Claims, Principal};
+use crate::{kv_store::Database, KVError, SharedState, INTERNAL_NAMESPACE};
+
+/// Who may access a key, or every key starting with it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Acl {
+    /// Token subjects, API keys may always access every key
+    principals: BTreeSet<String>,
+    #[serde(default)]
+    prefix: bool,
+}
+
+/// An ACL along with what it is set on, as `GET /kv/:key/acl` returns it
+#[derive(Serialize)]
+pub struct GoverningAcl {
+    key: String,
+    #[serde(flatten)]
+    acl: Acl,
+}
+
+/// Access control lists by key and by prefix. Keys without one are open
+/// to everyone who got past authentication. Persisted in the reserved
+/// namespace on every change.
+#[derive(Default, Serialize, Deserialize)]
+pub struct Acls {
+    keys: BTreeMap<String, BTreeSet<String>>,
+    prefixes: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl Acls {
+    /// The ACL of `key` itself, else the one of its longest prefix
+    fn governing(&self, key: &str) -> Option<GoverningAcl> {
+        if let Some(principals) = self.keys.get(key) {
+            return Some(GoverningAcl {
+                key: key.to_string(),
+                acl: Acl {
+                    principals: principals.clone(),
+                    prefix: false,
+                },
+            });
+        }
+        // Prefixes of `key` sort before it, the closest one last
+        self.prefixes
+            .range::<str, _>((Bound::Unbounded, Bound::Included(key)))
+            .rev()
+            .find(|(prefix, _)| key.starts_with(prefix.as_str()))
+            .map(|(prefix, principals)| GoverningAcl {
+                key: prefix.clone(),
+                acl: Acl {
+                    principals: principals.clone(),
+                    prefix: true,
+                },
+            })
+    }
+
+    pub(crate) fn check(&self, key: &str, principal: &Principal) -> Result<(), KVError> {
+        let Some(governing) = self.governing(key) else {
+            return Ok(());
+        };
+        match principal {
+            Principal::Trusted => Ok(()),
+            Principal::Subject(subject) if governing.acl.principals.contains(subject) => Ok(()),
+            _ => Err(KVError::forbidden()),
+        }
+    }
+}
+
+fn storage_key() -> String {
+    format!("{}/acls", INTERNAL_NAMESPACE)
+}
+
+/// Restores the ACLs persisted by an earlier run
+pub(crate) async fn load_acls(db: &Database) -> Result<Acls, KVError> {
+    match db.read(&storage_key()).await? {
+        Some((_, stored)) => serde_json::from_slice(&stored).map_err(KVError::internal),
+        None => Ok(Acls::default()),
+    }
+}
+
+/// Stores the ACLs as they are now, for `load_acls`
+async fn persist(state: &SharedState) -> Result<(), KVError> {
+    let (db, stored) = {
+        let state = state.read()?;
+        let stored = serde_json::to_vec(&state.acls).map_err(KVError::internal)?;
+        (state.db.clone(), stored)
+    };
+    db.insert(
+        storage_key(),
+        ("application/json".to_string(), Bytes::from(stored)),
+    )
+    .await
+}
+
+/// Fails unless `principal` may access `key`
+pub(crate) fn check_access(
+    state: &SharedState,
+    key: &str,
+    principal: &Principal,
+) -> Result<(), KVError> {
+    state.read()?.acls.check(key, principal)
+}
+
+/// Only operators manage ACLs: API keys, or tokens with `kv:admin`
+fn require_admin(principal: &Principal, claims: Option<&Claims>) -> Result<(), KVError> {
+    match (principal, claims) {
+        (Principal::Trusted, _) => Ok(()),
+        (_, Some(claims)) => require_scope(Some(claims), "kv:admin"),
+        (_, None) => Err(KVError::forbidden()),
+    }
+}
+
+pub async fn get_acl(
+    Path(key): Path<String>,
+    State(state): State<SharedState>,
+    principal: Principal,
+    claims: Option<Claims>,
+) -> Result<Json<GoverningAcl>, KVError> {
+    require_admin(&principal, claims.as_ref())?;
+    let governing = state.read()?.acls.governing(&key);
+    governing.map(Json).ok_or(KVError::NotFound)
+}
+
+pub async fn put_acl(
+    Path(key): Path<String>,
+    State(state): State<SharedState>,
+    principal: Principal,
+    claims: Option<Claims>,
+    Json(acl): Json<Acl>,
+) -> Result<StatusCode, KVError> {
+    require_admin(&principal, claims.as_ref())?;
+    {
+        let mut state = state.write()?;
+        let acls = if acl.prefix {
+            &mut state.acls.prefixes
+        } else {
+            &mut state.acls.keys
+        };
+        acls.insert(key, acl.principals);
+    }
+    persist(&state).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+pub struct AclQuery {
+    #[serde(default)]
+    prefix: bool,
+}
+
+/// Removes the ACL of the key, or with `?prefix=true` of the prefix
+pub async fn delete_acl(
+    Path(key): Path<String>,
+    Query(query): Query<AclQuery>,
+    State(state): State<SharedState>,
+    principal: Principal,
+    claims: Option<Claims>,
+) -> Result<StatusCode, KVError> {
+    require_admin(&principal, claims.as_ref())?;
+    let removed = {
+        let mut state = state.write()?;
+        let acls = if query.prefix {
+            &mut state.acls.prefixes
+        } else {
+            &mut state.acls.keys
+        };
+        acls.remove(&key)
+    };
+    if removed.is_none() {
+        return Err(KVError::NotFound);
+    }
+    persist(&state).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,42 @@
+use std::collections::HashSet;
+
+use hyper::HeaderMap;
+use sha2::{Digest, Sha256};
+
+use crate::KVError;
+
+const API_KEY: &str = "x-api-key";
+
+/// The keys clients authenticate with in the `X-Api-Key` header. Only
+/// digests are kept, so a dump of the state doesn't give the keys away.
+#[derive(Clone, Debug, Default)]
+pub struct ApiKeys {
+    digests: HashSet<[u8; 32]>,
+}
+
+impl ApiKeys {
+    pub fn new<K: AsRef<str>>(keys: impl IntoIterator<Item = K>) -> Self {
+        Self {
+            digests: keys
+                .into_iter()
+                .map(|key| Sha256::digest(key.as_ref().as_bytes()).into())
+                .collect(),
+        }
+    }
+
+    /// `None` if the request doesn't come with a key at all
+    pub(super) fn check(&self, headers: &HeaderMap) -> Option<Result<(), KVError>> {
+        headers.get(API_KEY)?;
+        Some(
+            self.identify(headers)
+                .map(|_| ())
+                .ok_or(KVError::Unauthorized("Invalid API key")),
+        )
+    }
+
+    /// The digest of the request's key, if it is a valid one
+    pub(crate) fn identify(&self, headers: &HeaderMap) -> Option<[u8; 32]> {
+        let digest: [u8; 32] = Sha256::digest(headers.get(API_KEY)?.as_bytes()).into();
+        self.digests.contains(&digest).then_some(digest)
+    }
+}
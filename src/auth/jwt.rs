@@ -0,0 +1,155 @@
+use async_trait::async_trait;
+use axum::{extract::FromRequestParts, http::request::Parts};
+use hyper::{header::AUTHORIZATION, HeaderMap};
+use jsonwebtoken::{
+    jwk::{AlgorithmParameters, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::{BoxError, KVError};
+
+/// Where the keys tokens are signed with come from
+#[derive(Clone)]
+enum Keys {
+    /// A shared secret, for HS256, HS384 and HS512
+    Secret(DecodingKey),
+    /// Public keys, picked by the `kid` of the token
+    Jwks(JwkSet),
+}
+
+/// Validates `Authorization: Bearer` JWTs. Tokens need an `exp`, and an
+/// `iss` and `aud` matching the configured ones, if configured.
+#[derive(Clone)]
+pub struct JwtAuth {
+    keys: Keys,
+    issuer: Option<String>,
+    audience: Option<String>,
+}
+
+impl JwtAuth {
+    /// Tokens signed with `secret`
+    pub fn secret(secret: impl AsRef<[u8]>) -> Self {
+        Self::new(Keys::Secret(DecodingKey::from_secret(secret.as_ref())))
+    }
+
+    /// Tokens signed with one of the keys in a JWKS document
+    pub fn jwks(jwks: &str) -> Result<Self, BoxError> {
+        Ok(Self::new(Keys::Jwks(serde_json::from_str(jwks)?)))
+    }
+
+    fn new(keys: Keys) -> Self {
+        Self {
+            keys,
+            issuer: None,
+            audience: None,
+        }
+    }
+
+    pub fn with_issuer(mut self, issuer: impl Into<String>) -> Self {
+        self.issuer = Some(issuer.into());
+        self
+    }
+
+    pub fn with_audience(mut self, audience: impl Into<String>) -> Self {
+        self.audience = Some(audience.into());
+        self
+    }
+
+    /// `None` if the request doesn't come with a bearer token
+    pub(super) fn check(&self, headers: &HeaderMap) -> Option<Result<Claims, KVError>> {
+        let token = headers
+            .get(AUTHORIZATION)?
+            .to_str()
+            .ok()?
+            .strip_prefix("Bearer ")?
+            .trim();
+        Some(self.decode(token))
+    }
+
+    fn decode(&self, token: &str) -> Result<Claims, KVError> {
+        let invalid = |_| KVError::Unauthorized("Invalid bearer token");
+        let header = jsonwebtoken::decode_header(token).map_err(invalid)?;
+        // Algorithms are pinned by the key, never taken from the token alone
+        let (key, algorithms) = match &self.keys {
+            Keys::Secret(key) => (
+                key.clone(),
+                vec![Algorithm::HS256, Algorithm::HS384, Algorithm::HS512],
+            ),
+            Keys::Jwks(jwks) => {
+                let jwk = match &header.kid {
+                    Some(kid) => jwks.find(kid),
+                    None if jwks.keys.len() == 1 => jwks.keys.first(),
+                    None => None,
+                }
+                .ok_or(KVError::Unauthorized("Unknown signing key"))?;
+                let algorithms = match jwk.algorithm {
+                    AlgorithmParameters::RSA(_) => vec![
+                        Algorithm::RS256,
+                        Algorithm::RS384,
+                        Algorithm::RS512,
+                        Algorithm::PS256,
+                        Algorithm::PS384,
+                        Algorithm::PS512,
+                    ],
+                    AlgorithmParameters::EllipticCurve(_) => {
+                        vec![Algorithm::ES256, Algorithm::ES384]
+                    }
+                    AlgorithmParameters::OctetKeyPair(_) => vec![Algorithm::EdDSA],
+                    AlgorithmParameters::OctetKey(_) => {
+                        vec![Algorithm::HS256, Algorithm::HS384, Algorithm::HS512]
+                    }
+                };
+                (DecodingKey::from_jwk(jwk).map_err(invalid)?, algorithms)
+            }
+        };
+        let mut validation = Validation::new(header.alg);
+        validation.algorithms = algorithms;
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        let data = jsonwebtoken::decode(token, &key, &validation).map_err(invalid)?;
+        Ok(data.claims)
+    }
+}
+
+/// The claims of a validated bearer token. Handlers take them as an
+/// extractor, requests without a token are rejected with 401.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Claims {
+    pub sub: Option<String>,
+    /// Space separated, as in RFC 8693
+    #[serde(default)]
+    scope: String,
+    /// Everything else the token carries, `exp` included
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl Claims {
+    pub fn scopes(&self) -> impl Iterator<Item = &str> {
+        self.scope.split_whitespace()
+    }
+
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes().any(|granted| granted == scope)
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Claims {
+    type Rejection = KVError;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, KVError> {
+        parts
+            .extensions
+            .get::<Claims>()
+            .cloned()
+            .ok_or(KVError::Unauthorized("Missing bearer token"))
+    }
+}
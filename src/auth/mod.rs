@@ -0,0 +1,141 @@
+use std::convert::Infallible;
+
+use async_trait::async_trait;
+use axum::{
+    extract::{FromRequestParts, State},
+    http::{request::Parts, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hyper::Method;
+
+use crate::{AppState, KVError, SharedState};
+
+pub(crate) use acl::{check_access, load_acls};
+pub use acl::{delete_acl, get_acl, put_acl, Acls};
+pub use api_keys::ApiKeys;
+pub use jwt::{Claims, JwtAuth};
+
+mod acl;
+mod api_keys;
+mod jwt;
+
+/// Who made a request, as far as `authenticate` could tell
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Principal {
+    /// No credentials, for public reads or tokens without a subject
+    #[default]
+    Anonymous,
+    /// Any API key, or anyone if no credentials are configured at all
+    Trusted,
+    /// The `sub` of a bearer token
+    Subject(String),
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Principal {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Infallible> {
+        Ok(parts
+            .extensions
+            .get::<Principal>()
+            .cloned()
+            .unwrap_or_default())
+    }
+}
+
+fn is_read(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+/// Checks the credentials of a request: an API key, or a bearer token
+/// whose claims are passed on to the handlers. `None` if it has none.
+fn credentials<B>(state: &AppState, request: &mut Request<B>) -> Option<Result<(), KVError>> {
+    if let Some(checked) = state
+        .api_keys
+        .as_ref()
+        .and_then(|keys| keys.check(request.headers()))
+    {
+        return Some(checked.map(|()| {
+            request.extensions_mut().insert(Principal::Trusted);
+        }));
+    }
+    let checked = state.jwt.as_ref()?.check(request.headers())?;
+    Some(checked.map(|claims| {
+        let principal = claims
+            .sub
+            .clone()
+            .map_or(Principal::Anonymous, Principal::Subject);
+        request.extensions_mut().insert(principal);
+        request.extensions_mut().insert(claims);
+    }))
+}
+
+/// Rejects requests without valid credentials with 401, reads only if
+/// they aren't public. Does nothing unless keys or tokens are configured.
+pub async fn authenticate<B>(
+    State(state): State<SharedState>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let checked = {
+        let state = state.read().expect("What, an error here?");
+        match credentials(&state, &mut request) {
+            Some(checked) => checked,
+            None if state.api_keys.is_none() && state.jwt.is_none() => {
+                request.extensions_mut().insert(Principal::Trusted);
+                Ok(())
+            }
+            None if state.public_reads && is_read(request.method()) => Ok(()),
+            None => Err(KVError::Unauthorized("Missing credentials")),
+        }
+    };
+    if let Err(error) = checked {
+        return error.into_response();
+    }
+    let principal = request.extensions().get::<Principal>().cloned();
+    let mut response = next.run(request).await;
+    // For usage accounting, which runs outside of the versioned routers
+    if let Some(principal) = principal {
+        response.extensions_mut().insert(principal);
+    }
+    response
+}
+
+/// Like `authenticate`, for operator endpoints. Reads are never public
+/// there, and tokens need the `kv:admin` scope.
+pub async fn authenticate_admin<B>(
+    State(state): State<SharedState>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let checked = {
+        let state = state.read().expect("What, an error here?");
+        match credentials(&state, &mut request) {
+            Some(checked) => checked
+                .and_then(|()| require_scope(request.extensions().get::<Claims>(), "kv:admin")),
+            None if state.api_keys.is_none() && state.jwt.is_none() => {
+                request.extensions_mut().insert(Principal::Trusted);
+                Ok(())
+            }
+            None => Err(KVError::Unauthorized("Missing credentials")),
+        }
+    };
+    match checked {
+        Ok(()) => next.run(request).await,
+        Err(error) => error.into_response(),
+    }
+}
+
+/// Requests with a bearer token need `scope` among its claims. Requests
+/// authenticated otherwise, or not at all, got past `authenticate` already.
+pub(crate) fn require_scope(claims: Option<&Claims>, scope: &str) -> Result<(), KVError> {
+    match claims {
+        Some(claims) if !claims.has_scope(scope) => Err(KVError::Forbidden(format!(
+            "The token lacks the {} scope",
+            scope
+        ))),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,87 @@
+use std::{
+    ops::Deref,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Where the service reads the time from. Everything that expires or
+/// cools down asks the clock in `AppState`, so tests can move time forward.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Wall clock time, for timestamps that are shown to clients
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// The real monotonic clock
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time.
+#[derive(Clone)]
+pub struct MockClock {
+    start: Instant,
+    start_system: SystemTime,
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            start_system: SystemTime::now(),
+            elapsed: Arc::default(),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().expect("What, an error here?") += by;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().expect("What, an error here?")
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.start_system + *self.elapsed.lock().expect("What, an error here?")
+    }
+}
+
+/// The clock stored in `AppState`, the system clock by default
+#[derive(Clone)]
+pub(crate) struct SharedClock(Arc<dyn Clock>);
+
+impl SharedClock {
+    pub(crate) fn new(clock: impl Clock + 'static) -> Self {
+        Self(Arc::new(clock))
+    }
+}
+
+impl Default for SharedClock {
+    fn default() -> Self {
+        Self::new(SystemClock)
+    }
+}
+
+impl Deref for SharedClock {
+    type Target = dyn Clock;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
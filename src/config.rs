@@ -0,0 +1,876 @@
+//! Settings of the service: a TOML file, with environment variables taking
+//! precedence over it
+
+use std::{
+    fmt::Display,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use axum::http::HeaderValue;
+use serde::{Deserialize, Deserializer};
+use thiserror::Error;
+
+use crate::{
+    admin::{load_holds, Mount, MountSource},
+    auth::load_acls,
+    kv_store::{load_records, Database},
+    ApiKeys, AppState, BatchLimits, BoundedLruDatabase, BoxError, BundleDatabase, ClamdScanner,
+    ContentTypePolicy, CorsOptions, FloodLimits, FsDatabase, JwtAuth, KVDatabase, KeyLimits,
+    Listen, MemoryDatabase, RateLimits, RequestLimits, ServerOptions, StatsdMetrics,
+    TieredDatabase, TlsOptions,
+};
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Can't read {}: {source}", path.display())]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Invalid config file {}: {source}", path.display())]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("Invalid {name}: {message}")]
+    Env { name: &'static str, message: String },
+}
+
+/// Everything `main` builds the service from. Sections and fields may be
+/// left out, the defaults serve in memory on `127.0.0.1:3000`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub backend: BackendConfig,
+    pub cache: CacheConfig,
+    pub write_behind: WriteBehindConfig,
+    pub limits: LimitsConfig,
+    pub auth: AuthConfig,
+    pub cors: CorsConfig,
+    pub metrics: MetricsConfig,
+    pub virus_scan: VirusScanConfig,
+    /// `KV_SITES`, namespaces served as static websites under
+    /// `/site/:namespace`
+    pub sites: Vec<String>,
+    /// `KV_MOUNTS`, bundles to serve read-only under the namespace each was
+    /// written from, see `export_bundle`
+    pub mounts: Vec<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// `KV_LISTEN`, e.g. `0.0.0.0:443` when exposed directly
+    pub listen: SocketAddr,
+    /// `KV_ADMIN_LISTEN`, e.g. `127.0.0.1:3001` or `unix:/run/kv/admin.sock`.
+    /// Admin routes are served with the public ones if not set.
+    #[serde(deserialize_with = "parsed")]
+    pub admin_listen: Option<Listen>,
+    /// `KV_HTTP_REDIRECT_LISTEN`, e.g. `0.0.0.0:80`, sends plain HTTP to
+    /// HTTPS
+    pub http_redirect_listen: Option<SocketAddr>,
+    /// `KV_TLS_CERT` and `KV_TLS_KEY`
+    pub tls: Option<TlsOptions>,
+    /// `KV_SHUTDOWN_TIMEOUT`, seconds requests in flight may take to finish
+    /// after SIGTERM
+    pub shutdown_timeout: u64,
+    /// `KV_READ_ONLY`, start refusing writes, toggled at runtime through
+    /// `/admin/readonly`
+    pub read_only: bool,
+    /// `KV_BACKLOG`, pending connections the kernel queues
+    pub backlog: u32,
+    /// `KV_TCP_NODELAY`
+    pub tcp_nodelay: bool,
+    /// `KV_TCP_KEEPALIVE`, seconds between TCP keepalive probes, 0 disables
+    /// them
+    pub tcp_keepalive: u64,
+    /// `KV_HTTP1_KEEPALIVE`
+    pub http1_keepalive: bool,
+    /// `KV_HTTP2_MAX_STREAMS`, concurrent streams per HTTP/2 connection, 0
+    /// for no limit
+    pub http2_max_concurrent_streams: u32,
+    /// `KV_HTTP2_KEEPALIVE`, seconds between HTTP/2 PING frames, 0 disables
+    /// them
+    pub http2_keep_alive_interval: u64,
+    /// `KV_HTTP2_KEEPALIVE_TIMEOUT`, seconds to wait for a PING to be
+    /// answered
+    pub http2_keep_alive_timeout: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        let options = ServerOptions::default();
+        let secs = |interval: Option<Duration>| interval.map_or(0, |interval| interval.as_secs());
+        Self {
+            listen: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            admin_listen: None,
+            http_redirect_listen: None,
+            tls: None,
+            shutdown_timeout: 30,
+            read_only: false,
+            backlog: options.backlog,
+            tcp_nodelay: options.tcp_nodelay,
+            tcp_keepalive: secs(options.tcp_keepalive),
+            http1_keepalive: options.http1_keepalive,
+            http2_max_concurrent_streams: options.http2_max_concurrent_streams.unwrap_or(0),
+            http2_keep_alive_interval: secs(options.http2_keep_alive_interval),
+            http2_keep_alive_timeout: options.http2_keep_alive_timeout.as_secs(),
+        }
+    }
+}
+
+/// Where entries are stored, chosen by `type`. Backends that need a
+/// feature fail to start without it.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum BackendConfig {
+    /// `KV_MAX_ENTRIES` and `KV_MAX_BYTES` bound it, unbounded by default
+    Memory {
+        max_entries: Option<usize>,
+        max_bytes: Option<usize>,
+    },
+    /// `KV_FS_ROOT`
+    Fs { root: PathBuf },
+    /// `KV_SLED_PATH`
+    Sled { path: PathBuf },
+    /// `KV_SQLITE_URL`, e.g. `sqlite:///var/lib/kv/kv.db`
+    Sqlite { url: String },
+    /// `KV_POSTGRES_URL`, e.g. `postgres://kv@localhost/kv`
+    Postgres {
+        url: String,
+        #[serde(default = "default_max_connections")]
+        max_connections: u32,
+    },
+    /// `KV_REDIS_URL`, e.g. `redis://127.0.0.1/`
+    Redis { url: String },
+    /// `KV_OBJECT_STORE_URL`, e.g. `s3://bucket/kv`
+    ObjectStore { url: String },
+}
+
+impl Default for BackendConfig {
+    fn default() -> Self {
+        BackendConfig::Memory {
+            max_entries: None,
+            max_bytes: None,
+        }
+    }
+}
+
+impl BackendConfig {
+    /// Fails if the backend needs a feature the build doesn't have
+    fn check_feature(&self) -> Result<(), BoxError> {
+        let (feature, enabled) = match self {
+            BackendConfig::Memory { .. } | BackendConfig::Fs { .. } => return Ok(()),
+            BackendConfig::Sled { .. } => ("sled", cfg!(feature = "sled")),
+            BackendConfig::Sqlite { .. } => ("sqlite", cfg!(feature = "sqlite")),
+            BackendConfig::Postgres { .. } => ("postgres", cfg!(feature = "postgres")),
+            BackendConfig::Redis { .. } => ("redis", cfg!(feature = "redis")),
+            BackendConfig::ObjectStore { .. } => ("object-store", cfg!(feature = "object-store")),
+        };
+        if enabled {
+            Ok(())
+        } else {
+            Err(format!("The {} backend needs the {} feature", feature, feature).into())
+        }
+    }
+}
+
+fn default_max_connections() -> u32 {
+    16
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// `KV_MAX_BODY_BYTES`, 16 MiB by default
+    pub max_body_bytes: Option<u64>,
+    /// `KV_ALLOWED_CONTENT_TYPES`, e.g. `image/*,text/plain`, everything if
+    /// empty
+    pub allowed_content_types: Vec<String>,
+    /// `KV_RATE_LIMIT_READS`, `KV_RATE_LIMIT_WRITES` and
+    /// `KV_RATE_LIMIT_TRANSFORMS`, e.g. `2:10` for 2 per second in bursts
+    /// of up to 10, per client
+    pub rate: RateLimits,
+    /// `KV_REQUEST_TIMEOUT_MS`, time a request may take before it's
+    /// answered with 504, callers can ask for less with
+    /// `X-Request-Deadline`. No limit if not set.
+    pub request_timeout_ms: Option<u64>,
+    /// `KV_MAX_KEY_LENGTH`, in bytes, 512 by default
+    pub max_key_length: Option<usize>,
+    /// `KV_MAX_KEY_DEPTH`, segments separated by `/`, 16 by default
+    pub max_key_depth: Option<usize>,
+    /// Blocking keys and clients that write too often, off if left out
+    pub flood: Option<FloodConfig>,
+}
+
+/// `KV_FLOOD_WINDOW`, `KV_FLOOD_MAX_WRITES_PER_KEY`,
+/// `KV_FLOOD_MAX_NEW_KEYS` and `KV_FLOOD_COOLDOWN`, setting any of them
+/// turns flood blocking on
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FloodConfig {
+    /// Seconds writes are counted over
+    pub window: u64,
+    pub max_writes_per_key: u32,
+    pub max_new_keys_per_client: u32,
+    /// Seconds a key or client stays blocked
+    pub cooldown: u64,
+}
+
+impl Default for FloodConfig {
+    fn default() -> Self {
+        let limits = FloodLimits::default();
+        Self {
+            window: limits.window.as_secs(),
+            max_writes_per_key: limits.max_writes_per_key,
+            max_new_keys_per_client: limits.max_new_keys_per_client,
+            cooldown: limits.cooldown.as_secs(),
+        }
+    }
+}
+
+impl FloodConfig {
+    fn limits(&self) -> FloodLimits {
+        FloodLimits {
+            window: Duration::from_secs(self.window),
+            max_writes_per_key: self.max_writes_per_key,
+            max_new_keys_per_client: self.max_new_keys_per_client,
+            cooldown: Duration::from_secs(self.cooldown),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// `KV_HOT_CACHE_BYTES`, memory for the most recently read values in
+    /// front of the backend, no cache if not set
+    pub hot_bytes: Option<usize>,
+    /// `KV_PREFETCH_KEYS`, how many of the most read keys are loaded into
+    /// the cache on the next start
+    pub prefetch_keys: usize,
+    /// `KV_HOT_KEYS_INTERVAL`, seconds between snapshots of the most read
+    /// keys, one is also taken on shutdown
+    pub snapshot_interval: u64,
+}
+
+impl CacheConfig {
+    /// Stores entries in `db`, behind the hot cache if there is one
+    fn with_database(&self, app_state: AppState, db: impl KVDatabase + 'static) -> AppState {
+        match self.hot_bytes {
+            Some(max_bytes) => app_state.with_database(TieredDatabase::new(db, max_bytes)),
+            None => app_state.with_database(db),
+        }
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            hot_bytes: None,
+            prefetch_keys: 1000,
+            snapshot_interval: 300,
+        }
+    }
+}
+
+/// Buffering writes to the SQL, Redis and object store backends, see
+/// `WriteBehindDatabase` for what is lost on a crash
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WriteBehindConfig {
+    /// `KV_WRITE_BEHIND_MS`, how long writes may be buffered, every write
+    /// goes to the backend right away if not set
+    pub max_delay_ms: Option<u64>,
+    /// `KV_WRITE_BEHIND_MAX_WRITES`, buffered writes that are sent on
+    /// without waiting
+    pub max_writes: usize,
+    /// `KV_WRITE_BEHIND_MAX_BYTES`, buffered bytes that are sent on without
+    /// waiting
+    pub max_bytes: usize,
+}
+
+impl Default for WriteBehindConfig {
+    fn default() -> Self {
+        let limits = BatchLimits::default();
+        Self {
+            max_delay_ms: None,
+            max_writes: limits.max_writes,
+            max_bytes: limits.max_bytes,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// `KV_API_KEYS`, e.g. `key1,key2`, no authentication if empty
+    pub api_keys: Vec<String>,
+    /// `KV_JWT_SECRET`, takes precedence over `jwks_path`
+    pub jwt_secret: Option<String>,
+    /// `KV_JWKS_PATH`, e.g. `/etc/kv/jwks.json`
+    pub jwks_path: Option<PathBuf>,
+    /// `KV_JWT_ISSUER`
+    pub jwt_issuer: Option<String>,
+    /// `KV_JWT_AUDIENCE`
+    pub jwt_audience: Option<String>,
+    /// `KV_PUBLIC_READS`, only matters with keys or tokens
+    pub public_reads: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// `KV_CORS_ORIGINS`, e.g. `https://app.example.com` or `*`, CORS is
+    /// off if empty
+    pub origins: Vec<String>,
+    /// `KV_CORS_METHODS`, e.g. `GET,POST`
+    pub methods: Option<Vec<String>>,
+    /// `KV_CORS_HEADERS`, e.g. `x-api-key`
+    pub headers: Option<Vec<String>>,
+    /// `KV_CORS_MAX_AGE`, in seconds
+    pub max_age: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// `KV_STATSD_ADDR`, e.g. `127.0.0.1:8125`
+    pub statsd_addr: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VirusScanConfig {
+    /// `KV_CLAMD_ADDR`, e.g. `127.0.0.1:3310`, uploads aren't scanned if
+    /// not set
+    pub clamd_addr: Option<String>,
+    /// `KV_CLAMD_TIMEOUT_MS`, 30 seconds by default
+    pub timeout_ms: Option<u64>,
+}
+
+/// For fields that are parsed from a string, like listeners
+fn parsed<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| value.parse().map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+/// Splits a comma separated list, skipping empty items
+fn list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn open_bundle(path: &Path) -> Result<BundleDatabase, ConfigError> {
+    BundleDatabase::open(path).map_err(|source| ConfigError::Read {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+impl Config {
+    /// The file at `path` if there is one, then the environment on top
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let config = match path {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        config.with_env(|name| std::env::var(name).ok())
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        toml::from_str(&text).map_err(|source| ConfigError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// Overrides settings with the variables `var` knows, e.g.
+    /// `KV_LISTEN`. The names are in the docs of the fields.
+    pub fn with_env(mut self, var: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        fn parse<T: FromStr>(name: &'static str, value: String) -> Result<T, ConfigError>
+        where
+            T::Err: Display,
+        {
+            value.parse().map_err(|error: T::Err| ConfigError::Env {
+                name,
+                message: error.to_string(),
+            })
+        }
+        let var = |name: &'static str| var(name).map(|value| (name, value));
+
+        let server = &mut self.server;
+        if let Some((name, value)) = var("KV_LISTEN") {
+            server.listen = parse(name, value)?;
+        }
+        if let Some((name, value)) = var("KV_ADMIN_LISTEN") {
+            server.admin_listen = Some(parse(name, value)?);
+        }
+        if let Some((name, value)) = var("KV_HTTP_REDIRECT_LISTEN") {
+            server.http_redirect_listen = Some(parse(name, value)?);
+        }
+        if let (Some((_, cert_path)), Some((_, key_path))) = (var("KV_TLS_CERT"), var("KV_TLS_KEY"))
+        {
+            server.tls = Some(TlsOptions {
+                cert_path: cert_path.into(),
+                key_path: key_path.into(),
+            });
+        }
+        if let Some((name, value)) = var("KV_SHUTDOWN_TIMEOUT") {
+            server.shutdown_timeout = parse(name, value)?;
+        }
+        if let Some((name, value)) = var("KV_READ_ONLY") {
+            server.read_only = parse(name, value)?;
+        }
+        if let Some((name, value)) = var("KV_BACKLOG") {
+            server.backlog = parse(name, value)?;
+        }
+        if let Some((name, value)) = var("KV_TCP_NODELAY") {
+            server.tcp_nodelay = parse(name, value)?;
+        }
+        if let Some((name, value)) = var("KV_TCP_KEEPALIVE") {
+            server.tcp_keepalive = parse(name, value)?;
+        }
+        if let Some((name, value)) = var("KV_HTTP1_KEEPALIVE") {
+            server.http1_keepalive = parse(name, value)?;
+        }
+        if let Some((name, value)) = var("KV_HTTP2_MAX_STREAMS") {
+            server.http2_max_concurrent_streams = parse(name, value)?;
+        }
+        if let Some((name, value)) = var("KV_HTTP2_KEEPALIVE") {
+            server.http2_keep_alive_interval = parse(name, value)?;
+        }
+        if let Some((name, value)) = var("KV_HTTP2_KEEPALIVE_TIMEOUT") {
+            server.http2_keep_alive_timeout = parse(name, value)?;
+        }
+
+        // In order of precedence, when several are set
+        let backend = if let Some((_, url)) = var("KV_REDIS_URL") {
+            Some(BackendConfig::Redis { url })
+        } else if let Some((_, path)) = var("KV_SLED_PATH") {
+            Some(BackendConfig::Sled { path: path.into() })
+        } else if let Some((_, url)) = var("KV_SQLITE_URL") {
+            Some(BackendConfig::Sqlite { url })
+        } else if let Some((_, url)) = var("KV_OBJECT_STORE_URL") {
+            Some(BackendConfig::ObjectStore { url })
+        } else if let Some((_, url)) = var("KV_POSTGRES_URL") {
+            Some(BackendConfig::Postgres {
+                url,
+                max_connections: default_max_connections(),
+            })
+        } else {
+            var("KV_FS_ROOT").map(|(_, root)| BackendConfig::Fs { root: root.into() })
+        };
+        if let Some(backend) = backend {
+            self.backend = backend;
+        }
+        if let BackendConfig::Memory {
+            max_entries,
+            max_bytes,
+        } = &mut self.backend
+        {
+            if let Some((name, value)) = var("KV_MAX_ENTRIES") {
+                *max_entries = Some(parse(name, value)?);
+            }
+            if let Some((name, value)) = var("KV_MAX_BYTES") {
+                *max_bytes = Some(parse(name, value)?);
+            }
+        }
+
+        let cache = &mut self.cache;
+        if let Some((name, value)) = var("KV_HOT_CACHE_BYTES") {
+            cache.hot_bytes = Some(parse(name, value)?);
+        }
+        if let Some((name, value)) = var("KV_PREFETCH_KEYS") {
+            cache.prefetch_keys = parse(name, value)?;
+        }
+        if let Some((name, value)) = var("KV_HOT_KEYS_INTERVAL") {
+            cache.snapshot_interval = parse(name, value)?;
+        }
+
+        let write_behind = &mut self.write_behind;
+        if let Some((name, value)) = var("KV_WRITE_BEHIND_MS") {
+            write_behind.max_delay_ms = Some(parse(name, value)?);
+        }
+        if let Some((name, value)) = var("KV_WRITE_BEHIND_MAX_WRITES") {
+            write_behind.max_writes = parse(name, value)?;
+        }
+        if let Some((name, value)) = var("KV_WRITE_BEHIND_MAX_BYTES") {
+            write_behind.max_bytes = parse(name, value)?;
+        }
+
+        let limits = &mut self.limits;
+        if let Some((name, value)) = var("KV_MAX_BODY_BYTES") {
+            limits.max_body_bytes = Some(parse(name, value)?);
+        }
+        if let Some((_, value)) = var("KV_ALLOWED_CONTENT_TYPES") {
+            limits.allowed_content_types = list(&value);
+        }
+        if let Some((name, value)) = var("KV_RATE_LIMIT_READS") {
+            limits.rate.reads = Some(parse(name, value)?);
+        }
+        if let Some((name, value)) = var("KV_RATE_LIMIT_WRITES") {
+            limits.rate.writes = Some(parse(name, value)?);
+        }
+        if let Some((name, value)) = var("KV_RATE_LIMIT_TRANSFORMS") {
+            limits.rate.transforms = Some(parse(name, value)?);
+        }
+        if let Some((name, value)) = var("KV_REQUEST_TIMEOUT_MS") {
+            limits.request_timeout_ms = Some(parse(name, value)?);
+        }
+        if let Some((name, value)) = var("KV_MAX_KEY_LENGTH") {
+            limits.max_key_length = Some(parse(name, value)?);
+        }
+        if let Some((name, value)) = var("KV_MAX_KEY_DEPTH") {
+            limits.max_key_depth = Some(parse(name, value)?);
+        }
+        if let Some((name, value)) = var("KV_FLOOD_WINDOW") {
+            limits.flood.get_or_insert_with(FloodConfig::default).window = parse(name, value)?;
+        }
+        if let Some((name, value)) = var("KV_FLOOD_MAX_WRITES_PER_KEY") {
+            limits
+                .flood
+                .get_or_insert_with(FloodConfig::default)
+                .max_writes_per_key = parse(name, value)?;
+        }
+        if let Some((name, value)) = var("KV_FLOOD_MAX_NEW_KEYS") {
+            limits
+                .flood
+                .get_or_insert_with(FloodConfig::default)
+                .max_new_keys_per_client = parse(name, value)?;
+        }
+        if let Some((name, value)) = var("KV_FLOOD_COOLDOWN") {
+            limits
+                .flood
+                .get_or_insert_with(FloodConfig::default)
+                .cooldown = parse(name, value)?;
+        }
+
+        let auth = &mut self.auth;
+        if let Some((_, value)) = var("KV_API_KEYS") {
+            auth.api_keys = list(&value);
+        }
+        if let Some((_, secret)) = var("KV_JWT_SECRET") {
+            auth.jwt_secret = Some(secret);
+        }
+        if let Some((_, path)) = var("KV_JWKS_PATH") {
+            auth.jwks_path = Some(path.into());
+        }
+        if let Some((_, issuer)) = var("KV_JWT_ISSUER") {
+            auth.jwt_issuer = Some(issuer);
+        }
+        if let Some((_, audience)) = var("KV_JWT_AUDIENCE") {
+            auth.jwt_audience = Some(audience);
+        }
+        if let Some((_, value)) = var("KV_PUBLIC_READS") {
+            auth.public_reads = value == "true";
+        }
+
+        let cors = &mut self.cors;
+        if let Some((_, value)) = var("KV_CORS_ORIGINS") {
+            cors.origins = list(&value);
+        }
+        if let Some((_, value)) = var("KV_CORS_METHODS") {
+            cors.methods = Some(list(&value));
+        }
+        if let Some((_, value)) = var("KV_CORS_HEADERS") {
+            cors.headers = Some(list(&value));
+        }
+        if let Some((name, value)) = var("KV_CORS_MAX_AGE") {
+            cors.max_age = Some(parse(name, value)?);
+        }
+
+        if let Some((_, addr)) = var("KV_STATSD_ADDR") {
+            self.metrics.statsd_addr = Some(addr);
+        }
+        if let Some((_, addr)) = var("KV_CLAMD_ADDR") {
+            self.virus_scan.clamd_addr = Some(addr);
+        }
+        if let Some((name, value)) = var("KV_CLAMD_TIMEOUT_MS") {
+            self.virus_scan.timeout_ms = Some(parse(name, value)?);
+        }
+        if let Some((_, value)) = var("KV_SITES") {
+            self.sites = list(&value);
+        }
+        if let Some((_, value)) = var("KV_MOUNTS") {
+            self.mounts = list(&value).into_iter().map(PathBuf::from).collect();
+        }
+        Ok(self)
+    }
+
+    /// Checks what parsing can't, like the TLS certificate, without
+    /// connecting to the backend
+    pub fn check(&self) -> Result<(), BoxError> {
+        self.backend.check_feature()?;
+        if let Some(tls) = &self.server.tls {
+            tls.server_config()?;
+        }
+        if let (None, Some(path)) = (&self.auth.jwt_secret, &self.auth.jwks_path) {
+            JwtAuth::jwks(&std::fs::read_to_string(path)?)?;
+        }
+        self.cors_options()?;
+        for path in &self.mounts {
+            open_bundle(path)?;
+        }
+        Ok(())
+    }
+
+    pub fn server_options(&self) -> ServerOptions {
+        let server = &self.server;
+        let interval = |secs| (secs > 0).then(|| Duration::from_secs(secs));
+        ServerOptions {
+            backlog: server.backlog,
+            tcp_nodelay: server.tcp_nodelay,
+            tcp_keepalive: interval(server.tcp_keepalive),
+            http1_keepalive: server.http1_keepalive,
+            http2_max_concurrent_streams: (server.http2_max_concurrent_streams > 0)
+                .then_some(server.http2_max_concurrent_streams),
+            http2_keep_alive_interval: interval(server.http2_keep_alive_interval),
+            http2_keep_alive_timeout: Duration::from_secs(server.http2_keep_alive_timeout),
+            tls: server.tls.clone(),
+            ..ServerOptions::default()
+        }
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.server.shutdown_timeout)
+    }
+
+    /// The state the routers serve, connected to the configured backend
+    pub async fn app_state(&self) -> Result<AppState, BoxError> {
+        let app_state = AppState::default();
+        #[cfg(feature = "prometheus")]
+        let app_state = app_state.with_metrics(crate::PrometheusMetrics::new());
+        let app_state = match &self.metrics.statsd_addr {
+            Some(addr) => app_state.with_metrics(StatsdMetrics::new(addr.as_str(), "kv.")?),
+            None => app_state,
+        };
+
+        let limits = &self.limits;
+        let app_state = match limits.max_body_bytes {
+            Some(max_body_bytes) => app_state.with_request_limits(RequestLimits {
+                max_body_bytes,
+                ..RequestLimits::default()
+            }),
+            None => app_state,
+        };
+        let app_state = if limits.allowed_content_types.is_empty() {
+            app_state
+        } else {
+            app_state.with_content_types(
+                limits
+                    .allowed_content_types
+                    .iter()
+                    .map(String::as_str)
+                    .fold(ContentTypePolicy::default(), ContentTypePolicy::allow),
+            )
+        };
+        let rate = &limits.rate;
+        let app_state =
+            if rate.reads.is_some() || rate.writes.is_some() || rate.transforms.is_some() {
+                app_state.with_rate_limits(rate.clone())
+            } else {
+                app_state
+            };
+        let app_state = match limits.request_timeout_ms {
+            Some(timeout) => app_state.with_request_timeout(Duration::from_millis(timeout)),
+            None => app_state,
+        };
+        let default_keys = KeyLimits::default();
+        let app_state = app_state.with_key_limits(KeyLimits {
+            max_length: limits.max_key_length.unwrap_or(default_keys.max_length),
+            max_depth: limits.max_key_depth.unwrap_or(default_keys.max_depth),
+        });
+        let app_state = match &limits.flood {
+            Some(flood) => app_state.with_flood_limits(flood.limits()),
+            None => app_state,
+        };
+        let app_state = match &self.virus_scan.clamd_addr {
+            Some(addr) => {
+                let mut scanner = ClamdScanner::new(addr);
+                if let Some(timeout) = self.virus_scan.timeout_ms {
+                    scanner = scanner.with_timeout(Duration::from_millis(timeout));
+                }
+                app_state.with_virus_scanner(scanner)
+            }
+            None => app_state,
+        };
+        let app_state = self
+            .sites
+            .iter()
+            .fold(app_state, |app_state, site| {
+                app_state.with_static_site(site)
+            })
+            .with_read_only(self.server.read_only);
+
+        let auth = &self.auth;
+        let app_state = if auth.api_keys.is_empty() {
+            app_state
+        } else {
+            app_state.with_api_keys(ApiKeys::new(&auth.api_keys))
+        };
+        let jwt = match (&auth.jwt_secret, &auth.jwks_path) {
+            (Some(secret), _) => Some(JwtAuth::secret(secret)),
+            (_, Some(path)) => Some(JwtAuth::jwks(&std::fs::read_to_string(path)?)?),
+            _ => None,
+        };
+        let app_state = match jwt {
+            Some(mut jwt) => {
+                if let Some(issuer) = &auth.jwt_issuer {
+                    jwt = jwt.with_issuer(issuer);
+                }
+                if let Some(audience) = &auth.jwt_audience {
+                    jwt = jwt.with_audience(audience);
+                }
+                app_state.with_jwt(jwt)
+            }
+            None => app_state,
+        };
+        let app_state = app_state.with_public_reads(auth.public_reads);
+
+        let app_state = match self.cors_options()? {
+            Some(cors) => app_state.with_cors(cors),
+            None => app_state,
+        };
+
+        let mut app_state = self.with_backend(app_state).await?;
+        for path in &self.mounts {
+            let bundle = open_bundle(path)?;
+            let namespace = bundle.namespace().to_string();
+            let mount = Mount {
+                db: Database::new(bundle),
+                source: Some(MountSource::Bundle { path: path.clone() }),
+            };
+            app_state.mount(namespace, mount);
+        }
+        app_state.acls = load_acls(&app_state.db).await?;
+        load_holds(&app_state.db, &app_state.holds).await?;
+        load_records(&mut app_state).await?;
+        Ok(app_state)
+    }
+
+    fn cors_options(&self) -> Result<Option<CorsOptions>, BoxError> {
+        let config = &self.cors;
+        if config.origins.is_empty() {
+            return Ok(None);
+        }
+        let mut cors = CorsOptions {
+            allowed_origins: config
+                .origins
+                .iter()
+                .map(|origin| HeaderValue::from_str(origin))
+                .collect::<Result<_, _>>()?,
+            ..CorsOptions::default()
+        };
+        if let Some(methods) = &config.methods {
+            cors.allowed_methods = methods
+                .iter()
+                .map(|method| method.parse())
+                .collect::<Result<_, _>>()?;
+        }
+        if let Some(headers) = &config.headers {
+            cors.allowed_headers = headers
+                .iter()
+                .map(|header| header.parse())
+                .collect::<Result<_, _>>()?;
+        }
+        if let Some(max_age) = config.max_age {
+            cors.max_age = Some(Duration::from_secs(max_age));
+        }
+        Ok(Some(cors))
+    }
+
+    /// Stores entries in `db`, behind the write buffer and the hot cache if
+    /// they are configured
+    #[cfg(any(
+        feature = "sqlite",
+        feature = "postgres",
+        feature = "redis",
+        feature = "object-store"
+    ))]
+    fn with_remote_database(&self, app_state: AppState, db: impl KVDatabase + 'static) -> AppState {
+        match self.write_behind.max_delay_ms {
+            Some(max_delay_ms) => {
+                let limits = BatchLimits {
+                    max_writes: self.write_behind.max_writes,
+                    max_bytes: self.write_behind.max_bytes,
+                    max_delay: Duration::from_millis(max_delay_ms),
+                };
+                let db = crate::WriteBehindDatabase::new(db, limits);
+                self.cache.with_database(app_state, db)
+            }
+            None => self.cache.with_database(app_state, db),
+        }
+    }
+
+    async fn with_backend(&self, app_state: AppState) -> Result<AppState, BoxError> {
+        self.backend.check_feature()?;
+        Ok(match &self.backend {
+            BackendConfig::Memory {
+                max_entries: None,
+                max_bytes: None,
+            } => app_state,
+            BackendConfig::Memory {
+                max_entries,
+                max_bytes,
+            } => {
+                let mut db = BoundedLruDatabase::new(MemoryDatabase::default())
+                    .with_holds(app_state.holds.clone());
+                if let Some(max_entries) = max_entries {
+                    db = db.with_max_entries(*max_entries);
+                }
+                if let Some(max_bytes) = max_bytes {
+                    db = db.with_max_bytes(*max_bytes);
+                }
+                app_state.with_database(db)
+            }
+            BackendConfig::Fs { root } => {
+                self.cache.with_database(app_state, FsDatabase::open(root)?)
+            }
+            #[cfg(feature = "sled")]
+            BackendConfig::Sled { path } => self
+                .cache
+                .with_database(app_state, crate::SledDatabase::open(path)?),
+            #[cfg(feature = "sqlite")]
+            BackendConfig::Sqlite { url } => {
+                self.with_remote_database(app_state, crate::SqliteDatabase::connect(url).await?)
+            }
+            #[cfg(feature = "postgres")]
+            BackendConfig::Postgres {
+                url,
+                max_connections,
+            } => self.with_remote_database(
+                app_state,
+                crate::PostgresDatabase::connect(url, *max_connections).await?,
+            ),
+            #[cfg(feature = "redis")]
+            BackendConfig::Redis { url } => {
+                self.with_remote_database(app_state, crate::RedisDatabase::connect(url).await?)
+            }
+            #[cfg(feature = "object-store")]
+            BackendConfig::ObjectStore { url } => {
+                self.with_remote_database(app_state, crate::ObjectStoreDatabase::from_env(url)?)
+            }
+            #[allow(unreachable_patterns)]
+            _ => unreachable!("Backends of missing features are rejected above"),
+        })
+    }
+}
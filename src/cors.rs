@@ -0,0 +1,72 @@
+use std::time::Duration;
+
+use hyper::{
+    header::{
+        HeaderName, HeaderValue, AUTHORIZATION, CONTENT_TYPE, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+        RANGE,
+    },
+    Method,
+};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+/// Which browser front-ends may call the API directly. CORS is off unless
+/// `AppState::with_cors` sets some origins.
+#[derive(Clone, Debug)]
+pub struct CorsOptions {
+    /// Origins like `https://app.example.com`, `*` allows any
+    pub allowed_origins: Vec<HeaderValue>,
+    pub allowed_methods: Vec<Method>,
+    /// Request headers front-ends may send beyond the safelisted ones
+    pub allowed_headers: Vec<HeaderName>,
+    /// How long browsers may cache the answer to a preflight
+    pub max_age: Option<Duration>,
+}
+
+impl Default for CorsOptions {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: vec![Method::GET, Method::HEAD, Method::POST, Method::DELETE],
+            allowed_headers: vec![
+                AUTHORIZATION,
+                CONTENT_TYPE,
+                IF_MODIFIED_SINCE,
+                IF_NONE_MATCH,
+                RANGE,
+                HeaderName::from_static("content-md5"),
+                HeaderName::from_static("x-api-key"),
+                HeaderName::from_static("x-burn-after-read"),
+                HeaderName::from_static("x-checksum-sha256"),
+                HeaderName::from_static("x-request-deadline"),
+                HeaderName::from_static("x-request-id"),
+                HeaderName::from_static("x-ttl-seconds"),
+            ],
+            max_age: Some(Duration::from_secs(600)),
+        }
+    }
+}
+
+impl CorsOptions {
+    pub(crate) fn layer(&self) -> CorsLayer {
+        let origins = if self.allowed_origins.iter().any(|origin| origin == "*") {
+            AllowOrigin::any()
+        } else {
+            AllowOrigin::list(self.allowed_origins.iter().cloned())
+        };
+        let layer = CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(self.allowed_methods.clone())
+            .allow_headers(self.allowed_headers.clone())
+            // Readable by scripts besides the safelisted ones
+            .expose_headers([
+                HeaderName::from_static("etag"),
+                HeaderName::from_static("retry-after"),
+                HeaderName::from_static("x-error-code"),
+                HeaderName::from_static("x-request-id"),
+            ]);
+        match self.max_age {
+            Some(max_age) => layer.max_age(max_age),
+            None => layer,
+        }
+    }
+}
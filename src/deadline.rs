@@ -0,0 +1,81 @@
+//! Deadlines for requests, so a slow backend is given up on instead of
+//! holding a connection long after the client stopped waiting. Backend
+//! reads made while handling a request are cancelled at its deadline,
+//! writes it started still complete, see `Database`.
+
+use std::{future::Future, time::Duration};
+
+use axum::{
+    extract::State,
+    http::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hyper::header::HeaderName;
+use tokio::time::{error::Elapsed, Instant};
+
+use crate::{KVError, SharedState};
+
+/// Milliseconds the caller will wait for the answer. Relative rather than
+/// a point in time, so clocks of different hosts needn't agree. Proxies
+/// forwarding the request pass on what is left.
+static X_REQUEST_DEADLINE: HeaderName = HeaderName::from_static("x-request-deadline");
+
+tokio::task_local! {
+    static DEADLINE: Instant;
+}
+
+fn requested_budget<B>(request: &Request<B>) -> Result<Option<Duration>, KVError> {
+    let Some(value) = request.headers().get(&X_REQUEST_DEADLINE) else {
+        return Ok(None);
+    };
+    value
+        .to_str()
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .map(|millis| Some(Duration::from_millis(millis)))
+        .ok_or_else(|| {
+            KVError::BadRequest(format!(
+                "{} must be a number of milliseconds",
+                X_REQUEST_DEADLINE
+            ))
+        })
+}
+
+/// Answers 504 once the configured request timeout or the caller's
+/// `X-Request-Deadline`, whichever is sooner, has passed
+pub async fn enforce_deadline<B>(
+    State(state): State<SharedState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let timeout = state.read().expect("What, an error here?").request_timeout;
+    let requested = match requested_budget(&request) {
+        Ok(requested) => requested,
+        Err(error) => return error.into_response(),
+    };
+    let budget = match (timeout, requested) {
+        (Some(timeout), Some(requested)) => timeout.min(requested),
+        (Some(budget), None) | (None, Some(budget)) => budget,
+        (None, None) => return next.run(request).await,
+    };
+    let started = Instant::now();
+    let deadline = started + budget;
+    let handled = DEADLINE.scope(deadline, next.run(request));
+    match tokio::time::timeout_at(deadline, handled).await {
+        Ok(response) => response,
+        Err(_) => KVError::DeadlineExceeded {
+            operation: "request".to_string(),
+            elapsed: started.elapsed(),
+        }
+        .into_response(),
+    }
+}
+
+/// Runs `call` until the deadline of the request it is made for, if any
+pub(crate) async fn before_deadline<F: Future>(call: F) -> Result<F::Output, Elapsed> {
+    match DEADLINE.try_with(|deadline| *deadline) {
+        Ok(deadline) => tokio::time::timeout_at(deadline, call).await,
+        Err(_) => Ok(call.await),
+    }
+}
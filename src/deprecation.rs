@@ -0,0 +1,64 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use axum::{
+    extract::{MatchedPath, State},
+    http::Request,
+    middleware::Next,
+    response::Response,
+};
+use hyper::header::{HeaderValue, LINK};
+
+use crate::SharedState;
+
+/// Metadata of a route that still works but is on its way out
+pub struct DeprecatedRoute {
+    /// The route as registered, e.g. `/kv/:key/grayscale`
+    pub path: &'static str,
+    /// Unix time the route was deprecated at
+    pub deprecated_at: u64,
+    /// Unix time after which the route may be removed
+    pub sunset_at: Option<u64>,
+    /// Where clients should move to
+    pub successor: Option<&'static str>,
+}
+
+/// Announces deprecated routes with `Deprecation` (RFC 9745), `Sunset`
+/// (RFC 8594) and a successor `Link`, and counts who still calls them.
+/// Needs the matched route, so it goes in a `route_layer`.
+pub async fn deprecation_headers<B>(
+    State((state, routes)): State<(SharedState, &'static [DeprecatedRoute])>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .and_then(|matched| routes.iter().find(|route| route.path == matched.as_str()));
+    let Some(route) = route else {
+        return next.run(request).await;
+    };
+    let metrics = state.read().expect("What, an error here?").metrics.clone();
+    metrics.counter("kv_deprecated_requests_total", &[("route", route.path)], 1);
+
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    let deprecation = format!("@{}", route.deprecated_at);
+    headers.insert(
+        "deprecation",
+        HeaderValue::from_str(&deprecation).expect("Digits are a valid header"),
+    );
+    if let Some(sunset_at) = route.sunset_at {
+        let sunset = httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(sunset_at));
+        headers.insert(
+            "sunset",
+            HeaderValue::from_str(&sunset).expect("HTTP dates are a valid header"),
+        );
+    }
+    if let Some(successor) = route.successor {
+        let link = format!("<{}>; rel=\"successor-version\"", successor);
+        if let Ok(link) = HeaderValue::from_str(&link) {
+            headers.append(LINK, link);
+        }
+    }
+    response
+}
@@ -0,0 +1,274 @@
+//! Single-file snapshots of a namespace, for edge nodes that serve content
+//! without a connection to the origin store. A bundle is laid out as
+//!
+//! ```text
+//! "KVPACK\0\x01"       magic and format version
+//! values               back to back
+//! index                JSON: the namespace and key, content type, offset
+//!                      and length of every entry
+//! index offset         u64, little endian
+//! index length         u64, little endian
+//! checksum             SHA-256 of everything before it
+//! ```
+
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    io::{self, ErrorKind, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use futures::{StreamExt, TryStreamExt};
+use hyper::body::Bytes;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter},
+};
+use tokio_util::io::ReaderStream;
+
+use crate::{
+    kv_store::{KVDatabase, KVError, ValueStream, INTERNAL_NAMESPACE},
+    SharedState,
+};
+
+const MAGIC: &[u8; 8] = b"KVPACK\0\x01";
+/// Index offset, index length and checksum
+const FOOTER: u64 = 8 + 8 + 32;
+/// Size of the chunks values are streamed in
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Serialize, Deserialize)]
+struct Index {
+    namespace: String,
+    entries: Vec<IndexEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct IndexEntry {
+    key: String,
+    content_type: String,
+    offset: u64,
+    len: u64,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+/// Writes every entry of `namespace` to a bundle at `path` and returns how
+/// many there were. The bundle appears at `path` only once it's complete,
+/// and not at all if an entry no longer matches its stored checksum.
+pub async fn export_bundle(
+    state: &SharedState,
+    namespace: &str,
+    path: &Path,
+) -> Result<usize, KVError> {
+    if namespace.is_empty() || namespace.contains('/') || namespace == INTERNAL_NAMESPACE {
+        return Err(KVError::BadRequest(format!(
+            "Can't bundle the namespace {:?}",
+            namespace
+        )));
+    }
+    let db = state.read()?.db.clone();
+    let mut keys = db.keys(&format!("{}/", namespace)).await?;
+    keys.sort();
+
+    let mut temp = OsString::from(path);
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    let mut file = BufWriter::new(fs::File::create(&temp).await?);
+    let mut checksum = Sha256::new();
+    file.write_all(MAGIC).await?;
+    checksum.update(MAGIC);
+    let mut offset = MAGIC.len() as u64;
+    let mut index = Index {
+        namespace: namespace.to_string(),
+        entries: Vec::with_capacity(keys.len()),
+    };
+    for key in keys {
+        // Removed since it was listed
+        let Some(mut value) = db.read_stream(&key).await? else {
+            continue;
+        };
+        let mut len = 0;
+        let mut digest = Sha256::new();
+        while let Some(chunk) = value.chunks.try_next().await? {
+            file.write_all(&chunk).await?;
+            checksum.update(&chunk);
+            digest.update(&chunk);
+            len += chunk.len() as u64;
+        }
+        // Entries with a stored checksum are re-verified on the way out
+        let stored = state
+            .read()?
+            .metadata
+            .get(&key)
+            .map(|metadata| metadata.sha256);
+        if stored.is_some_and(|sha256| sha256[..] != digest.finalize()[..]) {
+            drop(file);
+            let _ = fs::remove_file(&temp).await;
+            return Err(KVError::backend(format!(
+                "{} doesn't match its stored checksum",
+                key
+            )));
+        }
+        index.entries.push(IndexEntry {
+            key,
+            content_type: value.content_type,
+            offset,
+            len,
+        });
+        offset += len;
+    }
+
+    let encoded = serde_json::to_vec(&index).map_err(KVError::internal)?;
+    let mut footer = Vec::with_capacity(encoded.len() + FOOTER as usize);
+    footer.extend_from_slice(&encoded);
+    footer.extend_from_slice(&offset.to_le_bytes());
+    footer.extend_from_slice(&(encoded.len() as u64).to_le_bytes());
+    checksum.update(&footer);
+    footer.extend_from_slice(&checksum.finalize());
+    file.write_all(&footer).await?;
+    file.flush().await?;
+    file.into_inner().sync_all().await?;
+    fs::rename(&temp, path).await?;
+    Ok(index.entries.len())
+}
+
+/// Serves the entries of a bundle written by `export_bundle`, read-only.
+/// The checksum is verified once on open, the file is expected not to
+/// change afterwards.
+pub struct BundleDatabase {
+    path: PathBuf,
+    namespace: String,
+    entries: HashMap<String, IndexEntry>,
+}
+
+impl BundleDatabase {
+    /// Reads the index of the bundle at `path` after verifying its checksum
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let mut file = std::fs::File::open(&path)?;
+        let len = file.metadata()?.len();
+        if len < MAGIC.len() as u64 + FOOTER {
+            return Err(invalid("Not a bundle"));
+        }
+
+        let mut magic = [0; MAGIC.len()];
+        file.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("Not a bundle, or of an unknown version"));
+        }
+        let mut footer = [0; FOOTER as usize];
+        file.seek(SeekFrom::Start(len - FOOTER))?;
+        file.read_exact(&mut footer)?;
+        file.rewind()?;
+        let mut checksum = Sha256::new();
+        io::copy(&mut (&mut file).take(len - 32), &mut checksum)?;
+        if checksum.finalize()[..] != footer[16..] {
+            return Err(invalid("Bundle checksum mismatch"));
+        }
+
+        let index_offset = u64::from_le_bytes(footer[..8].try_into().expect("8 bytes"));
+        let index_len = u64::from_le_bytes(footer[8..16].try_into().expect("8 bytes"));
+        if index_offset.checked_add(index_len) != Some(len - FOOTER) {
+            return Err(invalid("Bundle index is out of place"));
+        }
+        file.seek(SeekFrom::Start(index_offset))?;
+        let mut encoded = vec![0; index_len as usize];
+        file.read_exact(&mut encoded)?;
+        let index: Index = serde_json::from_slice(&encoded).map_err(io::Error::from)?;
+        let prefix = format!("{}/", index.namespace);
+        if index.entries.iter().any(|entry| {
+            entry.offset.saturating_add(entry.len) > index_offset || !entry.key.starts_with(&prefix)
+        }) {
+            return Err(invalid("Bundle index is inconsistent"));
+        }
+        Ok(Self {
+            path,
+            namespace: index.namespace,
+            entries: index
+                .entries
+                .into_iter()
+                .map(|entry| (entry.key.clone(), entry))
+                .collect(),
+        })
+    }
+
+    /// The namespace the bundle was written from, all its keys are in it
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    async fn open_value(&self, key: &str) -> Result<Option<(&IndexEntry, fs::File)>, KVError> {
+        let Some(entry) = self.entries.get(key) else {
+            return Ok(None);
+        };
+        let mut file = fs::File::open(&self.path).await?;
+        file.seek(SeekFrom::Start(entry.offset)).await?;
+        Ok(Some((entry, file)))
+    }
+
+    fn read_only() -> KVError {
+        KVError::Forbidden("Bundles are read-only".to_string())
+    }
+}
+
+#[async_trait]
+impl KVDatabase for BundleDatabase {
+    async fn read(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        let Some((entry, file)) = self.open_value(key).await? else {
+            return Ok(None);
+        };
+        let mut data = Vec::with_capacity(entry.len as usize);
+        file.take(entry.len).read_to_end(&mut data).await?;
+        Ok(Some((entry.content_type.clone(), data.into())))
+    }
+
+    async fn read_stream(&self, key: &str) -> Result<Option<ValueStream>, KVError> {
+        let Some((entry, file)) = self.open_value(key).await? else {
+            return Ok(None);
+        };
+        Ok(Some(ValueStream {
+            content_type: entry.content_type.clone(),
+            len: entry.len,
+            chunks: ReaderStream::with_capacity(file.take(entry.len), CHUNK_SIZE)
+                .map_err(KVError::from)
+                .boxed(),
+        }))
+    }
+
+    async fn insert(&self, _key: String, _value: (String, Bytes)) -> Result<(), KVError> {
+        Err(Self::read_only())
+    }
+
+    async fn insert_file(
+        &self,
+        _key: String,
+        _content_type: String,
+        path: &Path,
+    ) -> Result<(), KVError> {
+        let _ = fs::remove_file(path).await;
+        Err(Self::read_only())
+    }
+
+    async fn remove(&self, _key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        Err(Self::read_only())
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, KVError> {
+        Ok(self
+            .entries
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+
+    async fn contains(&self, key: &str) -> Result<bool, KVError> {
+        Ok(self.entries.contains_key(key))
+    }
+}
@@ -0,0 +1,187 @@
+use std::{
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use futures::{StreamExt, TryStreamExt};
+use hyper::body::Bytes;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use tokio::fs;
+use tokio_util::io::ReaderStream;
+
+use crate::kv_store::{KVDatabase, KVError, ValueStream};
+
+/// Size of the chunks values are streamed in
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Everything but `-` and `_` is encoded, so a file name never contains a
+/// `/`, is never `.` or `..` and has no dot besides its extension
+const FILE_NAME: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_');
+
+const DATA: &str = ".data";
+const META: &str = ".meta";
+
+/// The longest file name common file systems take
+const NAME_MAX: usize = 255;
+
+/// Keeps every value in a file under `root`, with the content type in a
+/// `.meta` file next to it. Keys become percent-encoded file names, which
+/// limits them to a third of the file system's name length.
+#[derive(Clone)]
+pub struct FsDatabase {
+    root: PathBuf,
+}
+
+impl FsDatabase {
+    /// Stores entries in the directory `root`, creating it if needed
+    pub fn open(root: impl Into<PathBuf>) -> io::Result<Self> {
+        let root = root.into();
+        std::fs::create_dir_all(&root)?;
+        Ok(Self { root })
+    }
+
+    fn path(&self, key: &str, extension: &str) -> PathBuf {
+        let name = utf8_percent_encode(key, FILE_NAME).to_string();
+        self.root.join(name + extension)
+    }
+
+    /// A scratch file in `root`, hidden from `keys` by its leading dot
+    fn temp_path(&self) -> PathBuf {
+        self.root
+            .join(format!(".tmp-{:016x}", rand::random::<u64>()))
+    }
+
+    /// Writes through a temporary file, readers never see half a file
+    async fn write_atomic(&self, path: PathBuf, contents: &[u8]) -> io::Result<()> {
+        let temp = self.temp_path();
+        fs::write(&temp, contents).await?;
+        fs::rename(&temp, path).await
+    }
+}
+
+impl From<io::Error> for KVError {
+    fn from(error: io::Error) -> Self {
+        KVError::backend(error)
+    }
+}
+
+async fn read_if_exists(path: PathBuf) -> io::Result<Option<Vec<u8>>> {
+    match fs::read(path).await {
+        Ok(contents) => Ok(Some(contents)),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+#[async_trait]
+impl KVDatabase for FsDatabase {
+    async fn read(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        let Some(content_type) = read_if_exists(self.path(key, META)).await? else {
+            return Ok(None);
+        };
+        let Some(data) = read_if_exists(self.path(key, DATA)).await? else {
+            return Ok(None);
+        };
+        let content_type = String::from_utf8_lossy(&content_type).into_owned();
+        Ok(Some((content_type, data.into())))
+    }
+
+    async fn read_stream(&self, key: &str) -> Result<Option<ValueStream>, KVError> {
+        let Some(content_type) = read_if_exists(self.path(key, META)).await? else {
+            return Ok(None);
+        };
+        let file = match fs::File::open(self.path(key, DATA)).await {
+            Ok(file) => file,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+        // Writes replace the file, the open one keeps its contents
+        let len = file.metadata().await?.len();
+        Ok(Some(ValueStream {
+            content_type: String::from_utf8_lossy(&content_type).into_owned(),
+            len,
+            chunks: ReaderStream::with_capacity(file, CHUNK_SIZE)
+                .map_err(KVError::from)
+                .boxed(),
+        }))
+    }
+
+    async fn insert(
+        &self,
+        key: String,
+        (content_type, data): (String, Bytes),
+    ) -> Result<(), KVError> {
+        // The data file appears last, an entry without it doesn't exist yet
+        self.write_atomic(self.path(&key, META), content_type.as_bytes())
+            .await?;
+        self.write_atomic(self.path(&key, DATA), &data).await?;
+        Ok(())
+    }
+
+    async fn insert_file(
+        &self,
+        key: String,
+        content_type: String,
+        path: &Path,
+    ) -> Result<(), KVError> {
+        // Renaming only works within a file system, copy next to the entry first
+        let temp = self.temp_path();
+        if fs::rename(path, &temp).await.is_err() {
+            fs::copy(path, &temp).await?;
+        }
+        self.write_atomic(self.path(&key, META), content_type.as_bytes())
+            .await?;
+        fs::rename(&temp, self.path(&key, DATA)).await?;
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        // Only one of several concurrent renames finds the file
+        let taken = self.temp_path();
+        match fs::rename(self.path(key, DATA), &taken).await {
+            Ok(()) => {}
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        }
+        let data = fs::read(&taken).await?;
+        fs::remove_file(&taken).await?;
+        let content_type = read_if_exists(self.path(key, META)).await?;
+        match fs::remove_file(self.path(key, META)).await {
+            Ok(()) => {}
+            Err(error) if error.kind() == ErrorKind::NotFound => {}
+            Err(error) => return Err(error.into()),
+        }
+        let content_type = content_type.map_or_else(
+            || "application/octet-stream".to_string(),
+            |content_type| String::from_utf8_lossy(&content_type).into_owned(),
+        );
+        Ok(Some((content_type, data.into())))
+    }
+
+    /// Percent-encoding can triple a key, whatever it consists of has to fit
+    fn max_key_length(&self) -> Option<usize> {
+        Some((NAME_MAX - DATA.len().max(META.len())) / 3)
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, KVError> {
+        let mut keys = Vec::new();
+        let mut entries = fs::read_dir(&self.root).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let Some(encoded) = name.to_str().and_then(|name| name.strip_suffix(DATA)) else {
+                continue;
+            };
+            if let Ok(key) = percent_decode_str(encoded).decode_utf8() {
+                if key.starts_with(prefix) {
+                    keys.push(key.into_owned());
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    async fn contains(&self, key: &str) -> Result<bool, KVError> {
+        Ok(fs::try_exists(self.path(key, DATA)).await?)
+    }
+}
@@ -0,0 +1,194 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+use async_trait::async_trait;
+use hyper::body::Bytes;
+
+use crate::{
+    kv_store::{KVDatabase, KVError},
+    LegalHolds,
+};
+
+#[derive(Default)]
+struct Recency {
+    /// Last use and size of every tracked key
+    entries: HashMap<String, (u64, usize)>,
+    /// Tracked keys by last use, least recent first
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    bytes: usize,
+}
+
+impl Recency {
+    fn touch(&mut self, key: &str, size: Option<usize>) {
+        self.tick += 1;
+        let tick = self.tick;
+        match self.entries.get_mut(key) {
+            Some((last_use, old_size)) => {
+                self.order.remove(last_use);
+                *last_use = tick;
+                if let Some(size) = size {
+                    self.bytes = self.bytes - *old_size + size;
+                    *old_size = size;
+                }
+            }
+            // Reads of keys we never saw written don't start tracking them
+            None => match size {
+                Some(size) => {
+                    self.entries.insert(key.to_string(), (tick, size));
+                    self.bytes += size;
+                }
+                None => return,
+            },
+        }
+        self.order.insert(tick, key.to_string());
+    }
+
+    fn forget(&mut self, key: &str) {
+        if let Some((last_use, size)) = self.entries.remove(key) {
+            self.order.remove(&last_use);
+            self.bytes -= size;
+        }
+    }
+
+    /// Untracks least recently used keys until the limits hold again.
+    /// Held keys are skipped, even if that leaves the limits exceeded.
+    fn evict(
+        &mut self,
+        max_entries: Option<usize>,
+        max_bytes: Option<usize>,
+        holds: Option<&LegalHolds>,
+    ) -> Vec<String> {
+        let over = |entries: usize, bytes: usize| {
+            max_entries.is_some_and(|max| entries > max) || max_bytes.is_some_and(|max| bytes > max)
+        };
+        let mut entries = self.entries.len();
+        let mut bytes = self.bytes;
+        let mut victims = Vec::new();
+        for (tick, key) in &self.order {
+            if !over(entries, bytes) {
+                break;
+            }
+            if holds.is_some_and(|holds| holds.is_held(key)) {
+                continue;
+            }
+            entries -= 1;
+            bytes -= self.entries[key].1;
+            victims.push((*tick, key.clone()));
+        }
+        victims
+            .into_iter()
+            .map(|(tick, key)| {
+                self.order.remove(&tick);
+                if let Some((_, size)) = self.entries.remove(&key) {
+                    self.bytes -= size;
+                }
+                key
+            })
+            .collect()
+    }
+}
+
+/// Caps the number of entries and/or the bytes stored in `inner`, evicting
+/// the least recently used keys to make room. Only entries written through
+/// the wrapper count, what `inner` held before is never evicted.
+pub struct BoundedLruDatabase<T> {
+    inner: T,
+    max_entries: Option<usize>,
+    max_bytes: Option<usize>,
+    holds: Option<LegalHolds>,
+    recency: Mutex<Recency>,
+}
+
+impl<T: KVDatabase> BoundedLruDatabase<T> {
+    /// Without limits until `with_max_entries` or `with_max_bytes` is set
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            max_entries: None,
+            max_bytes: None,
+            holds: None,
+            recency: Mutex::default(),
+        }
+    }
+
+    /// Keep at most `max_entries` keys
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = Some(max_entries);
+        self
+    }
+
+    /// Keep at most `max_bytes` of values, content types and keys not counted
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Never evict keys under these holds
+    pub fn with_holds(mut self, holds: LegalHolds) -> Self {
+        self.holds = Some(holds);
+        self
+    }
+}
+
+#[async_trait]
+impl<T: KVDatabase> KVDatabase for BoundedLruDatabase<T> {
+    async fn read(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        let entry = self.inner.read(key).await?;
+        if entry.is_some() {
+            self.recency.lock()?.touch(key, None);
+        }
+        Ok(entry)
+    }
+
+    async fn insert(&self, key: String, value: (String, Bytes)) -> Result<(), KVError> {
+        let size = value.1.len();
+        if self.max_bytes.is_some_and(|max| size > max) {
+            return Err(KVError::TooLarge(
+                "Value is larger than the whole store".to_string(),
+            ));
+        }
+        self.inner.insert(key.clone(), value).await?;
+        let victims = {
+            let mut recency = self.recency.lock()?;
+            recency.touch(&key, Some(size));
+            recency.evict(self.max_entries, self.max_bytes, self.holds.as_ref())
+        };
+        for victim in victims {
+            self.inner.remove(&victim).await?;
+        }
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        let entry = self.inner.remove(key).await?;
+        self.recency.lock()?.forget(key);
+        Ok(entry)
+    }
+
+    fn max_key_length(&self) -> Option<usize> {
+        self.inner.max_key_length()
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, KVError> {
+        self.inner.keys(prefix).await
+    }
+
+    async fn contains(&self, key: &str) -> Result<bool, KVError> {
+        self.inner.contains(key).await
+    }
+
+    async fn hot_keys(&self, n: usize) -> Result<Vec<String>, KVError> {
+        self.inner.hot_keys(n).await
+    }
+
+    async fn prefetch(&self, keys: &[String]) -> Result<usize, KVError> {
+        self.inner.prefetch(keys).await
+    }
+
+    async fn close(&self) -> Result<(), KVError> {
+        self.inner.close().await
+    }
+}
@@ -0,0 +1,80 @@
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::BuildHasher,
+    sync::RwLock,
+};
+
+use async_trait::async_trait;
+use hyper::body::Bytes;
+
+use crate::kv_store::{KVDatabase, KVError};
+
+/// Shards of the default backend, enough that writers on a many-core
+/// machine rarely wait for each other
+const DEFAULT_SHARDS: usize = 64;
+
+type Shard = RwLock<HashMap<String, (String, Bytes)>>;
+
+/// Keeps everything in `HashMap`s, gone when the process exits. Keys are
+/// spread over shards with a lock each, so writes to different keys
+/// mostly proceed in parallel.
+pub struct MemoryDatabase {
+    shards: Box<[Shard]>,
+    hasher: RandomState,
+}
+
+impl MemoryDatabase {
+    /// Spreads keys over `shards` locks, a single one serializes all writes
+    pub fn with_shards(shards: usize) -> Self {
+        Self {
+            shards: (0..shards.max(1)).map(|_| Shard::default()).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard(&self, key: &str) -> &Shard {
+        let hash = self.hasher.hash_one(key);
+        &self.shards[hash as usize % self.shards.len()]
+    }
+}
+
+impl Default for MemoryDatabase {
+    fn default() -> Self {
+        Self::with_shards(DEFAULT_SHARDS)
+    }
+}
+
+#[async_trait]
+impl KVDatabase for MemoryDatabase {
+    async fn read(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        Ok(self.shard(key).read()?.get(key).cloned())
+    }
+
+    async fn insert(&self, key: String, value: (String, Bytes)) -> Result<(), KVError> {
+        self.shard(&key).write()?.insert(key, value);
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        Ok(self.shard(key).write()?.remove(key))
+    }
+
+    /// Not a snapshot, keys written meanwhile may or may not be listed
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, KVError> {
+        let mut keys = Vec::new();
+        for shard in self.shards.iter() {
+            keys.extend(
+                shard
+                    .read()?
+                    .keys()
+                    .filter(|key| key.starts_with(prefix))
+                    .cloned(),
+            );
+        }
+        Ok(keys)
+    }
+
+    async fn contains(&self, key: &str) -> Result<bool, KVError> {
+        Ok(self.shard(key).read()?.contains_key(key))
+    }
+}
@@ -0,0 +1,37 @@
+#[cfg(feature = "object-store")]
+pub use self::object_store::ObjectStoreDatabase;
+#[cfg(feature = "redis")]
+pub use self::redis::RedisDatabase;
+#[cfg(feature = "sled")]
+pub use self::sled::SledDatabase;
+pub use bundle::{export_bundle, BundleDatabase};
+pub use fs::FsDatabase;
+pub use lru::BoundedLruDatabase;
+pub use memory::MemoryDatabase;
+pub(crate) use overlay::OverlayDatabase;
+#[cfg(feature = "postgres")]
+pub use postgres::PostgresDatabase;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteDatabase;
+pub use tiered::TieredDatabase;
+pub use write_behind::{BatchLimits, WriteBehindDatabase};
+
+mod bundle;
+mod fs;
+mod lru;
+mod memory;
+#[cfg(feature = "object-store")]
+mod object_store;
+mod overlay;
+#[cfg(feature = "postgres")]
+mod postgres;
+#[cfg(feature = "redis")]
+mod redis;
+#[cfg(feature = "sled")]
+mod sled;
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+mod sql;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod tiered;
+mod write_behind;
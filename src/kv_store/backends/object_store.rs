@@ -0,0 +1,176 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::{stream, StreamExt, TryStreamExt};
+use hyper::body::Bytes;
+use object_store::{
+    path::{Path, PathPart},
+    Attribute, Attributes, GetResult, ObjectStore, PutOptions, PutPayload,
+};
+use percent_encoding::percent_decode_str;
+
+use crate::kv_store::{BatchWrite, KVDatabase, KVError, ValueStream};
+
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+/// Puts of a batch under way at once
+const CONCURRENT_PUTS: usize = 16;
+
+/// Keeps every entry as an object in S3, GCS, Azure or any other
+/// `object_store`, with the content type as object metadata. Values don't
+/// have to fit in memory on the service side.
+///
+/// Every key is one object directly under `prefix`, a `/` in the key is
+/// percent-encoded. Object stores have no atomic read-and-delete, so two
+/// concurrent reads of a burn-after-read entry can both see it.
+#[derive(Clone)]
+pub struct ObjectStoreDatabase {
+    store: Arc<dyn ObjectStore>,
+    prefix: Path,
+}
+
+impl ObjectStoreDatabase {
+    pub fn new(store: impl ObjectStore, prefix: Path) -> Self {
+        Self {
+            store: Arc::new(store),
+            prefix,
+        }
+    }
+
+    /// Opens the store at `url`, e.g. `s3://bucket/kv`. Credentials and
+    /// region come from the usual `AWS_*`, `GOOGLE_*` or `AZURE_*` variables.
+    pub fn from_env(url: &str) -> Result<Self, object_store::Error> {
+        let url = url::Url::parse(url).map_err(|error| object_store::Error::Generic {
+            store: "url",
+            source: Box::new(error),
+        })?;
+        let options = std::env::vars().map(|(key, value)| (key.to_ascii_lowercase(), value));
+        let (store, prefix) = object_store::parse_url_opts(&url, options)?;
+        Ok(Self {
+            store: Arc::from(store),
+            prefix,
+        })
+    }
+
+    fn location(&self, key: &str) -> Path {
+        self.prefix.child(PathPart::from(key))
+    }
+
+    /// Starts downloading `key`, along with its content type
+    async fn get(&self, key: &str) -> Result<Option<(String, GetResult)>, KVError> {
+        let result = match self.store.get(&self.location(key)).await {
+            Ok(result) => result,
+            Err(object_store::Error::NotFound { .. }) => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+        let content_type = result
+            .attributes
+            .get(&Attribute::ContentType)
+            .map_or(DEFAULT_CONTENT_TYPE, |value| value.as_ref())
+            .to_string();
+        Ok(Some((content_type, result)))
+    }
+
+    fn key(location: &Path) -> Option<String> {
+        let encoded = location.filename()?;
+        Some(percent_decode_str(encoded).decode_utf8().ok()?.into_owned())
+    }
+}
+
+impl From<object_store::Error> for KVError {
+    fn from(error: object_store::Error) -> Self {
+        KVError::backend(error)
+    }
+}
+
+#[async_trait]
+impl KVDatabase for ObjectStoreDatabase {
+    async fn read(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        match self.get(key).await? {
+            Some((content_type, result)) => Ok(Some((content_type, result.bytes().await?))),
+            None => Ok(None),
+        }
+    }
+
+    async fn read_stream(&self, key: &str) -> Result<Option<ValueStream>, KVError> {
+        Ok(self
+            .get(key)
+            .await?
+            .map(|(content_type, result)| ValueStream {
+                content_type,
+                len: result.meta.size as u64,
+                chunks: result.into_stream().map_err(KVError::from).boxed(),
+            }))
+    }
+
+    async fn insert(
+        &self,
+        key: String,
+        (content_type, data): (String, Bytes),
+    ) -> Result<(), KVError> {
+        let options = PutOptions {
+            attributes: Attributes::from_iter([(Attribute::ContentType, content_type)]),
+            ..PutOptions::default()
+        };
+        self.store
+            .put_opts(&self.location(&key), PutPayload::from(data), options)
+            .await?;
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        let entry = self.read(key).await?;
+        if entry.is_some() {
+            match self.store.delete(&self.location(key)).await {
+                Ok(()) | Err(object_store::Error::NotFound { .. }) => {}
+                Err(error) => return Err(error.into()),
+            }
+        }
+        Ok(entry)
+    }
+
+    /// Every key is an object of its own, so inserts are concurrent puts.
+    /// Removals are bulk deletes where the store has them, like S3.
+    async fn write_batch(&self, writes: Vec<BatchWrite>) -> Result<(), KVError> {
+        let mut puts = Vec::new();
+        let mut deletes = Vec::new();
+        for write in writes {
+            match write {
+                BatchWrite::Insert { key, value } => puts.push(self.insert(key, value)),
+                BatchWrite::Remove { key } => deletes.push(Ok(self.location(&key))),
+            }
+        }
+        stream::iter(puts)
+            .buffer_unordered(CONCURRENT_PUTS)
+            .try_collect::<()>()
+            .await?;
+        let mut deleted = self.store.delete_stream(stream::iter(deletes).boxed());
+        while let Some(result) = deleted.next().await {
+            match result {
+                Ok(_) | Err(object_store::Error::NotFound { .. }) => {}
+                Err(error) => return Err(error.into()),
+            }
+        }
+        Ok(())
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, KVError> {
+        // Listing works on path segments, and keys are a single segment
+        // each, so list everything and filter
+        let objects: Vec<_> = self.store.list(Some(&self.prefix)).try_collect().await?;
+        let depth = self.prefix.parts().count() + 1;
+        Ok(objects
+            .iter()
+            .filter(|object| object.location.parts().count() == depth)
+            .filter_map(|object| Self::key(&object.location))
+            .filter(|key| key.starts_with(prefix))
+            .collect())
+    }
+
+    async fn contains(&self, key: &str) -> Result<bool, KVError> {
+        match self.store.head(&self.location(key)).await {
+            Ok(_) => Ok(true),
+            Err(object_store::Error::NotFound { .. }) => Ok(false),
+            Err(error) => Err(error.into()),
+        }
+    }
+}
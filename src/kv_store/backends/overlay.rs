@@ -0,0 +1,103 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+use async_trait::async_trait;
+use hyper::body::Bytes;
+
+use crate::kv_store::{namespace, Database, KVDatabase, KVError, ValueStream};
+
+/// Layers a writable backend over read-only ones mounted under namespaces.
+/// Reads fall through to the mount of the key's namespace when `upper` has
+/// no entry, writes always go to `upper`. Entries of a mount can be
+/// shadowed by writing the same key, but not removed.
+pub struct OverlayDatabase {
+    upper: Database,
+    mounts: BTreeMap<String, Database>,
+}
+
+impl OverlayDatabase {
+    pub fn new(upper: Database, mounts: BTreeMap<String, Database>) -> Self {
+        Self { upper, mounts }
+    }
+
+    fn mount_of(&self, key: &str) -> Option<&Database> {
+        namespace(key).and_then(|namespace| self.mounts.get(namespace))
+    }
+}
+
+#[async_trait]
+impl KVDatabase for OverlayDatabase {
+    async fn read(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        match (self.upper.read(key).await?, self.mount_of(key)) {
+            (None, Some(mount)) => mount.read(key).await,
+            (entry, _) => Ok(entry),
+        }
+    }
+
+    async fn read_stream(&self, key: &str) -> Result<Option<ValueStream>, KVError> {
+        match (self.upper.read_stream(key).await?, self.mount_of(key)) {
+            (None, Some(mount)) => mount.read_stream(key).await,
+            (value, _) => Ok(value),
+        }
+    }
+
+    async fn insert(&self, key: String, value: (String, Bytes)) -> Result<(), KVError> {
+        self.upper.insert(key, value).await
+    }
+
+    async fn insert_file(
+        &self,
+        key: String,
+        content_type: String,
+        path: &Path,
+    ) -> Result<(), KVError> {
+        self.upper.insert_file(key, content_type, path).await
+    }
+
+    async fn remove(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        self.upper.remove(key).await
+    }
+
+    /// Mounts are read-only, only `upper` stores keys
+    fn max_key_length(&self) -> Option<usize> {
+        self.upper.max_key_length()
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, KVError> {
+        let mut keys: BTreeSet<String> = self.upper.keys(prefix).await?.into_iter().collect();
+        for (namespace, mount) in &self.mounts {
+            let root = format!("{}/", namespace);
+            if root.starts_with(prefix) || prefix.starts_with(&root) {
+                keys.extend(mount.keys(prefix).await?);
+            }
+        }
+        Ok(keys.into_iter().collect())
+    }
+
+    async fn contains(&self, key: &str) -> Result<bool, KVError> {
+        if self.upper.contains(key).await? {
+            return Ok(true);
+        }
+        match self.mount_of(key) {
+            Some(mount) => mount.contains(key).await,
+            None => Ok(false),
+        }
+    }
+
+    async fn hot_keys(&self, n: usize) -> Result<Vec<String>, KVError> {
+        self.upper.hot_keys(n).await
+    }
+
+    async fn prefetch(&self, keys: &[String]) -> Result<usize, KVError> {
+        self.upper.prefetch(keys).await
+    }
+
+    async fn close(&self) -> Result<(), KVError> {
+        for mount in self.mounts.values() {
+            mount.close().await?;
+        }
+        self.upper.close().await
+    }
+}
@@ -0,0 +1,118 @@
+use async_trait::async_trait;
+use hyper::body::Bytes;
+use sqlx::{
+    postgres::{PgPool, PgPoolOptions},
+    QueryBuilder,
+};
+
+use super::sql::{split_batch, ROWS_PER_STATEMENT, UPSERT};
+use crate::kv_store::{BatchWrite, KVDatabase, KVError};
+
+/// Keeps entries in PostgreSQL, shared by every instance pointing at the
+/// same database
+#[derive(Clone)]
+pub struct PostgresDatabase {
+    pool: PgPool,
+}
+
+impl PostgresDatabase {
+    /// Connects a pool of up to `max_connections` to `url`, e.g.
+    /// `postgres://kv@localhost/kv`, and brings the schema up to date
+    pub async fn connect(url: &str, max_connections: u32) -> Result<Self, sqlx::Error> {
+        let pool = PgPoolOptions::new()
+            .max_connections(max_connections)
+            .connect(url)
+            .await?;
+        sqlx::migrate!("migrations/postgres").run(&pool).await?;
+        Ok(Self::new(pool))
+    }
+
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl KVDatabase for PostgresDatabase {
+    async fn read(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        let row: Option<(String, Vec<u8>)> =
+            sqlx::query_as("SELECT content_type, body FROM kv WHERE key = $1")
+                .bind(key)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(row.map(|(content_type, body)| (content_type, body.into())))
+    }
+
+    async fn insert(
+        &self,
+        key: String,
+        (content_type, data): (String, Bytes),
+    ) -> Result<(), KVError> {
+        sqlx::query(
+            "INSERT INTO kv (key, content_type, body) VALUES ($1, $2, $3)
+             ON CONFLICT (key) DO UPDATE
+             SET content_type = excluded.content_type, body = excluded.body",
+        )
+        .bind(key)
+        .bind(content_type)
+        .bind(&data[..])
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        let row: Option<(String, Vec<u8>)> =
+            sqlx::query_as("DELETE FROM kv WHERE key = $1 RETURNING content_type, body")
+                .bind(key)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(row.map(|(content_type, body)| (content_type, body.into())))
+    }
+
+    /// Multi-row statements in one transaction
+    async fn write_batch(&self, writes: Vec<BatchWrite>) -> Result<(), KVError> {
+        let (inserts, removes) = split_batch(writes);
+        let mut transaction = self.pool.begin().await?;
+        for rows in inserts.chunks(ROWS_PER_STATEMENT) {
+            let mut query = QueryBuilder::new("INSERT INTO kv (key, content_type, body) ");
+            query.push_values(rows, |mut row, (key, (content_type, data))| {
+                row.push_bind(key)
+                    .push_bind(content_type)
+                    .push_bind(&data[..]);
+            });
+            query.push(UPSERT);
+            query.build().execute(&mut *transaction).await?;
+        }
+        if !removes.is_empty() {
+            sqlx::query("DELETE FROM kv WHERE key = ANY($1)")
+                .bind(&removes)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, KVError> {
+        // starts_with instead of LIKE, the prefix may contain wildcards
+        let keys = sqlx::query_scalar("SELECT key FROM kv WHERE starts_with(key, $1)")
+            .bind(prefix)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(keys)
+    }
+
+    async fn contains(&self, key: &str) -> Result<bool, KVError> {
+        let exists = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM kv WHERE key = $1)")
+            .bind(key)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(exists)
+    }
+
+    async fn close(&self) -> Result<(), KVError> {
+        self.pool.close().await;
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+use async_trait::async_trait;
+use hyper::body::Bytes;
+use redis::{aio::ConnectionManager, AsyncCommands, RedisError};
+
+use crate::kv_store::{BatchWrite, KVDatabase, KVError};
+
+const CONTENT_TYPE: &str = "content_type";
+const DATA: &str = "data";
+
+/// Keeps every entry as a Redis hash with the content type next to the
+/// value. Survives restarts and can be shared by several replicas.
+#[derive(Clone)]
+pub struct RedisDatabase {
+    connection: ConnectionManager,
+    prefix: String,
+}
+
+impl RedisDatabase {
+    /// Connects to `url`, e.g. `redis://127.0.0.1/`
+    pub async fn connect(url: &str) -> Result<Self, RedisError> {
+        let client = redis::Client::open(url)?;
+        Ok(Self::new(ConnectionManager::new(client).await?))
+    }
+
+    pub fn new(connection: ConnectionManager) -> Self {
+        Self {
+            connection,
+            prefix: "kv:".to_string(),
+        }
+    }
+
+    /// Store entries under `prefix` instead of `kv:`, to share a Redis
+    /// database between deployments
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    fn redis_key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+}
+
+impl From<RedisError> for KVError {
+    fn from(error: RedisError) -> Self {
+        KVError::backend(error)
+    }
+}
+
+fn entry((content_type, data): (Option<String>, Option<Vec<u8>>)) -> Option<(String, Bytes)> {
+    Some((content_type?, Bytes::from(data?)))
+}
+
+/// Escapes the glob characters `SCAN MATCH` would interpret
+fn escape_pattern(prefix: &str) -> String {
+    let mut pattern = String::with_capacity(prefix.len() + 1);
+    for c in prefix.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern
+}
+
+#[async_trait]
+impl KVDatabase for RedisDatabase {
+    async fn read(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        let mut connection = self.connection.clone();
+        let fields = connection
+            .hget(self.redis_key(key), &[CONTENT_TYPE, DATA])
+            .await?;
+        Ok(entry(fields))
+    }
+
+    async fn insert(
+        &self,
+        key: String,
+        (content_type, data): (String, Bytes),
+    ) -> Result<(), KVError> {
+        let mut connection = self.connection.clone();
+        let fields: [(&str, &[u8]); 2] = [(CONTENT_TYPE, content_type.as_bytes()), (DATA, &data)];
+        connection
+            .hset_multiple::<_, _, _, ()>(self.redis_key(&key), &fields)
+            .await?;
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        let mut connection = self.connection.clone();
+        let key = self.redis_key(key);
+        // Read and delete in one transaction, concurrent removals can't both see the value
+        let (fields,): ((Option<String>, Option<Vec<u8>>),) = redis::pipe()
+            .atomic()
+            .hget(&key, &[CONTENT_TYPE, DATA])
+            .del(&key)
+            .ignore()
+            .query_async(&mut connection)
+            .await?;
+        Ok(entry(fields))
+    }
+
+    /// One pipeline, applied as a transaction
+    async fn write_batch(&self, writes: Vec<BatchWrite>) -> Result<(), KVError> {
+        let mut connection = self.connection.clone();
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for write in &writes {
+            match write {
+                BatchWrite::Insert {
+                    key,
+                    value: (content_type, data),
+                } => {
+                    let fields: [(&str, &[u8]); 2] =
+                        [(CONTENT_TYPE, content_type.as_bytes()), (DATA, data)];
+                    pipe.hset_multiple(self.redis_key(key), &fields).ignore();
+                }
+                BatchWrite::Remove { key } => {
+                    pipe.del(self.redis_key(key)).ignore();
+                }
+            }
+        }
+        pipe.query_async::<_, ()>(&mut connection).await?;
+        Ok(())
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, KVError> {
+        let mut connection = self.connection.clone();
+        let pattern = format!("{}*", escape_pattern(&self.redis_key(prefix)));
+        let mut iter = connection.scan_match::<_, String>(pattern).await?;
+        let mut keys = Vec::new();
+        while let Some(key) = iter.next_item().await {
+            if let Some(key) = key.strip_prefix(&self.prefix) {
+                keys.push(key.to_string());
+            }
+        }
+        Ok(keys)
+    }
+
+    async fn contains(&self, key: &str) -> Result<bool, KVError> {
+        let mut connection = self.connection.clone();
+        Ok(connection.exists(self.redis_key(key)).await?)
+    }
+}
@@ -0,0 +1,92 @@
+use std::path::Path;
+
+use async_trait::async_trait;
+use hyper::body::Bytes;
+
+use crate::kv_store::{KVDatabase, KVError};
+
+/// Keeps entries in a sled tree on disk, no external service needed.
+/// Values are stored as a length-prefixed content type followed by the data.
+#[derive(Clone)]
+pub struct SledDatabase {
+    tree: sled::Tree,
+}
+
+impl SledDatabase {
+    /// Opens or creates the database in the directory at `path`
+    pub fn open(path: impl AsRef<Path>) -> sled::Result<Self> {
+        Ok(Self::new(sled::open(path)?.open_tree("kv")?))
+    }
+
+    pub fn new(tree: sled::Tree) -> Self {
+        Self { tree }
+    }
+}
+
+impl From<sled::Error> for KVError {
+    fn from(error: sled::Error) -> Self {
+        KVError::backend(error)
+    }
+}
+
+fn encode(content_type: &str, data: &[u8]) -> Vec<u8> {
+    let mut value = Vec::with_capacity(4 + content_type.len() + data.len());
+    value.extend_from_slice(&(content_type.len() as u32).to_be_bytes());
+    value.extend_from_slice(content_type.as_bytes());
+    value.extend_from_slice(data);
+    value
+}
+
+fn decode(value: &[u8]) -> Result<(String, Bytes), KVError> {
+    let corrupt = || KVError::backend("corrupt entry");
+    let (length, rest) = value.split_first_chunk::<4>().ok_or_else(corrupt)?;
+    let length = u32::from_be_bytes(*length) as usize;
+    if rest.len() < length {
+        return Err(corrupt());
+    }
+    let (content_type, data) = rest.split_at(length);
+    let content_type = std::str::from_utf8(content_type).map_err(|_| corrupt())?;
+    Ok((content_type.to_string(), Bytes::copy_from_slice(data)))
+}
+
+// sled serves reads from its page cache and batches writes in the
+// background, so calling it from async code doesn't block for long
+#[async_trait]
+impl KVDatabase for SledDatabase {
+    async fn read(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        self.tree.get(key)?.map(|value| decode(&value)).transpose()
+    }
+
+    async fn insert(
+        &self,
+        key: String,
+        (content_type, data): (String, Bytes),
+    ) -> Result<(), KVError> {
+        self.tree.insert(key, encode(&content_type, &data))?;
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        self.tree
+            .remove(key)?
+            .map(|value| decode(&value))
+            .transpose()
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, KVError> {
+        self.tree
+            .scan_prefix(prefix)
+            .keys()
+            .map(|key| Ok(String::from_utf8_lossy(&key?).into_owned()))
+            .collect()
+    }
+
+    async fn contains(&self, key: &str) -> Result<bool, KVError> {
+        Ok(self.tree.contains_key(key)?)
+    }
+
+    async fn close(&self) -> Result<(), KVError> {
+        self.tree.flush_async().await?;
+        Ok(())
+    }
+}
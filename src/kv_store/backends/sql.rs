@@ -0,0 +1,45 @@
+use hyper::body::Bytes;
+
+use crate::kv_store::{BatchWrite, KVError};
+
+/// Rows per multi-row `INSERT`, three parameters each stay well below the
+/// limits of SQLite and PostgreSQL
+pub(super) const ROWS_PER_STATEMENT: usize = 1000;
+
+pub(super) const UPSERT: &str = " ON CONFLICT (key) DO UPDATE
+    SET content_type = excluded.content_type, body = excluded.body";
+
+/// A key with its content type and value
+pub(super) type Entry = (String, (String, Bytes));
+
+/// Splits a batch into the entries to upsert and the keys to delete
+pub(super) fn split_batch(writes: Vec<BatchWrite>) -> (Vec<Entry>, Vec<String>) {
+    let mut inserts = Vec::new();
+    let mut removes = Vec::new();
+    for write in writes {
+        match write {
+            BatchWrite::Insert { key, value } => inserts.push((key, value)),
+            BatchWrite::Remove { key } => removes.push(key),
+        }
+    }
+    (inserts, removes)
+}
+
+impl From<sqlx::Error> for KVError {
+    fn from(error: sqlx::Error) -> Self {
+        match &error {
+            // The database is unreachable or overloaded, worth retrying
+            sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed | sqlx::Error::Io(_) => {
+                KVError::BackendUnavailable {
+                    source: error.into(),
+                }
+            }
+            sqlx::Error::Database(db_error) if db_error.is_unique_violation() => {
+                KVError::Conflict {
+                    source: error.into(),
+                }
+            }
+            _ => KVError::backend(error),
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use hyper::body::Bytes;
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePool},
+    QueryBuilder,
+};
+
+use super::sql::{split_batch, ROWS_PER_STATEMENT, UPSERT};
+use crate::kv_store::{BatchWrite, KVDatabase, KVError};
+
+/// Keeps entries in a single SQLite file, for small deployments that
+/// need durability without running a database server
+#[derive(Clone)]
+pub struct SqliteDatabase {
+    pool: SqlitePool,
+}
+
+impl SqliteDatabase {
+    /// Opens the database at `url`, e.g. `sqlite://kv.db`, creating it if
+    /// needed, and brings the schema up to date
+    pub async fn connect(url: &str) -> Result<Self, sqlx::Error> {
+        let options = SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+        let pool = SqlitePool::connect_with(options).await?;
+        sqlx::migrate!("migrations/sqlite").run(&pool).await?;
+        Ok(Self { pool })
+    }
+}
+
+#[async_trait]
+impl KVDatabase for SqliteDatabase {
+    async fn read(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        let row: Option<(String, Vec<u8>)> =
+            sqlx::query_as("SELECT content_type, body FROM kv WHERE key = ?")
+                .bind(key)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(row.map(|(content_type, body)| (content_type, body.into())))
+    }
+
+    async fn insert(
+        &self,
+        key: String,
+        (content_type, data): (String, Bytes),
+    ) -> Result<(), KVError> {
+        sqlx::query(
+            "INSERT INTO kv (key, content_type, body) VALUES (?, ?, ?)
+             ON CONFLICT (key) DO UPDATE
+             SET content_type = excluded.content_type, body = excluded.body",
+        )
+        .bind(key)
+        .bind(content_type)
+        .bind(&data[..])
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        let row: Option<(String, Vec<u8>)> =
+            sqlx::query_as("DELETE FROM kv WHERE key = ? RETURNING content_type, body")
+                .bind(key)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(row.map(|(content_type, body)| (content_type, body.into())))
+    }
+
+    /// Multi-row statements in one transaction
+    async fn write_batch(&self, writes: Vec<BatchWrite>) -> Result<(), KVError> {
+        let (inserts, removes) = split_batch(writes);
+        let mut transaction = self.pool.begin().await?;
+        for rows in inserts.chunks(ROWS_PER_STATEMENT) {
+            let mut query = QueryBuilder::new("INSERT INTO kv (key, content_type, body) ");
+            query.push_values(rows, |mut row, (key, (content_type, data))| {
+                row.push_bind(key)
+                    .push_bind(content_type)
+                    .push_bind(&data[..]);
+            });
+            query.push(UPSERT);
+            query.build().execute(&mut *transaction).await?;
+        }
+        for keys in removes.chunks(ROWS_PER_STATEMENT) {
+            let mut query = QueryBuilder::new("DELETE FROM kv WHERE key IN (");
+            let mut separated = query.separated(", ");
+            for key in keys {
+                separated.push_bind(key);
+            }
+            query.push(")");
+            query.build().execute(&mut *transaction).await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, KVError> {
+        // substr instead of LIKE, the prefix may contain wildcards
+        let keys = sqlx::query_scalar("SELECT key FROM kv WHERE substr(key, 1, length(?)) = ?")
+            .bind(prefix)
+            .bind(prefix)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(keys)
+    }
+
+    async fn contains(&self, key: &str) -> Result<bool, KVError> {
+        let row: Option<i64> = sqlx::query_scalar("SELECT 1 FROM kv WHERE key = ?")
+            .bind(key)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.is_some())
+    }
+
+    async fn close(&self) -> Result<(), KVError> {
+        self.pool.close().await;
+        Ok(())
+    }
+}
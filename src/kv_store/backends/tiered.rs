@@ -0,0 +1,146 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use async_trait::async_trait;
+use hyper::body::Bytes;
+
+use crate::kv_store::{BoundedLruDatabase, KVDatabase, KVError, MemoryDatabase, ValueStream};
+
+/// Keeps the most recently read values of a slow `cold` backend in memory,
+/// up to a number of bytes. Writes go through to `cold`. Counts reads per
+/// key, so the hottest keys can be loaded again after a restart.
+pub struct TieredDatabase<T> {
+    hot: BoundedLruDatabase<MemoryDatabase>,
+    cold: T,
+    reads: Mutex<HashMap<String, u64>>,
+    /// Bumped before and after every write, a value read from `cold` while
+    /// one was under way must not end up in `hot`
+    writes: AtomicU64,
+}
+
+impl<T: KVDatabase> TieredDatabase<T> {
+    pub fn new(cold: T, max_bytes: usize) -> Self {
+        Self {
+            hot: BoundedLruDatabase::new(MemoryDatabase::default()).with_max_bytes(max_bytes),
+            cold,
+            reads: Mutex::default(),
+            writes: AtomicU64::new(0),
+        }
+    }
+
+    /// Reads `key` from `cold` and keeps it in `hot` unless it was written
+    /// meanwhile
+    async fn load(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        let writes = self.writes.load(Ordering::Acquire);
+        let entry = self.cold.read(key).await?;
+        if let Some(entry) = &entry {
+            if self.writes.load(Ordering::Acquire) == writes {
+                // Values larger than the whole tier are only served from `cold`
+                let _ = self.hot.insert(key.to_string(), entry.clone()).await;
+            }
+        }
+        Ok(entry)
+    }
+
+    fn count_read(&self, key: &str) -> Result<(), KVError> {
+        *self.reads.lock()?.entry(key.to_string()).or_default() += 1;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<T: KVDatabase> KVDatabase for TieredDatabase<T> {
+    async fn read(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        self.count_read(key)?;
+        match self.hot.read(key).await? {
+            Some(entry) => Ok(Some(entry)),
+            None => self.load(key).await,
+        }
+    }
+
+    /// Values that aren't in memory yet are streamed from `cold` without
+    /// keeping them, large values would push out many small ones
+    async fn read_stream(&self, key: &str) -> Result<Option<ValueStream>, KVError> {
+        self.count_read(key)?;
+        match self.hot.read(key).await? {
+            Some((content_type, data)) => Ok(Some(ValueStream::whole(content_type, data))),
+            None => self.cold.read_stream(key).await,
+        }
+    }
+
+    async fn insert(&self, key: String, value: (String, Bytes)) -> Result<(), KVError> {
+        self.writes.fetch_add(1, Ordering::AcqRel);
+        let result = self.cold.insert(key.clone(), value.clone()).await;
+        if result.is_err() || self.hot.insert(key.clone(), value).await.is_err() {
+            self.hot.remove(&key).await?;
+        }
+        self.writes.fetch_add(1, Ordering::AcqRel);
+        result
+    }
+
+    async fn insert_file(
+        &self,
+        key: String,
+        content_type: String,
+        path: &Path,
+    ) -> Result<(), KVError> {
+        self.writes.fetch_add(1, Ordering::AcqRel);
+        self.hot.remove(&key).await?;
+        let result = self.cold.insert_file(key, content_type, path).await;
+        self.writes.fetch_add(1, Ordering::AcqRel);
+        result
+    }
+
+    async fn remove(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        self.writes.fetch_add(1, Ordering::AcqRel);
+        self.hot.remove(key).await?;
+        self.reads.lock()?.remove(key);
+        let result = self.cold.remove(key).await;
+        self.writes.fetch_add(1, Ordering::AcqRel);
+        result
+    }
+
+    fn max_key_length(&self) -> Option<usize> {
+        self.cold.max_key_length()
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, KVError> {
+        self.cold.keys(prefix).await
+    }
+
+    async fn contains(&self, key: &str) -> Result<bool, KVError> {
+        Ok(self.hot.contains(key).await? || self.cold.contains(key).await?)
+    }
+
+    async fn hot_keys(&self, n: usize) -> Result<Vec<String>, KVError> {
+        let reads = self.reads.lock()?;
+        let mut keys: Vec<(&String, &u64)> = reads.iter().collect();
+        keys.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        Ok(keys
+            .into_iter()
+            .take(n)
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+
+    async fn prefetch(&self, keys: &[String]) -> Result<usize, KVError> {
+        let mut loaded = 0;
+        // Hottest last, so they are the last to be evicted
+        for key in keys.iter().rev() {
+            if !self.hot.contains(key).await? && self.load(key).await?.is_some() {
+                loaded += 1;
+            }
+        }
+        Ok(loaded)
+    }
+
+    async fn close(&self) -> Result<(), KVError> {
+        self.cold.close().await
+    }
+}
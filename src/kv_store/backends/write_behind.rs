@@ -0,0 +1,293 @@
+//! Buffering small writes in front of a remote backend, so they reach it in
+//! batches instead of one round trip each. This trades durability for
+//! throughput:
+//!
+//! - A write is acknowledged once it is buffered. When the process dies
+//!   without shutting down, the writes of up to `max_delay` are lost.
+//!   Shutting down flushes, see `KVDatabase::close`.
+//! - Reads on the same instance see buffered writes, other instances
+//!   sharing the backend only see them once they are flushed.
+//! - A failed batch stays buffered and is retried. Once the buffer is over
+//!   its limits and can't be flushed, writes fail instead of piling up.
+//! - Only the latest write of a key is kept, a key written several times
+//!   between flushes reaches the backend once.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::Path,
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use hyper::body::Bytes;
+
+use crate::kv_store::{BatchWrite, KVDatabase, KVError, ValueStream};
+
+/// When buffered writes are sent on, whichever is reached first
+#[derive(Clone, Debug)]
+pub struct BatchLimits {
+    pub max_writes: usize,
+    pub max_bytes: usize,
+    pub max_delay: Duration,
+}
+
+impl Default for BatchLimits {
+    fn default() -> Self {
+        Self {
+            max_writes: 500,
+            max_bytes: 8 * 1024 * 1024,
+            max_delay: Duration::from_millis(100),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Pending {
+    /// The latest write of every key, numbered so a flush can tell whether
+    /// it was replaced while being sent
+    writes: HashMap<String, (u64, BatchWrite)>,
+    bytes: usize,
+    next: u64,
+}
+
+impl Pending {
+    fn size(write: &BatchWrite) -> usize {
+        match write {
+            BatchWrite::Insert { value, .. } => value.1.len(),
+            BatchWrite::Remove { .. } => 0,
+        }
+    }
+
+    fn push(&mut self, write: BatchWrite) {
+        self.next += 1;
+        self.bytes += Self::size(&write);
+        if let Some((_, replaced)) = self
+            .writes
+            .insert(write.key().to_string(), (self.next, write))
+        {
+            self.bytes -= Self::size(&replaced);
+        }
+    }
+
+    fn over(&self, limits: &BatchLimits) -> bool {
+        self.writes.len() >= limits.max_writes || self.bytes >= limits.max_bytes
+    }
+
+    /// `Some(None)` when the key's latest write is a removal
+    fn get(&self, key: &str) -> Option<Option<(String, Bytes)>> {
+        self.writes.get(key).map(|(_, write)| match write {
+            BatchWrite::Insert { value, .. } => Some(value.clone()),
+            BatchWrite::Remove { .. } => None,
+        })
+    }
+}
+
+struct Shared<T> {
+    inner: T,
+    limits: BatchLimits,
+    pending: Mutex<Pending>,
+    /// Held while a batch is sent, so batches reach the backend in order
+    flushing: tokio::sync::Mutex<()>,
+}
+
+/// Buffers inserts and removals for `inner` and sends them on with
+/// `KVDatabase::write_batch`, see the module docs for what that costs.
+/// Large uploads spooled to disk are not buffered.
+pub struct WriteBehindDatabase<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Clone for WriteBehindDatabase<T> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T: KVDatabase + 'static> WriteBehindDatabase<T> {
+    /// Flushes every `limits.max_delay` in a background task, which has to
+    /// be spawned on a Tokio runtime
+    pub fn new(inner: T, limits: BatchLimits) -> Self {
+        let shared = Arc::new(Shared {
+            inner,
+            limits,
+            pending: Mutex::default(),
+            flushing: tokio::sync::Mutex::default(),
+        });
+        tokio::spawn(flush_periodically(Arc::downgrade(&shared)));
+        Self { shared }
+    }
+}
+
+/// Ends once the database is dropped
+async fn flush_periodically<T: KVDatabase>(shared: Weak<Shared<T>>) {
+    let period = match shared.upgrade() {
+        Some(shared) => shared.limits.max_delay,
+        None => return,
+    };
+    let mut ticker = tokio::time::interval(period);
+    loop {
+        ticker.tick().await;
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        // Retried on the next tick
+        if let Err(error) = shared.flush().await {
+            tracing::warn!(%error, "Failed to flush buffered writes");
+        }
+    }
+}
+
+impl<T: KVDatabase> Shared<T> {
+    async fn flush(&self) -> Result<usize, KVError> {
+        let _flushing = self.flushing.lock().await;
+        let batch: Vec<(u64, BatchWrite)> = self.pending.lock()?.writes.values().cloned().collect();
+        if batch.is_empty() {
+            return Ok(0);
+        }
+        let sent: Vec<(String, u64)> = batch
+            .iter()
+            .map(|(number, write)| (write.key().to_string(), *number))
+            .collect();
+        self.inner
+            .write_batch(batch.into_iter().map(|(_, write)| write).collect())
+            .await?;
+
+        let mut pending = self.pending.lock()?;
+        for (key, number) in &sent {
+            // Keys written again meanwhile go with the next batch
+            if pending.writes.get(key).map(|(latest, _)| latest) == Some(number) {
+                if let Some((_, write)) = pending.writes.remove(key) {
+                    pending.bytes -= Pending::size(&write);
+                }
+            }
+        }
+        Ok(sent.len())
+    }
+
+    async fn buffer(&self, write: BatchWrite) -> Result<(), KVError> {
+        // Still over the limits, the last flush failed
+        if self.pending.lock()?.over(&self.limits) {
+            self.flush().await?;
+        }
+        let over = {
+            let mut pending = self.pending.lock()?;
+            pending.push(write);
+            pending.over(&self.limits)
+        };
+        if over {
+            // The write is buffered either way, a failing backend is
+            // retried by the next write or tick
+            if let Err(error) = self.flush().await {
+                tracing::warn!(%error, "Failed to flush buffered writes");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T: KVDatabase> WriteBehindDatabase<T> {
+    /// Sends every buffered write now and returns how many there were
+    pub async fn flush(&self) -> Result<usize, KVError> {
+        self.shared.flush().await
+    }
+
+    /// Writes not sent to the backend yet
+    pub fn buffered(&self) -> usize {
+        self.shared
+            .pending
+            .lock()
+            .expect("What, an error here?")
+            .writes
+            .len()
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Option<(String, Bytes)>>, KVError> {
+        Ok(self.shared.pending.lock()?.get(key))
+    }
+}
+
+#[async_trait]
+impl<T: KVDatabase> KVDatabase for WriteBehindDatabase<T> {
+    async fn read(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        match self.get(key)? {
+            Some(entry) => Ok(entry),
+            None => self.shared.inner.read(key).await,
+        }
+    }
+
+    async fn read_stream(&self, key: &str) -> Result<Option<ValueStream>, KVError> {
+        match self.get(key)? {
+            Some(entry) => {
+                Ok(entry.map(|(content_type, data)| ValueStream::whole(content_type, data)))
+            }
+            None => self.shared.inner.read_stream(key).await,
+        }
+    }
+
+    async fn insert(&self, key: String, value: (String, Bytes)) -> Result<(), KVError> {
+        self.shared.buffer(BatchWrite::Insert { key, value }).await
+    }
+
+    async fn insert_file(
+        &self,
+        key: String,
+        content_type: String,
+        path: &Path,
+    ) -> Result<(), KVError> {
+        // Sent first, or the buffered write would replace the file later
+        if self.get(&key)?.is_some() {
+            self.flush().await?;
+        }
+        self.shared.inner.insert_file(key, content_type, path).await
+    }
+
+    async fn remove(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        {
+            let mut pending = self.shared.pending.lock()?;
+            if let Some(entry) = pending.get(key) {
+                if entry.is_some() {
+                    pending.push(BatchWrite::Remove {
+                        key: key.to_string(),
+                    });
+                }
+                return Ok(entry);
+            }
+        }
+        self.shared.inner.remove(key).await
+    }
+
+    fn max_key_length(&self) -> Option<usize> {
+        self.shared.inner.max_key_length()
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, KVError> {
+        let mut keys: BTreeSet<String> =
+            self.shared.inner.keys(prefix).await?.into_iter().collect();
+        let pending = self.shared.pending.lock()?;
+        for (key, (_, write)) in &pending.writes {
+            if !key.starts_with(prefix) {
+                continue;
+            }
+            match write {
+                BatchWrite::Insert { .. } => keys.insert(key.clone()),
+                BatchWrite::Remove { .. } => keys.remove(key),
+            };
+        }
+        Ok(keys.into_iter().collect())
+    }
+
+    async fn contains(&self, key: &str) -> Result<bool, KVError> {
+        match self.get(key)? {
+            Some(entry) => Ok(entry.is_some()),
+            None => self.shared.inner.contains(key).await,
+        }
+    }
+
+    async fn close(&self) -> Result<(), KVError> {
+        self.flush().await?;
+        self.shared.inner.close().await
+    }
+}
@@ -0,0 +1,258 @@
+use std::{io::Cursor, sync::Arc};
+
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures::{stream, StreamExt};
+use hyper::{body::Bytes, StatusCode};
+use image::{DynamicImage, ImageFormat, ImageOutputFormat};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    flatten::{flatten_onto, BackgroundQuery},
+    overlaps_reserved, read_for_get, store,
+    thumbnail::ThumbnailQuery,
+    validate_user_key, KVError, Upload, WriteOptions,
+};
+use crate::{
+    auth::{check_access, require_scope, Claims, Principal},
+    SharedState,
+};
+
+/// Keys a single batch may name
+const MAX_KEYS: usize = 1000;
+const KEY_PLACEHOLDER: &str = "{key}";
+
+/// A step of a batch pipeline, with the parameters of its endpoint
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub(crate) enum Operation {
+    Grayscale,
+    Flatten(BackgroundQuery),
+    Thumbnail(ThumbnailQuery),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatchRequest {
+    keys: Vec<String>,
+    /// Applied in order, the result is stored as PNG
+    pipeline: Vec<Operation>,
+    /// Where results go, with `{key}` standing for the source key
+    target: String,
+}
+
+/// What became of one key of a batch
+#[derive(Clone, Serialize)]
+pub struct BatchResult {
+    key: String,
+    target: String,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct BatchReport {
+    results: Vec<BatchResult>,
+}
+
+/// A pipeline whose parameters were all checked
+pub(crate) struct Pipeline(Vec<Step>);
+
+enum Step {
+    Grayscale,
+    Flatten([u8; 3]),
+    Thumbnail(ThumbnailQuery),
+}
+
+impl Pipeline {
+    pub(crate) fn new(operations: &[Operation]) -> Result<Self, KVError> {
+        if operations.is_empty() {
+            return Err(KVError::BadRequest("The pipeline is empty".to_string()));
+        }
+        let steps = operations
+            .iter()
+            .map(|operation| {
+                Ok(match operation {
+                    Operation::Grayscale => Step::Grayscale,
+                    Operation::Flatten(query) => Step::Flatten(query.color()?),
+                    Operation::Thumbnail(query) => {
+                        query.check()?;
+                        Step::Thumbnail(query.clone())
+                    }
+                })
+            })
+            .collect::<Result<_, KVError>>()?;
+        Ok(Self(steps))
+    }
+
+    fn run(&self, image: DynamicImage) -> DynamicImage {
+        self.0.iter().fold(image, |image, step| match step {
+            Step::Grayscale => image.grayscale(),
+            Step::Flatten(background) => DynamicImage::ImageRgb8(flatten_onto(&image, *background)),
+            Step::Thumbnail(query) => query.apply(&image),
+        })
+    }
+}
+
+/// Runs the pipeline on `key` and stores the result under `target`. Fails
+/// with the response a single request doing the same would have got.
+async fn transform(
+    state: &SharedState,
+    pipeline: Arc<Pipeline>,
+    key: &str,
+    target: &str,
+    principal: &Principal,
+) -> Result<(), Response> {
+    // A placeholder's surroundings can make a reserved key of any key
+    validate_user_key(key)
+        .and_then(|()| validate_user_key(target))
+        .map_err(IntoResponse::into_response)?;
+    let png = render(state, pipeline, key, target, principal)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    // Through the same checks and policies as an upload of the result
+    let upload = Upload::from_bytes(png);
+    let options = WriteOptions {
+        derived_from: Some(key.to_string()),
+        ..WriteOptions::default()
+    };
+    store(
+        state,
+        target.to_string(),
+        "image/png".to_string(),
+        options,
+        upload,
+    )
+    .await
+}
+
+async fn render(
+    state: &SharedState,
+    pipeline: Arc<Pipeline>,
+    key: &str,
+    target: &str,
+    principal: &Principal,
+) -> Result<Bytes, KVError> {
+    check_access(state, key, principal)?;
+    check_access(state, target, principal)?;
+    // Like any transform, so expired entries are missing and burnt on read
+    let (content_type, data, _) = read_for_get(state, key.to_string()).await?;
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    let Some(format) = ImageFormat::from_mime_type(essence) else {
+        return Err(KVError::Forbidden(
+            "Not possible to transform this type of content".to_string(),
+        ));
+    };
+    tokio::task::spawn_blocking(move || {
+        let image = image::load_from_memory_with_format(&data, format)
+            .map_err(|source| KVError::DecodeFailed { source })?;
+        let mut png = Vec::new();
+        pipeline
+            .run(image)
+            .write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)
+            .map_err(|source| KVError::EncodeFailed { source })?;
+        Ok(Bytes::from(png))
+    })
+    .await
+    .map_err(KVError::internal)?
+}
+
+impl BatchResult {
+    pub(crate) fn succeeded(&self) -> bool {
+        self.status == StatusCode::OK.as_u16()
+    }
+
+    fn new(key: String, target: String, result: Result<(), Response>) -> Self {
+        let (status, error, message) = match result {
+            Ok(()) => (StatusCode::OK, None, None),
+            Err(response) => {
+                let error = response.extensions().get::<Arc<KVError>>();
+                (
+                    response.status(),
+                    error.map(|error| error.code()),
+                    error
+                        .map(|error| error.to_string())
+                        .or_else(|| response.status().canonical_reason().map(str::to_string)),
+                )
+            }
+        };
+        Self {
+            key,
+            target,
+            status: status.as_u16(),
+            error,
+            message,
+        }
+    }
+}
+
+pub(crate) fn check_target(target: &str) -> Result<(), KVError> {
+    let Some((before, _)) = target.split_once(KEY_PLACEHOLDER) else {
+        return Err(KVError::BadRequest(format!(
+            "The target needs a {} placeholder",
+            KEY_PLACEHOLDER
+        )));
+    };
+    // Targets that can only be reserved are turned down as a whole
+    if !before.is_empty() && overlaps_reserved(before) {
+        return Err(KVError::Forbidden(
+            "The target is reserved for internal use".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Where the result for `key` goes
+pub(crate) fn target_of(target: &str, key: &str) -> String {
+    target.replace(KEY_PLACEHOLDER, key)
+}
+
+/// Transforms `keys`, as many at a time as there are CPUs
+pub(crate) async fn run_batch(
+    state: &SharedState,
+    pipeline: Arc<Pipeline>,
+    keys: Vec<String>,
+    target: &str,
+    principal: &Principal,
+) -> Vec<BatchResult> {
+    let parallelism = std::thread::available_parallelism().map_or(4, usize::from);
+    stream::iter(keys)
+        .map(|key| {
+            let pipeline = Arc::clone(&pipeline);
+            async move {
+                let target = target_of(target, &key);
+                let result = transform(state, pipeline, &key, &target, principal).await;
+                BatchResult::new(key, target, result)
+            }
+        })
+        .buffered(parallelism)
+        .collect()
+        .await
+}
+
+/// Transforms many keys at once and reports on each. Useful to backfill derived images after a policy change.
+pub async fn transform_batch(
+    State(state): State<SharedState>,
+    principal: Principal,
+    claims: Option<Claims>,
+    Json(request): Json<BatchRequest>,
+) -> Result<Json<BatchReport>, KVError> {
+    require_scope(claims.as_ref(), "kv:read")?;
+    require_scope(claims.as_ref(), "kv:write")?;
+    if request.keys.len() > MAX_KEYS {
+        return Err(KVError::BadRequest(format!(
+            "A batch takes up to {} keys",
+            MAX_KEYS
+        )));
+    }
+    check_target(&request.target)?;
+    let pipeline = Arc::new(Pipeline::new(&request.pipeline)?);
+    let results = run_batch(&state, pipeline, request.keys, &request.target, &principal).await;
+    Ok(Json(BatchReport { results }))
+}
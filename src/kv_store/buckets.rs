@@ -0,0 +1,112 @@
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, Path, Query, State},
+    headers::ContentType,
+    response::{IntoResponse, Response},
+    Json, TypedHeader,
+};
+use hyper::HeaderMap;
+
+use super::{
+    delete_kv, get_kv, post_kv, reserved::BUCKET_NAMESPACE_PREFIX, BackgroundQuery, TtlQuery,
+    Upload,
+};
+use crate::{
+    auth::{require_scope, Claims, Principal},
+    KVError, SharedState,
+};
+
+/// Where the keys of `bucket` are stored. Each bucket is a namespace of its
+/// own, which `/kv/:key` can't reach into, but holds and erasure can name.
+fn bucket_prefix(bucket: &str) -> Result<String, KVError> {
+    let valid = (1..=63).contains(&bucket.len())
+        && bucket
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_');
+    if !valid {
+        return Err(KVError::BadRequest(format!(
+            "Invalid bucket name {}, use 1 to 63 of a-z, 0-9, - and _",
+            bucket
+        )));
+    }
+    Ok(format!("{}{}/", BUCKET_NAMESPACE_PREFIX, bucket))
+}
+
+/// The key as the bucket's client knows it, `key` itself outside of buckets
+pub(crate) fn local_key(key: &str) -> &str {
+    key.strip_prefix(BUCKET_NAMESPACE_PREFIX)
+        .and_then(|rest| rest.split_once('/'))
+        .map_or(key, |(_, local)| local)
+}
+
+pub async fn get_bucket_kv(
+    Path((bucket, key)): Path<(String, String)>,
+    headers: HeaderMap,
+    state: State<SharedState>,
+    principal: Principal,
+    claims: Option<Claims>,
+) -> Result<Response, KVError> {
+    let key = bucket_prefix(&bucket)? + &key;
+    get_kv(Path(key), headers, state, principal, claims).await
+}
+
+#[allow(clippy::too_many_arguments)] // axum extractors
+pub async fn post_bucket_kv(
+    Path((bucket, key)): Path<(String, String)>,
+    content_type: TypedHeader<ContentType>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    query: Query<TtlQuery>,
+    background: Query<BackgroundQuery>,
+    state: State<SharedState>,
+    principal: Principal,
+    claims: Option<Claims>,
+    upload: Upload,
+) -> Result<String, Response> {
+    let key = bucket_prefix(&bucket).map_err(IntoResponse::into_response)? + &key;
+    post_kv(
+        Path(key),
+        content_type,
+        connect_info,
+        headers,
+        query,
+        background,
+        state,
+        principal,
+        claims,
+        upload,
+    )
+    .await
+}
+
+pub async fn delete_bucket_kv(
+    Path((bucket, key)): Path<(String, String)>,
+    state: State<SharedState>,
+    principal: Principal,
+    claims: Option<Claims>,
+) -> Result<String, KVError> {
+    let key = bucket_prefix(&bucket)? + &key;
+    delete_kv(Path(key), state, principal, claims).await
+}
+
+/// The keys in `bucket` the principal may read, sorted
+pub async fn list_bucket(
+    Path(bucket): Path<String>,
+    State(state): State<SharedState>,
+    principal: Principal,
+    claims: Option<Claims>,
+) -> Result<Json<Vec<String>>, KVError> {
+    require_scope(claims.as_ref(), "kv:read")?;
+    let prefix = bucket_prefix(&bucket)?;
+    let db = state.read()?.db.clone();
+    let keys = db.keys(&prefix).await?;
+    let state = state.read()?;
+    let mut keys: Vec<String> = keys
+        .into_iter()
+        .filter(|key| !state.is_expired(key) && state.acls.check(key, &principal).is_ok())
+        .filter_map(|key| key.strip_prefix(&prefix).map(str::to_string))
+        .collect();
+    keys.sort();
+    Ok(Json(keys))
+}
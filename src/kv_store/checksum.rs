@@ -0,0 +1,69 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hyper::{header::HeaderValue, HeaderMap};
+
+use super::{EntryMetadata, KVError};
+
+const CONTENT_MD5: &str = "content-md5";
+const CHECKSUM_SHA256: &str = "x-checksum-sha256";
+
+/// Digests a client sent along with an upload, to be checked against the body
+#[derive(Default)]
+pub(crate) struct ExpectedChecksums {
+    pub(crate) md5: Option<[u8; 16]>,
+    pub(crate) sha256: Option<[u8; 32]>,
+}
+
+impl ExpectedChecksums {
+    /// Reads `Content-MD5`, base64 as in RFC 1864, and `X-Checksum-SHA256`,
+    /// base64 or hex
+    pub(crate) fn from_headers(headers: &HeaderMap) -> Result<Self, KVError> {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .map(|value| value.to_str().map(str::trim).unwrap_or_default())
+        };
+        let invalid = |name: &str| KVError::BadRequest(format!("Invalid {} header", name));
+        let md5 = header(CONTENT_MD5)
+            .map(|value| decode_digest(value).ok_or_else(|| invalid("Content-MD5")))
+            .transpose()?;
+        let sha256 = header(CHECKSUM_SHA256)
+            .map(|value| decode_digest(value).ok_or_else(|| invalid("X-Checksum-SHA256")))
+            .transpose()?;
+        Ok(Self { md5, sha256 })
+    }
+
+    pub(crate) fn verify(&self, md5: Option<[u8; 16]>, sha256: [u8; 32]) -> Result<(), KVError> {
+        if self.md5.is_some() && self.md5 != md5 {
+            return Err(KVError::ChecksumMismatch("Content-MD5"));
+        }
+        if self.sha256.is_some_and(|expected| expected != sha256) {
+            return Err(KVError::ChecksumMismatch("X-Checksum-SHA256"));
+        }
+        Ok(())
+    }
+}
+
+/// A digest of `N` bytes, in hex or base64
+fn decode_digest<const N: usize>(value: &str) -> Option<[u8; N]> {
+    if value.len() == 2 * N && value.bytes().all(|b| b.is_ascii_hexdigit()) {
+        let mut digest = [0; N];
+        for (byte, pair) in digest.iter_mut().zip(value.as_bytes().chunks(2)) {
+            let pair = std::str::from_utf8(pair).ok()?;
+            *byte = u8::from_str_radix(pair, 16).ok()?;
+        }
+        return Some(digest);
+    }
+    STANDARD.decode(value).ok()?.try_into().ok()
+}
+
+/// Sends the checksums of an entry along with all of its value, so clients
+/// can check what they received
+pub(crate) fn insert_checksum_headers(headers: &mut HeaderMap, metadata: &EntryMetadata) {
+    let base64 = |digest: &[u8]| {
+        HeaderValue::from_str(&STANDARD.encode(digest)).expect("base64 is a valid header")
+    };
+    headers.insert(CHECKSUM_SHA256, base64(&metadata.sha256));
+    if let Some(md5) = metadata.md5 {
+        headers.insert(CONTENT_MD5, base64(&md5));
+    }
+}
@@ -0,0 +1,54 @@
+/// Allow- and denylist of Content-Types accepted by `post_kv`.
+///
+/// Patterns are either exact MIME types (`image/png`) or wildcards over
+/// the subtype (`image/*`). Parameters like `charset` are ignored when
+/// matching. An empty allowlist accepts everything that isn't denied.
+#[derive(Default, Clone, Debug)]
+pub struct ContentTypePolicy {
+    allowed: Vec<String>,
+    denied: Vec<String>,
+}
+
+impl ContentTypePolicy {
+    pub fn allow(mut self, pattern: impl Into<String>) -> Self {
+        self.allowed.push(pattern.into().to_ascii_lowercase());
+        self
+    }
+
+    pub fn deny(mut self, pattern: impl Into<String>) -> Self {
+        self.denied.push(pattern.into().to_ascii_lowercase());
+        self
+    }
+
+    pub fn permits(&self, content_type: &str) -> bool {
+        let essence = essence(content_type);
+        let matching = |pattern: &String| matches(pattern, &essence);
+        if self.denied.iter().any(matching) {
+            return false;
+        }
+        self.allowed.is_empty() || self.allowed.iter().any(matching)
+    }
+
+    pub fn allowed(&self) -> &[String] {
+        &self.allowed
+    }
+}
+
+fn essence(content_type: &str) -> String {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+fn matches(pattern: &str, essence: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some("*") => true,
+        Some(main_type) => essence
+            .split_once('/')
+            .is_some_and(|(main, _)| main == main_type),
+        None => pattern == essence,
+    }
+}
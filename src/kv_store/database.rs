@@ -0,0 +1,374 @@
+use std::{
+    any::type_name,
+    future::Future,
+    ops::{Deref, Range},
+    path::Path,
+    sync::Arc,
+    time::Instant,
+};
+
+use async_trait::async_trait;
+use futures::{future, stream::BoxStream, StreamExt, TryStreamExt};
+use hyper::body::Bytes;
+use tracing::{field::Empty, Instrument, Span};
+
+use super::{backends::MemoryDatabase, KVError};
+use crate::{deadline::before_deadline, SharedState};
+
+/// A value that is sent on as it's read from the backend
+pub struct ValueStream {
+    pub content_type: String,
+    pub len: u64,
+    pub chunks: BoxStream<'static, Result<Bytes, KVError>>,
+}
+
+impl ValueStream {
+    /// A value that was read whole
+    pub fn whole(content_type: String, data: Bytes) -> Self {
+        Self {
+            content_type,
+            len: data.len() as u64,
+            chunks: futures::stream::once(future::ready(Ok(data))).boxed(),
+        }
+    }
+
+    /// Only the bytes in `range`, which has to be within the value. Stops
+    /// reading from the backend after its end.
+    pub(crate) fn slice(self, range: Range<u64>) -> Self {
+        let chunks = self
+            .chunks
+            .scan(0u64, move |offset, chunk| {
+                let chunk = match chunk {
+                    Ok(_) if *offset >= range.end => None,
+                    Ok(chunk) => {
+                        let start = *offset;
+                        *offset += chunk.len() as u64;
+                        let within = |position: u64| {
+                            position.saturating_sub(start).min(chunk.len() as u64) as usize
+                        };
+                        Some(Ok(chunk.slice(within(range.start)..within(range.end))))
+                    }
+                    Err(error) => Some(Err(error)),
+                };
+                future::ready(chunk)
+            })
+            .try_filter(|chunk| future::ready(!chunk.is_empty()))
+            .boxed();
+        Self {
+            content_type: self.content_type,
+            len: range.end - range.start,
+            chunks,
+        }
+    }
+}
+
+/// A change buffered by `WriteBehindDatabase`, see `KVDatabase::write_batch`
+#[derive(Clone, Debug)]
+pub enum BatchWrite {
+    Insert { key: String, value: (String, Bytes) },
+    Remove { key: String },
+}
+
+impl BatchWrite {
+    pub fn key(&self) -> &str {
+        match self {
+            Self::Insert { key, .. } | Self::Remove { key } => key,
+        }
+    }
+}
+
+/// Storage behind the KV API. Values are kept together with their content type.
+#[async_trait]
+pub trait KVDatabase: Send + Sync {
+    async fn read(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError>;
+
+    /// Opens `key` to be read in chunks, so large values are never held in
+    /// memory as a whole. By default the value is read whole.
+    async fn read_stream(&self, key: &str) -> Result<Option<ValueStream>, KVError> {
+        let entry = self.read(key).await?;
+        Ok(entry.map(|(content_type, data)| ValueStream::whole(content_type, data)))
+    }
+
+    async fn insert(&self, key: String, value: (String, Bytes)) -> Result<(), KVError>;
+
+    /// Inserts the contents of the file at `path`, a large upload that was
+    /// spooled to disk. Backends that can move the file into place should,
+    /// by default it's read into memory. The file is removed afterwards.
+    async fn insert_file(
+        &self,
+        key: String,
+        content_type: String,
+        path: &Path,
+    ) -> Result<(), KVError> {
+        let data = tokio::fs::read(path).await.map_err(KVError::internal)?;
+        self.insert(key, (content_type, data.into())).await
+    }
+
+    /// Removes `key` and returns what was stored. When several callers remove
+    /// the same key concurrently, only one of them gets the value.
+    async fn remove(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError>;
+
+    /// Applies `writes`, all to different keys, in as few round trips as
+    /// the backend allows. By default they are applied one by one.
+    async fn write_batch(&self, writes: Vec<BatchWrite>) -> Result<(), KVError> {
+        for write in writes {
+            match write {
+                BatchWrite::Insert { key, value } => self.insert(key, value).await?,
+                BatchWrite::Remove { key } => {
+                    self.remove(&key).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The longest key the backend can store, if it has a limit of its
+    /// own. Writes of longer keys are rejected before they reach it.
+    fn max_key_length(&self) -> Option<usize> {
+        None
+    }
+
+    /// All keys starting with `prefix`, in no particular order
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, KVError>;
+
+    async fn contains(&self, key: &str) -> Result<bool, KVError> {
+        Ok(self.read(key).await?.is_some())
+    }
+
+    /// The `n` most read keys, hottest first, for a cache to be warmed up
+    /// with after a restart. Backends without a cache have none.
+    async fn hot_keys(&self, _n: usize) -> Result<Vec<String>, KVError> {
+        Ok(Vec::new())
+    }
+
+    /// Loads `keys` into the cache ahead of the first reads and returns how
+    /// many were loaded. Backends without a cache have nothing to do.
+    async fn prefetch(&self, _keys: &[String]) -> Result<usize, KVError> {
+        Ok(0)
+    }
+
+    /// Called once on shutdown, after the last request was answered.
+    /// Backends persist buffered writes and release their connections here.
+    async fn close(&self) -> Result<(), KVError> {
+        Ok(())
+    }
+}
+
+/// Runs every call to a backend in a `kv.backend` span, with the key, the
+/// size of the value and how long the backend took. Reads are cancelled at
+/// the deadline of the request they are made for, writes run to completion.
+struct Traced<T> {
+    inner: Arc<T>,
+    backend: &'static str,
+}
+
+impl<T> Traced<T> {
+    fn new(inner: T) -> Self {
+        // `some::module::LruDatabase<other::FsDatabase>` becomes `LruDatabase`
+        let name = type_name::<T>();
+        let name = name.split('<').next().unwrap_or(name);
+        Self {
+            inner: Arc::new(inner),
+            backend: name.rsplit("::").next().unwrap_or(name),
+        }
+    }
+
+    fn span(&self, op: &'static str, key: &str) -> Span {
+        tracing::debug_span!(
+            "kv.backend",
+            backend = self.backend,
+            op,
+            key,
+            size = Empty,
+            latency_us = Empty,
+        )
+    }
+
+    async fn timed<R>(
+        &self,
+        span: &Span,
+        call: impl Future<Output = Result<R, KVError>>,
+    ) -> Result<R, KVError> {
+        let started = Instant::now();
+        let output = call.instrument(span.clone()).await;
+        span.record("latency_us", started.elapsed().as_micros() as u64);
+        output
+    }
+
+    /// Like `timed`, for calls that change the store. They run in a task of
+    /// their own, so even a request given up on at its deadline can't
+    /// cancel one halfway and leave a value torn or a batch half applied.
+    async fn detached<R: Send + 'static>(
+        &self,
+        span: &Span,
+        call: impl Future<Output = Result<R, KVError>> + Send + 'static,
+    ) -> Result<R, KVError> {
+        let started = Instant::now();
+        let output = tokio::spawn(call.instrument(span.clone())).await;
+        span.record("latency_us", started.elapsed().as_micros() as u64);
+        match output {
+            Ok(output) => output,
+            Err(error) if error.is_panic() => std::panic::resume_unwind(error.into_panic()),
+            Err(error) => Err(KVError::internal(error)),
+        }
+    }
+
+    /// Like `timed`, cancelled at the deadline of the request it is made
+    /// for, for calls that change nothing
+    async fn timed_read<R>(
+        &self,
+        span: &Span,
+        op: &'static str,
+        call: impl Future<Output = Result<R, KVError>>,
+    ) -> Result<R, KVError> {
+        let started = Instant::now();
+        before_deadline(self.timed(span, call))
+            .await
+            .unwrap_or_else(|_| {
+                let elapsed = started.elapsed();
+                span.record("latency_us", elapsed.as_micros() as u64);
+                Err(KVError::DeadlineExceeded {
+                    operation: format!("{} {}", self.backend, op),
+                    elapsed,
+                })
+            })
+    }
+}
+
+#[async_trait]
+impl<T: KVDatabase + 'static> KVDatabase for Traced<T> {
+    async fn read(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        let span = self.span("read", key);
+        let entry = self.timed_read(&span, "read", self.inner.read(key)).await?;
+        if let Some((_, data)) = &entry {
+            span.record("size", data.len());
+        }
+        Ok(entry)
+    }
+
+    async fn read_stream(&self, key: &str) -> Result<Option<ValueStream>, KVError> {
+        let span = self.span("read_stream", key);
+        let value = self
+            .timed_read(&span, "read_stream", self.inner.read_stream(key))
+            .await?;
+        if let Some(value) = &value {
+            span.record("size", value.len);
+        }
+        Ok(value)
+    }
+
+    async fn insert(&self, key: String, value: (String, Bytes)) -> Result<(), KVError> {
+        let span = self.span("insert", &key);
+        span.record("size", value.1.len());
+        let inner = Arc::clone(&self.inner);
+        self.detached(&span, async move { inner.insert(key, value).await })
+            .await
+    }
+
+    async fn insert_file(
+        &self,
+        key: String,
+        content_type: String,
+        path: &Path,
+    ) -> Result<(), KVError> {
+        let span = self.span("insert_file", &key);
+        if let Ok(metadata) = tokio::fs::metadata(path).await {
+            span.record("size", metadata.len());
+        }
+        let (inner, path) = (Arc::clone(&self.inner), path.to_path_buf());
+        self.detached(&span, async move {
+            inner.insert_file(key, content_type, &path).await
+        })
+        .await
+    }
+
+    async fn remove(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        let span = self.span("remove", key);
+        let (inner, key) = (Arc::clone(&self.inner), key.to_string());
+        let entry = self
+            .detached(&span, async move { inner.remove(&key).await })
+            .await?;
+        if let Some((_, data)) = &entry {
+            span.record("size", data.len());
+        }
+        Ok(entry)
+    }
+
+    async fn write_batch(&self, writes: Vec<BatchWrite>) -> Result<(), KVError> {
+        let span = self.span("write_batch", "");
+        let size: usize = writes
+            .iter()
+            .map(|write| match write {
+                BatchWrite::Insert { value, .. } => value.1.len(),
+                BatchWrite::Remove { .. } => 0,
+            })
+            .sum();
+        span.record("size", size);
+        let inner = Arc::clone(&self.inner);
+        self.detached(&span, async move { inner.write_batch(writes).await })
+            .await
+    }
+
+    fn max_key_length(&self) -> Option<usize> {
+        self.inner.max_key_length()
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, KVError> {
+        let span = self.span("keys", prefix);
+        self.timed_read(&span, "keys", self.inner.keys(prefix))
+            .await
+    }
+
+    async fn contains(&self, key: &str) -> Result<bool, KVError> {
+        let span = self.span("contains", key);
+        self.timed_read(&span, "contains", self.inner.contains(key))
+            .await
+    }
+
+    async fn hot_keys(&self, n: usize) -> Result<Vec<String>, KVError> {
+        self.inner.hot_keys(n).await
+    }
+
+    async fn prefetch(&self, keys: &[String]) -> Result<usize, KVError> {
+        let span = self.span("prefetch", "");
+        self.timed_read(&span, "prefetch", self.inner.prefetch(keys))
+            .await
+    }
+
+    async fn close(&self) -> Result<(), KVError> {
+        let span = self.span("close", "");
+        self.timed(&span, self.inner.close()).await
+    }
+}
+
+/// Cheaply cloneable handle to the configured backend, so handlers can
+/// release the state lock before talking to it
+#[derive(Clone)]
+pub struct Database(Arc<dyn KVDatabase>);
+
+impl Database {
+    pub fn new(db: impl KVDatabase + 'static) -> Self {
+        Self(Arc::new(Traced::new(db)))
+    }
+}
+
+impl Default for Database {
+    fn default() -> Self {
+        Self::new(MemoryDatabase::default())
+    }
+}
+
+impl Deref for Database {
+    type Target = dyn KVDatabase;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
+
+/// Closes the configured backend once the servers have drained, see
+/// `KVDatabase::close`
+pub async fn close_database(state: &SharedState) -> Result<(), KVError> {
+    let db = state.read()?.db.clone();
+    db.close().await
+}
@@ -0,0 +1,60 @@
+use axum::{
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+    Json,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hyper::HeaderMap;
+use serde::Serialize;
+
+use super::{conditional, read_for_get};
+use crate::{
+    auth::{check_access, require_scope, Claims, Principal},
+    KVError, SharedState,
+};
+
+/// An entry with its metadata, as `GET /v2/kv/:key` returns it
+#[derive(Serialize)]
+pub struct Envelope {
+    key: String,
+    content_type: String,
+    size: usize,
+    etag: String,
+    /// base64, as in the `X-Checksum-SHA256` header
+    sha256: String,
+    /// base64, only for uploads that were verified against a `Content-MD5`
+    md5: Option<String>,
+    /// HTTP date, `null` for entries from before a restart
+    last_modified: Option<String>,
+    /// The value, base64 encoded
+    data: String,
+}
+
+/// Like `get_kv`, but wraps the value in a JSON envelope. The raw value
+/// stays available under `/v2/kv/:key/raw`.
+pub async fn get_kv_envelope(
+    Path(key): Path<String>,
+    headers: HeaderMap,
+    State(state): State<SharedState>,
+    principal: Principal,
+    claims: Option<Claims>,
+) -> Result<Response, KVError> {
+    require_scope(claims.as_ref(), "kv:read")?;
+    check_access(&state, &key, &principal)?;
+    let (content_type, data, metadata) = read_for_get(&state, key.clone()).await?;
+    let envelope = Envelope {
+        key,
+        content_type,
+        size: data.len(),
+        etag: metadata.etag.clone(),
+        sha256: STANDARD.encode(metadata.sha256),
+        md5: metadata.md5.map(|md5| STANDARD.encode(md5)),
+        last_modified: metadata.last_modified.map(httpdate::fmt_http_date),
+        data: STANDARD.encode(&data),
+    };
+    Ok(conditional(
+        &headers,
+        &metadata,
+        Json(envelope).into_response(),
+    ))
+}
@@ -0,0 +1,93 @@
+use std::time::{Duration, Instant};
+
+use hyper::HeaderMap;
+use serde::Deserialize;
+use tokio::task::JoinHandle;
+
+use super::remove_record;
+use crate::{KVError, SharedState, Shutdown};
+
+#[derive(Deserialize)]
+pub struct TtlQuery {
+    /// Seconds until the entry expires
+    ttl: Option<u64>,
+}
+
+/// The TTL asked for with `X-TTL-Seconds` or `?ttl=`, the header wins
+pub(crate) fn requested_ttl(
+    headers: &HeaderMap,
+    query: &TtlQuery,
+) -> Result<Option<Duration>, KVError> {
+    let seconds = match headers.get("x-ttl-seconds") {
+        Some(value) => Some(
+            value
+                .to_str()
+                .ok()
+                .and_then(|value| value.trim().parse().ok())
+                .ok_or_else(|| KVError::BadRequest("Invalid X-TTL-Seconds header".to_string()))?,
+        ),
+        None => query.ttl,
+    };
+    Ok(seconds.map(Duration::from_secs))
+}
+
+/// Removes the expired entries that aren't under legal hold, returns how
+/// many were removed. Expired entries are hidden from readers even before
+/// they are swept, and those the backend fails to remove are retried by the
+/// next sweep.
+pub async fn sweep_expired(state: &SharedState) -> Result<usize, KVError> {
+    let (db, expired) = {
+        let state = state.read()?;
+        let now = state.clock.now();
+        let expired: Vec<(String, Instant)> = state
+            .expiries
+            .iter()
+            .filter(|(key, expires)| **expires <= now && !state.holds.is_held(key))
+            .map(|(key, expires)| (key.clone(), *expires))
+            .collect();
+        (state.db.clone(), expired)
+    };
+    let mut removed = 0;
+    for (key, expires) in expired {
+        let Ok(entry) = db.remove(&key).await else {
+            continue;
+        };
+        if entry.is_some() {
+            removed += 1;
+        }
+        let forgotten = {
+            let mut state = state.write()?;
+            // A key written again meanwhile keeps what its new write set
+            let unchanged = state.expiries.get(&key) == Some(&expires);
+            if unchanged {
+                state.forget(&key);
+            }
+            unchanged
+        };
+        // A record left behind is dropped on the next start
+        if forgotten {
+            let _ = remove_record(&db, &key).await;
+        }
+    }
+    Ok(removed)
+}
+
+/// Sweeps expired entries every `period` in a background task, which ends
+/// with `shutdown` once a sweep in progress is done
+pub fn spawn_expiry_sweeper(
+    state: SharedState,
+    period: Duration,
+    shutdown: Shutdown,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(period);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = shutdown.triggered() => return,
+            }
+            // A failing backend is retried on the next tick
+            let _ = sweep_expired(&state).await;
+        }
+    })
+}
@@ -0,0 +1,108 @@
+use std::io::Cursor;
+
+use axum::{
+    extract::{Path, Query, State},
+    response::Response,
+};
+use hyper::{body::Bytes, HeaderMap};
+use image::{DynamicImage, ImageFormat, ImageOutputFormat, RgbImage};
+use serde::{Deserialize, Serialize};
+
+use super::{not_modified, read_source, with_etag, Source};
+use crate::{
+    auth::{check_access, require_scope, Claims, Principal},
+    KVError, SharedState,
+};
+
+const WHITE: [u8; 3] = [0xff, 0xff, 0xff];
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct BackgroundQuery {
+    /// `rrggbb` in hex, white by default
+    background: Option<String>,
+}
+
+impl BackgroundQuery {
+    pub(crate) fn color(&self) -> Result<[u8; 3], KVError> {
+        let Some(hex) = &self.background else {
+            return Ok(WHITE);
+        };
+        let invalid = || KVError::BadRequest(format!("Invalid background {}, use rrggbb", hex));
+        let hex = hex.strip_prefix('#').unwrap_or(hex);
+        if hex.len() != 6 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid());
+        Ok([channel(0)?, channel(2)?, channel(4)?])
+    }
+
+    /// The color if one was asked for, e.g. to override a policy's
+    pub(crate) fn requested(&self) -> Result<Option<[u8; 3]>, KVError> {
+        self.background.as_ref().map(|_| self.color()).transpose()
+    }
+}
+
+/// Blends transparent pixels onto `background`, for formats without an
+/// alpha channel. Just dropping alpha turns them black, or whatever color
+/// the encoder left in fully transparent pixels.
+pub(crate) fn flatten_onto(image: &DynamicImage, background: [u8; 3]) -> RgbImage {
+    let image = image.to_rgba8();
+    RgbImage::from_fn(image.width(), image.height(), |x, y| {
+        let [r, g, b, alpha] = image.get_pixel(x, y).0;
+        let alpha = alpha as u32;
+        let blend = |color: u8, background: u8| {
+            ((color as u32 * alpha + background as u32 * (255 - alpha) + 127) / 255) as u8
+        };
+        image::Rgb([
+            blend(r, background[0]),
+            blend(g, background[1]),
+            blend(b, background[2]),
+        ])
+    })
+}
+
+/// Flattens an image onto `?background=rrggbb` and returns it as a PNG
+/// without alpha channel
+pub async fn flatten(
+    Path(key): Path<String>,
+    Query(query): Query<BackgroundQuery>,
+    headers: HeaderMap,
+    State(state): State<SharedState>,
+    principal: Principal,
+    claims: Option<Claims>,
+) -> Result<Response, KVError> {
+    require_scope(claims.as_ref(), "kv:read")?;
+    check_access(&state, &key, &principal)?;
+    let background = query.color()?;
+    let params = format!(
+        "{:02x}{:02x}{:02x}",
+        background[0], background[1], background[2]
+    );
+    let (content_type, data, etag) =
+        match read_source(&state, &key, &headers, "flatten", &params).await? {
+            Source::NotModified(etag) => return Ok(not_modified(&etag)),
+            Source::Value {
+                content_type,
+                data,
+                etag,
+            } => (content_type, data, etag),
+        };
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    let Some(format) = ImageFormat::from_mime_type(essence) else {
+        return Err(KVError::Forbidden(
+            "Not possible to flatten this type of content".to_string(),
+        ));
+    };
+    let png = tokio::task::spawn_blocking(move || {
+        let image = image::load_from_memory_with_format(&data, format)
+            .map_err(|source| KVError::DecodeFailed { source })?;
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(flatten_onto(&image, background))
+            .write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)
+            .map_err(|source| KVError::EncodeFailed { source })?;
+        Ok::<_, KVError>(Bytes::from(png))
+    })
+    .await
+    .map_err(KVError::internal)??;
+    Ok(with_etag(&etag, ([("content-type", "image/png")], png)))
+}
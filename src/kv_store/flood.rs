@@ -0,0 +1,156 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+/// Thresholds for write flood detection. Counters reset every `window`,
+/// offenders are blocked for `cooldown`.
+#[derive(Clone, Debug)]
+pub struct FloodLimits {
+    pub window: Duration,
+    pub max_writes_per_key: u32,
+    pub max_new_keys_per_client: u32,
+    pub cooldown: Duration,
+}
+
+impl Default for FloodLimits {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(10),
+            max_writes_per_key: 100,
+            max_new_keys_per_client: 1000,
+            cooldown: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(tag = "type", content = "value", rename_all = "lowercase")]
+pub enum BlockTarget {
+    Key(String),
+    Client(IpAddr),
+}
+
+struct Window {
+    started: Instant,
+    count: u32,
+}
+
+impl Window {
+    /// Counts one event, returns the number of events in the current window
+    fn hit(&mut self, now: Instant, length: Duration) -> u32 {
+        if now.duration_since(self.started) >= length {
+            self.started = now;
+            self.count = 0;
+        }
+        self.count += 1;
+        self.count
+    }
+}
+
+/// Detects clients hammering the same key or creating keys at an abnormal rate
+pub struct FloodGuard {
+    limits: FloodLimits,
+    key_writes: HashMap<String, Window>,
+    client_creations: HashMap<IpAddr, Window>,
+    blocked: HashMap<BlockTarget, Instant>,
+    last_prune: Instant,
+}
+
+impl FloodGuard {
+    pub fn new(limits: FloodLimits, now: Instant) -> Self {
+        Self {
+            limits,
+            key_writes: HashMap::new(),
+            client_creations: HashMap::new(),
+            blocked: HashMap::new(),
+            last_prune: now,
+        }
+    }
+
+    /// Records a write to `key` at `now`. Returns how long the writer has to
+    /// back off if the key or the client is blocked.
+    pub fn check_write(
+        &mut self,
+        key: &str,
+        client: Option<IpAddr>,
+        creates_key: bool,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        self.prune(now);
+
+        let key_target = BlockTarget::Key(key.to_string());
+        let client_target = client.map(BlockTarget::Client);
+        for target in std::iter::once(&key_target).chain(client_target.as_ref()) {
+            match self.blocked.get(target) {
+                Some(until) if *until > now => return Err(until.duration_since(now)),
+                _ => {}
+            }
+        }
+
+        let window = self.limits.window;
+        let writes = self
+            .key_writes
+            .entry(key.to_string())
+            .or_insert(Window {
+                started: now,
+                count: 0,
+            })
+            .hit(now, window);
+        if writes > self.limits.max_writes_per_key {
+            return Err(self.block(key_target, now));
+        }
+
+        if let (Some(client), true) = (client, creates_key) {
+            let creations = self
+                .client_creations
+                .entry(client)
+                .or_insert(Window {
+                    started: now,
+                    count: 0,
+                })
+                .hit(now, window);
+            if creations > self.limits.max_new_keys_per_client {
+                return Err(self.block(BlockTarget::Client(client), now));
+            }
+        }
+        Ok(())
+    }
+
+    /// Currently blocked keys and clients with the remaining cooldown
+    pub fn blocks(&self, now: Instant) -> Vec<(BlockTarget, Duration)> {
+        self.blocked
+            .iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(target, until)| (target.clone(), until.duration_since(now)))
+            .collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.blocked.clear();
+        self.key_writes.clear();
+        self.client_creations.clear();
+    }
+
+    fn block(&mut self, target: BlockTarget, now: Instant) -> Duration {
+        self.blocked.insert(target, now + self.limits.cooldown);
+        self.limits.cooldown
+    }
+
+    /// Drops expired blocks and stale counters, at most once per window
+    fn prune(&mut self, now: Instant) {
+        let window = self.limits.window;
+        if now.duration_since(self.last_prune) < window {
+            return;
+        }
+        self.last_prune = now;
+        self.blocked.retain(|_, until| *until > now);
+        self.key_writes
+            .retain(|_, w| now.duration_since(w.started) < window);
+        self.client_creations
+            .retain(|_, w| now.duration_since(w.started) < window);
+    }
+}
@@ -0,0 +1,85 @@
+use std::io::Cursor;
+
+use hyper::body::Bytes;
+use image::{imageops::FilterType, DynamicImage, ImageFormat, ImageOutputFormat};
+
+use super::{flatten::flatten_onto, KVError};
+
+/// The format images are re-encoded to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageEncoding {
+    Png,
+    /// Quality from 1 to 100. Transparent pixels are flattened onto the
+    /// policy's background.
+    Jpeg {
+        quality: u8,
+    },
+}
+
+impl ImageEncoding {
+    fn content_type(self) -> &'static str {
+        match self {
+            ImageEncoding::Png => "image/png",
+            ImageEncoding::Jpeg { .. } => "image/jpeg",
+        }
+    }
+}
+
+/// How images uploaded to a namespace are normalized before they are
+/// stored. Re-encoding drops everything but the pixels, EXIF and other
+/// metadata included.
+#[derive(Clone, Debug)]
+pub struct ImagePolicy {
+    pub encoding: ImageEncoding,
+    /// Larger images are scaled down to fit, keeping their aspect ratio
+    pub max_width: u32,
+    pub max_height: u32,
+    /// What transparency is flattened onto for encodings without alpha
+    pub background: [u8; 3],
+}
+
+impl Default for ImagePolicy {
+    fn default() -> Self {
+        Self {
+            encoding: ImageEncoding::Png,
+            max_width: 4096,
+            max_height: 4096,
+            background: [0xff, 0xff, 0xff],
+        }
+    }
+}
+
+impl ImagePolicy {
+    /// Re-encodes `data` if it's an image format the service can decode,
+    /// returns the new content type and data. `None` for everything else.
+    pub(crate) fn normalize(
+        &self,
+        content_type: &str,
+        data: &[u8],
+    ) -> Result<Option<(String, Bytes)>, KVError> {
+        let essence = content_type.split(';').next().unwrap_or_default().trim();
+        let Some(format) = ImageFormat::from_mime_type(essence) else {
+            return Ok(None);
+        };
+        let mut image = image::load_from_memory_with_format(data, format)
+            .map_err(|source| KVError::DecodeFailed { source })?;
+        if image.width() > self.max_width || image.height() > self.max_height {
+            image = image.resize(self.max_width, self.max_height, FilterType::Lanczos3);
+        }
+        let (image, output) = match self.encoding {
+            ImageEncoding::Png => (image, ImageOutputFormat::Png),
+            ImageEncoding::Jpeg { quality } => (
+                DynamicImage::ImageRgb8(flatten_onto(&image, self.background)),
+                ImageOutputFormat::Jpeg(quality),
+            ),
+        };
+        let mut encoded = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut encoded), output)
+            .map_err(|source| KVError::EncodeFailed { source })?;
+        Ok(Some((
+            self.encoding.content_type().to_string(),
+            encoded.into(),
+        )))
+    }
+}
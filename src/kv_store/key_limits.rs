@@ -0,0 +1,36 @@
+use hyper::StatusCode;
+
+/// Bounds on key size. Depth counts the `/` separated segments of a
+/// hierarchical key, so `avatars/2022/crab.png` has a depth of 3.
+#[derive(Clone, Debug)]
+pub struct KeyLimits {
+    pub max_length: usize,
+    pub max_depth: usize,
+}
+
+impl Default for KeyLimits {
+    fn default() -> Self {
+        Self {
+            max_length: 512,
+            max_depth: 16,
+        }
+    }
+}
+
+impl KeyLimits {
+    pub fn check(&self, key: &str) -> Result<(), (StatusCode, String)> {
+        if key.len() > self.max_length {
+            return Err((
+                StatusCode::URI_TOO_LONG,
+                format!("Key is longer than {} bytes", self.max_length),
+            ));
+        }
+        if key.split('/').count() > self.max_depth {
+            return Err((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("Key has more than {} segments", self.max_depth),
+            ));
+        }
+        Ok(())
+    }
+}
@@ -1,4 +1,176 @@
-use std::sync::PoisonError;
+use std::{
+    sync::{Arc, PoisonError},
+    time::Duration,
+};
 
-use axum::response::IntoResponse;
-use hyper::StatusCode;
+use axum::response::{IntoResponse, Response};
+use hyper::{header::HeaderValue, StatusCode};
+use image::ImageError;
+
+use crate::BoxError;
+
+/// Everything a KV request can fail with. Each variant is answered with a
+/// fixed status code, sources are kept for the server side log.
+#[derive(Debug, thiserror::Error)]
+pub enum KVError {
+    #[error("Key not found")]
+    NotFound,
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{0}")]
+    Unauthorized(&'static str),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("Key is under legal hold")]
+    Held,
+    #[error("{0}")]
+    TooLarge(String),
+    #[error("{0}")]
+    UnsupportedMediaType(String),
+    #[error("Body is larger than {limit} bytes")]
+    BodyTooLarge { limit: u64 },
+    #[error("Body doesn't match the {0} header")]
+    ChecksumMismatch(&'static str),
+    #[error("Request headers are too large")]
+    HeadersTooLarge,
+    #[error("Transfer-Encoding {0} is not supported")]
+    UnsupportedTransferEncoding(String),
+    #[error("Storage conflict: {source}")]
+    Conflict { source: BoxError },
+    /// The backend is unreachable or overloaded, worth retrying
+    #[error("Storage unavailable: {source}")]
+    BackendUnavailable { source: BoxError },
+    #[error("Storage error: {source}")]
+    Backend { source: BoxError },
+    /// The request's deadline passed while waiting for `operation`
+    #[error("Deadline exceeded after {elapsed:?} in {operation}")]
+    DeadlineExceeded {
+        operation: String,
+        elapsed: Duration,
+    },
+    #[error("Could not decode image: {source}")]
+    DecodeFailed { source: ImageError },
+    #[error("Could not encode image: {source}")]
+    EncodeFailed { source: ImageError },
+    #[error("{0}")]
+    Unavailable(String),
+    #[error("Internal error: {source}")]
+    Internal { source: BoxError },
+    #[error("Error accessing state")]
+    Poisoned,
+}
+
+impl KVError {
+    /// The storage backend failed
+    pub fn backend(error: impl Into<BoxError>) -> Self {
+        Self::Backend {
+            source: error.into(),
+        }
+    }
+
+    /// The principal of the request may not access the key
+    pub fn forbidden() -> Self {
+        Self::Forbidden("Access to this key is restricted".to_string())
+    }
+
+    /// `content_type` may not be stored, `allowed` are the patterns that may
+    pub fn unsupported_media_type(content_type: &str, allowed: &[String]) -> Self {
+        Self::UnsupportedMediaType(match allowed {
+            [] => format!("Content type {} is not allowed", content_type),
+            allowed => format!(
+                "Content type {} is not allowed, allowed types: {}",
+                content_type,
+                allowed.join(", ")
+            ),
+        })
+    }
+
+    pub fn internal(error: impl Into<BoxError>) -> Self {
+        Self::Internal {
+            source: error.into(),
+        }
+    }
+
+    /// Stable, machine readable name of the error, sent as `x-error-code`
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::NotFound => "not_found",
+            Self::BadRequest(_) => "bad_request",
+            Self::Unauthorized(_) => "unauthorized",
+            Self::Forbidden(_) => "forbidden",
+            Self::Held => "held",
+            Self::TooLarge(_) => "too_large",
+            Self::UnsupportedMediaType(_) => "unsupported_media_type",
+            Self::BodyTooLarge { .. } => "body_too_large",
+            Self::ChecksumMismatch(_) => "checksum_mismatch",
+            Self::HeadersTooLarge => "headers_too_large",
+            Self::UnsupportedTransferEncoding(_) => "unsupported_transfer_encoding",
+            Self::Conflict { .. } => "conflict",
+            Self::BackendUnavailable { .. } => "backend_unavailable",
+            Self::Backend { .. } => "backend",
+            Self::DeadlineExceeded { .. } => "deadline_exceeded",
+            Self::DecodeFailed { .. } => "decode_failed",
+            Self::EncodeFailed { .. } => "encode_failed",
+            Self::Unavailable(_) => "unavailable",
+            Self::Internal { .. } => "internal",
+            Self::Poisoned => "poisoned",
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::Held => StatusCode::LOCKED,
+            Self::TooLarge(_) | Self::BodyTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::HeadersTooLarge => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            Self::UnsupportedTransferEncoding(_) => StatusCode::NOT_IMPLEMENTED,
+            Self::Conflict { .. } => StatusCode::CONFLICT,
+            Self::BackendUnavailable { .. } | Self::Unavailable(_) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            Self::DeadlineExceeded { .. } => StatusCode::GATEWAY_TIMEOUT,
+            Self::DecodeFailed { .. } | Self::ChecksumMismatch(_) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            Self::Backend { .. }
+            | Self::EncodeFailed { .. }
+            | Self::Internal { .. }
+            | Self::Poisoned => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl<T> From<PoisonError<T>> for KVError {
+    fn from(_: PoisonError<T>) -> Self {
+        Self::Poisoned
+    }
+}
+
+impl IntoResponse for KVError {
+    fn into_response(self) -> Response {
+        let status_code = self.status_code();
+        // 501 is the client's fault, asking for something we don't do
+        if status_code.is_server_error() && status_code != StatusCode::NOT_IMPLEMENTED {
+            tracing::error!(error = ?self, "{}", self);
+        }
+        let mut response = (
+            status_code,
+            [("x-error-code", self.code())],
+            self.to_string(),
+        )
+            .into_response();
+        if let Self::DeadlineExceeded { elapsed, .. } = &self {
+            // For browser dev tools and tracing proxies
+            let timing = format!("deadline;dur={:.1}", elapsed.as_secs_f64() * 1000.0);
+            let timing = HeaderValue::from_str(&timing).expect("Digits are a valid header");
+            response.headers_mut().insert("server-timing", timing);
+        }
+        // For `localize_errors` and `structured_errors`, which rewrite the body
+        response.extensions_mut().insert(Arc::new(self));
+        response
+    }
+}
@@ -0,0 +1,63 @@
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    Json,
+};
+use hyper::{header::LOCATION, StatusCode};
+use serde::Serialize;
+
+use super::read_entry;
+use crate::{
+    auth::{check_access, require_scope, Claims, Principal},
+    KVError, SharedState,
+};
+
+const URL_CONTENT_TYPE: &str = "text/x-url";
+
+pub async fn follow_link(
+    Path(key): Path<String>,
+    State(state): State<SharedState>,
+    principal: Principal,
+    claims: Option<Claims>,
+) -> Result<impl IntoResponse, KVError> {
+    require_scope(claims.as_ref(), "kv:read")?;
+    check_access(&state, &key, &principal)?;
+    let (content_type, data) = read_entry(&state, &key).await?.ok_or(KVError::NotFound)?;
+    let is_link = content_type
+        .split(';')
+        .next()
+        .is_some_and(|essence| essence.trim().eq_ignore_ascii_case(URL_CONTENT_TYPE));
+    let target = std::str::from_utf8(&data).map(str::trim);
+    match (is_link, target) {
+        (true, Ok(target)) if target.starts_with("http://") || target.starts_with("https://") => {
+            *state.write()?.redirects.entry(key).or_default() += 1;
+            Ok((StatusCode::FOUND, [(LOCATION, target.to_string())]))
+        }
+        _ => Err(KVError::Forbidden(
+            "Not possible to follow this value".to_string(),
+        )),
+    }
+}
+
+#[derive(Serialize)]
+pub struct LinkStats {
+    redirects: u64,
+}
+
+pub async fn link_stats(
+    Path(key): Path<String>,
+    State(state): State<SharedState>,
+    principal: Principal,
+    claims: Option<Claims>,
+) -> Result<Json<LinkStats>, KVError> {
+    require_scope(claims.as_ref(), "kv:read")?;
+    check_access(&state, &key, &principal)?;
+    let db = state.read()?.db.clone();
+    if !db.contains(&key).await? {
+        return Err(KVError::NotFound);
+    }
+    let state = state.read()?;
+    Ok(Json(LinkStats {
+        redirects: state.redirects.get(&key).copied().unwrap_or_default(),
+    }))
+}
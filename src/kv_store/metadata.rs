@@ -0,0 +1,147 @@
+use std::{fmt::Write, time::SystemTime};
+
+use axum::headers::{HeaderMapExt, IfModifiedSince};
+use hyper::{body::Bytes, header::IF_NONE_MATCH, HeaderMap};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{Database, KVError, INTERNAL_NAMESPACE};
+use crate::AppState;
+
+/// What the service knows about an entry besides its value
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct EntryMetadata {
+    pub(crate) etag: String,
+    pub(crate) sha256: [u8; 32],
+    /// The `Content-MD5` the upload was verified against, if it had one
+    pub(crate) md5: Option<[u8; 16]>,
+    /// When the entry was written, unknown for entries from before a restart
+    pub(crate) last_modified: Option<SystemTime>,
+    /// The entry a transform made this one from
+    #[serde(default)]
+    pub(crate) derived_from: Option<String>,
+}
+
+impl EntryMetadata {
+    pub(crate) fn new(data: &[u8], last_modified: Option<SystemTime>) -> Self {
+        Self::from_digest(Sha256::digest(data).into(), last_modified)
+    }
+
+    /// For data that was hashed while it streamed in
+    pub(crate) fn from_digest(sha256: [u8; 32], last_modified: Option<SystemTime>) -> Self {
+        Self {
+            etag: etag(&sha256),
+            sha256,
+            md5: None,
+            last_modified,
+            derived_from: None,
+        }
+    }
+
+    /// Whether the client's cached copy is still current. `If-None-Match`
+    /// wins over `If-Modified-Since` when both are sent, as RFC 9110 asks.
+    pub(crate) fn not_modified(&self, headers: &HeaderMap) -> bool {
+        if headers.contains_key(IF_NONE_MATCH) {
+            return etag_matches(headers, &self.etag);
+        }
+        match (headers.typed_get::<IfModifiedSince>(), self.last_modified) {
+            (Some(since), Some(last_modified)) => !since.is_modified(last_modified),
+            _ => false,
+        }
+    }
+}
+
+/// Strong validator for data with the SHA-256 `digest`, its first 128 bits in quotes
+fn etag(digest: &[u8]) -> String {
+    format!("\"{}\"", hex(&digest[..16]))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(2 * bytes.len());
+    for byte in bytes {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+/// What is persisted of an entry besides its value, so that its checksums
+/// and how it is read outlive a restart
+#[derive(Serialize, Deserialize)]
+pub(crate) struct EntryRecord {
+    pub(crate) key: String,
+    pub(crate) metadata: EntryMetadata,
+    #[serde(default)]
+    pub(crate) burn_after_read: bool,
+    /// Wall clock time, the monotonic one doesn't carry over a restart
+    #[serde(default)]
+    pub(crate) expires_at: Option<SystemTime>,
+}
+
+fn records_prefix() -> String {
+    format!("{}/entries/", INTERNAL_NAMESPACE)
+}
+
+/// Records are named after a digest of the key, which fits wherever the
+/// key itself does
+fn record_key(key: &str) -> String {
+    format!(
+        "{}{}",
+        records_prefix(),
+        hex(&Sha256::digest(key.as_bytes()))
+    )
+}
+
+pub(crate) async fn persist_record(db: &Database, record: &EntryRecord) -> Result<(), KVError> {
+    let stored = serde_json::to_vec(record).map_err(KVError::internal)?;
+    db.insert(
+        record_key(&record.key),
+        ("application/json".to_string(), Bytes::from(stored)),
+    )
+    .await
+}
+
+pub(crate) async fn remove_record(db: &Database, key: &str) -> Result<(), KVError> {
+    db.remove(&record_key(key)).await?;
+    Ok(())
+}
+
+/// Restores what was persisted of the entries that are still stored, and
+/// drops the records of those that are gone
+pub(crate) async fn load_records(app_state: &mut AppState) -> Result<(), KVError> {
+    let db = app_state.db.clone();
+    for record_key in db.keys(&records_prefix()).await? {
+        let Some((_, stored)) = db.read(&record_key).await? else {
+            continue;
+        };
+        let record: EntryRecord = serde_json::from_slice(&stored).map_err(KVError::internal)?;
+        if !db.contains(&record.key).await? {
+            db.remove(&record_key).await?;
+            continue;
+        }
+        if let Some(expires_at) = record.expires_at {
+            let remaining = expires_at
+                .duration_since(app_state.clock.system_time())
+                .unwrap_or_default();
+            if let Some(expires) = app_state.clock.now().checked_add(remaining) {
+                app_state.expiries.insert(record.key.clone(), expires);
+            }
+        }
+        if record.burn_after_read {
+            app_state.burn_after_read.insert(record.key.clone());
+        }
+        app_state.metadata.insert(record.key, record.metadata);
+    }
+    Ok(())
+}
+
+/// Whether `If-None-Match` names `etag`, with the weak comparison
+/// RFC 9110 asks for, so `W/"..."` matches as well
+pub(super) fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
@@ -1,89 +1,596 @@
-use std::io::Cursor;
+use std::{
+    io::Cursor,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
 
 use axum::{
-    extract::{Path, State},
-    headers::ContentType,
-    response::IntoResponse,
-    TypedHeader,
+    extract::{ConnectInfo, Path, Query, State},
+    headers::{ContentType, HeaderMapExt, LastModified},
+    response::{IntoResponse, Response},
+    Json, TypedHeader,
+};
+use hyper::{
+    body::Bytes,
+    header::{HeaderValue, HOST},
+    HeaderMap, StatusCode,
 };
-use hyper::{body::Bytes, StatusCode};
 use image::ImageOutputFormat;
+use serde::Serialize;
+use tracing::{field::Empty, Span};
 
-use crate::SharedState;
+use crate::{
+    auth::{check_access, require_scope, Claims, Principal},
+    AppState, SharedState,
+};
 
+#[cfg(feature = "object-store")]
+pub use backends::ObjectStoreDatabase;
+pub(crate) use backends::OverlayDatabase;
+#[cfg(feature = "postgres")]
+pub use backends::PostgresDatabase;
+#[cfg(feature = "redis")]
+pub use backends::RedisDatabase;
+#[cfg(feature = "sled")]
+pub use backends::SledDatabase;
+#[cfg(feature = "sqlite")]
+pub use backends::SqliteDatabase;
+pub use backends::{
+    export_bundle, BatchLimits, BoundedLruDatabase, BundleDatabase, FsDatabase, MemoryDatabase,
+    TieredDatabase, WriteBehindDatabase,
+};
+pub use batch::transform_batch;
+pub(crate) use buckets::local_key;
+pub use buckets::{delete_bucket_kv, get_bucket_kv, list_bucket, post_bucket_kv};
+pub use content_types::ContentTypePolicy;
+pub use database::{close_database, BatchWrite, Database, KVDatabase, ValueStream};
+pub use envelope::get_kv_envelope;
+pub use expiry::{spawn_expiry_sweeper, sweep_expired, TtlQuery};
+pub use flatten::{flatten, BackgroundQuery};
+pub use flood::{BlockTarget, FloodGuard, FloodLimits};
+pub use image_policy::{ImageEncoding, ImagePolicy};
+pub use key_limits::KeyLimits;
+pub use kv_error::KVError;
+pub use links::{follow_link, link_stats};
+pub(crate) use prefetch::forget_hot_keys;
+pub use prefetch::{persist_hot_keys, prefetch_hot_keys, spawn_hot_key_snapshots};
+pub use preview::preview;
+pub use refresh::{
+    delete_refresh_rule, list_refresh_rules, put_refresh_rule, run_due_refreshes, run_refresh_rule,
+    spawn_refresh_scheduler, RefreshRules,
+};
+pub use request_headers::{validate_headers, RequestLimits};
+pub(crate) use reserved::{
+    is_reserved, overlaps_reserved, validate_data_key, validate_user_key, BUCKET_NAMESPACE_PREFIX,
+};
+pub use reserved::{reject_reserved_keys, INTERNAL_NAMESPACE};
+pub use site::{site_index, site_page};
+pub use thumbnail::thumbnail;
+pub use tiles::{get_tile, tile_info};
+pub use upload::Upload;
+pub use virus_scan::ClamdScanner;
+
+use expiry::requested_ttl;
+pub(crate) use metadata::{load_records, remove_record, EntryMetadata};
+use metadata::{persist_record, EntryRecord};
+pub(crate) use tiles::TileCache;
+use transform::{not_modified, read_source, with_etag, Source};
+use virus_scan::ScanVerdict;
+
+mod backends;
+mod batch;
+mod buckets;
+mod checksum;
+mod content_types;
+mod database;
+mod envelope;
+mod expiry;
+mod flatten;
+mod flood;
+mod image_policy;
+mod key_limits;
 mod kv_error;
+mod links;
+mod metadata;
+mod prefetch;
+mod preview;
+mod range;
+mod refresh;
+mod request_headers;
+mod reserved;
+mod site;
+mod thumbnail;
+mod tiles;
+mod transform;
+mod upload;
+mod virus_scan;
+
+/// The namespace of a key is everything before its first `/`
+pub(crate) fn namespace(key: &str) -> Option<&str> {
+    key.split_once('/').map(|(namespace, _)| namespace)
+}
+
+/// Returns the policy that rejected `content_type` for `key`, if any
+fn rejecting_policy<'a>(
+    state: &'a AppState,
+    key: &str,
+    content_type: &str,
+) -> Option<&'a ContentTypePolicy> {
+    let namespace_policy = namespace(key).and_then(|ns| state.namespace_content_types.get(ns));
+    std::iter::once(&state.content_types)
+        .chain(namespace_policy)
+        .find(|policy| !policy.permits(content_type))
+}
+
+fn too_many_writes(retry_after: Duration) -> Response {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [("retry-after", retry_after.as_secs().max(1).to_string())],
+        "Too many writes, slow down",
+    )
+        .into_response()
+}
+
+/// How `store` keeps an upload, besides where and what it is
+#[derive(Default)]
+struct WriteOptions {
+    /// Who flood limits count the write against
+    client: Option<IpAddr>,
+    burn_after_read: bool,
+    ttl: Option<Duration>,
+    /// The entry a transform made the upload from, erased along with it
+    derived_from: Option<String>,
+    /// What transparency is flattened onto if the namespace's image policy
+    /// re-encodes to a format without alpha, instead of the policy's
+    background: Option<[u8; 3]>,
+}
+
+/// Runs an upload through the write checks and stores it
+async fn store(
+    state: &SharedState,
+    key: String,
+    content_type: String,
+    options: WriteOptions,
+    upload: Upload,
+) -> Result<(), Response> {
+    let WriteOptions {
+        client,
+        burn_after_read,
+        ttl,
+        derived_from,
+        background,
+    } = options;
+    let (db, metrics, image_policy) = {
+        let state = state.read().expect("What, an error here?");
+        if state.holds.is_held(&key) {
+            return Err(KVError::Held.into_response());
+        }
+        // Inside a bucket, limits and policies apply as if it were the whole store
+        let local_key = buckets::local_key(&key);
+        let mut limits = namespace(local_key)
+            .and_then(|ns| state.namespace_key_limits.get(ns))
+            .unwrap_or(&state.key_limits)
+            .clone();
+        // Except for the backend's own limit, which counts the bucket's prefix
+        if let Some(max_length) = state.db.max_key_length() {
+            let prefix = key.len() - local_key.len();
+            limits.max_length = limits.max_length.min(max_length.saturating_sub(prefix));
+        }
+        limits
+            .check(local_key)
+            .map_err(IntoResponse::into_response)?;
+        if let Some(policy) = rejecting_policy(&state, local_key, &content_type) {
+            return Err(
+                KVError::unsupported_media_type(&content_type, policy.allowed()).into_response(),
+            );
+        }
+        let image_policy = namespace(local_key)
+            .and_then(|ns| state.namespace_image_policies.get(ns))
+            .cloned();
+        (state.db.clone(), state.metrics.clone(), image_policy)
+    };
+    let creates_key = !db
+        .contains(&key)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    let scanner = {
+        let mut state = state.write().expect("What, an error here?");
+        let now = state.clock.now();
+        if let Some(guard) = state.flood_guard.as_mut() {
+            if let Err(retry_after) = guard.check_write(&key, client, creates_key, now) {
+                return Err(too_many_writes(retry_after));
+            }
+        }
+        state.virus_scanner.clone()
+    };
+    if let Some(scanner) = scanner {
+        let started = Instant::now();
+        let verdict = match upload.reader().await {
+            Ok(reader) => scanner.scan(reader).await,
+            Err(error) => return Err(KVError::internal(error).into_response()),
+        };
+        metrics.histogram(
+            "kv_virus_scan_seconds",
+            &[],
+            started.elapsed().as_secs_f64(),
+        );
+        match verdict {
+            Ok(ScanVerdict::Clean) => {}
+            Ok(ScanVerdict::Infected(signature)) => {
+                metrics.counter("kv_virus_detections_total", &[], 1);
+                return Err((
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    format!("Upload rejected, virus scan found {}", signature),
+                )
+                    .into_response());
+            }
+            Err(_) => {
+                return Err(
+                    (StatusCode::SERVICE_UNAVAILABLE, "Virus scanner unavailable").into_response(),
+                )
+            }
+        }
+    }
+    let (content_type, upload) = match image_policy {
+        Some(mut policy) => {
+            if let Some(background) = background {
+                policy.background = background;
+            }
+            normalize_image(policy, content_type, upload)
+                .await
+                .map_err(IntoResponse::into_response)?
+        }
+        None => (content_type, upload),
+    };
+    let expires_at = {
+        let mut state = state.write().expect("What, an error here?");
+        let too_long = || KVError::BadRequest("TTL is too long".to_string()).into_response();
+        let expiry = match ttl {
+            Some(ttl) => {
+                let expires = state.clock.now().checked_add(ttl).ok_or_else(too_long)?;
+                let expires_at = state.clock.system_time().checked_add(ttl);
+                Some((expires, expires_at.ok_or_else(too_long)?))
+            }
+            None => None,
+        };
+        if burn_after_read {
+            state.burn_after_read.insert(key.clone());
+        } else {
+            state.burn_after_read.remove(&key);
+        }
+        match expiry {
+            Some((expires, _)) => {
+                state.expiries.insert(key.clone(), expires);
+            }
+            None => {
+                state.expiries.remove(&key);
+            }
+        }
+        expiry.map(|(_, expires_at)| expires_at)
+    };
+    let mut metadata = upload.metadata();
+    upload
+        .insert_into(&db, key.clone(), content_type)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    metadata.derived_from = derived_from;
+    metadata.last_modified = Some(
+        state
+            .read()
+            .expect("What, an error here?")
+            .clock
+            .system_time(),
+    );
+    let record = EntryRecord {
+        key,
+        metadata,
+        burn_after_read,
+        expires_at,
+    };
+    persist_record(&db, &record)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    state
+        .write()
+        .expect("What, an error here?")
+        .metadata
+        .insert(record.key, record.metadata);
+    metrics.counter("kv_writes_total", &[], 1);
+    Ok(())
+}
+
+/// Re-encodes an uploaded image as `policy` asks, passes anything else on
+async fn normalize_image(
+    policy: ImagePolicy,
+    content_type: String,
+    upload: Upload,
+) -> Result<(String, Upload), KVError> {
+    if !content_type.starts_with("image/") {
+        return Ok((content_type, upload));
+    }
+    let data = upload.into_bytes().await.map_err(KVError::internal)?;
+    let normalized = tokio::task::spawn_blocking(move || {
+        let normalized = policy.normalize(&content_type, &data)?;
+        Ok::<_, KVError>(normalized.unwrap_or((content_type, data)))
+    })
+    .await
+    .map_err(KVError::internal)??;
+    let (content_type, data) = normalized;
+    Ok((content_type, Upload::from_bytes(data)))
+}
+
+fn burn_after_read(headers: &HeaderMap) -> bool {
+    headers
+        .get("x-burn-after-read")
+        .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"true"))
+}
 
+#[allow(clippy::too_many_arguments)] // axum extractors
+#[tracing::instrument(level = "debug", skip_all, fields(key = %key, size = upload.len()))]
 pub async fn post_kv(
     Path(key): Path<String>,
     TypedHeader(content_type): TypedHeader<ContentType>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Query(query): Query<TtlQuery>,
+    Query(background): Query<BackgroundQuery>,
     State(state): State<SharedState>,
-    data: Bytes,
-) -> Result<String, ()> {
-    state
-        .write()
-        .expect("What, an error here?")
-        .db
-        .insert(key, (content_type.to_string(), data));
+    principal: Principal,
+    claims: Option<Claims>,
+    upload: Upload,
+) -> Result<String, Response> {
+    require_scope(claims.as_ref(), "kv:write")
+        .and_then(|()| check_access(&state, &key, &principal))
+        .map_err(IntoResponse::into_response)?;
+    let ttl = requested_ttl(&headers, &query).map_err(IntoResponse::into_response)?;
+    let background = background
+        .requested()
+        .map_err(IntoResponse::into_response)?;
+    let options = WriteOptions {
+        client: connect_info.map(|ConnectInfo(addr)| addr.ip()),
+        burn_after_read: burn_after_read(&headers),
+        ttl,
+        derived_from: None,
+        background,
+    };
+    store(&state, key, content_type.to_string(), options, upload).await?;
     Ok("OK".to_string())
 }
 
+#[derive(Serialize)]
+pub struct Created {
+    key: String,
+    url: String,
+}
+
+#[allow(clippy::too_many_arguments)] // axum extractors
+pub async fn post_kv_generated(
+    TypedHeader(content_type): TypedHeader<ContentType>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Query(query): Query<TtlQuery>,
+    Query(background): Query<BackgroundQuery>,
+    State(state): State<SharedState>,
+    claims: Option<Claims>,
+    upload: Upload,
+) -> Result<Json<Created>, Response> {
+    require_scope(claims.as_ref(), "kv:write").map_err(IntoResponse::into_response)?;
+    let ttl = requested_ttl(&headers, &query).map_err(IntoResponse::into_response)?;
+    let background = background
+        .requested()
+        .map_err(IntoResponse::into_response)?;
+    let db = state.read().expect("What, an error here?").db.clone();
+    let key = loop {
+        let key = state.read().expect("What, an error here?").random.key();
+        if !db
+            .contains(&key)
+            .await
+            .map_err(IntoResponse::into_response)?
+        {
+            break key;
+        }
+    };
+    let options = WriteOptions {
+        client: connect_info.map(|ConnectInfo(addr)| addr.ip()),
+        burn_after_read: burn_after_read(&headers),
+        ttl,
+        derived_from: None,
+        background,
+    };
+    store(
+        &state,
+        key.clone(),
+        content_type.to_string(),
+        options,
+        upload,
+    )
+    .await?;
+    let path = format!("/kv/{}", key);
+    let url = match headers.get(HOST).and_then(|host| host.to_str().ok()) {
+        Some(host) => format!("http://{}{}", host, path),
+        None => path,
+    };
+    Ok(Json(Created { key, url }))
+}
+
+/// Reads an entry. Burn-after-read entries are removed instead of read,
+/// so exactly one reader gets to see them. Expired entries are not found.
+async fn read_entry(state: &SharedState, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+    Ok(read_or_burn(state, key).await?.0)
+}
+
+/// Like `read_entry`, also telling whether the read removed the entry
+async fn read_or_burn(
+    state: &SharedState,
+    key: &str,
+) -> Result<(Option<(String, Bytes)>, bool), KVError> {
+    let (db, burn) = {
+        let state = state.read()?;
+        if state.is_expired(key) {
+            return Ok((None, false));
+        }
+        let burn = state.burn_after_read.contains(key) && !state.holds.is_held(key);
+        (state.db.clone(), burn)
+    };
+    if !burn {
+        return Ok((db.read(key).await?, false));
+    }
+    let entry = db.remove(key).await?;
+    if entry.is_some() {
+        state.write()?.forget(key);
+        remove_record(&db, key).await?;
+    }
+    Ok((entry, true))
+}
+
+/// Reads an entry for a GET, or any other request returning its value or
+/// something made from it, along with its validators
+async fn read_for_get(
+    state: &SharedState,
+    key: String,
+) -> Result<(String, Bytes, EntryMetadata), KVError> {
+    let (entry, burned) = read_or_burn(state, &key).await?;
+    let result = if entry.is_some() { "hit" } else { "miss" };
+    let metrics = state.read()?.metrics.clone();
+    metrics.counter("kv_reads_total", &[("result", result)], 1);
+    let (content_type, data) = entry.ok_or(KVError::NotFound)?;
+    // Entries written before a restart get their ETag on first read
+    let cached = state.read()?.metadata.get(&key).cloned();
+    let metadata = match cached {
+        Some(metadata) => metadata,
+        // Gone with the entry, not to be cached for a key that is no more
+        None if burned => EntryMetadata::new(&data, None),
+        None => {
+            let metadata = EntryMetadata::new(&data, None);
+            state
+                .write()?
+                .metadata
+                .entry(key)
+                .or_insert(metadata)
+                .clone()
+        }
+    };
+    Ok((content_type, data, metadata))
+}
+
+/// Answers with `response`, or 304 if the client's copy is current
+fn conditional(headers: &HeaderMap, metadata: &EntryMetadata, response: Response) -> Response {
+    let mut response = if metadata.not_modified(headers) {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        response
+    };
+    let response_headers = response.headers_mut();
+    response_headers.insert(
+        "etag",
+        HeaderValue::from_str(&metadata.etag).expect("Hex digits are a valid header"),
+    );
+    if let Some(last_modified) = metadata.last_modified {
+        response_headers.typed_insert(LastModified::from(last_modified));
+    }
+    response
+}
+
+/// Opens an entry for a GET. Entries with a known ETag are streamed from
+/// the backend, the others are read whole to compute it.
+async fn open_for_get(
+    state: &SharedState,
+    key: String,
+) -> Result<(ValueStream, EntryMetadata), KVError> {
+    let streamable = {
+        let state = state.read()?;
+        let plain = !state.burn_after_read.contains(&key) && !state.is_expired(&key);
+        let metadata = state.metadata.get(&key).filter(|_| plain).cloned();
+        metadata.map(|metadata| (state.db.clone(), state.metrics.clone(), metadata))
+    };
+    let Some((db, metrics, metadata)) = streamable else {
+        let (content_type, data, metadata) = read_for_get(state, key).await?;
+        return Ok((ValueStream::whole(content_type, data), metadata));
+    };
+    let value = db.read_stream(&key).await?;
+    let result = if value.is_some() { "hit" } else { "miss" };
+    metrics.counter("kv_reads_total", &[("result", result)], 1);
+    Ok((value.ok_or(KVError::NotFound)?, metadata))
+}
+
+#[tracing::instrument(level = "debug", skip_all, fields(key = %key, size = Empty))]
 pub async fn get_kv(
     Path(key): Path<String>,
+    headers: HeaderMap,
     State(state): State<SharedState>,
-) -> Result<impl IntoResponse, impl IntoResponse> {
-    match state.read().unwrap().db.get(&key) {
-        Some((content_type, data)) => Ok(([("content-type", content_type.clone())], data.clone())),
-        None => Err((StatusCode::NOT_FOUND, "Key not found").into_response()),
+    principal: Principal,
+    claims: Option<Claims>,
+) -> Result<Response, KVError> {
+    require_scope(claims.as_ref(), "kv:read")?;
+    check_access(&state, &key, &principal)?;
+    let (value, metadata) = open_for_get(&state, key).await?;
+    Span::current().record("size", value.len);
+    let mut response = range::ranged(&headers, &metadata, value);
+    if response.status() == StatusCode::OK {
+        checksum::insert_checksum_headers(response.headers_mut(), &metadata);
     }
+    Ok(conditional(&headers, &metadata, response))
 }
 
-pub async fn grayscale(
+pub async fn delete_kv(
     Path(key): Path<String>,
     State(state): State<SharedState>,
-) -> Result<impl IntoResponse, impl IntoResponse> {
-    let state = state.read();
-    if state.is_err() {
-        return Err((StatusCode::INTERNAL_SERVER_ERROR, "Error accessing state").into_response());
-    } else {
-        let state = state.unwrap();
-        let db = state.db.clone();
-        if db.contains_key(&key) {
-            let (content_type, data) = db.get(&key).unwrap();
-            if content_type == "image/png" {
-                let image = image::load_from_memory(&data);
-                let image = if image.is_err() {
-                    return Err((
-                        StatusCode::FORBIDDEN,
-                        "Not possible to grayscale this type of image",
-                    )
-                        .into_response());
-                } else {
-                    image.unwrap()
-                };
-                let mut vec: Vec<u8> = Vec::new();
-                let mut cursor = Cursor::new(&mut vec);
-                let result = image
-                    .grayscale()
-                    .write_to(&mut cursor, ImageOutputFormat::Png);
-                if result.is_err() {
-                    return Err((
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        "Error writing grayscale image",
-                    )
-                        .into_response());
-                }
-                let bytes: Bytes = vec.into();
-
-                return Ok(([("content-type", "image/png")], bytes).into_response());
-            } else {
-                return Err((
-                    StatusCode::FORBIDDEN,
-                    "Not possible to grayscale this type of image",
-                )
-                    .into_response());
-            }
-        } else {
-            return Err((StatusCode::NOT_FOUND, "Key not found").into_response());
+    principal: Principal,
+    claims: Option<Claims>,
+) -> Result<String, KVError> {
+    require_scope(claims.as_ref(), "kv:write")?;
+    check_access(&state, &key, &principal)?;
+    let db = {
+        let state = state.read()?;
+        if state.holds.is_held(&key) {
+            return Err(KVError::Held);
         }
+        state.db.clone()
+    };
+    let removed = db.remove(&key).await?;
+    // Only now, a failed removal keeps the entry as it was
+    state.write()?.forget(&key);
+    remove_record(&db, &key).await?;
+    match removed {
+        Some(_) => Ok("OK".to_string()),
+        None => Err(KVError::NotFound),
+    }
+}
+
+#[tracing::instrument(level = "debug", skip_all, fields(key = %key, size = Empty))]
+pub async fn grayscale(
+    Path(key): Path<String>,
+    headers: HeaderMap,
+    State(state): State<SharedState>,
+    principal: Principal,
+    claims: Option<Claims>,
+) -> Result<Response, KVError> {
+    require_scope(claims.as_ref(), "kv:read")?;
+    check_access(&state, &key, &principal)?;
+    let (content_type, data, etag) =
+        match read_source(&state, &key, &headers, "grayscale", "").await? {
+            Source::NotModified(etag) => return Ok(not_modified(&etag)),
+            Source::Value {
+                content_type,
+                data,
+                etag,
+            } => (content_type, data, etag),
+        };
+    Span::current().record("size", data.len());
+    if content_type != "image/png" {
+        return Err(KVError::Forbidden(
+            "Not possible to grayscale this type of image".to_string(),
+        ));
     }
+    let image =
+        image::load_from_memory(&data).map_err(|source| KVError::DecodeFailed { source })?;
+    let mut png = Vec::new();
+    image
+        .grayscale()
+        .write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)
+        .map_err(|source| KVError::EncodeFailed { source })?;
+    Ok(with_etag(
+        &etag,
+        ([("content-type", "image/png")], Bytes::from(png)),
+    ))
 }
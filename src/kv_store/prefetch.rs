@@ -0,0 +1,87 @@
+//! Warming up the hot cache after a restart with the keys that were read
+//! most before it, so a deploy doesn't send every first read to the cold
+//! backend at once
+
+use std::{collections::HashSet, time::Duration};
+
+use hyper::body::Bytes;
+use tokio::task::JoinHandle;
+
+use super::{Database, KVError, INTERNAL_NAMESPACE};
+use crate::{SharedState, Shutdown};
+
+fn storage_key() -> String {
+    format!("{}/hot-keys", INTERNAL_NAMESPACE)
+}
+
+/// Stores the `n` most read keys for `prefetch_hot_keys` to load on the
+/// next start. Nothing is stored for backends without a cache.
+pub async fn persist_hot_keys(state: &SharedState, n: usize) -> Result<(), KVError> {
+    let db = state.read()?.db.clone();
+    let keys = db.hot_keys(n).await?;
+    if keys.is_empty() {
+        return Ok(());
+    }
+    let list = serde_json::to_vec(&keys).map_err(KVError::internal)?;
+    db.insert(
+        storage_key(),
+        ("application/json".to_string(), Bytes::from(list)),
+    )
+    .await
+}
+
+/// Takes `erased` keys off the list stored by `persist_hot_keys`
+pub(crate) async fn forget_hot_keys(
+    db: &Database,
+    erased: &HashSet<String>,
+) -> Result<(), KVError> {
+    let Some((_, list)) = db.read(&storage_key()).await? else {
+        return Ok(());
+    };
+    let keys: Vec<String> = serde_json::from_slice(&list).map_err(KVError::internal)?;
+    let kept: Vec<&String> = keys.iter().filter(|key| !erased.contains(*key)).collect();
+    if kept.len() == keys.len() {
+        return Ok(());
+    }
+    let list = serde_json::to_vec(&kept).map_err(KVError::internal)?;
+    db.insert(
+        storage_key(),
+        ("application/json".to_string(), Bytes::from(list)),
+    )
+    .await
+}
+
+/// Loads the keys stored by the last `persist_hot_keys` into the cache and
+/// returns how many were loaded
+pub async fn prefetch_hot_keys(state: &SharedState) -> Result<usize, KVError> {
+    let db = state.read()?.db.clone();
+    let Some((_, list)) = db.read(&storage_key()).await? else {
+        return Ok(0);
+    };
+    let keys: Vec<String> = serde_json::from_slice(&list).map_err(KVError::internal)?;
+    db.prefetch(&keys).await
+}
+
+/// Stores the `n` most read keys every `period` in a background task, so a
+/// crash loses no more than that. Ends with `shutdown`, persisting the keys
+/// on the way out is up to the caller.
+pub fn spawn_hot_key_snapshots(
+    state: SharedState,
+    period: Duration,
+    n: usize,
+    shutdown: Shutdown,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(period);
+        // The first tick completes right away, with nothing read yet
+        ticker.tick().await;
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = shutdown.triggered() => return,
+            }
+            // A failing backend is retried on the next tick
+            let _ = persist_hot_keys(&state, n).await;
+        }
+    })
+}
@@ -0,0 +1,118 @@
+use std::{fmt::Write, sync::OnceLock};
+
+use axum::{
+    extract::{Path, State},
+    response::{Html, Response},
+};
+use hyper::HeaderMap;
+use syntect::{
+    easy::HighlightLines,
+    highlighting::{Theme, ThemeSet},
+    html::{styled_line_to_highlighted_html, IncludeBackground},
+    parsing::{SyntaxReference, SyntaxSet},
+    util::LinesWithEndings,
+};
+
+use super::{not_modified, read_source, with_etag, Source};
+use crate::{
+    auth::{check_access, require_scope, Claims, Principal},
+    KVError, SharedState,
+};
+
+const THEME: &str = "InspiredGitHub";
+
+/// Loading syntaxes and themes is expensive, do it once
+fn syntaxes() -> &'static (SyntaxSet, Theme) {
+    static SYNTAXES: OnceLock<(SyntaxSet, Theme)> = OnceLock::new();
+    SYNTAXES.get_or_init(|| {
+        let mut themes = ThemeSet::load_defaults();
+        let theme = themes.themes.remove(THEME).expect("default theme exists");
+        (SyntaxSet::load_defaults_newlines(), theme)
+    })
+}
+
+/// Picks a syntax from the key's extension, or from `text/x-<language>`
+/// style content types
+fn find_syntax<'a>(
+    syntax_set: &'a SyntaxSet,
+    key: &str,
+    content_type: &str,
+) -> Option<&'a SyntaxReference> {
+    let by_extension = key
+        .rsplit_once('.')
+        .and_then(|(_, extension)| syntax_set.find_syntax_by_extension(extension));
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    let language = essence
+        .strip_prefix("text/x-")
+        .or_else(|| essence.strip_prefix("application/x-"))
+        .or_else(|| essence.strip_prefix("application/"));
+    by_extension.or_else(|| language.and_then(|language| syntax_set.find_syntax_by_token(language)))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn render(key: &str, text: &str, syntax: &SyntaxReference) -> Result<String, syntect::Error> {
+    let (syntax_set, theme) = syntaxes();
+    let background = theme
+        .settings
+        .background
+        .unwrap_or(syntect::highlighting::Color::WHITE);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{}</title></head><body>\n\
+         <pre style=\"background-color:#{:02x}{:02x}{:02x};padding:1em\">",
+        escape(key),
+        background.r,
+        background.g,
+        background.b
+    );
+    let mut highlighter = HighlightLines::new(syntax, theme);
+    for (number, line) in LinesWithEndings::from(text).enumerate() {
+        let regions = highlighter.highlight_line(line, syntax_set)?;
+        let line = styled_line_to_highlighted_html(&regions, IncludeBackground::No)?;
+        let _ = write!(
+            html,
+            "<span style=\"color:#999;user-select:none\">{:>4} </span>{}",
+            number + 1,
+            line
+        );
+    }
+    html.push_str("</pre>\n</body></html>\n");
+    Ok(html)
+}
+
+pub async fn preview(
+    Path(key): Path<String>,
+    headers: HeaderMap,
+    State(state): State<SharedState>,
+    principal: Principal,
+    claims: Option<Claims>,
+) -> Result<Response, KVError> {
+    require_scope(claims.as_ref(), "kv:read")?;
+    check_access(&state, &key, &principal)?;
+    let (content_type, data, etag) =
+        match read_source(&state, &key, &headers, "preview", "").await? {
+            Source::NotModified(etag) => return Ok(not_modified(&etag)),
+            Source::Value {
+                content_type,
+                data,
+                etag,
+            } => (content_type, data, etag),
+        };
+    let (syntax_set, _) = syntaxes();
+    let syntax = find_syntax(syntax_set, &key, &content_type);
+    let text = std::str::from_utf8(&data).ok();
+    match (syntax, text) {
+        (Some(syntax), Some(text)) => {
+            let html = render(&key, text, syntax).map_err(KVError::internal)?;
+            Ok(with_etag(&etag, Html(html)))
+        }
+        _ => Err(KVError::Forbidden(
+            "Not possible to preview this type of value".to_string(),
+        )),
+    }
+}
@@ -0,0 +1,115 @@
+use std::ops::Range;
+
+use axum::{
+    body::StreamBody,
+    response::{IntoResponse, Response},
+};
+use hyper::{
+    header::{
+        HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, IF_RANGE, RANGE,
+    },
+    HeaderMap, StatusCode,
+};
+
+use super::{EntryMetadata, ValueStream};
+
+enum Requested {
+    Everything,
+    Part(Range<u64>),
+    Unsatisfiable,
+}
+
+/// Parses a single range of a `bytes=` range set. Ranges that can't be
+/// parsed are `None`, and the whole `Range` header is ignored.
+fn parse_range(range: &str, size: u64) -> Option<Requested> {
+    let (first, last) = range.trim().split_once('-')?;
+    let part = if first.is_empty() {
+        // `-500`, the last 500 bytes
+        let length: u64 = last.parse().ok()?;
+        if length == 0 {
+            return Some(Requested::Unsatisfiable);
+        }
+        size.saturating_sub(length)..size
+    } else {
+        let first: u64 = first.parse().ok()?;
+        if last.is_empty() {
+            // `500-`, everything from byte 500 on
+            first..size
+        } else {
+            let last: u64 = last.parse().ok()?;
+            if last < first {
+                return None;
+            }
+            first..size.min(last.saturating_add(1))
+        }
+    };
+    if part.start >= size {
+        Some(Requested::Unsatisfiable)
+    } else {
+        Some(Requested::Part(part))
+    }
+}
+
+/// What the client asks for with `Range`. Only single ranges are served,
+/// for more than one the whole value is sent, which RFC 9110 allows.
+fn requested(headers: &HeaderMap, metadata: &EntryMetadata, size: u64) -> Requested {
+    let Some(ranges) = headers
+        .get(RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("bytes="))
+    else {
+        return Requested::Everything;
+    };
+    // A range of a value that changed since the client's copy is useless
+    if let Some(if_range) = headers.get(IF_RANGE) {
+        if if_range.as_bytes() != metadata.etag.as_bytes() {
+            return Requested::Everything;
+        }
+    }
+    match ranges.split_once(',') {
+        Some(_) => Requested::Everything,
+        None => parse_range(ranges, size).unwrap_or(Requested::Everything),
+    }
+}
+
+/// Answers with the part of `value` the client asked for with `Range`, or
+/// all of it
+pub(super) fn ranged(
+    headers: &HeaderMap,
+    metadata: &EntryMetadata,
+    value: ValueStream,
+) -> Response {
+    let size = value.len;
+    let accept_ranges = (ACCEPT_RANGES, "bytes".to_string());
+    let (status, content_range, value) = match requested(headers, metadata, size) {
+        Requested::Everything => (StatusCode::OK, None, value),
+        Requested::Part(part) => (
+            StatusCode::PARTIAL_CONTENT,
+            Some(format!("bytes {}-{}/{}", part.start, part.end - 1, size)),
+            value.slice(part),
+        ),
+        Requested::Unsatisfiable => {
+            return (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [accept_ranges, (CONTENT_RANGE, format!("bytes */{}", size))],
+            )
+                .into_response()
+        }
+    };
+    let mut response = (
+        status,
+        [
+            (CONTENT_TYPE, value.content_type),
+            (CONTENT_LENGTH, value.len.to_string()),
+            accept_ranges,
+        ],
+        StreamBody::new(value.chunks),
+    )
+        .into_response();
+    if let Some(content_range) = content_range {
+        let content_range =
+            HeaderValue::from_str(&content_range).expect("Digits are a valid header");
+        response.headers_mut().insert(CONTENT_RANGE, content_range);
+    }
+    response
+}
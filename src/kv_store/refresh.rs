@@ -0,0 +1,289 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+
+use super::{
+    batch::{check_target, run_batch, target_of, BatchResult, Operation, Pipeline},
+    is_reserved, KVError,
+};
+use crate::{auth::Principal, SharedState, Shutdown};
+
+/// Runs kept per rule, older ones are dropped
+const HISTORY: usize = 10;
+/// Failed keys kept per run, the count covers all of them
+const FAILURES: usize = 20;
+
+/// Regenerates derived images of every key under `prefix` each `interval`
+/// seconds, e.g. the thumbnails of `banners/` nightly, so they follow
+/// changes to transform defaults
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RefreshRule {
+    prefix: String,
+    pipeline: Vec<Operation>,
+    /// Where results go, with `{key}` standing for the source key
+    target: String,
+    interval: u64,
+}
+
+/// How a run of a rule went
+#[derive(Clone, Serialize)]
+pub struct RefreshRun {
+    /// Unix time
+    started_at: u64,
+    duration_ms: u64,
+    keys: usize,
+    failed: usize,
+    /// The first few failed keys
+    failures: Vec<BatchResult>,
+}
+
+struct Scheduled {
+    rule: RefreshRule,
+    pipeline: Arc<Pipeline>,
+    /// Who registered the rule, runs have their access and no more
+    principal: Principal,
+    next_run: Instant,
+    running: bool,
+    /// Most recent last
+    runs: VecDeque<RefreshRun>,
+}
+
+/// Registered refresh rules by name
+#[derive(Default)]
+pub struct RefreshRules {
+    rules: BTreeMap<String, Scheduled>,
+}
+
+#[derive(Serialize)]
+pub struct RuleStatus {
+    #[serde(flatten)]
+    rule: RefreshRule,
+    /// Seconds until the next scheduled run
+    next_run_in: u64,
+    running: bool,
+    runs: VecDeque<RefreshRun>,
+}
+
+pub async fn list_refresh_rules(
+    State(state): State<SharedState>,
+) -> Result<Json<BTreeMap<String, RuleStatus>>, KVError> {
+    let state = state.read()?;
+    let now = state.clock.now();
+    Ok(Json(
+        state
+            .refresh
+            .rules
+            .iter()
+            .map(|(name, scheduled)| {
+                let status = RuleStatus {
+                    rule: scheduled.rule.clone(),
+                    next_run_in: scheduled.next_run.saturating_duration_since(now).as_secs(),
+                    running: scheduled.running,
+                    runs: scheduled.runs.clone(),
+                };
+                (name.clone(), status)
+            })
+            .collect(),
+    ))
+}
+
+/// Registers or replaces a rule, run as the principal registering it. Its
+/// first run is one interval away, runs can be started right away through
+/// `run_refresh_rule`.
+pub async fn put_refresh_rule(
+    Path(name): Path<String>,
+    State(state): State<SharedState>,
+    principal: Principal,
+    Json(rule): Json<RefreshRule>,
+) -> Result<StatusCode, KVError> {
+    check_target(&rule.target)?;
+    // Results under the prefix would be refreshed themselves next time
+    if target_of(&rule.target, &rule.prefix).starts_with(&rule.prefix) {
+        return Err(KVError::BadRequest(
+            "The target can't be under the prefix".to_string(),
+        ));
+    }
+    if rule.interval == 0 {
+        return Err(KVError::BadRequest(
+            "The interval is at least a second".to_string(),
+        ));
+    }
+    let pipeline = Arc::new(Pipeline::new(&rule.pipeline)?);
+    let mut state = state.write()?;
+    let next_run = state.clock.now() + Duration::from_secs(rule.interval);
+    let runs = state
+        .refresh
+        .rules
+        .remove(&name)
+        .map(|scheduled| scheduled.runs)
+        .unwrap_or_default();
+    state.refresh.rules.insert(
+        name,
+        Scheduled {
+            rule,
+            pipeline,
+            principal,
+            next_run,
+            running: false,
+            runs,
+        },
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn delete_refresh_rule(
+    Path(name): Path<String>,
+    State(state): State<SharedState>,
+) -> Result<StatusCode, KVError> {
+    match state.write()?.refresh.rules.remove(&name) {
+        Some(_) => Ok(StatusCode::NO_CONTENT),
+        None => Err(KVError::NotFound),
+    }
+}
+
+/// Runs a rule now, regardless of its schedule
+pub async fn run_refresh_rule(
+    Path(name): Path<String>,
+    State(state): State<SharedState>,
+) -> Result<Json<RefreshRun>, KVError> {
+    run_rule(&state, &name).await.map(Json)
+}
+
+async fn run_rule(state: &SharedState, name: &str) -> Result<RefreshRun, KVError> {
+    let (db, pipeline, rule, principal, started, started_at) = {
+        let mut state = state.write()?;
+        let now = state.clock.now();
+        let started_at = state.clock.system_time();
+        if state.paused_writes() {
+            return Err(KVError::Unavailable(
+                "Writes are paused, refreshes wait until they resume".to_string(),
+            ));
+        }
+        let scheduled = state.refresh.rules.get_mut(name).ok_or(KVError::NotFound)?;
+        if scheduled.running {
+            return Err(KVError::Unavailable(format!(
+                "The refresh rule {} is running already",
+                name
+            )));
+        }
+        scheduled.running = true;
+        scheduled.next_run = now + Duration::from_secs(scheduled.rule.interval);
+        let (pipeline, rule) = (Arc::clone(&scheduled.pipeline), scheduled.rule.clone());
+        let principal = scheduled.principal.clone();
+        (state.db.clone(), pipeline, rule, principal, now, started_at)
+    };
+    let results: Result<Vec<BatchResult>, KVError> = async {
+        let keys = db
+            .keys(&rule.prefix)
+            .await?
+            .into_iter()
+            .filter(|key| !is_reserved(key))
+            .collect();
+        let pipeline = Arc::clone(&pipeline);
+        Ok(run_batch(state, pipeline, keys, &rule.target, &principal).await)
+    }
+    .await;
+
+    let mut state = state.write()?;
+    let finished = state.clock.now();
+    let metrics = state.metrics.clone();
+    // Unless the rule was deleted or replaced in the meantime
+    let mut scheduled = state
+        .refresh
+        .rules
+        .get_mut(name)
+        .filter(|scheduled| Arc::ptr_eq(&scheduled.pipeline, &pipeline));
+    if let Some(scheduled) = scheduled.as_mut() {
+        scheduled.running = false;
+    }
+    let results = results?;
+    let failures: Vec<BatchResult> = results
+        .iter()
+        .filter(|result| !result.succeeded())
+        .cloned()
+        .collect();
+    let run = RefreshRun {
+        started_at: started_at
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs()),
+        duration_ms: finished.duration_since(started).as_millis() as u64,
+        keys: results.len(),
+        failed: failures.len(),
+        failures: failures.into_iter().take(FAILURES).collect(),
+    };
+    metrics.counter("kv_refresh_runs_total", &[("rule", name)], 1);
+    if run.failed > 0 {
+        metrics.counter(
+            "kv_refresh_failures_total",
+            &[("rule", name)],
+            run.failed as u64,
+        );
+        tracing::warn!(
+            rule = name,
+            failed = run.failed,
+            "Refresh failed for some keys"
+        );
+    }
+    if let Some(scheduled) = scheduled {
+        if scheduled.runs.len() == HISTORY {
+            scheduled.runs.pop_front();
+        }
+        scheduled.runs.push_back(run.clone());
+    }
+    Ok(run)
+}
+
+/// Runs the rules that are due, returns how many were
+pub async fn run_due_refreshes(state: &SharedState) -> Result<usize, KVError> {
+    let due: Vec<String> = {
+        let state = state.read()?;
+        let now = state.clock.now();
+        // Due rules stay due until writes resume
+        if state.paused_writes() {
+            return Ok(0);
+        }
+        state
+            .refresh
+            .rules
+            .iter()
+            .filter(|(_, scheduled)| !scheduled.running && scheduled.next_run <= now)
+            .map(|(name, _)| name.clone())
+            .collect()
+    };
+    for name in &due {
+        if let Err(error) = run_rule(state, name).await {
+            tracing::warn!(rule = name.as_str(), %error, "Refresh failed");
+        }
+    }
+    Ok(due.len())
+}
+
+/// Runs refresh rules as they come due, checking every `period`. Ends with
+/// `shutdown`, after finishing the runs in progress.
+pub fn spawn_refresh_scheduler(
+    state: SharedState,
+    period: Duration,
+    shutdown: Shutdown,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(period);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = shutdown.triggered() => return,
+            }
+            let _ = run_due_refreshes(&state).await;
+        }
+    })
+}
@@ -0,0 +1,113 @@
+use axum::{
+    extract::State,
+    headers::{ContentType, HeaderMapExt},
+    http::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hyper::{
+    header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING},
+    HeaderMap,
+};
+
+use super::KVError;
+use crate::SharedState;
+
+/// Bounds on what a request may announce in its headers
+#[derive(Clone, Debug)]
+pub struct RequestLimits {
+    /// Largest `Content-Length` accepted
+    pub max_body_bytes: u64,
+    /// Largest total size of all header names and values
+    pub max_header_bytes: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            max_body_bytes: 16 * 1024 * 1024,
+            max_header_bytes: 32 * 1024,
+        }
+    }
+}
+
+impl RequestLimits {
+    /// Checks the headers of a request, and rewrites its Content-Type to
+    /// the normalized form handlers and policies see
+    fn check(&self, headers: &mut HeaderMap) -> Result<(), KVError> {
+        let header_bytes: usize = headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        if header_bytes > self.max_header_bytes {
+            return Err(KVError::HeadersTooLarge);
+        }
+        for value in headers.get_all(TRANSFER_ENCODING) {
+            let codings = value.to_str().unwrap_or_default();
+            if let Some(coding) = codings
+                .split(',')
+                .map(str::trim)
+                .find(|coding| !coding.eq_ignore_ascii_case("chunked"))
+            {
+                return Err(KVError::UnsupportedTransferEncoding(coding.to_string()));
+            }
+        }
+        // Bodies without a length, chunked or over HTTP/2, are cut off by `Upload`
+        if let Some(length) = headers.get(CONTENT_LENGTH) {
+            let length: u64 = length
+                .to_str()
+                .ok()
+                .and_then(|length| length.parse().ok())
+                .ok_or_else(|| KVError::BadRequest("Invalid Content-Length header".to_string()))?;
+            if length > self.max_body_bytes {
+                return Err(KVError::BodyTooLarge {
+                    limit: self.max_body_bytes,
+                });
+            }
+        }
+        if let Some(content_type) = headers.get(CONTENT_TYPE) {
+            let invalid = || KVError::BadRequest("Invalid Content-Type header".to_string());
+            let normalized = normalize_content_type(content_type.to_str().map_err(|_| invalid())?);
+            let normalized = HeaderValue::from_str(&normalized).map_err(|_| invalid())?;
+            headers.insert(CONTENT_TYPE, normalized);
+            headers.typed_get::<ContentType>().ok_or_else(invalid)?;
+        }
+        Ok(())
+    }
+}
+
+/// `Text/HTML ;Charset=utf-8` becomes `text/html; charset=utf-8`. Parameter
+/// values keep their case, some of them are case sensitive.
+fn normalize_content_type(content_type: &str) -> String {
+    let mut parts = content_type
+        .split(';')
+        .map(str::trim)
+        .filter(|part| !part.is_empty());
+    let essence = parts.next().unwrap_or_default().to_ascii_lowercase();
+    let params = parts.map(|param| match param.split_once('=') {
+        Some((name, value)) => format!("{}={}", name.trim().to_ascii_lowercase(), value.trim()),
+        None => param.to_string(),
+    });
+    std::iter::once(essence)
+        .chain(params)
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Rejects requests whose headers break the `RequestLimits`, before any
+/// handler extracts them
+pub async fn validate_headers<B>(
+    State(state): State<SharedState>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let limits = state
+        .read()
+        .expect("What, an error here?")
+        .request_limits
+        .clone();
+    match limits.check(request.headers_mut()) {
+        Ok(()) => next.run(request).await,
+        Err(error) => error.into_response(),
+    }
+}
@@ -0,0 +1,67 @@
+use axum::{
+    http::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use percent_encoding::percent_decode_str;
+
+use super::KVError;
+
+/// Namespace for state the service keeps about itself, never reachable
+/// through the user facing routes
+pub const INTERNAL_NAMESPACE: &str = "__internal";
+
+/// Buckets are namespaces named with this prefix. What they hold is user
+/// data that erasure and holds apply to, but only the bucket routes reach it.
+pub(crate) const BUCKET_NAMESPACE_PREFIX: &str = "__bucket.";
+
+pub(crate) fn is_reserved(key: &str) -> bool {
+    key.split('/').next() == Some(INTERNAL_NAMESPACE)
+}
+
+pub(crate) fn in_bucket(key: &str) -> bool {
+    key.starts_with(BUCKET_NAMESPACE_PREFIX)
+}
+
+/// Whether keys starting with `prefix` can be reserved, as every prefix of
+/// the namespace's name matches it too
+pub(crate) fn overlaps_reserved(prefix: &str) -> bool {
+    is_reserved(prefix) || INTERNAL_NAMESPACE.starts_with(prefix)
+}
+
+/// Every key an operator names goes through here, as in an erasure. Keys
+/// in buckets are fine, those of the service itself are not.
+pub(crate) fn validate_data_key(key: &str) -> Result<(), KVError> {
+    if is_reserved(key) {
+        return Err(KVError::Forbidden(
+            "Key is reserved for internal use".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Every key a client names goes through here, whether in the path or in
+/// the body of a batch or a refresh rule
+pub(crate) fn validate_user_key(key: &str) -> Result<(), KVError> {
+    validate_data_key(key)?;
+    if in_bucket(key) {
+        return Err(KVError::Forbidden(
+            "Keys in buckets are only reachable through /bucket/:bucket/kv/:key".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Rejects requests to user facing routes whose path addresses a reserved
+/// key, before they are even routed. Keys in bodies are left to handlers.
+pub async fn reject_reserved_keys<B>(request: Request<B>, next: Next<B>) -> Response {
+    let path = percent_decode_str(request.uri().path()).decode_utf8_lossy();
+    let key = path
+        .strip_prefix("/kv/")
+        .or_else(|| path.strip_prefix("/r/"))
+        .or_else(|| path.strip_prefix("/site/"));
+    match key.map(validate_user_key) {
+        Some(Err(error)) => error.into_response(),
+        _ => next.run(request).await,
+    }
+}
@@ -0,0 +1,85 @@
+use axum::{
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+};
+use hyper::StatusCode;
+
+use super::read_entry;
+use crate::{
+    auth::{check_access, require_scope, Claims, Principal},
+    SharedState,
+};
+
+const CACHE_CONTROL: &str = "public, max-age=300";
+
+/// Keys to try for a site path, `index.html` stands in for directories
+fn candidates(namespace: &str, path: &str) -> Vec<String> {
+    let path = path.trim_start_matches('/');
+    if path.is_empty() || path.ends_with('/') {
+        vec![format!("{}/{}index.html", namespace, path)]
+    } else {
+        vec![
+            format!("{}/{}", namespace, path),
+            format!("{}/{}/index.html", namespace, path),
+        ]
+    }
+}
+
+pub async fn site_index(
+    Path(namespace): Path<String>,
+    state: State<SharedState>,
+    principal: Principal,
+    claims: Option<Claims>,
+) -> Result<Response, Response> {
+    site_page(Path((namespace, String::new())), state, principal, claims).await
+}
+
+pub async fn site_page(
+    Path((namespace, path)): Path<(String, String)>,
+    State(state): State<SharedState>,
+    principal: Principal,
+    claims: Option<Claims>,
+) -> Result<Response, Response> {
+    require_scope(claims.as_ref(), "kv:read").map_err(IntoResponse::into_response)?;
+    if !state
+        .read()
+        .expect("What, an error here?")
+        .sites
+        .contains(&namespace)
+    {
+        return Err((StatusCode::NOT_FOUND, "Site not found").into_response());
+    }
+    for key in candidates(&namespace, &path) {
+        check_access(&state, &key, &principal).map_err(IntoResponse::into_response)?;
+        if let Some((content_type, data)) = read_entry(&state, &key)
+            .await
+            .map_err(IntoResponse::into_response)?
+        {
+            return Ok((
+                [
+                    ("content-type", content_type),
+                    ("cache-control", CACHE_CONTROL.to_string()),
+                ],
+                data,
+            )
+                .into_response());
+        }
+    }
+    let not_found_key = format!("{}/404.html", namespace);
+    // Without access to the error page, the plain one will do
+    let not_found = match check_access(&state, &not_found_key, &principal) {
+        Ok(()) => read_entry(&state, &not_found_key)
+            .await
+            .map_err(IntoResponse::into_response)?,
+        Err(_) => None,
+    };
+    match not_found {
+        Some((content_type, data)) => Err((
+            StatusCode::NOT_FOUND,
+            [("content-type", content_type)],
+            data,
+        )
+            .into_response()),
+        None => Err((StatusCode::NOT_FOUND, "Page not found").into_response()),
+    }
+}
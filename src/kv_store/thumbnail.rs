@@ -0,0 +1,200 @@
+use std::io::Cursor;
+
+use axum::{
+    extract::{Path, Query, State},
+    response::Response,
+};
+use hyper::{body::Bytes, HeaderMap};
+use image::{imageops::FilterType, DynamicImage, GrayImage, ImageFormat, ImageOutputFormat};
+use serde::{Deserialize, Serialize};
+
+use super::{not_modified, read_source, with_etag, KVError, Source};
+use crate::{
+    auth::{check_access, require_scope, Claims, Principal},
+    SharedState,
+};
+
+/// Largest thumbnail edge, so a query can't make us render a poster
+const MAX_EDGE: u32 = 2048;
+/// Edge length saliency is computed at, detail below that doesn't matter
+/// for picking a window
+const ANALYSIS_EDGE: u32 = 128;
+
+/// Where the crop window goes when the aspect ratio has to change
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Gravity {
+    #[default]
+    Center,
+    /// Where most edges are, which is usually the subject rather than
+    /// background. Centered for images without any detail.
+    Smart,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ThumbnailQuery {
+    width: u32,
+    height: u32,
+    #[serde(default)]
+    gravity: Gravity,
+}
+
+impl ThumbnailQuery {
+    pub(crate) fn check(&self) -> Result<(), KVError> {
+        if !(1..=MAX_EDGE).contains(&self.width) || !(1..=MAX_EDGE).contains(&self.height) {
+            return Err(KVError::BadRequest(format!(
+                "Thumbnails are 1 to {} pixels wide and high",
+                MAX_EDGE
+            )));
+        }
+        Ok(())
+    }
+
+    /// Crops `image` as far as the aspect ratio requires and scales it
+    pub(crate) fn apply(&self, image: &DynamicImage) -> DynamicImage {
+        let [x, y, width, height] = crop_window(image, self.width, self.height, self.gravity);
+        image.crop_imm(x, y, width, height).resize_exact(
+            self.width,
+            self.height,
+            FilterType::Lanczos3,
+        )
+    }
+}
+
+/// Edge magnitude of every pixel, as the sum of absolute luma differences
+/// to the right and bottom neighbours
+fn edges(luma: &GrayImage) -> Vec<u64> {
+    let (width, height) = luma.dimensions();
+    let at = |x: u32, y: u32| luma.get_pixel(x.min(width - 1), y.min(height - 1)).0[0] as i32;
+    (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .map(|(x, y)| ((at(x, y) - at(x + 1, y)).abs() + (at(x, y) - at(x, y + 1)).abs()) as u64)
+        .collect()
+}
+
+/// Offset of the `window` wide slice of `totals` with the most edges,
+/// centered unless some other offset has strictly more
+fn best_offset(totals: &[u64], window: usize) -> usize {
+    let center = (totals.len() - window) / 2;
+    let mut sum: u64 = totals[..window].iter().sum();
+    let mut sums = vec![sum];
+    for start in 1..=totals.len() - window {
+        sum = sum + totals[start + window - 1] - totals[start - 1];
+        sums.push(sum);
+    }
+    let mut best = center;
+    for (start, &sum) in sums.iter().enumerate() {
+        let closer = start.abs_diff(center) < best.abs_diff(center);
+        if sum > sums[best] || (sum == sums[best] && closer) {
+            best = start;
+        }
+    }
+    best
+}
+
+/// The `[x, y, width, height]` of the largest window with the aspect ratio
+/// of `width` x `height` inside `image`
+fn crop_window(image: &DynamicImage, width: u32, height: u32, gravity: Gravity) -> [u32; 4] {
+    let (source_width, source_height) = (image.width() as u64, image.height() as u64);
+    let (window_width, window_height) =
+        if source_width * height as u64 > source_height * width as u64 {
+            (
+                (source_height * width as u64 / height as u64).max(1),
+                source_height,
+            )
+        } else {
+            (
+                source_width,
+                (source_width * height as u64 / width as u64).max(1),
+            )
+        };
+    let (free, window, horizontal) = if window_width < source_width {
+        (source_width, window_width, true)
+    } else {
+        (source_height, window_height, false)
+    };
+    let offset = match gravity {
+        Gravity::Center => (free - window) / 2,
+        Gravity::Smart if window == free => 0,
+        Gravity::Smart => {
+            // Scaled down so the search stays cheap for large images
+            let scale = (source_width.max(source_height) as f64 / ANALYSIS_EDGE as f64).max(1.0);
+            let analysed = |size: u64| ((size as f64 / scale).round() as u32).max(1);
+            let luma = image
+                .resize_exact(
+                    analysed(source_width),
+                    analysed(source_height),
+                    FilterType::Triangle,
+                )
+                .to_luma8();
+            let (luma_width, luma_height) = luma.dimensions();
+            let edges = edges(&luma);
+            // Edges per column or row along the free axis
+            let totals: Vec<u64> = if horizontal {
+                (0..luma_width as usize)
+                    .map(|x| edges.iter().skip(x).step_by(luma_width as usize).sum())
+                    .collect()
+            } else {
+                edges
+                    .chunks(luma_width as usize)
+                    .map(|row| row.iter().sum())
+                    .collect()
+            };
+            let size = if horizontal { luma_width } else { luma_height } as u64;
+            let scaled_window = (window * size / free).clamp(1, size) as usize;
+            let offset = best_offset(&totals, scaled_window) as u64 * free / size;
+            offset.min(free - window)
+        }
+    };
+    let [x, y] = if horizontal { [offset, 0] } else { [0, offset] };
+    [
+        x as u32,
+        y as u32,
+        window_width as u32,
+        window_height as u32,
+    ]
+}
+
+/// A PNG of exactly `width` x `height`, cropped from a stored image as far
+/// as the aspect ratio requires and scaled to fit
+pub async fn thumbnail(
+    Path(key): Path<String>,
+    Query(query): Query<ThumbnailQuery>,
+    headers: HeaderMap,
+    State(state): State<SharedState>,
+    principal: Principal,
+    claims: Option<Claims>,
+) -> Result<Response, KVError> {
+    require_scope(claims.as_ref(), "kv:read")?;
+    check_access(&state, &key, &principal)?;
+    query.check()?;
+    let params = format!("{}x{} {:?}", query.width, query.height, query.gravity);
+    let (content_type, data, etag) =
+        match read_source(&state, &key, &headers, "thumbnail", &params).await? {
+            Source::NotModified(etag) => return Ok(not_modified(&etag)),
+            Source::Value {
+                content_type,
+                data,
+                etag,
+            } => (content_type, data, etag),
+        };
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    let Some(format) = ImageFormat::from_mime_type(essence) else {
+        return Err(KVError::Forbidden(
+            "Not possible to thumbnail this type of content".to_string(),
+        ));
+    };
+    let png = tokio::task::spawn_blocking(move || {
+        let image = image::load_from_memory_with_format(&data, format)
+            .map_err(|source| KVError::DecodeFailed { source })?;
+        let mut png = Vec::new();
+        query
+            .apply(&image)
+            .write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)
+            .map_err(|source| KVError::EncodeFailed { source })?;
+        Ok::<_, KVError>(Bytes::from(png))
+    })
+    .await
+    .map_err(KVError::internal)??;
+    Ok(with_etag(&etag, ([("content-type", "image/png")], png)))
+}
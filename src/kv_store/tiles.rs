@@ -0,0 +1,273 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Cursor,
+    sync::Mutex,
+};
+
+use axum::{
+    extract::{Path, State},
+    response::Response,
+    Json,
+};
+use hyper::{body::Bytes, HeaderMap};
+use image::{imageops::FilterType, io::Reader, ImageFormat, ImageOutputFormat};
+use serde::Serialize;
+
+use super::{
+    metadata::etag_matches,
+    read_for_get,
+    transform::{not_modified, transform_etag, with_etag},
+    KVError,
+};
+use crate::{
+    auth::{check_access, require_scope, Claims, Principal},
+    SharedState,
+};
+
+/// Edge length of a tile, edge tiles are cut off at the image border
+const TILE_SIZE: u32 = 256;
+
+/// A tile of one version of an entry, so rewritten entries never hit
+/// tiles of their old value
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct TileId {
+    key: String,
+    etag: String,
+    z: u32,
+    x: u32,
+    y: u32,
+}
+
+#[derive(Default)]
+struct Tiles {
+    /// Last use and PNG of every cached tile
+    entries: HashMap<TileId, (u64, Bytes)>,
+    /// Cached tiles by last use, least recent first
+    order: BTreeMap<u64, TileId>,
+    tick: u64,
+    bytes: usize,
+}
+
+/// Rendered tiles, the least recently used ones are dropped once they
+/// take up more than `max_bytes`
+pub(crate) struct TileCache {
+    max_bytes: usize,
+    tiles: Mutex<Tiles>,
+}
+
+impl TileCache {
+    pub(crate) fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            tiles: Mutex::default(),
+        }
+    }
+
+    fn get(&self, id: &TileId) -> Option<Bytes> {
+        let mut tiles = self.tiles.lock().expect("What, an error here?");
+        tiles.tick += 1;
+        let tick = tiles.tick;
+        let (last_use, png) = tiles.entries.get_mut(id)?;
+        let previous = std::mem::replace(last_use, tick);
+        let png = png.clone();
+        tiles.order.remove(&previous);
+        tiles.order.insert(tick, id.clone());
+        Some(png)
+    }
+
+    fn insert(&self, id: TileId, png: Bytes) {
+        if png.len() > self.max_bytes {
+            return;
+        }
+        let mut tiles = self.tiles.lock().expect("What, an error here?");
+        tiles.tick += 1;
+        let tick = tiles.tick;
+        tiles.bytes += png.len();
+        if let Some((last_use, old)) = tiles.entries.insert(id.clone(), (tick, png)) {
+            tiles.order.remove(&last_use);
+            tiles.bytes -= old.len();
+        }
+        tiles.order.insert(tick, id);
+        while tiles.bytes > self.max_bytes {
+            let Some((_, victim)) = tiles.order.pop_first() else {
+                break;
+            };
+            if let Some((_, png)) = tiles.entries.remove(&victim) {
+                tiles.bytes -= png.len();
+            }
+        }
+    }
+
+    /// Drops the tiles of every version of `key`
+    pub(crate) fn forget(&self, key: &str) {
+        let mut tiles = self.tiles.lock().expect("What, an error here?");
+        let Tiles {
+            entries,
+            order,
+            bytes,
+            ..
+        } = &mut *tiles;
+        entries.retain(|id, (last_use, png)| {
+            if id.key != key {
+                return true;
+            }
+            order.remove(last_use);
+            *bytes -= png.len();
+            false
+        });
+    }
+
+    #[cfg(feature = "debug-state")]
+    pub(crate) fn len(&self) -> usize {
+        self.tiles
+            .lock()
+            .expect("What, an error here?")
+            .entries
+            .len()
+    }
+}
+
+impl Default for TileCache {
+    fn default() -> Self {
+        Self::new(64 * 1024 * 1024)
+    }
+}
+
+/// The pyramid of an image, as zoomable viewers need to know it
+#[derive(Serialize)]
+pub struct TileInfo {
+    width: u32,
+    height: u32,
+    tile_size: u32,
+    /// The level at full resolution. Each level below halves the size,
+    /// down to a single pixel at level 0.
+    max_level: u32,
+}
+
+impl TileInfo {
+    fn new(width: u32, height: u32) -> Self {
+        let longest = width.max(height).max(1);
+        Self {
+            width,
+            height,
+            tile_size: TILE_SIZE,
+            max_level: u32::BITS - (longest - 1).leading_zeros(),
+        }
+    }
+
+    /// The area of the full resolution image tile `x`, `y` of level `z`
+    /// shows, and the size the tile has. `None` outside the pyramid.
+    fn tile(&self, z: u32, x: u32, y: u32) -> Option<([u32; 4], [u32; 2])> {
+        if z > self.max_level {
+            return None;
+        }
+        let scale = 1u64 << (self.max_level - z);
+        let axis = |full: u32, index: u32| {
+            let level = (full as u64).div_ceil(scale);
+            let start = index as u64 * TILE_SIZE as u64;
+            if start >= level {
+                return None;
+            }
+            let size = (level - start).min(TILE_SIZE as u64);
+            let source_start = start * scale;
+            let source_size = (size * scale).min(full as u64 - source_start);
+            Some((source_start as u32, source_size as u32, size as u32))
+        };
+        let (sx, sw, width) = axis(self.width, x)?;
+        let (sy, sh, height) = axis(self.height, y)?;
+        Some(([sx, sy, sw, sh], [width, height]))
+    }
+}
+
+/// The image format of `content_type`, or why there are no tiles for it
+fn image_format(content_type: &str) -> Result<ImageFormat, KVError> {
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    ImageFormat::from_mime_type(essence)
+        .ok_or_else(|| KVError::Forbidden("Not possible to tile this type of content".to_string()))
+}
+
+/// Size and levels of the tile pyramid of an image
+pub async fn tile_info(
+    Path(key): Path<String>,
+    State(state): State<SharedState>,
+    principal: Principal,
+    claims: Option<Claims>,
+) -> Result<Json<TileInfo>, KVError> {
+    require_scope(claims.as_ref(), "kv:read")?;
+    check_access(&state, &key, &principal)?;
+    let (content_type, data, _) = read_for_get(&state, key).await?;
+    let format = image_format(&content_type)?;
+    let (width, height) = Reader::with_format(Cursor::new(&data), format)
+        .into_dimensions()
+        .map_err(|source| KVError::DecodeFailed { source })?;
+    Ok(Json(TileInfo::new(width, height)))
+}
+
+/// A PNG tile of a stored image, Deep Zoom style: level `z` scales the
+/// image down by `2^(max_level - z)`, `x` and `y` count tiles from the top
+/// left. Rendered tiles are cached until their entry changes.
+pub async fn get_tile(
+    Path((key, z, x, y)): Path<(String, u32, u32, u32)>,
+    headers: HeaderMap,
+    State(state): State<SharedState>,
+    principal: Principal,
+    claims: Option<Claims>,
+) -> Result<Response, KVError> {
+    require_scope(claims.as_ref(), "kv:read")?;
+    check_access(&state, &key, &principal)?;
+    let params = format!("{}/{}/{}", z, x, y);
+    let known = {
+        let state = state.read()?;
+        let current = !state.is_expired(&key) && !state.burn_after_read.contains(&key);
+        let metadata = state.metadata.get(&key).filter(|_| current);
+        metadata.map(|metadata| {
+            let id = TileId {
+                key: key.clone(),
+                etag: metadata.etag.clone(),
+                z,
+                x,
+                y,
+            };
+            let etag = transform_etag(&metadata.etag, "tile", &params);
+            (etag, state.tiles.get(&id))
+        })
+    };
+    match known {
+        Some((etag, _)) if etag_matches(&headers, &etag) => return Ok(not_modified(&etag)),
+        Some((etag, Some(png))) => {
+            return Ok(with_etag(&etag, ([("content-type", "image/png")], png)))
+        }
+        _ => {}
+    }
+    let (content_type, data, metadata) = read_for_get(&state, key.clone()).await?;
+    let etag = transform_etag(&metadata.etag, "tile", &params);
+    if etag_matches(&headers, &etag) {
+        return Ok(not_modified(&etag));
+    }
+    let format = image_format(&content_type)?;
+    let png = tokio::task::spawn_blocking(move || {
+        let image = image::load_from_memory_with_format(&data, format)
+            .map_err(|source| KVError::DecodeFailed { source })?;
+        let info = TileInfo::new(image.width(), image.height());
+        let ([sx, sy, sw, sh], [width, height]) = info.tile(z, x, y).ok_or(KVError::NotFound)?;
+        let mut tile = image.crop_imm(sx, sy, sw, sh);
+        if (sw, sh) != (width, height) {
+            tile = tile.resize_exact(width, height, FilterType::Lanczos3);
+        }
+        let mut png = Vec::new();
+        tile.write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)
+            .map_err(|source| KVError::EncodeFailed { source })?;
+        Ok::<_, KVError>(Bytes::from(png))
+    })
+    .await
+    .map_err(KVError::internal)??;
+    let id = TileId {
+        key,
+        etag: metadata.etag,
+        z,
+        x,
+        y,
+    };
+    state.read()?.tiles.insert(id, png.clone());
+    Ok(with_etag(&etag, ([("content-type", "image/png")], png)))
+}
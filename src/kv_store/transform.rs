@@ -0,0 +1,89 @@
+use std::fmt::Write;
+
+use axum::response::{IntoResponse, Response};
+use hyper::{body::Bytes, header::HeaderValue, HeaderMap, StatusCode};
+use sha2::{Digest, Sha256};
+
+use super::{metadata::etag_matches, read_for_get, KVError};
+use crate::SharedState;
+
+/// Strong validator of a transform's output. It hashes the source's ETag
+/// with the operation and its parameters, so it changes with any of them,
+/// and rewriting the source invalidates every transform of it.
+pub(crate) fn transform_etag(source_etag: &str, operation: &str, params: &str) -> String {
+    let digest = Sha256::new()
+        .chain_update(source_etag)
+        .chain_update([0])
+        .chain_update(operation)
+        .chain_update([0])
+        .chain_update(params)
+        .finalize();
+    let mut etag = String::with_capacity(34);
+    etag.push('"');
+    for byte in &digest[..16] {
+        let _ = write!(etag, "{:02x}", byte);
+    }
+    etag.push('"');
+    etag
+}
+
+/// The input of a transform
+pub(crate) enum Source {
+    /// The client's copy of the output, with this ETag, is still current
+    NotModified(String),
+    Value {
+        content_type: String,
+        data: Bytes,
+        /// Of the output
+        etag: String,
+    },
+}
+
+/// Reads the entry `operation` transforms, the way a GET of it would.
+/// Clients revalidating an output whose source ETag is known don't even
+/// cause a read.
+pub(crate) async fn read_source(
+    state: &SharedState,
+    key: &str,
+    headers: &HeaderMap,
+    operation: &str,
+    params: &str,
+) -> Result<Source, KVError> {
+    let known = {
+        let state = state.read()?;
+        // Not even a 304 for a client still holding an expired entry
+        if state.is_expired(key) {
+            return Err(KVError::NotFound);
+        }
+        state
+            .metadata
+            .get(key)
+            .map(|metadata| transform_etag(&metadata.etag, operation, params))
+    };
+    if let Some(etag) = known.as_ref().filter(|etag| etag_matches(headers, etag)) {
+        return Ok(Source::NotModified(etag.clone()));
+    }
+    let (content_type, data, metadata) = read_for_get(state, key.to_string()).await?;
+    let etag = transform_etag(&metadata.etag, operation, params);
+    // Entries written before a restart get their ETag on first read
+    if known.is_none() && etag_matches(headers, &etag) {
+        return Ok(Source::NotModified(etag));
+    }
+    Ok(Source::Value {
+        content_type,
+        data,
+        etag,
+    })
+}
+
+/// `response` with the ETag of a transform output
+pub(crate) fn with_etag(etag: &str, response: impl IntoResponse) -> Response {
+    let mut response = response.into_response();
+    let etag = HeaderValue::from_str(etag).expect("Hex digits are a valid header");
+    response.headers_mut().insert("etag", etag);
+    response
+}
+
+pub(crate) fn not_modified(etag: &str) -> Response {
+    with_etag(etag, StatusCode::NOT_MODIFIED)
+}
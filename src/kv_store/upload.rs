@@ -0,0 +1,171 @@
+use std::{io, path::PathBuf, pin::pin};
+
+use async_trait::async_trait;
+use axum::{extract::FromRequest, http::Request};
+use hyper::body::{Bytes, HttpBody};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncWriteExt},
+};
+
+use super::{checksum::ExpectedChecksums, Database, EntryMetadata, KVError};
+use crate::{BoxError, SharedState};
+
+/// Uploads up to this size stay in memory, larger ones go to a temporary file
+const SPOOL_THRESHOLD: usize = 1024 * 1024;
+
+/// A temporary file, removed when dropped unless a backend took it over
+struct SpoolFile {
+    path: PathBuf,
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+enum Spool {
+    Memory(Vec<u8>),
+    File(SpoolFile),
+}
+
+/// A request body, received chunk by chunk instead of buffered at once.
+/// Large bodies are spooled to a temporary file, bodies longer than
+/// `RequestLimits::max_body_bytes` are rejected as soon as they cross it,
+/// whether they announced their length or not.
+pub struct Upload {
+    spool: Spool,
+    len: u64,
+    sha256: [u8; 32],
+    /// Only computed to verify a `Content-MD5`
+    md5: Option<[u8; 16]>,
+}
+
+impl Upload {
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub(crate) fn metadata(&self) -> EntryMetadata {
+        EntryMetadata {
+            md5: self.md5,
+            ..EntryMetadata::from_digest(self.sha256, None)
+        }
+    }
+
+    /// An upload that was made up on the server side
+    pub(crate) fn from_bytes(data: Bytes) -> Self {
+        Self {
+            len: data.len() as u64,
+            sha256: Sha256::digest(&data).into(),
+            md5: None,
+            spool: Spool::Memory(data.into()),
+        }
+    }
+
+    /// Loads the whole upload into memory
+    pub(crate) async fn into_bytes(self) -> io::Result<Bytes> {
+        match self.spool {
+            Spool::Memory(data) => Ok(data.into()),
+            Spool::File(file) => Ok(tokio::fs::read(&file.path).await?.into()),
+        }
+    }
+
+    /// Reads the upload again, from memory or from its spool file
+    pub(crate) async fn reader(&self) -> io::Result<Box<dyn AsyncRead + Send + Unpin + '_>> {
+        Ok(match &self.spool {
+            Spool::Memory(data) => Box::new(&data[..]),
+            Spool::File(file) => Box::new(File::open(&file.path).await?),
+        })
+    }
+
+    /// Stores the upload under `key`. Spooled uploads are handed to the
+    /// backend as a file, so backends that can take them over don't need
+    /// to load them into memory.
+    pub(crate) async fn insert_into(
+        self,
+        db: &Database,
+        key: String,
+        content_type: String,
+    ) -> Result<(), KVError> {
+        match self.spool {
+            Spool::Memory(data) => db.insert(key, (content_type, data.into())).await,
+            Spool::File(file) => db.insert_file(key, content_type, &file.path).await,
+        }
+    }
+}
+
+/// Receives a body into a `Spool`, moving it to a file once it gets large,
+/// and checks it against the checksums the client sent
+async fn receive<B>(body: B, max_len: u64, expected: ExpectedChecksums) -> Result<Upload, KVError>
+where
+    B: HttpBody<Data = Bytes> + Send,
+    B::Error: Into<BoxError> + Send,
+{
+    let mut body = pin!(body);
+    let mut memory = Vec::new();
+    let mut file: Option<(SpoolFile, File)> = None;
+    let mut len = 0u64;
+    let mut sha256 = Sha256::new();
+    let mut md5 = expected.md5.map(|_| Md5::new());
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|error| {
+            KVError::BadRequest(format!("Could not read body: {}", error.into()))
+        })?;
+        len += chunk.len() as u64;
+        if len > max_len {
+            return Err(KVError::BodyTooLarge { limit: max_len });
+        }
+        sha256.update(&chunk);
+        if let Some(md5) = &mut md5 {
+            md5.update(&chunk);
+        }
+        if file.is_none() && memory.len() + chunk.len() > SPOOL_THRESHOLD {
+            let path =
+                std::env::temp_dir().join(format!("kv-upload-{:016x}", rand::random::<u64>()));
+            let mut handle = File::create(&path).await.map_err(KVError::internal)?;
+            let spool = SpoolFile { path };
+            handle.write_all(&memory).await.map_err(KVError::internal)?;
+            memory = Vec::new();
+            file = Some((spool, handle));
+        }
+        match &mut file {
+            Some((_, handle)) => handle.write_all(&chunk).await.map_err(KVError::internal)?,
+            None => memory.extend_from_slice(&chunk),
+        }
+    }
+    let spool = match file {
+        Some((spool, mut handle)) => {
+            handle.flush().await.map_err(KVError::internal)?;
+            Spool::File(spool)
+        }
+        None => Spool::Memory(memory),
+    };
+    let sha256 = sha256.finalize().into();
+    let md5 = md5.map(|md5| md5.finalize().into());
+    expected.verify(md5, sha256)?;
+    Ok(Upload {
+        spool,
+        len,
+        sha256,
+        md5,
+    })
+}
+
+#[async_trait]
+impl<B> FromRequest<SharedState, B> for Upload
+where
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError> + Send,
+{
+    type Rejection = KVError;
+
+    async fn from_request(request: Request<B>, state: &SharedState) -> Result<Self, KVError> {
+        let max_len = state.read()?.request_limits.max_body_bytes;
+        let expected = ExpectedChecksums::from_headers(request.headers())?;
+        receive(request.into_body(), max_len, expected).await
+    }
+}
@@ -0,0 +1,84 @@
+use std::{io, time::Duration};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// clamd rejects INSTREAM chunks bigger than its StreamMaxLength, keep them small
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Generous for large uploads, but a hung clamd doesn't hang uploads with it
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Result of scanning an upload
+#[derive(Debug, PartialEq, Eq)]
+pub enum ScanVerdict {
+    Clean,
+    Infected(String),
+}
+
+/// Scans uploads with a ClamAV daemon using the INSTREAM command
+#[derive(Clone, Debug)]
+pub struct ClamdScanner {
+    addr: String,
+    timeout: Duration,
+}
+
+impl ClamdScanner {
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self {
+            addr: addr.into(),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Gives up on scans that take longer than `timeout`, connecting included
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Streams everything `data` yields to clamd
+    pub async fn scan(&self, data: impl AsyncRead + Unpin) -> io::Result<ScanVerdict> {
+        tokio::time::timeout(self.timeout, self.instream(data))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "clamd did not answer in time"))?
+    }
+
+    async fn instream(&self, mut data: impl AsyncRead + Unpin) -> io::Result<ScanVerdict> {
+        let mut stream = TcpStream::connect(&self.addr).await?;
+        stream.write_all(b"zINSTREAM\0").await?;
+        let mut chunk = vec![0; CHUNK_SIZE];
+        loop {
+            let len = data.read(&mut chunk).await?;
+            if len == 0 {
+                break;
+            }
+            stream.write_all(&(len as u32).to_be_bytes()).await?;
+            stream.write_all(&chunk[..len]).await?;
+        }
+        stream.write_all(&0u32.to_be_bytes()).await?;
+
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await?;
+        parse_reply(&reply)
+    }
+}
+
+/// Replies look like `stream: OK` or `stream: Eicar-Signature FOUND`
+fn parse_reply(reply: &[u8]) -> io::Result<ScanVerdict> {
+    let reply = String::from_utf8_lossy(reply);
+    let reply = reply.trim_end_matches(['\0', '\n']);
+    let result = reply.strip_prefix("stream: ").unwrap_or(reply);
+    if result == "OK" {
+        Ok(ScanVerdict::Clean)
+    } else if let Some(signature) = result.strip_suffix(" FOUND") {
+        Ok(ScanVerdict::Infected(signature.to_string()))
+    } else {
+        Err(io::Error::other(format!(
+            "unexpected clamd reply: {}",
+            reply
+        )))
+    }
+}
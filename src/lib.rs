@@ -1,24 +1,363 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
     sync::{Arc, RwLock},
+    time::{Duration, Instant},
 };
 
+use admin::{Feature, Maintenance, Mount};
+use auth::Acls;
 use axum::{
-    body::Bytes,
     extract::{Query, State},
-    headers::ContentType,
+    middleware,
     response::IntoResponse,
-    routing::get,
+    routing::{get, post, put, MethodRouter},
     Router,
 };
-use kv_store::{get_kv, grayscale, post_kv};
+use clock::SharedClock;
+use kv_store::{
+    delete_bucket_kv, delete_kv, flatten, follow_link, get_bucket_kv, get_kv, get_kv_envelope,
+    get_tile, grayscale, link_stats, list_bucket, post_bucket_kv, post_kv, post_kv_generated,
+    preview, reject_reserved_keys, site_index, site_page, thumbnail, tile_info, transform_batch,
+    validate_headers, Database, EntryMetadata, FloodGuard, OverlayDatabase, RefreshRules,
+    TileCache,
+};
+use localization::SharedCatalog;
+use metrics::{RedStats, SharedMetrics, UsageLog};
+use random::Random;
+use rate_limit::RateLimiter;
 use serde::Deserialize;
+use tower_http::trace::TraceLayer;
+use versioning::ApiVersion;
+
+pub use admin::{load_features, LegalHolds};
+pub use auth::{ApiKeys, Claims, JwtAuth, Principal};
+pub use clock::{Clock, MockClock, SystemClock};
+pub use config::{
+    AuthConfig, BackendConfig, CacheConfig, Config, ConfigError, CorsConfig, LimitsConfig,
+    MetricsConfig, ServerConfig, WriteBehindConfig,
+};
+pub use cors::CorsOptions;
+pub use deprecation::DeprecatedRoute;
+#[cfg(feature = "object-store")]
+pub use kv_store::ObjectStoreDatabase;
+#[cfg(feature = "postgres")]
+pub use kv_store::PostgresDatabase;
+#[cfg(feature = "redis")]
+pub use kv_store::RedisDatabase;
+#[cfg(feature = "sled")]
+pub use kv_store::SledDatabase;
+#[cfg(feature = "sqlite")]
+pub use kv_store::SqliteDatabase;
+pub use kv_store::{
+    close_database, export_bundle, persist_hot_keys, prefetch_hot_keys, run_due_refreshes,
+    spawn_expiry_sweeper, spawn_hot_key_snapshots, spawn_refresh_scheduler, sweep_expired,
+    BatchLimits, BatchWrite, BlockTarget, BoundedLruDatabase, BundleDatabase, ClamdScanner,
+    ContentTypePolicy, FloodLimits, FsDatabase, ImageEncoding, ImagePolicy, KVDatabase, KVError,
+    KeyLimits, MemoryDatabase, RequestLimits, TieredDatabase, ValueStream, WriteBehindDatabase,
+    INTERNAL_NAMESPACE,
+};
+pub use localization::{MessageCatalog, MessageTable};
+#[cfg(feature = "prometheus")]
+pub use metrics::PrometheusMetrics;
+pub use metrics::{spawn_runtime_metrics, Metrics, NoopMetrics, StatsdMetrics};
+pub use rate_limit::{InvalidRate, Rate, RateLimits};
+pub use self_test::self_test;
+pub use server::{
+    https_redirect, redirect_to_https, serve, serve_bound, serve_on, systemd, Bound, BoxError,
+    Listen, ServerOptions, Shutdown, TlsOptions,
+};
 
+mod admin;
+mod auth;
+mod clock;
+mod config;
+mod cors;
+mod deadline;
+mod deprecation;
 mod kv_store;
+mod localization;
+mod metrics;
+mod openapi;
+mod random;
+mod rate_limit;
+mod request_id;
+mod self_test;
+mod server;
+mod versioning;
 
 #[derive(Default)]
 pub struct AppState {
-    db: HashMap<String, (String, Bytes)>,
+    /// What handlers read and write, the backend layered over the mounts
+    db: Database,
+    /// The configured backend, set while there are mounts
+    backend: Option<Database>,
+    /// Read-only backends by the namespace they serve
+    mounts: BTreeMap<String, Mount>,
+    content_types: ContentTypePolicy,
+    namespace_content_types: HashMap<String, ContentTypePolicy>,
+    namespace_image_policies: HashMap<String, ImagePolicy>,
+    virus_scanner: Option<ClamdScanner>,
+    holds: LegalHolds,
+    flood_guard: Option<FloodGuard>,
+    rate_limiter: Option<RateLimiter>,
+    sites: HashSet<String>,
+    burn_after_read: HashSet<String>,
+    expiries: HashMap<String, Instant>,
+    metadata: HashMap<String, EntryMetadata>,
+    tiles: TileCache,
+    refresh: RefreshRules,
+    usage: UsageLog,
+    red: RedStats,
+    redirects: HashMap<String, u64>,
+    key_limits: KeyLimits,
+    namespace_key_limits: HashMap<String, KeyLimits>,
+    request_limits: RequestLimits,
+    request_timeout: Option<Duration>,
+    api_keys: Option<ApiKeys>,
+    jwt: Option<JwtAuth>,
+    acls: Acls,
+    public_reads: bool,
+    cors: Option<CorsOptions>,
+    read_only: bool,
+    maintenance: Option<Maintenance>,
+    disabled_features: HashSet<Feature>,
+    random: Random,
+    clock: SharedClock,
+    metrics: SharedMetrics,
+    catalog: SharedCatalog,
+}
+
+impl AppState {
+    /// Store entries in `db` instead of in memory
+    pub fn with_database(mut self, db: impl KVDatabase + 'static) -> Self {
+        self.db = Database::new(db);
+        self.backend = None;
+        self.layer_mounts();
+        self
+    }
+
+    /// Serve the keys of `namespace` from `db` where the backend has no
+    /// entry, e.g. a `BundleDatabase`. Writes still go to the backend.
+    pub fn with_mount(
+        mut self,
+        namespace: impl Into<String>,
+        db: impl KVDatabase + 'static,
+    ) -> Self {
+        let mount = Mount {
+            db: Database::new(db),
+            source: None,
+        };
+        self.mount(namespace.into(), mount);
+        self
+    }
+
+    /// Replaces what was mounted under `namespace`
+    pub(crate) fn mount(&mut self, namespace: String, mount: Mount) {
+        self.forget_namespace_metadata(&namespace);
+        self.mounts.insert(namespace, mount);
+        self.layer_mounts();
+    }
+
+    pub(crate) fn unmount(&mut self, namespace: &str) -> bool {
+        if self.mounts.remove(namespace).is_none() {
+            return false;
+        }
+        self.forget_namespace_metadata(namespace);
+        match self.backend.take() {
+            Some(backend) if self.mounts.is_empty() => self.db = backend,
+            backend => {
+                self.backend = backend;
+                self.layer_mounts();
+            }
+        }
+        true
+    }
+
+    fn layer_mounts(&mut self) {
+        if self.mounts.is_empty() {
+            return;
+        }
+        let backend = self.backend.get_or_insert_with(|| self.db.clone()).clone();
+        let mounts = self
+            .mounts
+            .iter()
+            .map(|(namespace, mount)| (namespace.clone(), mount.db.clone()))
+            .collect();
+        self.db = Database::new(OverlayDatabase::new(backend, mounts));
+    }
+
+    /// Cached ETags of a namespace may belong to entries of another mount
+    fn forget_namespace_metadata(&mut self, namespace: &str) {
+        self.metadata
+            .retain(|key, _| kv_store::namespace(key) != Some(namespace));
+    }
+
+    /// Restrict the Content-Types accepted for every key
+    pub fn with_content_types(mut self, policy: ContentTypePolicy) -> Self {
+        self.content_types = policy;
+        self
+    }
+
+    /// Restrict the Content-Types accepted for keys in `namespace`, i.e.
+    /// keys of the form `namespace/...`. Applies on top of the global policy.
+    pub fn with_namespace_content_types(
+        mut self,
+        namespace: impl Into<String>,
+        policy: ContentTypePolicy,
+    ) -> Self {
+        let namespace = namespace.into();
+        self.namespace_content_types.insert(namespace, policy);
+        self
+    }
+
+    /// Re-encode images uploaded to keys in `namespace` as `policy` says
+    pub fn with_namespace_image_policy(
+        mut self,
+        namespace: impl Into<String>,
+        policy: ImagePolicy,
+    ) -> Self {
+        let namespace = namespace.into();
+        self.namespace_image_policies.insert(namespace, policy);
+        self
+    }
+
+    /// Scan every upload with clamd before storing it
+    pub fn with_virus_scanner(mut self, scanner: ClamdScanner) -> Self {
+        self.virus_scanner = Some(scanner);
+        self
+    }
+
+    /// Bound the length and depth of keys that can be written
+    pub fn with_key_limits(mut self, limits: KeyLimits) -> Self {
+        self.key_limits = limits;
+        self
+    }
+
+    /// Key limits for `namespace`, replacing the global ones
+    pub fn with_namespace_key_limits(
+        mut self,
+        namespace: impl Into<String>,
+        limits: KeyLimits,
+    ) -> Self {
+        self.namespace_key_limits.insert(namespace.into(), limits);
+        self
+    }
+
+    /// Bound the size of request headers and of announced bodies
+    pub fn with_request_limits(mut self, limits: RequestLimits) -> Self {
+        self.request_limits = limits;
+        self
+    }
+
+    /// Answer 504 to requests taking longer than `timeout`, cancelling the
+    /// backend calls they are waiting for
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Keep up to `max_bytes` of rendered tiles, 64 MiB by default
+    pub fn with_tile_cache(mut self, max_bytes: usize) -> Self {
+        self.tiles = TileCache::new(max_bytes);
+        self
+    }
+
+    /// Require one of `keys` in `X-Api-Key`, or a token if those are
+    /// configured too. Anyone may read and write otherwise.
+    pub fn with_api_keys(mut self, keys: ApiKeys) -> Self {
+        self.api_keys = Some(keys);
+        self
+    }
+
+    /// Accept `Authorization: Bearer` tokens, with `kv:read` and `kv:write`
+    /// scopes for entries and `kv:admin` for operator endpoints
+    pub fn with_jwt(mut self, jwt: JwtAuth) -> Self {
+        self.jwt = Some(jwt);
+        self
+    }
+
+    /// Let reads through without credentials, when keys or tokens are required
+    pub fn with_public_reads(mut self, public_reads: bool) -> Self {
+        self.public_reads = public_reads;
+        self
+    }
+
+    /// Answer cross-origin requests from the browser front-ends in `options`
+    pub fn with_cors(mut self, options: CorsOptions) -> Self {
+        self.cors = Some(options);
+        self
+    }
+
+    /// Start in read-only mode, toggled at runtime through `/admin/readonly`
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Temporarily block keys and clients that write too often
+    pub fn with_flood_limits(mut self, limits: FloodLimits) -> Self {
+        self.flood_guard = Some(FloodGuard::new(limits, self.clock.now()));
+        self
+    }
+
+    /// Limit how often each client may read, write and transform images,
+    /// per API key or else per IP address
+    pub fn with_rate_limits(mut self, limits: RateLimits) -> Self {
+        self.rate_limiter = Some(RateLimiter::new(limits));
+        self
+    }
+
+    /// Draw generated keys from an RNG seeded with `seed`, for reproducible tests
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.random = Random::seeded(seed);
+        self
+    }
+
+    /// Read the time from `clock`, e.g. a `MockClock` in tests
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = SharedClock::new(clock);
+        self
+    }
+
+    /// Report instrumentation to `metrics` instead of dropping it
+    pub fn with_metrics(mut self, metrics: impl Metrics + 'static) -> Self {
+        self.metrics = SharedMetrics::new(metrics);
+        self
+    }
+
+    /// Translate error messages for clients sending `Accept-Language`
+    pub fn with_message_catalog(mut self, catalog: impl MessageCatalog + 'static) -> Self {
+        self.catalog = SharedCatalog::new(catalog);
+        self
+    }
+
+    /// Serve the keys in `namespace` as a static website under `/site/:namespace`
+    pub fn with_static_site(mut self, namespace: impl Into<String>) -> Self {
+        self.sites.insert(namespace.into());
+        self
+    }
+
+    /// Drops the flags attached to an entry that is being removed
+    pub(crate) fn forget(&mut self, key: &str) {
+        self.burn_after_read.remove(key);
+        self.expiries.remove(key);
+        self.metadata.remove(key);
+        self.redirects.remove(key);
+    }
+
+    /// Whether read-only or maintenance mode holds back writes
+    pub(crate) fn paused_writes(&self) -> bool {
+        self.read_only || self.maintenance.is_some()
+    }
+
+    /// Whether `key` was stored with a TTL that has run out
+    /// Held entries outlive their TTL, as with the sweeper
+    pub(crate) fn is_expired(&self, key: &str) -> bool {
+        self.expiries
+            .get(key)
+            .is_some_and(|expires| *expires <= self.clock.now())
+            && !self.holds.is_held(key)
+    }
 }
 
 /// Custom type for a shared state
@@ -40,16 +379,241 @@ async fn hello_handler(Query(name): Query<Name>) -> impl IntoResponse {
     }
 }
 
-async fn poison(State(state): State<SharedState>) -> impl IntoResponse {
+async fn poison(State(state): State<SharedState>) {
     let _guard = state.write().unwrap();
     panic!("At the disco");
 }
 
-pub fn router(state: &SharedState) -> Router<SharedState> {
-    Router::with_state(Arc::clone(state))
+/// Routes that still work but will go away, announced to clients through
+/// the `Deprecation` and `Sunset` headers
+const DEPRECATED_ROUTES: &[DeprecatedRoute] = &[DeprecatedRoute {
+    // The greeting demo from the first lesson
+    path: "/hello",
+    deprecated_at: 1_792_108_800,   // 2026-10-16
+    sunset_at: Some(1_807_833_600), // 2027-04-16
+    successor: None,
+}];
+
+/// The KV API and the pages built on it, as of `version`
+/// The routes of `api`, by path
+fn api_routes(version: ApiVersion) -> Vec<(&'static str, MethodRouter<SharedState>)> {
+    let mut routes = vec![
+        ("/kv", post(post_kv_generated)),
+        ("/kv/:key/grayscale", get(grayscale)),
+        ("/kv/:key/flatten", get(flatten)),
+        ("/kv/:key/preview", get(preview)),
+        (
+            "/kv/:key/acl",
+            get(auth::get_acl)
+                .put(auth::put_acl)
+                .delete(auth::delete_acl),
+        ),
+        ("/kv/:key/thumbnail", get(thumbnail)),
+        ("/kv/:key/tiles", get(tile_info)),
+        ("/kv/:key/tiles/:z/:x/:y", get(get_tile)),
+        ("/transform/batch", post(transform_batch)),
+        ("/bucket/:bucket/kv", get(list_bucket)),
+        (
+            "/bucket/:bucket/kv/:key",
+            get(get_bucket_kv)
+                .post(post_bucket_kv)
+                .delete(delete_bucket_kv),
+        ),
+        ("/r/:key", get(follow_link)),
+        ("/r/:key/stats", get(link_stats)),
+        ("/site/:namespace", get(site_index)),
+        ("/site/:namespace/*path", get(site_page)),
+    ];
+    match version {
+        ApiVersion::V1 => routes.push(("/kv/:key", get(get_kv).post(post_kv).delete(delete_kv))),
+        ApiVersion::V2 => routes.extend([
+            (
+                "/kv/:key",
+                get(get_kv_envelope).post(post_kv).delete(delete_kv),
+            ),
+            ("/kv/:key/raw", get(get_kv)),
+        ]),
+    }
+    routes
+}
+
+/// The paths of the versioned API as the OpenAPI document writes them, e.g.
+/// `/kv/{key}` for v1 and `/v2/kv/{key}/raw` for v2
+pub fn api_paths() -> Vec<String> {
+    let openapi = |path: &str| {
+        path.split('/')
+            .map(|segment| match segment.strip_prefix([':', '*']) {
+                Some(name) => format!("{{{}}}", name),
+                None => segment.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    };
+    let v1 = api_routes(ApiVersion::V1)
+        .into_iter()
+        .map(|(path, _)| openapi(path));
+    let v2 = api_routes(ApiVersion::V2)
+        .into_iter()
+        .map(|(path, _)| format!("/v2{}", openapi(path)));
+    v1.chain(v2).collect()
+}
+
+fn api(state: &SharedState, version: ApiVersion) -> Router<SharedState> {
+    let router = api_routes(version)
+        .into_iter()
+        .fold(Router::new(), |router, (path, route)| {
+            router.route(path, route)
+        });
+    let router = match version {
+        ApiVersion::V1 => router,
+        ApiVersion::V2 => router.layer(middleware::from_fn(versioning::structured_errors)),
+    };
+    // Inside the versioned routers, so they see paths without the prefix
+    router
+        .layer(middleware::from_fn(reject_reserved_keys))
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(state),
+            admin::reject_disabled_features,
+        ))
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(state),
+            validate_headers,
+        ))
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(state),
+            auth::authenticate,
+        ))
+}
+
+/// Everything clients talk to. The unprefixed routes are v1, kept for
+/// clients from before versioning.
+pub fn public_router(state: &SharedState) -> Router {
+    let router = Router::new()
         .route("/", get(handler))
         .route("/hello", get(hello_handler))
-        .route("/kv/:key", get(get_kv).post(post_kv))
-        .route("/kv/:key/grayscale", get(grayscale))
+        .route("/openapi.json", get(openapi::openapi_json))
+        .route("/docs", get(openapi::swagger_ui))
+        .route("/docs/swagger-ui.css", get(openapi::swagger_ui_css))
+        .route(
+            "/docs/swagger-ui-bundle.js",
+            get(openapi::swagger_ui_bundle),
+        )
+        .merge(api(state, ApiVersion::V1))
+        .nest("/v1", api(state, ApiVersion::V1))
+        .nest("/v2", api(state, ApiVersion::V2))
+        .route_layer(middleware::from_fn_with_state(
+            (Arc::clone(state), DEPRECATED_ROUTES),
+            deprecation::deprecation_headers,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            Arc::clone(state),
+            metrics::record_red_metrics,
+        ))
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(state),
+            admin::reject_writes,
+        ))
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(state),
+            localization::localize_errors,
+        ))
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(state),
+            metrics::record_usage,
+        ))
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(state),
+            deadline::enforce_deadline,
+        ))
+        // Before everything else, so clients over their rate cost little
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(state),
+            rate_limit::rate_limit,
+        ));
+    let cors = state
+        .read()
+        .expect("What, an error here?")
+        .cors
+        .as_ref()
+        .map(CorsOptions::layer);
+    // Around everything, so preflights and rejections get the headers too
+    let router = match cors {
+        Some(cors) => router.layer(cors),
+        None => router,
+    };
+    router
+        .layer(TraceLayer::new_for_http().make_span_with(request_id::request_span))
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(state),
+            request_id::request_id,
+        ))
+        .with_state(Arc::clone(state))
+}
+
+/// Operator endpoints, which can be bound to a separate listener
+pub fn admin_router(state: &SharedState) -> Router {
+    let router = Router::new()
         .route("/poison", get(poison))
+        .route(
+            "/admin/readonly",
+            get(admin::get_read_only).post(admin::set_read_only),
+        )
+        .route(
+            "/admin/maintenance",
+            get(admin::get_maintenance)
+                .put(admin::start_maintenance)
+                .delete(admin::end_maintenance),
+        )
+        .route("/admin/features", get(admin::list_features))
+        .route("/admin/features/:feature", put(admin::set_feature))
+        .route("/admin/erase", post(admin::erase))
+        .route("/admin/mounts", get(admin::list_mounts))
+        .route(
+            "/admin/mounts/:namespace",
+            put(admin::mount).delete(admin::unmount),
+        )
+        .route("/admin/holds", get(admin::list_holds))
+        .route(
+            "/admin/holds/keys/:key",
+            put(admin::hold_key).delete(admin::release_key),
+        )
+        .route(
+            "/admin/holds/namespaces/:namespace",
+            put(admin::hold_namespace).delete(admin::release_namespace),
+        )
+        .route("/admin/reports/usage", get(metrics::usage_report))
+        .route("/admin/red", get(metrics::red_summary))
+        .route("/admin/refresh", get(kv_store::list_refresh_rules))
+        .route(
+            "/admin/refresh/:name",
+            put(kv_store::put_refresh_rule).delete(kv_store::delete_refresh_rule),
+        )
+        .route("/admin/refresh/:name/run", post(kv_store::run_refresh_rule))
+        .route(
+            "/admin/blocks",
+            get(admin::list_blocks).delete(admin::clear_blocks),
+        );
+    #[cfg(feature = "debug-state")]
+    let router = router.route("/debug/state", get(admin::debug_state));
+    #[cfg(feature = "heap-profile")]
+    let router = router.route("/debug/heap", get(admin::heap_profile));
+    router
+        .route_layer(middleware::from_fn_with_state(
+            Arc::clone(state),
+            auth::authenticate_admin,
+        ))
+        // Left open for scrapers
+        .route("/metrics", get(metrics::render_metrics))
+        .layer(TraceLayer::new_for_http().make_span_with(request_id::request_span))
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(state),
+            request_id::request_id,
+        ))
+        .with_state(Arc::clone(state))
+}
+
+/// Public and admin routes on a single listener. Both trace requests with
+/// a `TraceLayer` of their own.
+pub fn router(state: &SharedState) -> Router {
+    public_router(state).merge(admin_router(state))
 }
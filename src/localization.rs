@@ -0,0 +1,121 @@
+use std::{collections::HashMap, ops::Deref, sync::Arc};
+
+use axum::{extract::State, http::Request, middleware::Next, response::Response};
+use hyper::header::{HeaderValue, ACCEPT_LANGUAGE, CONTENT_LANGUAGE, VARY};
+
+use crate::{versioning::with_error_message, KVError, SharedState};
+
+/// Translations for the messages of `KVError` responses. English is built
+/// into `KVError` itself, the `x-error-code` header is never translated.
+pub trait MessageCatalog: Send + Sync {
+    /// The message for `error` in `language`, a lowercase tag like `de` or
+    /// `pt-br`. `None` falls through to the next language the client accepts.
+    fn message(&self, language: &str, error: &KVError) -> Option<String>;
+}
+
+/// A catalog of fixed messages by language and error code
+#[derive(Default)]
+pub struct MessageTable {
+    messages: HashMap<(String, &'static str), String>,
+}
+
+impl MessageTable {
+    /// Answer errors with `code`, e.g. `not_found`, with `message` for clients accepting `language`
+    pub fn with(mut self, language: &str, code: &'static str, message: impl Into<String>) -> Self {
+        self.messages
+            .insert((language.to_ascii_lowercase(), code), message.into());
+        self
+    }
+}
+
+impl MessageCatalog for MessageTable {
+    fn message(&self, language: &str, error: &KVError) -> Option<String> {
+        self.messages
+            .get(&(language.to_string(), error.code()))
+            .cloned()
+    }
+}
+
+/// The catalog stored in `AppState`, without translations by default
+#[derive(Clone)]
+pub(crate) struct SharedCatalog(Arc<dyn MessageCatalog>);
+
+impl SharedCatalog {
+    pub(crate) fn new(catalog: impl MessageCatalog + 'static) -> Self {
+        Self(Arc::new(catalog))
+    }
+}
+
+impl Default for SharedCatalog {
+    fn default() -> Self {
+        Self::new(MessageTable::default())
+    }
+}
+
+impl Deref for SharedCatalog {
+    type Target = dyn MessageCatalog;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
+
+/// The languages in an `Accept-Language` header, most preferred first.
+/// `de-CH` is followed by `de` unless the client lists `de` itself.
+fn accepted_languages(header: &str) -> Vec<String> {
+    let mut ranges: Vec<(String, f32)> = header
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let tag = parts.next()?.trim().to_ascii_lowercase();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse().ok())?;
+            (!tag.is_empty() && tag != "*" && quality > 0.0).then_some((tag, quality))
+        })
+        .collect();
+    // Stable, so equally preferred languages keep the client's order
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+    let mut languages: Vec<String> = Vec::new();
+    for (tag, _) in &ranges {
+        let primary = tag.split('-').next().unwrap_or(tag);
+        for language in [tag.as_str(), primary] {
+            if !languages.iter().any(|known| known == language) {
+                languages.push(language.to_string());
+            }
+        }
+    }
+    languages
+}
+
+/// Replaces the message of `KVError` responses with one in a language the
+/// client accepts, if the catalog has one. Keeps v2's JSON error format.
+pub async fn localize_errors<B>(
+    State(state): State<SharedState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let languages = request
+        .headers()
+        .get(ACCEPT_LANGUAGE)
+        .and_then(|header| header.to_str().ok())
+        .map(accepted_languages)
+        .unwrap_or_default();
+    let response = next.run(request).await;
+    let Some(error) = response.extensions().get::<Arc<KVError>>().cloned() else {
+        return response;
+    };
+    let catalog = state.read().expect("What, an error here?").catalog.clone();
+    let translation = languages.iter().find_map(|language| {
+        let message = catalog.message(language, &error)?;
+        Some((HeaderValue::from_str(language).ok()?, message))
+    });
+    let Some((language, message)) = translation else {
+        return response;
+    };
+    let mut response = with_error_message(response, &error, &message);
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_LANGUAGE, language);
+    headers.append(VARY, HeaderValue::from_static("accept-language"));
+    response
+}
@@ -1,19 +1,299 @@
-use std::net::SocketAddr;
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    process::ExitCode,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 
-use microservice_rust_workshop::{router, SharedState};
+use clap::{Parser, Subcommand, ValueEnum};
+use microservice_rust_workshop::{
+    admin_router, close_database, export_bundle, https_redirect, load_features, persist_hot_keys,
+    prefetch_hot_keys, public_router, router, self_test, serve_bound, spawn_expiry_sweeper,
+    spawn_hot_key_snapshots, spawn_refresh_scheduler, spawn_runtime_metrics, systemd,
+    BackendConfig, BoxError, Config, Listen, ServerOptions, SharedState, Shutdown,
+};
+#[cfg(not(feature = "tokio-console"))]
+use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
 
-type BoxError = Box<dyn std::error::Error>;
+#[cfg(feature = "heap-profile")]
+#[global_allocator]
+static ALLOC: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 
-#[tokio::main]
-async fn main() -> Result<(), BoxError> {
-    let state = SharedState::default();
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
+/// Samples an allocation every 512 KiB from startup on, for `/debug/heap`
+#[cfg(feature = "heap-profile")]
+#[allow(non_upper_case_globals)]
+#[export_name = "_rjem_malloc_conf"]
+pub static malloc_conf: &[u8] = b"prof:true,prof_active:true,lg_prof_sample:19\0";
 
-    let app = router(&state);
+/// A key-value store for text and images
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// TOML file with the settings. KV_* variables override it, and the
+    /// options below override both.
+    #[arg(long, env = "KV_CONFIG", global = true)]
+    config: Option<PathBuf>,
+    /// Address of the public listener
+    #[arg(long, global = true)]
+    bind: Option<SocketAddr>,
+    #[arg(long, value_enum, global = true)]
+    backend: Option<Backend>,
+    /// Where the sled and fs backends keep entries
+    #[arg(long, global = true)]
+    data_dir: Option<PathBuf>,
+    /// Bundle to serve read-only under its namespace, may be repeated
+    #[arg(long, value_name = "FILE", global = true)]
+    mount: Vec<PathBuf>,
+    /// Same as the self-test command, for existing health checks
+    #[arg(long, hide = true)]
+    self_test: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Backend {
+    Memory,
+    Fs,
+    Sled,
+    /// Connects to KV_REDIS_URL or the url of the config file
+    Redis,
+}
+
+#[derive(Clone, Subcommand)]
+enum Command {
+    /// Serve the API, the default
+    Serve,
+    /// Validate the settings without serving
+    CheckConfig,
+    /// Run a write, read and delete cycle against the backend
+    SelfTest,
+    /// Write the entries of a namespace to a single file that other
+    /// instances can serve with --mount
+    Bundle {
+        #[arg(long)]
+        namespace: String,
+        /// e.g. assets.kvpack
+        #[arg(long)]
+        out: PathBuf,
+    },
+}
+
+impl Cli {
+    /// The settings, with the options of the command line applied
+    fn config(&self) -> Result<Config, BoxError> {
+        let mut config = Config::load(self.config.as_deref())?;
+        if let Some(bind) = self.bind {
+            config.server.listen = bind;
+        }
+        config.mounts.extend(self.mount.iter().cloned());
+        let data_dir = || {
+            self.data_dir
+                .clone()
+                .ok_or_else(|| BoxError::from("This backend needs --data-dir"))
+        };
+        config.backend = match (self.backend, config.backend) {
+            (None, backend) if self.data_dir.is_none() => backend,
+            // Keeps the bounds of the configured one
+            (Some(Backend::Memory), backend @ BackendConfig::Memory { .. }) => backend,
+            (Some(Backend::Memory), _) => BackendConfig::default(),
+            (Some(Backend::Fs), _) => BackendConfig::Fs { root: data_dir()? },
+            (Some(Backend::Sled), _) => BackendConfig::Sled { path: data_dir()? },
+            (Some(Backend::Redis), BackendConfig::Redis { url }) => BackendConfig::Redis { url },
+            (Some(Backend::Redis), _) => {
+                return Err("The redis backend needs KV_REDIS_URL or a url in the config".into())
+            }
+            // A new place for the configured backend
+            (None, BackendConfig::Fs { .. }) => BackendConfig::Fs { root: data_dir()? },
+            (None, BackendConfig::Sled { .. }) => BackendConfig::Sled { path: data_dir()? },
+            (_, _) => return Err("--data-dir is for the fs and sled backends".into()),
+        };
+        Ok(config)
+    }
+}
+
+fn main() -> Result<ExitCode, BoxError> {
+    // Changes the environment, which is only sound before there are threads
+    let inherited = systemd::listen_fds();
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(inherited))
+}
+
+async fn run(inherited: Vec<std::net::TcpListener>) -> Result<ExitCode, BoxError> {
+    // Serves tokio-console on 127.0.0.1:6669
+    #[cfg(feature = "tokio-console")]
+    console_subscriber::init();
+    // RUST_LOG=debug shows the spans of backend calls as they close, with
+    // their latency
+    #[cfg(not(feature = "tokio-console"))]
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .with_span_events(FmtSpan::CLOSE)
+        .init();
+    let cli = Cli::parse();
+    let config = cli.config()?;
+    match cli.command {
+        Some(Command::CheckConfig) => {
+            config.check()?;
+            println!("The configuration is valid");
+            Ok(ExitCode::SUCCESS)
+        }
+        Some(Command::SelfTest) => run_self_test(config).await,
+        Some(Command::Bundle { namespace, out }) => {
+            let state: SharedState = Arc::new(RwLock::new(config.app_state().await?));
+            let entries = export_bundle(&state, &namespace, &out).await?;
+            close_database(&state).await?;
+            println!("Wrote {} entries to {}", entries, out.display());
+            Ok(ExitCode::SUCCESS)
+        }
+        None if cli.self_test => run_self_test(config).await,
+        Some(Command::Serve) | None => {
+            serve(config, inherited).await?;
+            Ok(ExitCode::SUCCESS)
+        }
+    }
+}
+
+/// Fails the process if a check fails, once the database is closed
+async fn run_self_test(config: Config) -> Result<ExitCode, BoxError> {
+    let state: SharedState = Arc::new(RwLock::new(config.app_state().await?));
+    load_features(&state).await?;
+    let passed = self_test(&state).await;
+    close_database(&state).await?;
+    Ok(if passed {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+async fn serve(config: Config, inherited: Vec<std::net::TcpListener>) -> Result<(), BoxError> {
+    let state: SharedState = Arc::new(RwLock::new(config.app_state().await?));
+    let drain_timeout = config.shutdown_timeout();
+    let options = ServerOptions {
+        shutdown: Shutdown::on_signals()?,
+        ..config.server_options()
+    };
+    load_features(&state).await?;
+    let hot_cache = config.cache.hot_bytes.is_some();
+    if hot_cache {
+        // Before serving, so the first requests after a deploy find them
+        match prefetch_hot_keys(&state).await {
+            Ok(keys) => tracing::info!(keys, "Prefetched hot keys"),
+            Err(error) => tracing::warn!(%error, "Could not prefetch hot keys"),
+        }
+    }
+
+    // With socket activation the first socket is public, the second admin
+    let mut inherited = inherited.into_iter().map(Listen::Inherited);
+    let public = inherited
+        .next()
+        .unwrap_or(Listen::Tcp(config.server.listen));
+    let admin = inherited.next().or(config.server.admin_listen);
+
+    let redirect = match config.server.http_redirect_listen {
+        Some(redirect) if options.tls.is_some() => {
+            let https_port = match &public {
+                Listen::Tcp(addr) => addr.port(),
+                Listen::Inherited(listener) => listener.local_addr()?.port(),
+                Listen::Unix(_) => return Err("HTTPS needs a TCP listener".into()),
+            };
+            Some((redirect, https_port))
+        }
+        _ => None,
+    };
+
+    // Every listener is up before systemd hears that the service is ready
+    let public = public.bind(options.backlog)?;
+    let admin = admin.map(|admin| admin.bind(options.backlog)).transpose()?;
+    let redirect = match redirect {
+        Some((addr, https_port)) => Some((Listen::Tcp(addr).bind(options.backlog)?, https_port)),
+        None => None,
+    };
+    let _ = systemd::notify("READY=1");
+
+    let plain = ServerOptions {
+        tls: None,
+        ..options.clone()
+    };
+    let redirect = async {
+        match redirect {
+            Some((bound, https_port)) => {
+                serve_bound(bound, https_redirect(https_port), &plain).await
+            }
+            None => Ok(()),
+        }
+    };
 
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await?;
+    systemd::spawn_watchdog();
+    spawn_runtime_metrics(&state, Duration::from_secs(10));
+    // Tasks using the database, stopped and waited for before it is closed
+    let mut background = vec![
+        spawn_expiry_sweeper(
+            Arc::clone(&state),
+            Duration::from_secs(30),
+            options.shutdown.clone(),
+        ),
+        spawn_refresh_scheduler(
+            Arc::clone(&state),
+            Duration::from_secs(60),
+            options.shutdown.clone(),
+        ),
+    ];
+    if hot_cache {
+        background.push(spawn_hot_key_snapshots(
+            Arc::clone(&state),
+            Duration::from_secs(config.cache.snapshot_interval.max(1)),
+            config.cache.prefetch_keys,
+            options.shutdown.clone(),
+        ));
+    }
 
-    Ok(())
+    let servers = async {
+        match admin {
+            Some(admin) => tokio::try_join!(
+                serve_bound(public, public_router(&state), &options),
+                serve_bound(admin, admin_router(&state), &options),
+                redirect,
+            )
+            .map(|_| ()),
+            None => tokio::try_join!(serve_bound(public, router(&state), &options), redirect)
+                .map(|_| ()),
+        }
+    };
+    tokio::pin!(servers);
+    let served = tokio::select! {
+        result = &mut servers => {
+            let _ = systemd::notify("STOPPING=1");
+            result
+        }
+        _ = options.shutdown.triggered() => {
+            let _ = systemd::notify("STOPPING=1");
+            match tokio::time::timeout(drain_timeout, &mut servers).await {
+                Ok(result) => result,
+                Err(_) => {
+                    tracing::warn!("Requests still in flight at the shutdown timeout");
+                    Ok(())
+                }
+            }
+        }
+    };
+    // Also when a server failed, so the background tasks end either way
+    options.shutdown.trigger();
+    for task in background {
+        if let Err(error) = task.await {
+            tracing::warn!(%error, "A background task failed");
+        }
+    }
+    if hot_cache {
+        persist_hot_keys(&state, config.cache.prefetch_keys).await?;
+    }
+    // Only once nothing writes anymore
+    close_database(&state).await?;
+    served
 }
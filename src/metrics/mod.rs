@@ -0,0 +1,120 @@
+use std::{ops::Deref, sync::Arc};
+
+use axum::{extract::State, response::IntoResponse};
+use hyper::{
+    header::{HeaderMap, ACCEPT},
+    StatusCode,
+};
+
+use crate::SharedState;
+
+#[cfg(feature = "prometheus")]
+pub use prometheus::PrometheusMetrics;
+pub(crate) use red::RedStats;
+pub use red::{record_red_metrics, red_summary};
+pub use runtime::spawn_runtime_metrics;
+pub use statsd::StatsdMetrics;
+pub(crate) use usage::UsageLog;
+pub use usage::{record_usage, usage_report};
+
+#[cfg(feature = "prometheus")]
+mod prometheus;
+mod red;
+mod runtime;
+mod statsd;
+mod usage;
+
+/// Sink for the service's instrumentation. Implementations decide how the
+/// numbers reach a monitoring system, recording never fails the request.
+pub trait Metrics: Send + Sync {
+    /// Adds `value` to a counter that only goes up
+    fn counter(&self, name: &str, labels: &[(&str, &str)], value: u64);
+
+    /// Sets a value that can go up and down
+    fn gauge(&self, name: &str, labels: &[(&str, &str)], value: f64);
+
+    /// Records one observation of a distribution, e.g. a duration in seconds
+    fn histogram(&self, name: &str, labels: &[(&str, &str)], value: f64);
+
+    /// Like `histogram`, linking the observation to the trace it was part
+    /// of. Sinks without exemplars record just the value.
+    fn histogram_with_exemplar(
+        &self,
+        name: &str,
+        labels: &[(&str, &str)],
+        value: f64,
+        trace_id: &str,
+    ) {
+        let _ = trace_id;
+        self.histogram(name, labels, value);
+    }
+
+    /// Text exposition for pull based systems, `None` for push based ones
+    fn render(&self) -> Option<String> {
+        None
+    }
+
+    /// OpenMetrics exposition, which carries exemplars. `None` if the sink
+    /// only has the plain text format.
+    fn render_openmetrics(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Drops everything, the default
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {
+    fn counter(&self, _: &str, _: &[(&str, &str)], _: u64) {}
+
+    fn gauge(&self, _: &str, _: &[(&str, &str)], _: f64) {}
+
+    fn histogram(&self, _: &str, _: &[(&str, &str)], _: f64) {}
+}
+
+/// The metrics sink stored in `AppState`
+#[derive(Clone)]
+pub(crate) struct SharedMetrics(Arc<dyn Metrics>);
+
+impl SharedMetrics {
+    pub(crate) fn new(metrics: impl Metrics + 'static) -> Self {
+        Self(Arc::new(metrics))
+    }
+}
+
+impl Default for SharedMetrics {
+    fn default() -> Self {
+        Self::new(NoopMetrics)
+    }
+}
+
+impl Deref for SharedMetrics {
+    type Target = dyn Metrics;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
+
+/// Scrape endpoint, only there when the configured sink is pull based.
+/// Scrapers asking for OpenMetrics get exemplars as well.
+pub async fn render_metrics(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let metrics = state.read().expect("What, an error here?").metrics.clone();
+    let openmetrics = headers
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/openmetrics-text"));
+    if openmetrics {
+        if let Some(text) = metrics.render_openmetrics() {
+            let content_type = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+            return Ok(([("content-type", content_type)], text));
+        }
+    }
+    match metrics.render() {
+        Some(text) => Ok(([("content-type", "text/plain; version=0.0.4")], text)),
+        None => Err((StatusCode::NOT_FOUND, "Metrics are pushed, not scraped")),
+    }
+}
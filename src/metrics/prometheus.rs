@@ -0,0 +1,228 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use prometheus::{
+    core::{MetricVec, MetricVecBuilder},
+    proto::{LabelPair, MetricType},
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder,
+    DEFAULT_BUCKETS,
+};
+
+use super::Metrics;
+
+/// A histogram series: its name and labels sorted by name
+type SeriesKey = (String, Vec<(String, String)>);
+
+/// The observation last seen in a bucket, with the trace it was part of
+#[derive(Clone)]
+struct Exemplar {
+    trace_id: String,
+    value: f64,
+    /// Unix time in seconds
+    timestamp: f64,
+}
+
+/// Collects measurements in a Prometheus registry, served by `/metrics`.
+/// The label names of a metric are fixed by its first use.
+#[derive(Default)]
+pub struct PrometheusMetrics {
+    registry: Registry,
+    counters: Mutex<HashMap<String, IntCounterVec>>,
+    gauges: Mutex<HashMap<String, GaugeVec>>,
+    histograms: Mutex<HashMap<String, HistogramVec>>,
+    /// Per histogram series, by bucket with `+Inf` last
+    exemplars: Mutex<HashMap<SeriesKey, Vec<Option<Exemplar>>>>,
+}
+
+impl PrometheusMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The metric family `name`, registered on first use
+    fn family<T: MetricVecBuilder + 'static>(
+        &self,
+        families: &Mutex<HashMap<String, MetricVec<T>>>,
+        name: &str,
+        labels: &[(&str, &str)],
+        create: impl FnOnce(&[&str]) -> prometheus::Result<MetricVec<T>>,
+    ) -> Option<T::M> {
+        let mut families = families.lock().expect("What, an error here?");
+        let family = match families.get(name) {
+            Some(family) => family.clone(),
+            None => {
+                let names: Vec<&str> = labels.iter().map(|(label, _)| *label).collect();
+                let family = create(&names).ok()?;
+                self.registry.register(Box::new(family.clone())).ok()?;
+                families.insert(name.to_string(), family.clone());
+                family
+            }
+        };
+        let values: Vec<&str> = labels.iter().map(|(_, value)| *value).collect();
+        family.get_metric_with_label_values(&values).ok()
+    }
+}
+
+fn series_key<'a>(name: &str, labels: impl Iterator<Item = (&'a str, &'a str)>) -> SeriesKey {
+    let mut labels: Vec<(String, String)> = labels
+        .map(|(label, value)| (label.to_string(), value.to_string()))
+        .collect();
+    labels.sort();
+    (name.to_string(), labels)
+}
+
+/// `{a="1",b="2"}` with `extra` appended, nothing if there are no labels
+fn label_set(labels: &[LabelPair], extra: Option<(&str, &str)>) -> String {
+    let pairs: Vec<String> = labels
+        .iter()
+        .map(|pair| (pair.get_name(), pair.get_value()))
+        .chain(extra)
+        .map(|(label, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", label, value)
+        })
+        .collect();
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+impl Metrics for PrometheusMetrics {
+    fn counter(&self, name: &str, labels: &[(&str, &str)], value: u64) {
+        let counter = self.family(&self.counters, name, labels, |names| {
+            IntCounterVec::new(Opts::new(name, name), names)
+        });
+        if let Some(counter) = counter {
+            counter.inc_by(value);
+        }
+    }
+
+    fn gauge(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let gauge = self.family(&self.gauges, name, labels, |names| {
+            GaugeVec::new(Opts::new(name, name), names)
+        });
+        if let Some(gauge) = gauge {
+            gauge.set(value);
+        }
+    }
+
+    fn histogram(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let histogram = self.family(&self.histograms, name, labels, |names| {
+            HistogramVec::new(HistogramOpts::new(name, name), names)
+        });
+        if let Some(histogram) = histogram {
+            histogram.observe(value);
+        }
+    }
+
+    fn histogram_with_exemplar(
+        &self,
+        name: &str,
+        labels: &[(&str, &str)],
+        value: f64,
+        trace_id: &str,
+    ) {
+        self.histogram(name, labels, value);
+        let bucket = DEFAULT_BUCKETS
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(DEFAULT_BUCKETS.len());
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |since| since.as_secs_f64());
+        let mut exemplars = self.exemplars.lock().expect("What, an error here?");
+        let buckets = exemplars
+            .entry(series_key(name, labels.iter().copied()))
+            .or_insert_with(|| vec![None; DEFAULT_BUCKETS.len() + 1]);
+        buckets[bucket] = Some(Exemplar {
+            trace_id: trace_id.to_string(),
+            value,
+            timestamp,
+        });
+    }
+
+    fn render(&self) -> Option<String> {
+        let mut text = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut text)
+            .ok()?;
+        String::from_utf8(text).ok()
+    }
+
+    fn render_openmetrics(&self) -> Option<String> {
+        let exemplars = self.exemplars.lock().expect("What, an error here?");
+        let mut text = String::new();
+        for family in self.registry.gather() {
+            let name = family.get_name();
+            match family.get_field_type() {
+                MetricType::COUNTER => {
+                    // Counter families are named without the suffix of their samples
+                    let name = name.strip_suffix("_total").unwrap_or(name);
+                    let _ = writeln!(text, "# TYPE {} counter", name);
+                    for metric in family.get_metric() {
+                        let labels = label_set(metric.get_label(), None);
+                        let value = metric.get_counter().get_value();
+                        let _ = writeln!(text, "{}_total{} {}", name, labels, value);
+                    }
+                }
+                MetricType::GAUGE => {
+                    let _ = writeln!(text, "# TYPE {} gauge", name);
+                    for metric in family.get_metric() {
+                        let labels = label_set(metric.get_label(), None);
+                        let value = metric.get_gauge().get_value();
+                        let _ = writeln!(text, "{}{} {}", name, labels, value);
+                    }
+                }
+                MetricType::HISTOGRAM => {
+                    let _ = writeln!(text, "# TYPE {} histogram", name);
+                    for metric in family.get_metric() {
+                        let pairs = metric
+                            .get_label()
+                            .iter()
+                            .map(|pair| (pair.get_name(), pair.get_value()));
+                        let series = exemplars.get(&series_key(name, pairs));
+                        let histogram = metric.get_histogram();
+                        let buckets = histogram
+                            .get_bucket()
+                            .iter()
+                            .map(|bucket| {
+                                let bound = format!("{:?}", bucket.get_upper_bound());
+                                (bound, bucket.get_cumulative_count())
+                            })
+                            .chain([("+Inf".to_string(), histogram.get_sample_count())]);
+                        for (index, (bound, count)) in buckets.enumerate() {
+                            let labels = label_set(metric.get_label(), Some(("le", &bound)));
+                            let _ = write!(text, "{}_bucket{} {}", name, labels, count);
+                            let exemplar = series.and_then(|series| series.get(index)?.as_ref());
+                            if let Some(exemplar) = exemplar {
+                                let _ = write!(
+                                    text,
+                                    " # {{trace_id=\"{}\"}} {} {:.3}",
+                                    exemplar.trace_id, exemplar.value, exemplar.timestamp
+                                );
+                            }
+                            text.push('\n');
+                        }
+                        let labels = label_set(metric.get_label(), None);
+                        let sum = histogram.get_sample_sum();
+                        let _ = writeln!(text, "{}_sum{} {}", name, labels, sum);
+                        let count = histogram.get_sample_count();
+                        let _ = writeln!(text, "{}_count{} {}", name, labels, count);
+                    }
+                }
+                _ => {}
+            }
+        }
+        text.push_str("# EOF\n");
+        Some(text)
+    }
+}
@@ -0,0 +1,228 @@
+//! Rate, errors and duration per route over sliding windows, for
+//! dashboards without a Prometheus server behind them
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::Mutex,
+    time::{Instant, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{MatchedPath, State},
+    http::Request,
+    middleware::Next,
+    response::Response,
+    Json,
+};
+use serde::Serialize;
+
+use crate::{KVError, SharedState};
+
+/// Upper bounds of the duration buckets in seconds, Prometheus' defaults
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+/// Seconds covered by a slot
+const SLOT: u64 = 10;
+/// Windows of the summary, in seconds
+const WINDOWS: [(&str, u64); 3] = [("1m", 60), ("5m", 300), ("15m", 900)];
+
+#[derive(Clone, Default)]
+struct Slot {
+    /// Unix time the slot starts at
+    start: u64,
+    requests: u64,
+    errors: u64,
+    duration_sum: f64,
+    duration_max: f64,
+    /// Per bucket, the last one for durations beyond all bounds
+    buckets: [u64; BUCKETS.len() + 1],
+}
+
+/// Requests to all routes, in slots of `SLOT` seconds going back as far as
+/// the longest window
+#[derive(Default)]
+pub(crate) struct RedStats {
+    routes: Mutex<HashMap<(String, String), VecDeque<Slot>>>,
+}
+
+impl RedStats {
+    fn record(&self, route: (String, String), now: u64, seconds: f64, error: bool) {
+        let longest = WINDOWS[WINDOWS.len() - 1].1;
+        let mut routes = self.routes.lock().expect("What, an error here?");
+        let slots = routes.entry(route).or_default();
+        let start = now - now % SLOT;
+        while slots
+            .front()
+            .is_some_and(|slot| slot.start + longest <= start)
+        {
+            slots.pop_front();
+        }
+        if slots.back().is_none_or(|slot| slot.start != start) {
+            slots.push_back(Slot {
+                start,
+                ..Slot::default()
+            });
+        }
+        let slot = slots.back_mut().expect("Pushed if missing");
+        slot.requests += 1;
+        slot.errors += u64::from(error);
+        slot.duration_sum += seconds;
+        slot.duration_max = slot.duration_max.max(seconds);
+        let bucket = BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(BUCKETS.len());
+        slot.buckets[bucket] += 1;
+    }
+
+    fn summary(&self, now: u64) -> Vec<RouteSummary> {
+        let routes = self.routes.lock().expect("What, an error here?");
+        let mut summaries: Vec<RouteSummary> = routes
+            .iter()
+            .map(|((method, route), slots)| RouteSummary {
+                method: method.clone(),
+                route: route.clone(),
+                windows: WINDOWS
+                    .iter()
+                    .map(|(name, seconds)| {
+                        let since = now.saturating_sub(*seconds);
+                        let slots = slots.iter().filter(|slot| slot.start + SLOT > since);
+                        (*name, WindowSummary::of(slots, *seconds))
+                    })
+                    .collect(),
+            })
+            .collect();
+        summaries.sort_by(|a, b| (&a.route, &a.method).cmp(&(&b.route, &b.method)));
+        summaries
+    }
+}
+
+#[derive(Serialize)]
+pub struct RouteSummary {
+    method: String,
+    route: String,
+    windows: BTreeMap<&'static str, WindowSummary>,
+}
+
+#[derive(Serialize)]
+pub struct WindowSummary {
+    requests: u64,
+    /// Requests per second
+    rate: f64,
+    /// Requests answered with a 5xx status
+    errors: u64,
+    error_ratio: f64,
+    /// Durations in seconds, quantiles are the upper bounds of buckets
+    duration_mean: f64,
+    duration_p50: f64,
+    duration_p90: f64,
+    duration_p99: f64,
+}
+
+impl WindowSummary {
+    fn of<'a>(slots: impl Iterator<Item = &'a Slot>, seconds: u64) -> Self {
+        let total = slots.fold(Slot::default(), |mut total, slot| {
+            total.requests += slot.requests;
+            total.errors += slot.errors;
+            total.duration_sum += slot.duration_sum;
+            total.duration_max = total.duration_max.max(slot.duration_max);
+            for (sum, count) in total.buckets.iter_mut().zip(slot.buckets) {
+                *sum += count;
+            }
+            total
+        });
+        let ratio = |part: f64| match total.requests {
+            0 => 0.0,
+            requests => part / requests as f64,
+        };
+        let quantile = |q: f64| {
+            let rank = (q * total.requests as f64).ceil() as u64;
+            let mut seen = 0;
+            for (bucket, count) in total.buckets.iter().enumerate() {
+                seen += count;
+                if seen >= rank && seen > 0 {
+                    let bound = BUCKETS.get(bucket).copied().unwrap_or(f64::INFINITY);
+                    return bound.min(total.duration_max);
+                }
+            }
+            0.0
+        };
+        Self {
+            requests: total.requests,
+            rate: total.requests as f64 / seconds as f64,
+            errors: total.errors,
+            error_ratio: ratio(total.errors as f64),
+            duration_mean: ratio(total.duration_sum),
+            duration_p50: quantile(0.5),
+            duration_p90: quantile(0.9),
+            duration_p99: quantile(0.99),
+        }
+    }
+}
+
+/// The trace ID of a W3C `traceparent` header, to link latencies to traces
+fn trace_id<B>(request: &Request<B>) -> Option<String> {
+    let traceparent = request.headers().get("traceparent")?.to_str().ok()?;
+    let trace_id = traceparent.split('-').nth(1)?;
+    let valid = trace_id.len() == 32
+        && trace_id.bytes().all(|byte| byte.is_ascii_hexdigit())
+        && trace_id.bytes().any(|byte| byte != b'0');
+    valid.then(|| trace_id.to_ascii_lowercase())
+}
+
+/// Times requests per route, for `kv_http_request_duration_seconds` and
+/// `/admin/red`. Needs the matched route, so it goes in a `route_layer`.
+pub async fn record_red_metrics<B>(
+    State(state): State<SharedState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(route) = request.extensions().get::<MatchedPath>() else {
+        return next.run(request).await;
+    };
+    let route = route.as_str().to_string();
+    let method = request.method().to_string();
+    let trace_id = trace_id(&request);
+    let started = Instant::now();
+    let response = next.run(request).await;
+    let seconds = started.elapsed().as_secs_f64();
+
+    let state = state.read().expect("What, an error here?");
+    let status = response.status();
+    let labels = [
+        ("route", route.as_str()),
+        ("method", method.as_str()),
+        ("status", status.as_str()),
+    ];
+    let name = "kv_http_request_duration_seconds";
+    match &trace_id {
+        Some(trace_id) => state
+            .metrics
+            .histogram_with_exemplar(name, &labels, seconds, trace_id),
+        None => state.metrics.histogram(name, &labels, seconds),
+    }
+    let now = state
+        .clock
+        .system_time()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    state
+        .red
+        .record((method, route), now, seconds, status.is_server_error());
+    response
+}
+
+/// Rate, errors and duration of every public route over the last 1, 5 and 15
+/// minutes
+pub async fn red_summary(
+    State(state): State<SharedState>,
+) -> Result<Json<Vec<RouteSummary>>, KVError> {
+    let state = state.read()?;
+    let now = state
+        .clock
+        .system_time()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    Ok(Json(state.red.summary(now)))
+}
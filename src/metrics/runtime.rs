@@ -0,0 +1,44 @@
+use std::time::Duration;
+
+use tokio::runtime::Handle;
+
+use crate::SharedState;
+
+/// Copies the tokio runtime's own numbers into the metrics sink every
+/// `period`, to tell a runtime stalled by blocking work from a slow backend.
+/// The blocking pool is only visible with `RUSTFLAGS="--cfg tokio_unstable"`.
+pub fn spawn_runtime_metrics(state: &SharedState, period: Duration) {
+    let metrics = state.read().expect("What, an error here?").metrics.clone();
+    let runtime = Handle::current().metrics();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(period);
+        loop {
+            ticker.tick().await;
+            metrics.gauge("tokio_workers", &[], runtime.num_workers() as f64);
+            metrics.gauge("tokio_alive_tasks", &[], runtime.num_alive_tasks() as f64);
+            metrics.gauge(
+                "tokio_global_queue_depth",
+                &[],
+                runtime.global_queue_depth() as f64,
+            );
+            #[cfg(tokio_unstable)]
+            {
+                metrics.gauge(
+                    "tokio_blocking_threads",
+                    &[],
+                    runtime.num_blocking_threads() as f64,
+                );
+                metrics.gauge(
+                    "tokio_idle_blocking_threads",
+                    &[],
+                    runtime.num_idle_blocking_threads() as f64,
+                );
+                metrics.gauge(
+                    "tokio_blocking_queue_depth",
+                    &[],
+                    runtime.blocking_queue_depth() as f64,
+                );
+            }
+        }
+    });
+}
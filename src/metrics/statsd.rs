@@ -0,0 +1,63 @@
+use std::{
+    fmt::Display,
+    io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+};
+
+use super::Metrics;
+
+/// Pushes every measurement to a StatsD server over UDP. Labels are sent
+/// as DogStatsD tags.
+pub struct StatsdMetrics {
+    socket: UdpSocket,
+    prefix: String,
+}
+
+impl StatsdMetrics {
+    /// Sends to `addr`, e.g. `127.0.0.1:8125`, with `prefix` in front of every name
+    pub fn new(addr: impl ToSocketAddrs, prefix: impl Into<String>) -> io::Result<Self> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::other("StatsD address resolves to nothing"))?;
+        let local: SocketAddr = if addr.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        // Never hold up a request for a metric
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            prefix: prefix.into(),
+        })
+    }
+
+    fn send(&self, name: &str, labels: &[(&str, &str)], value: impl Display, kind: &str) {
+        let mut line = format!("{}{}:{}|{}", self.prefix, name, value, kind);
+        for (i, (label, value)) in labels.iter().enumerate() {
+            line.push_str(if i == 0 { "|#" } else { "," });
+            line.push_str(label);
+            line.push(':');
+            line.push_str(value);
+        }
+        // Lost packets are lost metrics, nothing to do about it
+        let _ = self.socket.send(line.as_bytes());
+    }
+}
+
+impl Metrics for StatsdMetrics {
+    fn counter(&self, name: &str, labels: &[(&str, &str)], value: u64) {
+        self.send(name, labels, value, "c");
+    }
+
+    fn gauge(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.send(name, labels, value, "g");
+    }
+
+    fn histogram(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.send(name, labels, value, "h");
+    }
+}
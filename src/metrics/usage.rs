@@ -0,0 +1,282 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::Mutex,
+    time::{Instant, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Query, State},
+    http::{header::CONTENT_LENGTH, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use hyper::body::HttpBody;
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    admin::Feature,
+    kv_store::{namespace, BUCKET_NAMESPACE_PREFIX},
+    versioning::unversioned,
+    AppState, KVError, Principal, SharedState,
+};
+
+const HOUR: u64 = 3600;
+/// Usage older than this is dropped, about three months
+const RETENTION: u64 = 92 * 24 * HOUR;
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct UsageKey {
+    /// Unix time the hour starts at, first so buckets sort by time
+    hour: u64,
+    namespace: String,
+    client: String,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Usage {
+    requests: u64,
+    bytes_served: u64,
+    transform_cpu_seconds: f64,
+}
+
+/// Requests of the public API by hour, namespace and client, the data
+/// behind usage reports
+#[derive(Default)]
+pub(crate) struct UsageLog {
+    buckets: Mutex<BTreeMap<UsageKey, Usage>>,
+}
+
+impl UsageLog {
+    fn record(&self, key: UsageKey, bytes_served: u64, transform_cpu_seconds: f64) {
+        let mut buckets = self.buckets.lock().expect("What, an error here?");
+        let oldest = key.hour.saturating_sub(RETENTION);
+        while buckets
+            .first_key_value()
+            .is_some_and(|(first, _)| first.hour < oldest)
+        {
+            buckets.pop_first();
+        }
+        let usage = buckets.entry(key).or_default();
+        usage.requests += 1;
+        usage.bytes_served += bytes_served;
+        usage.transform_cpu_seconds += transform_cpu_seconds;
+    }
+
+    /// Drops the usage of the namespaces `erased` says were erased whole
+    pub(crate) fn forget_namespaces(&self, erased: impl Fn(&str) -> bool) {
+        self.buckets
+            .lock()
+            .expect("What, an error here?")
+            .retain(|key, _| !erased(&key.namespace));
+    }
+
+    /// Usage of the hours starting in `from..to`, summed per namespace and
+    /// client
+    fn report(&self, from: u64, to: u64) -> Vec<UsageRow> {
+        let buckets = self.buckets.lock().expect("What, an error here?");
+        let start = UsageKey {
+            hour: from,
+            namespace: String::new(),
+            client: String::new(),
+        };
+        let mut totals: BTreeMap<(&str, &str), Usage> = BTreeMap::new();
+        for (key, usage) in buckets.range(start..).take_while(|(key, _)| key.hour < to) {
+            let total = totals.entry((&key.namespace, &key.client)).or_default();
+            total.requests += usage.requests;
+            total.bytes_served += usage.bytes_served;
+            total.transform_cpu_seconds += usage.transform_cpu_seconds;
+        }
+        totals
+            .into_iter()
+            .map(|((namespace, client), usage)| UsageRow {
+                namespace: namespace.to_string(),
+                client: client.to_string(),
+                requests: usage.requests,
+                bytes_served: usage.bytes_served,
+                transform_cpu_seconds: usage.transform_cpu_seconds,
+            })
+            .collect()
+    }
+}
+
+/// The namespace a request addresses: the one of its key, the bucket's for
+/// buckets, the site for static sites, and empty for everything else
+fn namespace_of(path: &str) -> String {
+    let first_segment = |rest: &str| {
+        let segment = rest.split('/').next().unwrap_or_default();
+        percent_decode_str(segment).decode_utf8_lossy().into_owned()
+    };
+    if let Some(rest) = path.strip_prefix("/bucket/") {
+        return format!("{}{}", BUCKET_NAMESPACE_PREFIX, first_segment(rest));
+    }
+    if let Some(rest) = path.strip_prefix("/site/") {
+        return first_segment(rest);
+    }
+    path.strip_prefix("/kv/")
+        .or_else(|| path.strip_prefix("/r/"))
+        .map(first_segment)
+        .and_then(|key| namespace(&key).map(str::to_string))
+        .unwrap_or_default()
+}
+
+fn unix_time(state: &AppState) -> u64 {
+    state
+        .clock
+        .system_time()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// Who to bill: the API key by the first 8 hex digits of its SHA-256, else
+/// the token subject, else `anonymous`
+fn client_of(api_key: Option<[u8; 32]>, principal: Option<&Principal>) -> String {
+    match (api_key, principal) {
+        (Some(digest), _) => {
+            let mut client = String::from("key:");
+            for byte in &digest[..4] {
+                let _ = write!(client, "{:02x}", byte);
+            }
+            client
+        }
+        (None, Some(Principal::Subject(subject))) => format!("sub:{}", subject),
+        (None, _) => "anonymous".to_string(),
+    }
+}
+
+/// Records every request to the public API in the usage log. Bytes are
+/// those of bodies with a known length, transforms are timed as a whole.
+pub async fn record_usage<B>(
+    State(state): State<SharedState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let path = unversioned(request.uri().path());
+    let namespace = namespace_of(path);
+    let transform = Feature::of_path(path) == Some(Feature::Transforms);
+    let api_key = state
+        .read()
+        .expect("What, an error here?")
+        .api_keys
+        .as_ref()
+        .and_then(|keys| keys.identify(request.headers()));
+    let started = Instant::now();
+    let response = next.run(request).await;
+    let transform_cpu_seconds = if transform {
+        started.elapsed().as_secs_f64()
+    } else {
+        0.0
+    };
+    let client = client_of(api_key, response.extensions().get::<Principal>());
+    let bytes_served = response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok()?.parse().ok())
+        .or_else(|| response.body().size_hint().exact())
+        .unwrap_or(0);
+    let state = state.read().expect("What, an error here?");
+    let now = unix_time(&state);
+    let key = UsageKey {
+        hour: now - now % HOUR,
+        namespace,
+        client,
+    };
+    state.usage.record(key, bytes_served, transform_cpu_seconds);
+    response
+}
+
+#[derive(Serialize)]
+pub struct UsageRow {
+    namespace: String,
+    client: String,
+    requests: u64,
+    bytes_served: u64,
+    transform_cpu_seconds: f64,
+}
+
+#[derive(Serialize)]
+pub struct UsageReport {
+    from: u64,
+    to: u64,
+    usage: Vec<UsageRow>,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Deserialize)]
+pub struct ReportQuery {
+    /// Unix time, usage is kept by the hour
+    #[serde(default)]
+    from: u64,
+    /// Unix time, now by default
+    to: Option<u64>,
+    #[serde(default)]
+    format: ReportFormat,
+}
+
+fn csv_field(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "\"\""))
+}
+
+/// Usage per namespace and client between `from` and `to`, for chargeback
+pub async fn usage_report(
+    State(state): State<SharedState>,
+    Query(query): Query<ReportQuery>,
+) -> Result<Response, KVError> {
+    let (to, usage) = {
+        let state = state.read()?;
+        let to = query.to.unwrap_or_else(|| unix_time(&state));
+        if query.from > to {
+            return Err(KVError::BadRequest("from is after to".to_string()));
+        }
+        (to, state.usage.report(query.from, to))
+    };
+    let filename = |extension| {
+        format!(
+            "attachment; filename=\"usage-{}-{}.{}\"",
+            query.from, to, extension
+        )
+    };
+    Ok(match query.format {
+        ReportFormat::Json => (
+            [("content-disposition", filename("json"))],
+            Json(UsageReport {
+                from: query.from,
+                to,
+                usage,
+            }),
+        )
+            .into_response(),
+        ReportFormat::Csv => {
+            let mut csv =
+                String::from("namespace,client,requests,bytes_served,transform_cpu_seconds\n");
+            for row in &usage {
+                let _ = writeln!(
+                    csv,
+                    "{},{},{},{},{:.3}",
+                    csv_field(&row.namespace),
+                    csv_field(&row.client),
+                    row.requests,
+                    row.bytes_served,
+                    row.transform_cpu_seconds
+                );
+            }
+            (
+                [
+                    ("content-type", "text/csv".to_string()),
+                    ("content-disposition", filename("csv")),
+                ],
+                csv,
+            )
+                .into_response()
+        }
+    })
+}
@@ -0,0 +1,491 @@
+//! An OpenAPI description of the public routes, so clients don't have to
+//! read the handlers to learn the API. Written by hand next to the router,
+//! a route added to `api` belongs in here as well, which `api_paths` lets
+//! the tests check.
+
+use axum::{
+    response::{Html, IntoResponse},
+    Json,
+};
+use serde_json::{json, Value};
+
+fn key_parameter() -> Value {
+    json!({
+        "name": "key",
+        "in": "path",
+        "required": true,
+        "description": "Percent-encode a `/` in namespaced keys, e.g. `site%2Findex.html`",
+        "schema": { "type": "string" },
+    })
+}
+
+fn image_response(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": { "image/png": { "schema": { "type": "string", "format": "binary" } } },
+    })
+}
+
+fn json_response(description: &str, schema: &str) -> Value {
+    json!({
+        "description": description,
+        "content": {
+            "application/json": { "schema": { "$ref": format!("#/components/schemas/{}", schema) } },
+        },
+    })
+}
+
+/// What every route can fail with, besides its own errors
+fn errors() -> Value {
+    json!({
+        "4XX": { "$ref": "#/components/responses/Error" },
+        "5XX": { "$ref": "#/components/responses/Error" },
+    })
+}
+
+fn responses_with(status: &str, ok: Value) -> Value {
+    let mut responses = errors();
+    responses[status] = ok;
+    responses
+}
+
+fn responses(ok: Value) -> Value {
+    responses_with("200", ok)
+}
+
+fn upload() -> Value {
+    json!({
+        "required": true,
+        "description": "Stored as is, along with its `Content-Type`",
+        "content": { "*/*": { "schema": { "type": "string", "format": "binary" } } },
+    })
+}
+
+fn write_parameters() -> Value {
+    json!([
+        { "$ref": "#/components/parameters/Ttl" },
+        { "$ref": "#/components/parameters/TtlSeconds" },
+        { "$ref": "#/components/parameters/BurnAfterRead" },
+        { "$ref": "#/components/parameters/ChecksumSha256" },
+        { "$ref": "#/components/parameters/Background" },
+    ])
+}
+
+fn paths() -> Value {
+    let value = json!({
+        "description": "The value, with the `Content-Type` it was stored with",
+        "content": { "*/*": { "schema": { "type": "string", "format": "binary" } } },
+    });
+    let entry = json!({
+        "parameters": [key_parameter()],
+        "get": {
+            "summary": "Reads the value of a key",
+            "description": "Supports `Range`, `If-None-Match` and `If-Modified-Since`",
+            "responses": responses(value.clone()),
+        },
+        "post": {
+            "summary": "Stores a value under a key",
+            "parameters": write_parameters(),
+            "requestBody": upload(),
+            "responses": responses(json!({ "description": "Stored" })),
+        },
+        "delete": {
+            "summary": "Removes a key",
+            "responses": responses(json!({ "description": "Removed" })),
+        },
+    });
+    let mut v2_entry = entry.clone();
+    v2_entry["get"]["summary"] = json!("Reads the value of a key in a JSON envelope");
+    v2_entry["get"]["responses"] = responses(json_response("The entry", "Envelope"));
+
+    json!({
+        "/kv": {
+            "post": {
+                "summary": "Stores a value under a generated key",
+                "parameters": write_parameters(),
+                "requestBody": upload(),
+                "responses": responses(json_response("Stored", "Created")),
+            },
+        },
+        "/kv/{key}": entry,
+        "/v2/kv/{key}": v2_entry,
+        "/v2/kv/{key}/raw": {
+            "parameters": [key_parameter()],
+            "get": {
+                "summary": "Reads the value of a key as is",
+                "responses": responses(value),
+            },
+        },
+        "/kv/{key}/grayscale": {
+            "parameters": [key_parameter()],
+            "get": {
+                "summary": "The image under the key in grayscale",
+                "responses": responses(image_response("The image in grayscale")),
+            },
+        },
+        "/kv/{key}/flatten": {
+            "parameters": [key_parameter()],
+            "get": {
+                "summary": "The image under the key on a solid background",
+                "parameters": [{ "$ref": "#/components/parameters/Background" }],
+                "responses": responses(image_response("The image without transparency")),
+            },
+        },
+        "/kv/{key}/thumbnail": {
+            "parameters": [key_parameter()],
+            "get": {
+                "summary": "The image under the key scaled and cropped",
+                "parameters": [
+                    { "name": "width", "in": "query", "required": true, "schema": { "type": "integer", "minimum": 1 } },
+                    { "name": "height", "in": "query", "required": true, "schema": { "type": "integer", "minimum": 1 } },
+                    { "name": "gravity", "in": "query", "schema": { "type": "string", "enum": ["center", "smart"] } },
+                ],
+                "responses": responses(image_response("The thumbnail")),
+            },
+        },
+        "/kv/{key}/preview": {
+            "parameters": [key_parameter()],
+            "get": {
+                "summary": "Text under the key as syntax highlighted HTML",
+                "responses": responses(json!({
+                    "description": "The highlighted text",
+                    "content": { "text/html": { "schema": { "type": "string" } } },
+                })),
+            },
+        },
+        "/kv/{key}/tiles": {
+            "parameters": [key_parameter()],
+            "get": {
+                "summary": "The levels of the tile pyramid of the image under the key",
+                "responses": responses(json_response("The pyramid", "TileInfo")),
+            },
+        },
+        "/kv/{key}/tiles/{z}/{x}/{y}": {
+            "parameters": [
+                key_parameter(),
+                { "name": "z", "in": "path", "required": true, "schema": { "type": "integer", "minimum": 0 } },
+                { "name": "x", "in": "path", "required": true, "schema": { "type": "integer", "minimum": 0 } },
+                { "name": "y", "in": "path", "required": true, "schema": { "type": "integer", "minimum": 0 } },
+            ],
+            "get": {
+                "summary": "A tile of the image under the key",
+                "responses": responses(image_response("The tile")),
+            },
+        },
+        "/kv/{key}/acl": {
+            "parameters": [key_parameter()],
+            "get": {
+                "summary": "The ACL governing the key",
+                "responses": responses(json_response("The ACL and what it is set on", "GoverningAcl")),
+            },
+            "put": {
+                "summary": "Restricts the key, or with `prefix` every key below it, to principals",
+                "requestBody": {
+                    "required": true,
+                    "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Acl" } } },
+                },
+                "responses": responses_with("204", json!({ "description": "Set" })),
+            },
+            "delete": {
+                "summary": "Removes the ACL of the key",
+                "parameters": [{ "name": "prefix", "in": "query", "schema": { "type": "boolean" } }],
+                "responses": responses_with("204", json!({ "description": "Removed" })),
+            },
+        },
+        "/transform/batch": {
+            "post": {
+                "summary": "Runs an image pipeline over many keys",
+                "requestBody": {
+                    "required": true,
+                    "content": { "application/json": { "schema": { "$ref": "#/components/schemas/BatchRequest" } } },
+                },
+                "responses": responses(json_response("What became of every key", "BatchReport")),
+            },
+        },
+        "/bucket/{bucket}/kv": {
+            "parameters": [{ "name": "bucket", "in": "path", "required": true, "schema": { "type": "string" } }],
+            "get": {
+                "summary": "The keys in a bucket",
+                "responses": responses(json!({
+                    "description": "The keys",
+                    "content": {
+                        "application/json": { "schema": { "type": "array", "items": { "type": "string" } } },
+                    },
+                })),
+            },
+        },
+        "/bucket/{bucket}/kv/{key}": {
+            "parameters": [
+                { "name": "bucket", "in": "path", "required": true, "schema": { "type": "string" } },
+                key_parameter(),
+            ],
+            "get": {
+                "summary": "Reads a key of a bucket",
+                "responses": responses(json!({ "description": "The value" })),
+            },
+            "post": {
+                "summary": "Stores a value in a bucket",
+                "parameters": write_parameters(),
+                "requestBody": upload(),
+                "responses": responses(json!({ "description": "Stored" })),
+            },
+            "delete": {
+                "summary": "Removes a key of a bucket",
+                "responses": responses(json!({ "description": "Removed" })),
+            },
+        },
+        "/r/{key}": {
+            "parameters": [key_parameter()],
+            "get": {
+                "summary": "Redirects to the URL stored under the key",
+                "responses": responses_with("302", json!({ "description": "To the stored URL" })),
+            },
+        },
+        "/r/{key}/stats": {
+            "parameters": [key_parameter()],
+            "get": {
+                "summary": "How often the link under the key was followed",
+                "responses": responses(json_response("The count", "LinkStats")),
+            },
+        },
+        "/site/{namespace}": {
+            "parameters": [
+                { "name": "namespace", "in": "path", "required": true, "schema": { "type": "string" } },
+            ],
+            "get": {
+                "summary": "The `index.html` of a namespace served as a static site",
+                "responses": responses(json!({ "description": "The page" })),
+            },
+        },
+        "/site/{namespace}/{path}": {
+            "parameters": [
+                { "name": "namespace", "in": "path", "required": true, "schema": { "type": "string" } },
+                {
+                    "name": "path",
+                    "in": "path",
+                    "required": true,
+                    "description": "`index.html` of the namespace if empty",
+                    "schema": { "type": "string" },
+                },
+            ],
+            "get": {
+                "summary": "Serves the keys of a namespace as a static site",
+                "responses": responses(json!({ "description": "The page" })),
+            },
+        },
+    })
+}
+
+fn components() -> Value {
+    json!({
+        "parameters": {
+            "Background": {
+                "name": "background",
+                "in": "query",
+                "description": "`rrggbb` in hex that transparency is flattened onto, white by \
+                    default. Uploads use it where images are re-encoded to JPEG, instead of \
+                    the namespace's.",
+                "schema": { "type": "string", "pattern": "^#?[0-9a-fA-F]{6}$" },
+            },
+            "Ttl": {
+                "name": "ttl",
+                "in": "query",
+                "description": "Seconds until the entry expires",
+                "schema": { "type": "integer", "minimum": 1 },
+            },
+            "TtlSeconds": {
+                "name": "X-TTL-Seconds",
+                "in": "header",
+                "description": "Like `ttl`, takes precedence",
+                "schema": { "type": "integer", "minimum": 1 },
+            },
+            "BurnAfterRead": {
+                "name": "X-Burn-After-Read",
+                "in": "header",
+                "description": "Removes the entry once it was read",
+                "schema": { "type": "boolean" },
+            },
+            "ChecksumSha256": {
+                "name": "X-Checksum-SHA256",
+                "in": "header",
+                "description": "base64 SHA-256 the body must match, `Content-MD5` works as well",
+                "schema": { "type": "string" },
+            },
+        },
+        "responses": {
+            "Error": {
+                "description": "Plain text, or JSON under `/v2`. `X-Error-Code` names the error either way.",
+                "headers": {
+                    "X-Error-Code": { "schema": { "type": "string" } },
+                    "X-Request-Id": { "schema": { "type": "string" } },
+                },
+                "content": {
+                    "text/plain": { "schema": { "type": "string" } },
+                    "application/json": { "schema": { "$ref": "#/components/schemas/Error" } },
+                },
+            },
+        },
+        "schemas": {
+            "Error": {
+                "type": "object",
+                "properties": {
+                    "error": {
+                        "type": "object",
+                        "properties": {
+                            "code": { "type": "string" },
+                            "message": { "type": "string" },
+                            "request_id": { "type": "string" },
+                        },
+                        "required": ["code", "message"],
+                    },
+                },
+            },
+            "Created": {
+                "type": "object",
+                "properties": { "key": { "type": "string" }, "url": { "type": "string" } },
+            },
+            "Envelope": {
+                "type": "object",
+                "properties": {
+                    "key": { "type": "string" },
+                    "content_type": { "type": "string" },
+                    "size": { "type": "integer" },
+                    "etag": { "type": "string" },
+                    "sha256": { "type": "string", "description": "base64" },
+                    "md5": { "type": "string", "nullable": true, "description": "base64, for uploads verified against a `Content-MD5`" },
+                    "last_modified": { "type": "string", "nullable": true, "description": "HTTP date" },
+                    "data": { "type": "string", "format": "byte" },
+                },
+            },
+            "TileInfo": {
+                "type": "object",
+                "properties": {
+                    "width": { "type": "integer" },
+                    "height": { "type": "integer" },
+                    "tile_size": { "type": "integer" },
+                    "max_level": { "type": "integer", "description": "The level at full resolution" },
+                },
+            },
+            "Acl": {
+                "type": "object",
+                "properties": {
+                    "principals": { "type": "array", "items": { "type": "string" } },
+                    "prefix": { "type": "boolean" },
+                },
+                "required": ["principals"],
+            },
+            "GoverningAcl": {
+                "allOf": [
+                    { "$ref": "#/components/schemas/Acl" },
+                    { "type": "object", "properties": { "key": { "type": "string" } } },
+                ],
+            },
+            "BatchRequest": {
+                "type": "object",
+                "properties": {
+                    "keys": { "type": "array", "items": { "type": "string" }, "maxItems": 1000 },
+                    "pipeline": {
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "properties": {
+                                "op": { "type": "string", "enum": ["grayscale", "flatten", "thumbnail"] },
+                                "background": { "type": "string" },
+                                "width": { "type": "integer" },
+                                "height": { "type": "integer" },
+                                "gravity": { "type": "string", "enum": ["center", "smart"] },
+                            },
+                            "required": ["op"],
+                        },
+                    },
+                    "target": { "type": "string", "description": "With `{key}` standing for the source key" },
+                },
+                "required": ["keys", "pipeline", "target"],
+            },
+            "BatchReport": {
+                "type": "object",
+                "properties": {
+                    "results": {
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "properties": {
+                                "key": { "type": "string" },
+                                "target": { "type": "string" },
+                                "status": { "type": "integer" },
+                                "error": { "type": "string" },
+                                "message": { "type": "string" },
+                            },
+                        },
+                    },
+                },
+            },
+            "LinkStats": {
+                "type": "object",
+                "properties": { "redirects": { "type": "integer" } },
+            },
+        },
+        "securitySchemes": {
+            "ApiKey": { "type": "apiKey", "in": "header", "name": "X-API-Key" },
+            "Bearer": { "type": "http", "scheme": "bearer", "bearerFormat": "JWT" },
+        },
+    })
+}
+
+/// The OpenAPI 3 document
+pub(crate) fn spec() -> Value {
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Key-value store",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Every path is also served under `/v1`, which is what the unprefixed \
+                paths are, and `/v2`, which answers errors as JSON.",
+        },
+        "paths": paths(),
+        "components": components(),
+        "security": [{}, { "ApiKey": [] }, { "Bearer": [] }],
+    })
+}
+
+pub async fn openapi_json() -> Json<Value> {
+    Json(spec())
+}
+
+/// Swagger UI for `/openapi.json`. Its assets are vendored from the
+/// 5.17.14 release in `assets/swagger-ui` and served with it, so the docs
+/// page runs no script the build didn't pin.
+pub async fn swagger_ui() -> Html<&'static str> {
+    Html(
+        r##"<!doctype html>
+<html>
+<head>
+  <meta charset="utf-8">
+  <title>Key-value store API</title>
+  <link rel="stylesheet" href="docs/swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="docs/swagger-ui-bundle.js"></script>
+  <script>
+    SwaggerUIBundle({ url: "openapi.json", dom_id: "#swagger-ui" });
+  </script>
+</body>
+</html>
+"##,
+    )
+}
+
+pub async fn swagger_ui_css() -> impl IntoResponse {
+    (
+        [("content-type", "text/css")],
+        include_str!("../assets/swagger-ui/swagger-ui.css"),
+    )
+}
+
+pub async fn swagger_ui_bundle() -> impl IntoResponse {
+    (
+        [("content-type", "application/javascript")],
+        include_str!("../assets/swagger-ui/swagger-ui-bundle.js"),
+    )
+}
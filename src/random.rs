@@ -0,0 +1,33 @@
+use std::sync::Mutex;
+
+use rand::{distributions::Alphanumeric, rngs::StdRng, Rng, SeedableRng};
+
+/// Source of everything random the service hands out. Seeded in tests so
+/// that their output is reproducible.
+pub(crate) struct Random(Mutex<StdRng>);
+
+impl Random {
+    pub(crate) fn seeded(seed: u64) -> Self {
+        Self(Mutex::new(StdRng::seed_from_u64(seed)))
+    }
+
+    /// Short random key, 62^8 possibilities
+    pub(crate) fn key(&self) -> String {
+        let mut rng = self.0.lock().expect("What, an error here?");
+        (0..8)
+            .map(|_| char::from(rng.sample(Alphanumeric)))
+            .collect()
+    }
+
+    /// 128 random bits in hex, like the trace IDs of `traceparent`
+    pub(crate) fn request_id(&self) -> String {
+        let id: u128 = self.0.lock().expect("What, an error here?").gen();
+        format!("{:032x}", id)
+    }
+}
+
+impl Default for Random {
+    fn default() -> Self {
+        Self(Mutex::new(StdRng::from_entropy()))
+    }
+}
@@ -0,0 +1,249 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hyper::{Method, StatusCode};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{admin::Feature, versioning::unversioned, AppState, SharedState};
+
+/// A token bucket: clients may send `burst` requests at once, and then
+/// `per_second` on average
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Rate {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+#[derive(Debug, Error)]
+#[error("Invalid rate {0}, use <per second>[:<burst>]")]
+pub struct InvalidRate(String);
+
+impl TryFrom<String> for Rate {
+    type Error = InvalidRate;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl FromStr for Rate {
+    type Err = InvalidRate;
+
+    /// `10` or `10:50`, the burst is the rate rounded up by default
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidRate(s.to_string());
+        let (per_second, burst) = match s.split_once(':') {
+            Some((per_second, burst)) => (per_second, Some(burst)),
+            None => (s, None),
+        };
+        let per_second: f64 = per_second.trim().parse().map_err(|_| invalid())?;
+        if !per_second.is_finite() || per_second <= 0.0 {
+            return Err(invalid());
+        }
+        let burst = match burst {
+            Some(burst) => burst.trim().parse().map_err(|_| invalid())?,
+            None => per_second.ceil() as u32,
+        };
+        if burst == 0 {
+            return Err(invalid());
+        }
+        Ok(Self { per_second, burst })
+    }
+}
+
+/// Per client rates for each kind of request, unlimited where `None`.
+/// Transforms get their own since they cost far more CPU than the rest.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimits {
+    pub reads: Option<Rate>,
+    pub writes: Option<Rate>,
+    pub transforms: Option<Rate>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum RequestClass {
+    Read,
+    Write,
+    Transform,
+}
+
+impl RequestClass {
+    fn of(method: &Method, path: &str) -> Self {
+        if Feature::of_path(unversioned(path)) == Some(Feature::Transforms) {
+            RequestClass::Transform
+        } else if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) {
+            RequestClass::Read
+        } else {
+            RequestClass::Write
+        }
+    }
+
+    fn rate(self, limits: &RateLimits) -> Option<Rate> {
+        match self {
+            RequestClass::Read => limits.reads,
+            RequestClass::Write => limits.writes,
+            RequestClass::Transform => limits.transforms,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            RequestClass::Read => "read",
+            RequestClass::Write => "write",
+            RequestClass::Transform => "transform",
+        }
+    }
+}
+
+/// Whose bucket a request takes from. Clients behind one API key share
+/// it wherever they connect from.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Client {
+    ApiKey([u8; 32]),
+    Ip(IpAddr),
+    /// Connections without an address, e.g. over a Unix socket
+    Unknown,
+}
+
+impl Client {
+    fn of<B>(state: &AppState, request: &Request<B>) -> Self {
+        let api_key = state
+            .api_keys
+            .as_ref()
+            .and_then(|keys| keys.identify(request.headers()));
+        let ip = request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        match (api_key, ip) {
+            (Some(digest), _) => Client::ApiKey(digest),
+            (None, Some(ip)) => Client::Ip(ip),
+            (None, None) => Client::Unknown,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Takes a token, or tells how long until the next one
+    fn take(&mut self, rate: Rate, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate.per_second).min(rate.burst as f64);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            let missing = (1.0 - self.tokens) / rate.per_second;
+            Err(Duration::try_from_secs_f64(missing).unwrap_or(Duration::MAX))
+        }
+    }
+}
+
+#[derive(Default)]
+struct Buckets {
+    buckets: HashMap<(Client, RequestClass), Bucket>,
+    last_prune: Option<Instant>,
+}
+
+/// Token buckets per client and kind of request
+pub(crate) struct RateLimiter {
+    limits: RateLimits,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Counts a request at `now`, or tells how long the client has to wait
+    fn check(&self, client: Client, class: RequestClass, now: Instant) -> Result<(), Duration> {
+        let Some(rate) = class.rate(&self.limits) else {
+            return Ok(());
+        };
+        let mut buckets = self.buckets.lock().expect("What, an error here?");
+        buckets.prune(&self.limits, now);
+        buckets
+            .buckets
+            .entry((client, class))
+            .or_insert(Bucket {
+                tokens: rate.burst as f64,
+                updated: now,
+            })
+            .take(rate, now)
+    }
+}
+
+impl Buckets {
+    /// Drops buckets that have filled up again, at most once a minute.
+    /// A fresh bucket would behave exactly the same.
+    fn prune(&mut self, limits: &RateLimits, now: Instant) {
+        let last_prune = *self.last_prune.get_or_insert(now);
+        if now.duration_since(last_prune) < Duration::from_secs(60) {
+            return;
+        }
+        self.last_prune = Some(now);
+        self.buckets.retain(|(_, class), bucket| {
+            class.rate(limits).is_some_and(|rate| {
+                let refill = (rate.burst as f64 - bucket.tokens) / rate.per_second;
+                now.duration_since(bucket.updated).as_secs_f64() < refill
+            })
+        });
+    }
+}
+
+/// Answers 429 to clients that exceed their rate for a kind of request,
+/// with `Retry-After` set to when their next request would get through
+pub async fn rate_limit<B>(
+    State(state): State<SharedState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let limited = {
+        let state = state.read().expect("What, an error here?");
+        state.rate_limiter.as_ref().and_then(|limiter| {
+            let class = RequestClass::of(request.method(), request.uri().path());
+            let client = Client::of(&state, &request);
+            let retry_after = limiter.check(client, class, state.clock.now()).err()?;
+            state
+                .metrics
+                .counter("kv_rate_limited_total", &[("class", class.name())], 1);
+            Some(retry_after)
+        })
+    };
+    match limited {
+        Some(retry_after) => {
+            // Whole seconds, rounded up so the retry doesn't come too early
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [("retry-after", seconds.max(1).to_string())],
+                "Too many requests, slow down",
+            )
+                .into_response()
+        }
+        None => next.run(request).await,
+    }
+}
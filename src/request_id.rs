@@ -0,0 +1,70 @@
+//! `X-Request-Id` on every request, so a client's bug report can be matched
+//! with the server's log
+
+use axum::{
+    extract::State,
+    http::{HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use hyper::header::HeaderName;
+use tracing::Span;
+
+use crate::SharedState;
+
+pub(crate) static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// IDs from clients longer than this are replaced
+const MAX_LEN: usize = 128;
+
+/// The ID of a request, in its extensions
+#[derive(Clone)]
+pub(crate) struct RequestId(pub(crate) HeaderValue);
+
+fn acceptable(id: &HeaderValue) -> bool {
+    let id = id.as_bytes();
+    !id.is_empty() && id.len() <= MAX_LEN && id.iter().all(u8::is_ascii_graphic)
+}
+
+/// Keeps the `X-Request-Id` a client or proxy sent, or makes one up. The
+/// ID goes into the request's extensions and back in the response.
+pub async fn request_id<B>(
+    State(state): State<SharedState>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let id = match request.headers().get(&X_REQUEST_ID) {
+        Some(id) if acceptable(id) => id.clone(),
+        _ => {
+            let id = state
+                .read()
+                .expect("What, an error here?")
+                .random
+                .request_id();
+            HeaderValue::from_str(&id).expect("Hex digits are a valid header")
+        }
+    };
+    request
+        .headers_mut()
+        .insert(X_REQUEST_ID.clone(), id.clone());
+    request.extensions_mut().insert(RequestId(id.clone()));
+    let mut response = next.run(request).await;
+    response.headers_mut().insert(X_REQUEST_ID.clone(), id);
+    response
+}
+
+/// The span of `TraceLayer`, with the request ID so every event logged
+/// while handling the request carries it
+pub(crate) fn request_span<B>(request: &Request<B>) -> Span {
+    let id = request
+        .headers()
+        .get(&X_REQUEST_ID)
+        .and_then(|id| id.to_str().ok())
+        .unwrap_or_default();
+    tracing::info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        request_id = id,
+    )
+}
@@ -0,0 +1,153 @@
+use std::{
+    future::Future,
+    io::Cursor,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    http::{Method, Request, StatusCode},
+    Router,
+};
+use image::{ImageOutputFormat, Rgb, RgbImage};
+use tower::ServiceExt;
+
+use crate::{router, SharedState};
+
+struct Step {
+    name: &'static str,
+    elapsed: Duration,
+    result: Result<(), String>,
+}
+
+#[derive(Default)]
+struct Report {
+    steps: Vec<Step>,
+}
+
+impl Report {
+    async fn run(&mut self, name: &'static str, step: impl Future<Output = Result<(), String>>) {
+        let started = Instant::now();
+        let result = step.await;
+        self.steps.push(Step {
+            name,
+            elapsed: started.elapsed(),
+            result,
+        });
+    }
+
+    fn passed(&self) -> bool {
+        self.steps.iter().all(|step| step.result.is_ok())
+    }
+
+    fn print(&self) {
+        for step in &self.steps {
+            match &step.result {
+                Ok(()) => println!("  {:<10} ok      {:?}", step.name, step.elapsed),
+                Err(error) => println!("  {:<10} FAILED  {:?}  {}", step.name, step.elapsed, error),
+            }
+        }
+        println!(
+            "self-test {}",
+            if self.passed() { "passed" } else { "failed" }
+        );
+    }
+}
+
+/// A tiny PNG to have something to transform
+fn sample_png() -> Bytes {
+    let image = RgbImage::from_fn(4, 4, |x, y| Rgb([(x * 60) as u8, (y * 60) as u8, 200]));
+    let mut png = Vec::new();
+    image
+        .write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)
+        .expect("Encoding a PNG in memory can't fail");
+    png.into()
+}
+
+async fn send(
+    app: &Router,
+    method: Method,
+    uri: &str,
+    body: Option<Bytes>,
+) -> Result<(StatusCode, Bytes), String> {
+    let mut request = Request::builder().method(method).uri(uri);
+    if body.is_some() {
+        request = request.header("content-type", "image/png");
+    }
+    let request = request
+        .body(body.map(Body::from).unwrap_or_else(Body::empty))
+        .map_err(|error| error.to_string())?;
+    let response = app
+        .clone()
+        .oneshot(request)
+        .await
+        .unwrap_or_else(|e| match e {});
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(|error| error.to_string())?;
+    Ok((status, body))
+}
+
+fn expect_status(expected: StatusCode, (status, body): &(StatusCode, Bytes)) -> Result<(), String> {
+    if *status == expected {
+        Ok(())
+    } else {
+        Err(format!(
+            "expected {}, got {}: {}",
+            expected,
+            status,
+            String::from_utf8_lossy(body)
+        ))
+    }
+}
+
+/// Runs a write/read/transform/delete cycle against the configured backend
+/// through an in-process router. Prints a report, returns whether all steps passed.
+pub async fn self_test(state: &SharedState) -> bool {
+    let app = router(state);
+    let key = state.read().expect("What, an error here?").random.key();
+    let uri = format!("/kv/self-test-{}", key);
+    let png = sample_png();
+    let mut report = Report::default();
+    println!("self-test on {}", uri);
+
+    report
+        .run("write", async {
+            let response = send(&app, Method::POST, &uri, Some(png.clone())).await?;
+            expect_status(StatusCode::OK, &response)
+        })
+        .await;
+    report
+        .run("read", async {
+            let response = send(&app, Method::GET, &uri, None).await?;
+            expect_status(StatusCode::OK, &response)?;
+            if response.1 == png {
+                Ok(())
+            } else {
+                Err("read back different bytes than written".to_string())
+            }
+        })
+        .await;
+    report
+        .run("transform", async {
+            let uri = format!("{}/grayscale", uri);
+            let response = send(&app, Method::GET, &uri, None).await?;
+            expect_status(StatusCode::OK, &response)?;
+            image::load_from_memory(&response.1)
+                .map(drop)
+                .map_err(|error| format!("transform returned no image: {}", error))
+        })
+        .await;
+    report
+        .run("delete", async {
+            let response = send(&app, Method::DELETE, &uri, None).await?;
+            expect_status(StatusCode::OK, &response)?;
+            let response = send(&app, Method::GET, &uri, None).await?;
+            expect_status(StatusCode::NOT_FOUND, &response)
+        })
+        .await;
+
+    report.print();
+    report.passed()
+}
@@ -0,0 +1,182 @@
+use std::{
+    net::{AddrParseError, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+use axum::Router;
+use hyper::server::{accept, conn::AddrIncoming};
+use tokio::net::{TcpListener, TcpSocket, UnixListener};
+
+mod shutdown;
+pub mod systemd;
+mod tls;
+
+pub use shutdown::Shutdown;
+pub use tls::{https_redirect, redirect_to_https, TlsOptions};
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Tuning knobs for the HTTP server. The defaults suit long-lived,
+/// high-throughput internal clients.
+#[derive(Clone, Debug)]
+pub struct ServerOptions {
+    /// Pending connections the kernel queues before refusing new ones
+    pub backlog: u32,
+    pub tcp_nodelay: bool,
+    /// Interval of TCP keepalive probes, `None` disables them
+    pub tcp_keepalive: Option<Duration>,
+    pub http1_keepalive: bool,
+    pub http2_max_concurrent_streams: Option<u32>,
+    /// Interval of HTTP/2 PING frames, `None` disables them
+    pub http2_keep_alive_interval: Option<Duration>,
+    pub http2_keep_alive_timeout: Duration,
+    /// Serve HTTPS on TCP listeners, Unix sockets stay plain
+    pub tls: Option<TlsOptions>,
+    /// Servers drain and return once this is triggered
+    pub shutdown: Shutdown,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            backlog: 1024,
+            tcp_nodelay: true,
+            tcp_keepalive: Some(Duration::from_secs(60)),
+            http1_keepalive: true,
+            http2_max_concurrent_streams: Some(256),
+            http2_keep_alive_interval: Some(Duration::from_secs(30)),
+            http2_keep_alive_timeout: Duration::from_secs(20),
+            tls: None,
+            shutdown: Shutdown::default(),
+        }
+    }
+}
+
+/// Where a listener accepts connections, parsed from `127.0.0.1:3001`
+/// or `unix:/run/kv/admin.sock`
+#[derive(Debug)]
+pub enum Listen {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+    /// A socket that is already listening, e.g. one passed by systemd
+    Inherited(std::net::TcpListener),
+}
+
+impl FromStr for Listen {
+    type Err = AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) => Ok(Listen::Unix(path.into())),
+            None => s.parse().map(Listen::Tcp),
+        }
+    }
+}
+
+/// A socket that is bound and listening. Connections wait in its backlog
+/// until it is served.
+pub enum Bound {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listen {
+    /// Binds the socket without serving it yet, so every listener can be
+    /// up before the service reports it is ready
+    pub fn bind(self, backlog: u32) -> Result<Bound, BoxError> {
+        match self {
+            Listen::Tcp(addr) => {
+                let socket = match addr {
+                    SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                    SocketAddr::V6(_) => TcpSocket::new_v6()?,
+                };
+                socket.set_reuseaddr(true)?;
+                socket.bind(addr)?;
+                Ok(Bound::Tcp(socket.listen(backlog)?))
+            }
+            Listen::Unix(path) => {
+                // A socket file left behind by a previous run would make bind fail
+                let _ = std::fs::remove_file(&path);
+                Ok(Bound::Unix(UnixListener::bind(&path)?))
+            }
+            Listen::Inherited(listener) => {
+                listener.set_nonblocking(true)?;
+                Ok(Bound::Tcp(TcpListener::from_std(listener)?))
+            }
+        }
+    }
+}
+
+pub async fn serve_on(
+    listen: Listen,
+    app: Router,
+    options: &ServerOptions,
+) -> Result<(), BoxError> {
+    serve_bound(listen.bind(options.backlog)?, app, options).await
+}
+
+/// Serves `app` on a socket bound with `Listen::bind` until the server
+/// fails or shuts down
+pub async fn serve_bound(
+    bound: Bound,
+    app: Router,
+    options: &ServerOptions,
+) -> Result<(), BoxError> {
+    match bound {
+        Bound::Tcp(listener) => serve_listener(listener, app, options).await,
+        Bound::Unix(listener) => serve_unix(listener, app, options).await,
+    }
+}
+
+/// Serves `app` on a Unix domain socket, so access can be restricted with
+/// file permissions instead of a firewall
+async fn serve_unix(
+    listener: UnixListener,
+    app: Router,
+    options: &ServerOptions,
+) -> Result<(), BoxError> {
+    let incoming = accept::poll_fn(move |cx| {
+        listener
+            .poll_accept(cx)
+            .map(|result| Some(result.map(|(stream, _)| stream)))
+    });
+
+    axum::Server::builder(incoming)
+        .http1_keepalive(options.http1_keepalive)
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(options.shutdown.triggered())
+        .await?;
+
+    Ok(())
+}
+
+/// Binds `addr` and serves `app` until the server fails or shuts down
+pub async fn serve(addr: SocketAddr, app: Router, options: &ServerOptions) -> Result<(), BoxError> {
+    serve_on(Listen::Tcp(addr), app, options).await
+}
+
+async fn serve_listener(
+    listener: TcpListener,
+    app: Router,
+    options: &ServerOptions,
+) -> Result<(), BoxError> {
+    if let Some(tls) = &options.tls {
+        return tls::serve_tls(listener, app, options, tls).await;
+    }
+    let mut incoming = AddrIncoming::from_listener(listener)?;
+    incoming.set_nodelay(options.tcp_nodelay);
+    incoming.set_keepalive(options.tcp_keepalive);
+
+    axum::Server::builder(incoming)
+        .http1_keepalive(options.http1_keepalive)
+        .http2_max_concurrent_streams(options.http2_max_concurrent_streams)
+        .http2_keep_alive_interval(options.http2_keep_alive_interval)
+        .http2_keep_alive_timeout(options.http2_keep_alive_timeout)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(options.shutdown.triggered())
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,54 @@
+//! Graceful shutdown: servers stop accepting connections and drain the
+//! requests in flight once a shutdown is triggered
+
+use std::sync::Arc;
+
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+
+/// Triggered once, clones share the trigger. Servers stop accepting
+/// connections and finish the requests in flight.
+#[derive(Clone, Debug)]
+pub struct Shutdown(Arc<watch::Sender<bool>>);
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self(Arc::new(watch::channel(false).0))
+    }
+}
+
+impl Shutdown {
+    /// Triggered by SIGTERM, as sent by systemd or Kubernetes, or SIGINT
+    pub fn on_signals() -> std::io::Result<Self> {
+        let shutdown = Self::default();
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let trigger = shutdown.clone();
+        tokio::spawn(async move {
+            let name = tokio::select! {
+                _ = terminate.recv() => "SIGTERM",
+                _ = interrupt.recv() => "SIGINT",
+            };
+            tracing::info!(signal = name, "Shutting down");
+            trigger.trigger();
+        });
+        Ok(shutdown)
+    }
+
+    pub fn trigger(&self) {
+        self.0.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Completes once the shutdown is triggered
+    pub async fn triggered(&self) {
+        let mut receiver = self.0.subscribe();
+        // The sender lives as long as `self`, so this can't fail
+        let _ = receiver.wait_for(|triggered| *triggered).await;
+    }
+}
@@ -0,0 +1,90 @@
+//! systemd integration: socket activation and sd_notify readiness/watchdog
+
+use std::{
+    env, io,
+    os::unix::{
+        ffi::OsStrExt,
+        io::{FromRawFd, RawFd},
+        net::UnixDatagram,
+    },
+    process,
+    time::Duration,
+};
+
+/// First file descriptor passed by systemd, see sd_listen_fds(3)
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Takes the listening sockets systemd passed to this process, in the
+/// order of the `ListenStream=` lines of the socket unit. Unsets the
+/// variables describing them, so call it while the process has a single
+/// thread, before the Tokio runtime is built.
+pub fn listen_fds() -> Vec<std::net::TcpListener> {
+    let for_us = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(process::id());
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<RawFd>().ok())
+        .unwrap_or(0);
+    // Child processes must not inherit the sockets a second time
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    if !for_us {
+        return Vec::new();
+    }
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        // SAFETY: systemd hands us ownership of these descriptors, and
+        // nothing else in the process knows about them
+        .map(|fd| unsafe { std::net::TcpListener::from_raw_fd(fd) })
+        .collect()
+}
+
+/// Sends a state like `READY=1` to systemd. Does nothing when the
+/// service isn't run with `Type=notify`.
+pub fn notify(state: &str) -> io::Result<()> {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => send_abstract(&socket, name, state),
+        None => socket.send_to(state.as_bytes(), path).map(|_| ()),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send_abstract(socket: &UnixDatagram, name: &[u8], state: &str) -> io::Result<()> {
+    use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+    let addr = SocketAddr::from_abstract_name(name)?;
+    socket.send_to_addr(state.as_bytes(), &addr).map(|_| ())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_abstract(_: &UnixDatagram, _: &[u8], _: &str) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Pings the systemd watchdog at half the configured interval. If the
+/// runtime hangs the pings stop and systemd restarts the service.
+pub fn spawn_watchdog() {
+    let interval = env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse().ok())
+        .map(|usec| Duration::from_micros(usec) / 2);
+    let for_us = env::var("WATCHDOG_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_none_or(|pid| pid == process::id());
+    if let (Some(interval), true) = (interval, for_us) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let _ = notify("WATCHDOG=1");
+            }
+        });
+    }
+}
@@ -0,0 +1,208 @@
+//! HTTPS with rustls, so the service can face clients without a proxy
+
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    extract::connect_info::Connected,
+    http::{uri::Authority, Request, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+    Router,
+};
+use futures::future::poll_fn;
+use hyper::server::{
+    accept::{self, Accept},
+    conn::{AddrIncoming, AddrStream},
+};
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpListener,
+    sync::mpsc,
+};
+use tokio_rustls::{
+    rustls::{crypto::ring, ServerConfig},
+    server::TlsStream,
+    TlsAcceptor,
+};
+
+use super::{BoxError, ServerOptions};
+
+/// Clients that don't finish the handshake in time are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Certificate chain and private key, both PEM files
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsOptions {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl TlsOptions {
+    pub(crate) fn server_config(&self) -> Result<ServerConfig, BoxError> {
+        let certs =
+            rustls_pemfile::certs(&mut open(&self.cert_path)?).collect::<Result<Vec<_>, _>>()?;
+        let key = rustls_pemfile::private_key(&mut open(&self.key_path)?)?
+            .ok_or_else(|| format!("No private key in {}", self.key_path.display()))?;
+        let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(config)
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, BoxError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|error| format!("Can't open {}: {}", path.display(), error).into())
+}
+
+/// A connection after the handshake
+pub struct TlsConnection {
+    stream: TlsStream<AddrStream>,
+    remote_addr: SocketAddr,
+}
+
+impl Connected<&TlsConnection> for SocketAddr {
+    fn connect_info(target: &TlsConnection) -> Self {
+        target.remote_addr
+    }
+}
+
+impl AsyncRead for TlsConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Serves `app` over TLS. Handshakes run in tasks of their own, so a slow
+/// client doesn't hold up the ones behind it.
+pub(super) async fn serve_tls(
+    listener: TcpListener,
+    app: Router,
+    options: &ServerOptions,
+    tls: &TlsOptions,
+) -> Result<(), BoxError> {
+    let acceptor = TlsAcceptor::from(Arc::new(tls.server_config()?));
+    let mut incoming = AddrIncoming::from_listener(listener)?;
+    incoming.set_nodelay(options.tcp_nodelay);
+    incoming.set_keepalive(options.tcp_keepalive);
+
+    let (sender, mut receiver) = mpsc::channel(options.backlog as usize);
+    tokio::spawn(async move {
+        loop {
+            let accepted = tokio::select! {
+                accepted = poll_fn(|cx| Pin::new(&mut incoming).poll_accept(cx)) => accepted,
+                // The server is gone, release the listener
+                _ = sender.closed() => break,
+            };
+            let Some(Ok(stream)) = accepted else {
+                continue;
+            };
+            let (acceptor, sender) = (acceptor.clone(), sender.clone());
+            tokio::spawn(async move {
+                let remote_addr = stream.remote_addr();
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        let _ = sender
+                            .send(TlsConnection {
+                                stream,
+                                remote_addr,
+                            })
+                            .await;
+                    }
+                    Ok(Err(error)) => {
+                        tracing::debug!(%remote_addr, %error, "TLS handshake failed")
+                    }
+                    Err(_) => tracing::debug!(%remote_addr, "TLS handshake timed out"),
+                }
+            });
+        }
+    });
+    let incoming = accept::poll_fn(move |cx| {
+        receiver
+            .poll_recv(cx)
+            .map(|stream| stream.map(Ok::<_, io::Error>))
+    });
+
+    axum::Server::builder(incoming)
+        .http1_keepalive(options.http1_keepalive)
+        .http2_max_concurrent_streams(options.http2_max_concurrent_streams)
+        .http2_keep_alive_interval(options.http2_keep_alive_interval)
+        .http2_keep_alive_timeout(options.http2_keep_alive_timeout)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(options.shutdown.triggered())
+        .await?;
+
+    Ok(())
+}
+
+/// Sends plain HTTP requests on `addr` to the same URL over HTTPS on
+/// `https_port`
+pub async fn redirect_to_https(addr: SocketAddr, https_port: u16) -> Result<(), BoxError> {
+    axum::Server::try_bind(&addr)?
+        .serve(https_redirect(https_port).into_make_service())
+        .await?;
+    Ok(())
+}
+
+/// Answers every request with a redirect to the same URL over HTTPS on
+/// `https_port`
+pub fn https_redirect(https_port: u16) -> Router {
+    Router::new().fallback(move |request: Request<hyper::Body>| async move {
+        https_url(&request, https_port)
+    })
+}
+
+fn https_url<B>(request: &Request<B>, https_port: u16) -> Response {
+    let host = request
+        .headers()
+        .get("host")
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<Authority>().ok());
+    let Some(host) = host else {
+        return (StatusCode::BAD_REQUEST, "Missing Host header").into_response();
+    };
+    let path = request
+        .uri()
+        .path_and_query()
+        .map_or("/", |path| path.as_str());
+    let url = match https_port {
+        443 => format!("https://{}{}", host.host(), path),
+        port => format!("https://{}:{}{}", host.host(), port, path),
+    };
+    Redirect::permanent(&url).into_response()
+}
@@ -0,0 +1,91 @@
+use std::sync::Arc;
+
+use axum::{
+    body::{boxed, Body},
+    http::Request,
+    middleware::Next,
+    response::Response,
+};
+use hyper::header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE};
+use serde::Serialize;
+
+use crate::{request_id::RequestId, KVError};
+
+/// The versions of the public API. v1 is the API as it was before
+/// versioning and is frozen, breaking changes only go into v2.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ApiVersion {
+    V1,
+    V2,
+}
+
+/// `path` without its `/v1` or `/v2` prefix, for middleware outside the
+/// versioned routers
+pub(crate) fn unversioned(path: &str) -> &str {
+    ["/v1", "/v2"]
+        .iter()
+        .find_map(|prefix| path.strip_prefix(prefix))
+        .filter(|rest| rest.starts_with('/'))
+        .unwrap_or(path)
+}
+
+/// Marks responses of routes that answer errors as JSON
+#[derive(Clone)]
+struct StructuredErrors {
+    request_id: Option<HeaderValue>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: ErrorDetail<'a>,
+}
+
+#[derive(Serialize)]
+struct ErrorDetail<'a> {
+    code: &'a str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<&'a str>,
+}
+
+/// Swaps the body of a `KVError` response for `message`, as plain text or,
+/// on routes with structured errors, as `{"error": {"code", "message",
+/// "request_id"}}`
+pub(crate) fn with_error_message(response: Response, error: &KVError, message: &str) -> Response {
+    let (mut parts, _) = response.into_parts();
+    parts.headers.remove(CONTENT_LENGTH);
+    let Some(structured) = parts.extensions.get::<StructuredErrors>() else {
+        return Response::from_parts(parts, boxed(Body::from(message.to_string())));
+    };
+    let body = ErrorBody {
+        error: ErrorDetail {
+            code: error.code(),
+            message,
+            request_id: structured
+                .request_id
+                .as_ref()
+                .and_then(|id| id.to_str().ok()),
+        },
+    };
+    let json = serde_json::to_vec(&body).expect("Strings always serialize");
+    parts
+        .headers
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    Response::from_parts(parts, boxed(Body::from(json)))
+}
+
+/// Answers `KVError`s as JSON, for v2 routes
+pub async fn structured_errors<B>(request: Request<B>, next: Next<B>) -> Response {
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .map(|RequestId(id)| id.clone());
+    let mut response = next.run(request).await;
+    let Some(error) = response.extensions().get::<Arc<KVError>>().cloned() else {
+        return response;
+    };
+    response
+        .extensions_mut()
+        .insert(StructuredErrors { request_id });
+    with_error_message(response, &error, &error.to_string())
+}
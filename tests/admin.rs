@@ -0,0 +1,1158 @@
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    extract::ConnectInfo,
+    http::{Request, StatusCode},
+    response::Response,
+};
+
+use microservice_rust_workshop::{
+    admin_router, public_router, router, run_due_refreshes, self_test, sweep_expired, AppState,
+    CorsOptions, FloodLimits, FsDatabase, KVDatabase, MockClock, Rate, RateLimits, SharedState,
+};
+use tower::Service; // for `call`
+
+trait App: Service<Request<Body>, Response = Response, Error = Infallible> {}
+
+impl<T: Service<Request<Body>, Response = Response, Error = Infallible>> App for T {}
+
+async fn post_text(app: &mut impl App, key: &str) {
+    let response = app
+        .call(
+            Request::builder()
+                .uri(format!("/kv/{}", key))
+                .method("POST")
+                .header("content-type", "text/plain")
+                .body("Hello World".into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+async fn get_status(app: &mut impl App, key: &str) -> StatusCode {
+    app.call(
+        Request::builder()
+            .uri(format!("/kv/{}", key))
+            .method("GET")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await
+    .unwrap()
+    .status()
+}
+
+#[tokio::test]
+async fn erase_keys_and_prefixes() {
+    let state = SharedState::default();
+    let mut app = router(&state);
+
+    post_text(&mut app, "user123%2Fprofile").await;
+    post_text(&mut app, "user123%2Favatar").await;
+    post_text(&mut app, "invoice-7").await;
+    post_text(&mut app, "unrelated").await;
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/admin/erase")
+                .method("POST")
+                .header("content-type", "application/json")
+                .body(r#"{"keys": ["invoice-7"], "prefixes": ["user123/"]}"#.into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(
+        &body[..],
+        br#"{"erased":["invoice-7","user123/avatar","user123/profile"],"count":3,"held":[]}"#
+    );
+
+    assert_eq!(
+        get_status(&mut app, "user123%2Fprofile").await,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        get_status(&mut app, "invoice-7").await,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(get_status(&mut app, "unrelated").await, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn erasure_spares_internal_state() {
+    let state = SharedState::default();
+    let mut app = router(&state);
+
+    post_text(&mut app, "note").await;
+
+    for (selection, status) in [
+        (r#"{"prefixes": [""]}"#, StatusCode::BAD_REQUEST),
+        (r#"{"prefixes": ["__int"]}"#, StatusCode::FORBIDDEN),
+        (
+            r#"{"prefixes": ["__internal/features/"]}"#,
+            StatusCode::FORBIDDEN,
+        ),
+        (
+            r#"{"keys": ["__internal/features/sites"]}"#,
+            StatusCode::FORBIDDEN,
+        ),
+    ] {
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/admin/erase")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(selection.into())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), status, "{}", selection);
+    }
+    assert_eq!(get_status(&mut app, "note").await, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn legal_hold_blocks_erasure() {
+    let state = SharedState::default();
+    let mut app = router(&state);
+
+    post_text(&mut app, "case-42%2Fevidence").await;
+    post_text(&mut app, "case-42%2Fnotes").await;
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/admin/holds/namespaces/case-42")
+                .method("PUT")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/admin/holds")
+                .method("GET")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(&body[..], br#"{"keys":[],"namespaces":["case-42"]}"#);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/admin/erase")
+                .method("POST")
+                .header("content-type", "application/json")
+                .body(r#"{"prefixes": ["case-42/"]}"#.into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(
+        &body[..],
+        br#"{"erased":[],"count":0,"held":["case-42/evidence","case-42/notes"]}"#
+    );
+    assert_eq!(
+        get_status(&mut app, "case-42%2Fnotes").await,
+        StatusCode::OK
+    );
+    // Nor can held keys be overwritten
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/case-42%2Fnotes")
+                .method("POST")
+                .header("content-type", "text/plain")
+                .body("Nothing to see".into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::LOCKED);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/admin/holds/namespaces/case-42")
+                .method("DELETE")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    post_text(&mut app, "case-42%2Fnotes").await;
+}
+
+#[tokio::test]
+async fn buckets_can_be_held_and_erased() {
+    let state = SharedState::default();
+    let mut app = router(&state);
+    let request = |method: &str, uri: &str, body: &str| {
+        Request::builder()
+            .uri(uri)
+            .method(method)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+
+    for uri in ["/bucket/app/kv/a", "/bucket/app/kv/b"] {
+        let response = app.call(request("POST", uri, "{}")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let response = app
+        .call(request("PUT", "/admin/holds/keys/__bucket.app%2Fa", ""))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let response = app
+        .call(request(
+            "POST",
+            "/admin/erase",
+            r#"{"prefixes": ["__bucket.app/"]}"#,
+        ))
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(
+        &body[..],
+        br#"{"erased":["__bucket.app/b"],"count":1,"held":["__bucket.app/a"]}"#
+    );
+
+    // Holding the bucket's namespace holds the whole bucket
+    let response = app
+        .call(request("PUT", "/admin/holds/namespaces/__bucket.app", ""))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = app
+        .call(request("POST", "/bucket/app/kv/c", "{}"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::LOCKED);
+}
+
+#[tokio::test]
+async fn erasure_leaves_no_copies() {
+    let root = std::env::temp_dir().join(format!("kv-erase-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    let state = Arc::new(RwLock::new(
+        AppState::default().with_database(FsDatabase::open(&root).unwrap()),
+    ));
+    let mut app = router(&state);
+    let request = |method: &str, uri: &str, content_type: &str, body: Body| {
+        Request::builder()
+            .uri(uri)
+            .method(method)
+            .header("content-type", content_type)
+            .body(body)
+            .unwrap()
+    };
+
+    let photo = Body::from(&include_bytes!("../crab-small.png")[..]);
+    let response = app
+        .call(request(
+            "POST",
+            "/kv/user-1%2Fphoto.png",
+            "image/png",
+            photo,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let batch = serde_json::json!({
+        "keys": ["user-1/photo.png"],
+        "pipeline": [{"op": "grayscale"}],
+        "target": "thumbs/{key}",
+    });
+    let response = app
+        .call(request(
+            "POST",
+            "/transform/batch",
+            "application/json",
+            batch.to_string().into(),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app
+        .call(request(
+            "GET",
+            "/kv/user-1%2Fphoto.png/tiles/0/0/0",
+            "image/png",
+            Body::empty(),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app
+        .call(request(
+            "POST",
+            "/bucket/app/kv/user-1%2Fnote",
+            "text/plain",
+            "Hello World".into(),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let hot_keys = FsDatabase::open(&root).unwrap();
+    let list = (
+        "application/json".to_string(),
+        r#"["user-1/photo.png","other"]"#.into(),
+    );
+    hot_keys
+        .insert("__internal/hot-keys".to_string(), list)
+        .await
+        .unwrap();
+
+    let response = app
+        .call(request(
+            "POST",
+            "/admin/erase",
+            "application/json",
+            r#"{"prefixes": ["user-1/"]}"#.into(),
+        ))
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(
+        &body[..],
+        br#"{"erased":["__bucket.app/user-1/note","thumbs/user-1/photo.png","user-1/photo.png"],"count":3,"held":[]}"#
+    );
+
+    // Nor anything the service keeps about them
+    let db = FsDatabase::open(&root).unwrap();
+    assert!(db.keys("__internal/entries/").await.unwrap().is_empty());
+    let (_, list) = db.read("__internal/hot-keys").await.unwrap().unwrap();
+    assert_eq!(&list[..], br#"["other"]"#);
+    let response = app
+        .call(request(
+            "GET",
+            "/admin/reports/usage?from=0",
+            "application/json",
+            Body::empty(),
+        ))
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let report: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(report["usage"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .all(|row| row["namespace"] != "user-1"));
+    #[cfg(feature = "debug-state")]
+    {
+        let response = app
+            .call(request(
+                "GET",
+                "/debug/state",
+                "application/json",
+                Body::empty(),
+            ))
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let debug: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(debug["cached_tiles"], 0);
+    }
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn write_flood_blocks_key() {
+    let state: SharedState = Arc::new(RwLock::new(AppState::default().with_flood_limits(
+        FloodLimits {
+            max_writes_per_key: 2,
+            ..FloodLimits::default()
+        },
+    )));
+    let mut app = router(&state);
+
+    post_text(&mut app, "hot").await;
+    post_text(&mut app, "hot").await;
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/hot")
+                .method("POST")
+                .header("content-type", "text/plain")
+                .body("Hello World".into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()["retry-after"], "60");
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/admin/blocks")
+                .method("GET")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert!(String::from_utf8(body.to_vec())
+        .unwrap()
+        .contains(r#"{"type":"key","value":"hot"}"#));
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/admin/blocks")
+                .method("DELETE")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    post_text(&mut app, "hot").await;
+}
+
+#[tokio::test]
+async fn write_flood_cooldown() {
+    let clock = MockClock::new();
+    let state: SharedState = Arc::new(RwLock::new(
+        AppState::default()
+            .with_clock(clock.clone())
+            .with_flood_limits(FloodLimits {
+                max_writes_per_key: 1,
+                ..FloodLimits::default()
+            }),
+    ));
+    let mut app = router(&state);
+
+    post_text(&mut app, "hot").await;
+    let write = || {
+        Request::builder()
+            .uri("/kv/hot")
+            .method("POST")
+            .header("content-type", "text/plain")
+            .body("Hello World".into())
+            .unwrap()
+    };
+
+    let response = app.call(write()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    clock.advance(Duration::from_secs(59));
+    let response = app.call(write()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()["retry-after"], "1");
+
+    clock.advance(Duration::from_secs(1));
+    post_text(&mut app, "hot").await;
+}
+
+#[tokio::test]
+async fn ttl_expiry() {
+    let clock = MockClock::new();
+    let state: SharedState = Arc::new(RwLock::new(AppState::default().with_clock(clock.clone())));
+    let mut app = router(&state);
+    let write = |uri: &str, ttl: Option<&str>| {
+        let mut request = Request::builder()
+            .uri(uri)
+            .method("POST")
+            .header("content-type", "text/plain");
+        if let Some(ttl) = ttl {
+            request = request.header("x-ttl-seconds", ttl);
+        }
+        request.body("Hello World".into()).unwrap()
+    };
+
+    let response = app.call(write("/kv/short?ttl=30", None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app.call(write("/kv/short.rs?ttl=30", None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app.call(write("/kv/long", Some("60"))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app.call(write("/kv/held", Some("60"))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app.call(write("/kv/bad", Some("soon"))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = app
+        .call(write("/kv/bad", Some(&u64::MAX.to_string())))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    post_text(&mut app, "forever").await;
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/admin/holds/keys/held")
+                .method("PUT")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let preview = |etag: Option<&str>| {
+        let mut request = Request::builder().uri("/kv/short.rs/preview");
+        if let Some(etag) = etag {
+            request = request.header("if-none-match", etag);
+        }
+        request.body(Body::empty()).unwrap()
+    };
+    let response = app.call(preview(None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let etag = response.headers()["etag"].to_str().unwrap().to_string();
+
+    clock.advance(Duration::from_secs(30));
+    assert_eq!(get_status(&mut app, "short").await, StatusCode::NOT_FOUND);
+    assert_eq!(get_status(&mut app, "long").await, StatusCode::OK);
+    // Not even a 304 for the copy the client still has
+    let response = app.call(preview(Some(&etag))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    clock.advance(Duration::from_secs(30));
+    assert_eq!(get_status(&mut app, "long").await, StatusCode::NOT_FOUND);
+    // Held entries outlive their TTL, until the hold is lifted
+    assert_eq!(get_status(&mut app, "held").await, StatusCode::OK);
+    assert_eq!(sweep_expired(&state).await.unwrap(), 3);
+    assert_eq!(sweep_expired(&state).await.unwrap(), 0);
+    assert_eq!(get_status(&mut app, "forever").await, StatusCode::OK);
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/admin/holds/keys/held")
+                .method("DELETE")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(get_status(&mut app, "held").await, StatusCode::NOT_FOUND);
+    assert_eq!(sweep_expired(&state).await.unwrap(), 1);
+
+    // Writing the key again without a TTL keeps it for good
+    post_text(&mut app, "long").await;
+    clock.advance(Duration::from_secs(60));
+    assert_eq!(get_status(&mut app, "long").await, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn separate_admin_router() {
+    let state = SharedState::default();
+    let mut public = public_router(&state);
+    let mut admin = admin_router(&state);
+
+    let response = public
+        .call(
+            Request::builder()
+                .uri("/admin/holds")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = admin
+        .call(
+            Request::builder()
+                .uri("/admin/holds")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    assert_eq!(get_status(&mut admin, "crab").await, StatusCode::NOT_FOUND);
+    post_text(&mut public, "crab").await;
+    assert_eq!(get_status(&mut public, "crab").await, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn read_only_mode() {
+    let state = SharedState::default();
+    let mut app = router(&state);
+
+    post_text(&mut app, "crab").await;
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/admin/readonly")
+                .method("POST")
+                .header("content-type", "application/json")
+                .body(r#"{"enabled": true}"#.into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/crab")
+                .method("DELETE")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(get_status(&mut app, "crab").await, StatusCode::OK);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/admin/readonly")
+                .method("POST")
+                .header("content-type", "application/json")
+                .body(r#"{"enabled": false}"#.into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    post_text(&mut app, "crab").await;
+}
+
+#[tokio::test]
+async fn maintenance_mode() {
+    let state = SharedState::default();
+    let mut app = router(&state);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/admin/maintenance")
+                .method("PUT")
+                .header("content-type", "application/json")
+                .body(r#"{"message": "Migrating to new disks", "retry_after_secs": 120}"#.into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/crab")
+                .method("POST")
+                .header("content-type", "text/plain")
+                .body("Hello World".into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers()["retry-after"], "120");
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(
+        &body[..],
+        br#"{"error":"maintenance","message":"Migrating to new disks","retry_after_secs":120}"#
+    );
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/admin/maintenance")
+                .method("DELETE")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    post_text(&mut app, "crab").await;
+}
+
+#[tokio::test]
+async fn feature_flags() {
+    let state = SharedState::default();
+    let mut app = router(&state);
+
+    post_text(&mut app, "main.rs").await;
+
+    let set_transforms = |enabled: bool| {
+        Request::builder()
+            .uri("/admin/features/transforms")
+            .method("PUT")
+            .header("content-type", "application/json")
+            .body(format!(r#"{{"enabled": {}}}"#, enabled).into())
+            .unwrap()
+    };
+    let preview = || {
+        Request::builder()
+            .uri("/kv/main.rs/preview")
+            .method("GET")
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let response = app.call(set_transforms(false)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = app.call(preview()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(get_status(&mut app, "main.rs").await, StatusCode::OK);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/admin/features")
+                .method("GET")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(
+        &body[..],
+        br#"{"transforms":false,"batch":true,"links":true,"sites":true}"#
+    );
+
+    // Batches run transforms, so they are off with them
+    let batch = serde_json::json!({
+        "keys": ["main.rs"],
+        "pipeline": [{"op": "grayscale"}],
+        "target": "gray-{key}",
+    });
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/transform/batch")
+                .method("POST")
+                .header("content-type", "application/json")
+                .body(batch.to_string().into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    let response = app.call(set_transforms(true)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = app.call(preview()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn self_test_cycle() {
+    let state = SharedState::default();
+    assert!(self_test(&state).await);
+
+    let state = Arc::new(RwLock::new(AppState::default().with_read_only(true)));
+    assert!(!self_test(&state).await);
+}
+
+#[cfg(feature = "debug-state")]
+#[tokio::test]
+async fn debug_state() {
+    let state = SharedState::default();
+    let mut app = router(&state);
+
+    post_text(&mut app, "small").await;
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/large")
+                .method("POST")
+                .header("content-type", "text/plain")
+                .body("Hello World, but longer".into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/debug/state?top=1")
+                .method("GET")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.starts_with(
+        r#"{"entries":2,"largest":[{"key":"large","content_type":"text/plain","bytes":23}]"#
+    ));
+}
+
+#[tokio::test]
+async fn rate_limits() {
+    let clock = MockClock::new();
+    let state: SharedState = Arc::new(RwLock::new(
+        AppState::default()
+            .with_clock(clock.clone())
+            .with_rate_limits(RateLimits {
+                writes: Some("1:2".parse().unwrap()),
+                transforms: Some(Rate {
+                    per_second: 0.5,
+                    burst: 1,
+                }),
+                ..RateLimits::default()
+            }),
+    ));
+    let mut app = router(&state);
+    let request = |uri: &str, method: &str, client: Option<[u8; 4]>| {
+        let mut request = Request::builder()
+            .uri(uri)
+            .method(method)
+            .header("content-type", "text/plain");
+        if let Some(ip) = client {
+            request = request.extension(ConnectInfo(SocketAddr::from((ip, 1234))));
+        }
+        request.body(Body::from("Hello World")).unwrap()
+    };
+
+    post_text(&mut app, "a").await;
+    post_text(&mut app, "b").await;
+    // The versioned routes draw from the same bucket
+    for uri in ["/kv/c", "/v2/kv/c"] {
+        let response = app.call(request(uri, "POST", None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["retry-after"], "1");
+    }
+    // Reads are unlimited, other clients have buckets of their own
+    assert_eq!(get_status(&mut app, "a").await, StatusCode::OK);
+    let response = app
+        .call(request("/kv/c", "POST", Some([10, 0, 0, 1])))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Transforms count separately, and refill at their own pace
+    let response = app
+        .call(request("/kv/a/grayscale", "GET", None))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = app
+        .call(request("/kv/a/grayscale", "GET", None))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()["retry-after"], "2");
+
+    clock.advance(Duration::from_secs(1));
+    post_text(&mut app, "c").await;
+    let response = app
+        .call(request("/kv/a/grayscale", "GET", None))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()["retry-after"], "1");
+
+    assert!("0".parse::<Rate>().is_err());
+    assert!("5:0".parse::<Rate>().is_err());
+    assert_eq!(
+        "2.5".parse::<Rate>().unwrap(),
+        Rate {
+            per_second: 2.5,
+            burst: 3
+        }
+    );
+}
+
+#[tokio::test]
+async fn cors() {
+    let request = |method: &str, origin: &str| {
+        Request::builder()
+            .uri("/kv/a")
+            .method(method)
+            .header("origin", origin)
+            .header("access-control-request-method", "POST")
+            .header("access-control-request-headers", "x-api-key")
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    // Off by default
+    let mut app = router(&SharedState::default());
+    let response = app
+        .call(request("GET", "https://app.example.com"))
+        .await
+        .unwrap();
+    assert!(!response
+        .headers()
+        .contains_key("access-control-allow-origin"));
+
+    let state: SharedState = Arc::new(RwLock::new(AppState::default().with_cors(CorsOptions {
+        allowed_origins: vec!["https://app.example.com".parse().unwrap()],
+        ..CorsOptions::default()
+    })));
+    let mut app = router(&state);
+    let response = app
+        .call(request("OPTIONS", "https://app.example.com"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let headers = response.headers();
+    assert_eq!(
+        headers["access-control-allow-origin"],
+        "https://app.example.com"
+    );
+    assert!(headers["access-control-allow-methods"]
+        .to_str()
+        .unwrap()
+        .contains("POST"));
+    assert!(headers["access-control-allow-headers"]
+        .to_str()
+        .unwrap()
+        .contains("x-api-key"));
+    assert_eq!(headers["access-control-max-age"], "600");
+
+    // Errors are readable by the front-end too
+    let response = app
+        .call(request("GET", "https://app.example.com"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(
+        response.headers()["access-control-allow-origin"],
+        "https://app.example.com"
+    );
+    assert!(response.headers()["access-control-expose-headers"]
+        .to_str()
+        .unwrap()
+        .contains("x-error-code"));
+
+    let response = app
+        .call(request("GET", "https://evil.example.com"))
+        .await
+        .unwrap();
+    assert!(!response
+        .headers()
+        .contains_key("access-control-allow-origin"));
+}
+
+#[tokio::test]
+async fn scheduled_refresh() {
+    let clock = MockClock::new();
+    let state: SharedState = Arc::new(RwLock::new(AppState::default().with_clock(clock.clone())));
+    let mut app = router(&state);
+    let mut png = Vec::new();
+    image::RgbImage::from_pixel(16, 8, image::Rgb([255, 0, 0]))
+        .write_to(
+            &mut std::io::Cursor::new(&mut png),
+            image::ImageOutputFormat::Png,
+        )
+        .unwrap();
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/banner-a")
+                .method("POST")
+                .header("content-type", "image/png")
+                .body(png.into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    post_text(&mut app, "banner-b").await;
+
+    let mut put_rule = |target: &str| {
+        let rule = serde_json::json!({
+            "prefix": "banner-",
+            "pipeline": [{"op": "thumbnail", "width": 4, "height": 4}],
+            "target": target,
+            "interval": 86400,
+        });
+        app.call(
+            Request::builder()
+                .uri("/admin/refresh/banners")
+                .method("PUT")
+                .header("content-type", "application/json")
+                .body(rule.to_string().into())
+                .unwrap(),
+        )
+    };
+    // Results under the prefix would be refreshed themselves
+    let response = put_rule("banner-{key}-thumb").await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = put_rule("__internal/features/{key}").await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = put_rule("thumb-{key}").await.unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    assert_eq!(run_due_refreshes(&state).await.unwrap(), 0);
+    assert_eq!(
+        get_status(&mut app, "thumb-banner-a").await,
+        StatusCode::NOT_FOUND
+    );
+    clock.advance(Duration::from_secs(86400));
+    assert_eq!(run_due_refreshes(&state).await.unwrap(), 1);
+    assert_eq!(run_due_refreshes(&state).await.unwrap(), 0);
+    assert_eq!(get_status(&mut app, "thumb-banner-a").await, StatusCode::OK);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/admin/refresh")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let rules: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let rule = &rules["banners"];
+    assert_eq!(rule["next_run_in"], 86400);
+    assert_eq!(rule["runs"][0]["keys"], 2);
+    assert_eq!(rule["runs"][0]["failed"], 1);
+    assert_eq!(rule["runs"][0]["failures"][0]["key"], "banner-b");
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/admin/refresh/banners/run")
+                .method("POST")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    for expected in [StatusCode::NO_CONTENT, StatusCode::NOT_FOUND] {
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/admin/refresh/banners")
+                    .method("DELETE")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), expected);
+    }
+}
+
+#[tokio::test]
+async fn runtime_mounts() {
+    let root = std::env::temp_dir().join(format!("kv-mount-{}", std::process::id()));
+    let baseline = FsDatabase::open(&root).unwrap();
+    for key in ["base/logo", "base/motd"] {
+        let value = ("text/plain".to_string(), "Baseline".into());
+        baseline.insert(key.to_string(), value).await.unwrap();
+    }
+    let state = SharedState::default();
+    let mut app = router(&state);
+    post_text(&mut app, "base%2Fmotd").await;
+
+    for (source, expected) in [
+        (
+            r#"{"type": "bundle", "path": "/nonexistent"}"#.to_string(),
+            StatusCode::BAD_REQUEST,
+        ),
+        (
+            format!(r#"{{"type": "fs", "root": {:?}}}"#, root),
+            StatusCode::OK,
+        ),
+    ] {
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/admin/mounts/base")
+                    .method("PUT")
+                    .header("content-type", "application/json")
+                    .body(source.into())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), expected);
+    }
+
+    // Writes overlay the mount
+    for (key, expected) in [("base%2Flogo", "Baseline"), ("base%2Fmotd", "Hello World")] {
+        let response = app
+            .call(
+                Request::builder()
+                    .uri(format!("/kv/{}", key))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], expected.as_bytes());
+    }
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/admin/mounts")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let mounts: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(mounts[0]["namespace"], "base");
+    assert_eq!(mounts[0]["source"]["type"], "fs");
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/admin/mounts/base")
+                .method("DELETE")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(
+        get_status(&mut app, "base%2Flogo").await,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(get_status(&mut app, "base%2Fmotd").await, StatusCode::OK);
+    std::fs::remove_dir_all(&root).unwrap();
+}
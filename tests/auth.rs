@@ -0,0 +1,550 @@
+use std::{
+    sync::{Arc, RwLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+};
+
+use jsonwebtoken::{EncodingKey, Header};
+use microservice_rust_workshop::{router, ApiKeys, AppState, JwtAuth};
+use tower::Service; // for `call`
+
+fn post_text(key: Option<&str>) -> Request<Body> {
+    let request = Request::builder()
+        .uri("/kv/test")
+        .method("POST")
+        .header("content-type", "text/plain");
+    match key {
+        Some(key) => request.header("x-api-key", key),
+        None => request,
+    }
+    .body("Hello World".into())
+    .unwrap()
+}
+
+fn get(uri: &str, key: Option<&str>) -> Request<Body> {
+    let request = Request::builder().uri(uri).method("GET");
+    match key {
+        Some(key) => request.header("x-api-key", key),
+        None => request,
+    }
+    .body(Body::empty())
+    .unwrap()
+}
+
+#[tokio::test]
+async fn api_keys() {
+    let state = Arc::new(RwLock::new(
+        AppState::default().with_api_keys(ApiKeys::new(["secret", "other"])),
+    ));
+    let mut app = router(&state);
+
+    let response = app.call(post_text(None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(response.headers()["x-error-code"], "unauthorized");
+
+    let response = app.call(post_text(Some("wrong"))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = app.call(post_text(Some("secret"))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Reads need a key too unless they are public
+    let response = app.call(get("/kv/test", None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = app.call(get("/v2/kv/test", Some("other"))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Admin endpoints as well, only metrics stay open
+    let response = app.call(get("/admin/readonly", None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = app
+        .call(get("/admin/readonly", Some("secret")))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = app.call(get("/", None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn public_reads() {
+    let state = Arc::new(RwLock::new(
+        AppState::default()
+            .with_api_keys(ApiKeys::new(["secret"]))
+            .with_public_reads(true),
+    ));
+    let mut app = router(&state);
+
+    let response = app.call(post_text(None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = app.call(post_text(Some("secret"))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = app.call(get("/kv/test", None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = app.call(get("/admin/readonly", None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+fn token(secret: &str, scope: &str, expires_in: i64) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let claims = serde_json::json!({
+        "sub": "alice",
+        "scope": scope,
+        "exp": now + expires_in,
+    });
+    jsonwebtoken::encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(secret.as_bytes()),
+    )
+    .unwrap()
+}
+
+fn with_token(mut request: Request<Body>, token: &str) -> Request<Body> {
+    request.headers_mut().insert(
+        "authorization",
+        format!("Bearer {}", token).parse().unwrap(),
+    );
+    request
+}
+
+#[tokio::test]
+async fn bearer_tokens() {
+    let state = Arc::new(RwLock::new(
+        AppState::default().with_jwt(JwtAuth::secret("hunter2")),
+    ));
+    let mut app = router(&state);
+
+    let response = app.call(post_text(None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let writer = token("hunter2", "kv:read kv:write", 60);
+    let response = app
+        .call(with_token(post_text(None), &writer))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Scopes are enforced per operation
+    let reader = token("hunter2", "kv:read", 60);
+    let response = app
+        .call(with_token(post_text(None), &reader))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = app
+        .call(with_token(get("/kv/test", None), &reader))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app
+        .call(with_token(get("/admin/readonly", None), &reader))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let admin = token("hunter2", "kv:admin", 60);
+    let response = app
+        .call(with_token(get("/admin/readonly", None), &admin))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    for invalid in [
+        token("hunter3", "kv:read", 60),
+        token("hunter2", "kv:read", -120),
+        "not.a.token".to_string(),
+    ] {
+        let response = app
+            .call(with_token(get("/kv/test", None), &invalid))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()["x-error-code"], "unauthorized");
+    }
+}
+
+#[tokio::test]
+async fn write_only_tokens() {
+    let state = Arc::new(RwLock::new(
+        AppState::default()
+            .with_jwt(JwtAuth::secret("hunter2"))
+            .with_static_site("docs"),
+    ));
+    let mut app = router(&state);
+    let writer = token("hunter2", "kv:write", 60);
+    let response = app
+        .call(with_token(post_text(None), &writer))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Anything returning a value or something made from it is a read
+    for uri in [
+        "/kv/test/grayscale",
+        "/kv/test/flatten",
+        "/kv/test/preview",
+        "/kv/test/thumbnail?width=8&height=8",
+        "/kv/test/tiles",
+        "/kv/test/tiles/0/0/0",
+        "/r/test",
+        "/site/docs",
+        "/site/docs/test",
+    ] {
+        let response = app.call(with_token(get(uri, None), &writer)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN, "{}", uri);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8_lossy(&body);
+        assert!(
+            body.contains("lacks the kv:read scope"),
+            "{}: {}",
+            uri,
+            body
+        );
+    }
+}
+
+#[tokio::test]
+async fn jwks_tokens() {
+    // "hunter2" in base64url
+    let jwks = r#"{"keys": [{"kty": "oct", "kid": "2024", "k": "aHVudGVyMg"}]}"#;
+    let jwt = JwtAuth::jwks(jwks).unwrap().with_audience("kv");
+    let state = Arc::new(RwLock::new(AppState::default().with_jwt(jwt)));
+    let mut app = router(&state);
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let sign = |kid: &str, audience: &str| {
+        let header = Header {
+            kid: Some(kid.to_string()),
+            ..Header::default()
+        };
+        let claims = serde_json::json!({"scope": "kv:write", "aud": audience, "exp": now + 60});
+        jsonwebtoken::encode(&header, &claims, &EncodingKey::from_secret(b"hunter2")).unwrap()
+    };
+
+    let response = app
+        .call(with_token(post_text(None), &sign("2024", "kv")))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    for token in [sign("2023", "kv"), sign("2024", "other")] {
+        let response = app.call(with_token(post_text(None), &token)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
+
+fn subject_token(subject: &str) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let claims = serde_json::json!({
+        "sub": subject,
+        "scope": "kv:read kv:write",
+        "exp": now + 60,
+    });
+    jsonwebtoken::encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(b"hunter2"),
+    )
+    .unwrap()
+}
+
+fn put_acl(uri: &str, acl: &str) -> Request<Body> {
+    Request::builder()
+        .uri(uri)
+        .method("PUT")
+        .header("content-type", "application/json")
+        .header("x-api-key", "secret")
+        .body(acl.to_string().into())
+        .unwrap()
+}
+
+#[tokio::test]
+async fn access_control_lists() {
+    let state = Arc::new(RwLock::new(
+        AppState::default()
+            .with_api_keys(ApiKeys::new(["secret"]))
+            .with_jwt(JwtAuth::secret("hunter2")),
+    ));
+    let mut app = router(&state);
+    let (alice, bob) = (subject_token("alice"), subject_token("bob"));
+
+    let response = app.call(post_text(Some("secret"))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Open to every principal until an ACL is set
+    let response = app
+        .call(with_token(get("/kv/test", None), &bob))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = app
+        .call(put_acl("/kv/test/acl", r#"{"principals": ["alice"]}"#))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    for uri in ["/kv/test", "/v2/kv/test", "/kv/test/preview"] {
+        let response = app.call(with_token(get(uri, None), &bob)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN, "{}", uri);
+        assert_eq!(response.headers()["x-error-code"], "forbidden");
+    }
+    let response = app.call(with_token(post_text(None), &bob)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = app
+        .call(with_token(get("/kv/test", None), &alice))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app.call(get("/kv/test", Some("secret"))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Prefixes cover every key below them, keys' own ACLs win
+    let response = app
+        .call(put_acl(
+            "/kv/team%2F/acl",
+            r#"{"principals": ["bob"], "prefix": true}"#,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = app
+        .call(get("/kv/team%2Fplan/acl", Some("secret")))
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(
+        &body[..],
+        br#"{"key":"team/","principals":["bob"],"prefix":true}"#
+    );
+    let request = Request::builder()
+        .uri("/kv/team%2Fplan")
+        .method("POST")
+        .header("content-type", "text/plain")
+        .body("Hello World".into())
+        .unwrap();
+    let response = app.call(with_token(request, &alice)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // Only operators manage ACLs
+    let request = Request::builder()
+        .uri("/kv/test/acl")
+        .method("DELETE")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.call(with_token(request, &alice)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let request = Request::builder()
+        .uri("/kv/test/acl")
+        .method("DELETE")
+        .header("x-api-key", "secret")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.call(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = app
+        .call(with_token(get("/kv/test", None), &bob))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn access_control_lists_cover_sites_and_links() {
+    let state = Arc::new(RwLock::new(
+        AppState::default()
+            .with_api_keys(ApiKeys::new(["secret"]))
+            .with_jwt(JwtAuth::secret("hunter2"))
+            .with_static_site("docs"),
+    ));
+    let mut app = router(&state);
+    let (alice, bob) = (subject_token("alice"), subject_token("bob"));
+
+    for (key, content_type, body) in [
+        ("docs%2Findex.html", "text/html", "<h1>Home</h1>"),
+        ("docs%2F404.html", "text/html", "<h1>Lost</h1>"),
+        ("link", "text/x-url", "https://example.com"),
+    ] {
+        let request = Request::builder()
+            .uri(format!("/kv/{}", key))
+            .method("POST")
+            .header("content-type", content_type)
+            .header("x-api-key", "secret")
+            .body(body.into())
+            .unwrap();
+        let response = app.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    for (uri, acl) in [
+        (
+            "/kv/docs%2F/acl",
+            r#"{"principals": ["alice"], "prefix": true}"#,
+        ),
+        ("/kv/link/acl", r#"{"principals": ["alice"]}"#),
+    ] {
+        let response = app.call(put_acl(uri, acl)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    }
+
+    for uri in ["/site/docs", "/site/docs/missing", "/r/link/stats"] {
+        let response = app.call(with_token(get(uri, None), &bob)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN, "{}", uri);
+    }
+    let response = app
+        .call(with_token(get("/site/docs", None), &alice))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app
+        .call(with_token(get("/r/link/stats", None), &alice))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn refresh_rules_run_as_their_owner() {
+    let state = Arc::new(RwLock::new(
+        AppState::default()
+            .with_api_keys(ApiKeys::new(["secret"]))
+            .with_jwt(JwtAuth::secret("hunter2")),
+    ));
+    let mut app = router(&state);
+
+    for key in ["banner-a", "banner-b"] {
+        let request = Request::builder()
+            .uri(format!("/kv/{}", key))
+            .method("POST")
+            .header("content-type", "image/png")
+            .header("x-api-key", "secret")
+            .body(include_bytes!("../crab-small.png")[..].into())
+            .unwrap();
+        let response = app.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let response = app
+        .call(put_acl("/kv/banner-b/acl", r#"{"principals": ["bob"]}"#))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    // Registered by alice, so her ACLs apply rather than an operator's
+    let admin = token("hunter2", "kv:admin", 60);
+    let rule = serde_json::json!({
+        "prefix": "banner-",
+        "pipeline": [{"op": "grayscale"}],
+        "target": "gray-{key}",
+        "interval": 86400,
+    });
+    let request = Request::builder()
+        .uri("/admin/refresh/banners")
+        .method("PUT")
+        .header("content-type", "application/json")
+        .body(rule.to_string().into())
+        .unwrap();
+    let response = app.call(with_token(request, &admin)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let request = Request::builder()
+        .uri("/admin/refresh/banners/run")
+        .method("POST")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.call(with_token(request, &admin)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let run: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(run["keys"], 2);
+    assert_eq!(run["failed"], 1);
+    assert_eq!(run["failures"][0]["key"], "banner-b");
+    assert_eq!(run["failures"][0]["status"], 403);
+}
+
+#[tokio::test]
+async fn usage_report() {
+    let state = Arc::new(RwLock::new(
+        AppState::default().with_api_keys(ApiKeys::new(["secret", "other"])),
+    ));
+    let mut app = router(&state);
+
+    let request = Request::builder()
+        .uri("/kv/tenant%2Fa")
+        .method("POST")
+        .header("content-type", "text/plain")
+        .header("x-api-key", "secret")
+        .body("Hello World".into())
+        .unwrap();
+    let response = app.call(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    for _ in 0..2 {
+        let response = app
+            .call(get("/kv/tenant%2Fa", Some("other")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let response = app.call(get("/kv/tenant%2Fa", None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = app
+        .call(get("/admin/reports/usage", Some("secret")))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers()["content-disposition"]
+        .to_str()
+        .unwrap()
+        .ends_with(".json\""));
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let report: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    // Keys show by the start of their SHA-256
+    let usage = &report["usage"];
+    assert_eq!(usage[0]["namespace"], "tenant");
+    assert_eq!(usage[0]["client"], "anonymous");
+    assert_eq!(usage[1]["client"], "key:2bb80d53");
+    assert_eq!(usage[1]["requests"], 1);
+    assert_eq!(usage[2]["requests"], 2);
+    assert_eq!(usage[2]["bytes_served"], 22);
+    assert_eq!(usage.as_array().unwrap().len(), 3);
+
+    let response = app
+        .call(get("/admin/reports/usage?format=csv", Some("secret")))
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["content-type"], "text/csv");
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let csv = String::from_utf8(body.to_vec()).unwrap();
+    assert_eq!(csv.lines().count(), 4);
+    assert!(csv.starts_with("namespace,client,requests,bytes_served,transform_cpu_seconds\n"));
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let response = app
+        .call(get(
+            &format!("/admin/reports/usage?from={}&to=0", now),
+            Some("secret"),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
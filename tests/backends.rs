@@ -0,0 +1,687 @@
+use std::{
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+    response::IntoResponse,
+};
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use microservice_rust_workshop::{
+    export_bundle, persist_hot_keys, prefetch_hot_keys, router, sweep_expired, AppState,
+    BatchLimits, BatchWrite, BoundedLruDatabase, BundleDatabase, FsDatabase, KVDatabase, KVError,
+    LegalHolds, MemoryDatabase, MockClock, TieredDatabase, WriteBehindDatabase,
+};
+use tower::Service; // for `call`
+
+async fn post(db: impl KVDatabase + 'static) {
+    let state = Arc::new(RwLock::new(AppState::default().with_database(db)));
+    let mut app = router(&state);
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/test")
+                .method("POST")
+                .header("content-type", "text/plain")
+                .body("Hello World".into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+async fn get(db: impl KVDatabase + 'static) {
+    let state = Arc::new(RwLock::new(AppState::default().with_database(db)));
+    let mut app = router(&state);
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/test")
+                .method("GET")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "text/plain");
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(&body[..], b"Hello World");
+}
+
+#[tokio::test]
+async fn fs_survives_reopen() {
+    let root = std::env::temp_dir().join(format!("kv-fs-{}", std::process::id()));
+    post(FsDatabase::open(&root).unwrap()).await;
+    get(FsDatabase::open(&root).unwrap()).await;
+
+    let db = FsDatabase::open(&root).unwrap();
+    // Along with the record of its metadata
+    let keys = db.keys("").await.unwrap();
+    assert_eq!(keys.len(), 2);
+    assert!(keys.contains(&"test".to_string()));
+    assert!(db.remove("test").await.unwrap().is_some());
+    assert!(db.remove("test").await.unwrap().is_none());
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn fs_rejects_keys_too_long_for_file_names() {
+    let root = std::env::temp_dir().join(format!("kv-fs-names-{}", std::process::id()));
+    let db = FsDatabase::open(&root).unwrap();
+    let state = Arc::new(RwLock::new(AppState::default().with_database(db)));
+    let mut app = router(&state);
+
+    // Encoded as %C3%A9, 82 of them make a name of 251 bytes
+    let accented = "%C3%A9".repeat(41);
+    for (uri, status) in [
+        (format!("/kv/{}", accented), StatusCode::OK),
+        (format!("/kv/{}", "a".repeat(84)), StatusCode::URI_TOO_LONG),
+        // The bucket's prefix is part of the file name too
+        (
+            format!("/bucket/b/kv/{}", "a".repeat(73)),
+            StatusCode::URI_TOO_LONG,
+        ),
+        (format!("/bucket/b/kv/{}", "a".repeat(72)), StatusCode::OK),
+    ] {
+        let response = app
+            .call(
+                Request::builder()
+                    .uri(&uri)
+                    .method("POST")
+                    .header("content-type", "text/plain")
+                    .body("Hello World".into())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), status, "{}", uri);
+    }
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn fs_streams_large_values() {
+    let root = std::env::temp_dir().join(format!("kv-fs-spool-{}", std::process::id()));
+    let db = FsDatabase::open(&root).unwrap();
+    let state = Arc::new(RwLock::new(AppState::default().with_database(db)));
+    let mut app = router(&state);
+
+    let data: Vec<u8> = (0..3 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/large")
+                .method("POST")
+                .header("content-type", "application/octet-stream")
+                .body(data.clone().into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let db = FsDatabase::open(&root).unwrap();
+    let (_, stored) = db.read("large").await.unwrap().unwrap();
+    assert!(stored[..] == data[..]);
+    // The spooled upload was moved, nothing is left besides the entry and
+    // the record of its metadata
+    assert_eq!(std::fs::read_dir(&root).unwrap().count(), 4);
+
+    // Ranges across the chunks the file is streamed in
+    for (first, last) in [
+        (65_530, 65_545),
+        (0, 3 * 1024 * 1024 - 1),
+        (1_000_000, 2_500_000),
+    ] {
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/kv/large")
+                    .header("range", format!("bytes={}-{}", first, last))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            response.headers()["content-length"],
+            (last - first + 1).to_string()
+        );
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(body[..] == data[first..=last]);
+    }
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn memory_writes_in_parallel() {
+    for db in [MemoryDatabase::with_shards(1), MemoryDatabase::default()] {
+        let db = Arc::new(db);
+        let writers: Vec<_> = (0..8)
+            .map(|task| {
+                let db = Arc::clone(&db);
+                tokio::spawn(async move {
+                    for i in 0..500 {
+                        let value = ("text/plain".to_string(), Bytes::from(i.to_string()));
+                        db.insert(format!("{}/{}", task, i), value).await.unwrap();
+                    }
+                    assert!(db.remove(&format!("{}/0", task)).await.unwrap().is_some());
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.await.unwrap();
+        }
+        assert_eq!(db.keys("").await.unwrap().len(), 8 * 499);
+        assert_eq!(db.keys("3/").await.unwrap().len(), 499);
+        assert_eq!(db.read("7/499").await.unwrap().unwrap().1, "499");
+    }
+}
+
+#[tokio::test]
+async fn lru_evicts_least_recently_used() {
+    let db = BoundedLruDatabase::new(MemoryDatabase::default())
+        .with_max_entries(2)
+        .with_max_bytes(10);
+    let value = |data: &'static [u8]| ("text/plain".to_string(), Bytes::from_static(data));
+
+    db.insert("a".to_string(), value(b"1")).await.unwrap();
+    db.insert("b".to_string(), value(b"2")).await.unwrap();
+    db.read("a").await.unwrap();
+    db.insert("c".to_string(), value(b"3")).await.unwrap();
+    let mut keys = db.keys("").await.unwrap();
+    keys.sort();
+    assert_eq!(keys, ["a", "c"]);
+
+    db.insert("d".to_string(), value(b"123456789"))
+        .await
+        .unwrap();
+    let mut keys = db.keys("").await.unwrap();
+    keys.sort();
+    assert_eq!(keys, ["c", "d"]);
+
+    let error = db.insert("e".to_string(), value(b"12345678901")).await;
+    let response = error.unwrap_err().into_response();
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[tokio::test]
+async fn lru_keeps_held_keys() {
+    let holds = LegalHolds::default();
+    let db = BoundedLruDatabase::new(MemoryDatabase::default())
+        .with_max_entries(2)
+        .with_holds(holds.clone());
+    let value = |data: &'static [u8]| ("text/plain".to_string(), Bytes::from_static(data));
+
+    holds.hold_key("a");
+    for key in ["a", "b", "c"] {
+        db.insert(key.to_string(), value(b"1")).await.unwrap();
+    }
+    let mut keys = db.keys("").await.unwrap();
+    keys.sort();
+    assert_eq!(keys, ["a", "c"]);
+
+    holds.release_key("a");
+    db.insert("d".to_string(), value(b"1")).await.unwrap();
+    let mut keys = db.keys("").await.unwrap();
+    keys.sort();
+    assert_eq!(keys, ["c", "d"]);
+}
+
+#[tokio::test]
+async fn bundle_mounts_read_only() {
+    let value = |data: &[u8]| ("text/plain".to_string(), Bytes::copy_from_slice(data));
+    let db = MemoryDatabase::default();
+    for key in ["assets/logo", "assets/style", "other/a"] {
+        db.insert(key.to_string(), value(key.as_bytes()))
+            .await
+            .unwrap();
+    }
+    let origin = Arc::new(RwLock::new(AppState::default().with_database(db)));
+    let path = std::env::temp_dir().join(format!("kv-{}.kvpack", std::process::id()));
+    assert_eq!(export_bundle(&origin, "assets", &path).await.unwrap(), 2);
+
+    let bundle = BundleDatabase::open(&path).unwrap();
+    assert_eq!(bundle.namespace(), "assets");
+    let error = bundle.insert("assets/new".to_string(), value(b"new")).await;
+    assert_eq!(
+        error.unwrap_err().into_response().status(),
+        StatusCode::FORBIDDEN
+    );
+
+    let state = Arc::new(RwLock::new(
+        AppState::default().with_mount("assets", bundle),
+    ));
+    let mut app = router(&state);
+    let mut call = |method: &str, uri: &str| {
+        app.call(
+            Request::builder()
+                .uri(uri)
+                .method(method)
+                .header("content-type", "text/plain")
+                .body(Body::from("shadowed"))
+                .unwrap(),
+        )
+    };
+    let response = call("GET", "/kv/assets%2Flogo").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "text/plain");
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(&body[..], b"assets/logo");
+    let response = call("GET", "/kv/other%2Fa").await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // Writes shadow the bundle, which can't be removed from
+    let response = call("POST", "/kv/assets%2Fstyle").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = call("GET", "/kv/assets%2Fstyle").await.unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(&body[..], b"shadowed");
+    let response = call("DELETE", "/kv/assets%2Flogo").await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // A damaged bundle isn't mounted
+    let mut contents = std::fs::read(&path).unwrap();
+    contents[10] ^= 1;
+    std::fs::write(&path, contents).unwrap();
+    assert!(BundleDatabase::open(&path).is_err());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn tiered_prefetches_hot_keys() {
+    let root = std::env::temp_dir().join(format!("kv-tiered-{}", std::process::id()));
+    let value = |data: &'static [u8]| ("text/plain".to_string(), Bytes::from_static(data));
+    let cold = FsDatabase::open(&root).unwrap();
+    for key in ["a", "b", "c"] {
+        cold.insert(key.to_string(), value(b"cold")).await.unwrap();
+    }
+
+    let db = TieredDatabase::new(FsDatabase::open(&root).unwrap(), 1024);
+    for key in ["a", "b", "a", "c", "a", "b"] {
+        db.read(key).await.unwrap();
+    }
+    assert_eq!(db.hot_keys(2).await.unwrap(), ["a", "b"]);
+    let state = Arc::new(RwLock::new(AppState::default().with_database(db)));
+    persist_hot_keys(&state, 2).await.unwrap();
+
+    // After a restart, the hot keys are served from memory
+    let db = TieredDatabase::new(FsDatabase::open(&root).unwrap(), 1024);
+    let state = Arc::new(RwLock::new(AppState::default().with_database(db)));
+    assert_eq!(prefetch_hot_keys(&state).await.unwrap(), 2);
+    for key in ["a", "b", "c"] {
+        cold.insert(key.to_string(), value(b"changed behind the cache"))
+            .await
+            .unwrap();
+    }
+    let mut app = router(&state);
+    for (key, expected) in [
+        ("a", "cold"),
+        ("b", "cold"),
+        ("c", "changed behind the cache"),
+    ] {
+        let response = app
+            .call(
+                Request::builder()
+                    .uri(format!("/kv/{}", key))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], expected.as_bytes());
+    }
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+/// Records the size of every batch written to a shared memory backend
+#[derive(Clone, Default)]
+struct Batches {
+    db: Arc<MemoryDatabase>,
+    sizes: Arc<Mutex<Vec<usize>>>,
+}
+
+#[async_trait]
+impl KVDatabase for Batches {
+    async fn read(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        self.db.read(key).await
+    }
+
+    async fn insert(&self, key: String, value: (String, Bytes)) -> Result<(), KVError> {
+        self.db.insert(key, value).await
+    }
+
+    async fn remove(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        self.db.remove(key).await
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, KVError> {
+        self.db.keys(prefix).await
+    }
+
+    async fn write_batch(&self, writes: Vec<BatchWrite>) -> Result<(), KVError> {
+        self.sizes.lock().unwrap().push(writes.len());
+        self.db.write_batch(writes).await
+    }
+}
+
+#[tokio::test]
+async fn write_behind_batches_writes() {
+    let backend = Batches::default();
+    let limits = BatchLimits {
+        max_writes: 3,
+        max_bytes: 1 << 20,
+        max_delay: Duration::from_secs(3600),
+    };
+    let db = WriteBehindDatabase::new(backend.clone(), limits);
+    let value = |data: &'static [u8]| ("text/plain".to_string(), Bytes::from_static(data));
+
+    db.insert("a".to_string(), value(b"1")).await.unwrap();
+    db.insert("b".to_string(), value(b"2")).await.unwrap();
+    db.insert("a".to_string(), value(b"3")).await.unwrap();
+    assert_eq!(db.buffered(), 2);
+    assert_eq!(db.read("a").await.unwrap().unwrap().1, "3");
+    assert!(backend.db.read("a").await.unwrap().is_none());
+    assert_eq!(db.remove("b").await.unwrap().unwrap().1, "2");
+    assert!(db.remove("b").await.unwrap().is_none());
+    assert_eq!(db.keys("").await.unwrap(), ["a"]);
+
+    // Flushed on demand, a key written several times is sent once
+    assert_eq!(db.flush().await.unwrap(), 2);
+    assert_eq!(db.buffered(), 0);
+    assert_eq!(backend.db.read("a").await.unwrap().unwrap().1, "3");
+    assert!(backend.db.read("b").await.unwrap().is_none());
+
+    // Flushed once the batch is full
+    for key in ["c", "d", "e"] {
+        db.insert(key.to_string(), value(b"4")).await.unwrap();
+    }
+    assert_eq!(db.buffered(), 0);
+    assert!(backend.db.contains("e").await.unwrap());
+    assert_eq!(*backend.sizes.lock().unwrap(), [2, 3]);
+
+    // Unbuffered keys are removed right away
+    assert!(db.remove("c").await.unwrap().is_some());
+    assert!(!backend.db.contains("c").await.unwrap());
+
+    // Flushed on shutdown
+    db.insert("f".to_string(), value(b"5")).await.unwrap();
+    db.close().await.unwrap();
+    assert!(backend.db.contains("f").await.unwrap());
+}
+
+#[tokio::test]
+async fn write_behind_flushes_in_the_background() {
+    let backend = Batches::default();
+    let limits = BatchLimits {
+        max_delay: Duration::from_millis(10),
+        ..BatchLimits::default()
+    };
+    let db = WriteBehindDatabase::new(backend.clone(), limits);
+    let value = ("text/plain".to_string(), Bytes::from_static(b"1"));
+    db.insert("a".to_string(), value).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(db.buffered(), 0);
+    assert!(backend.db.contains("a").await.unwrap());
+}
+
+/// Takes a second to answer reads
+struct Slow(MemoryDatabase);
+
+#[async_trait]
+impl KVDatabase for Slow {
+    async fn read(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        self.0.read(key).await
+    }
+
+    async fn insert(&self, key: String, value: (String, Bytes)) -> Result<(), KVError> {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        self.0.insert(key, value).await
+    }
+
+    async fn remove(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        self.0.remove(key).await
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, KVError> {
+        self.0.keys(prefix).await
+    }
+
+    async fn contains(&self, key: &str) -> Result<bool, KVError> {
+        self.0.contains(key).await
+    }
+}
+
+#[tokio::test]
+async fn slow_backends_hit_the_deadline() {
+    let state = Arc::new(RwLock::new(
+        AppState::default().with_database(Slow(MemoryDatabase::default())),
+    ));
+    let mut app = router(&state);
+    let get = |uri: &str, deadline: Option<&str>| {
+        let mut request = Request::builder().uri(uri);
+        if let Some(deadline) = deadline {
+            request = request.header("x-request-deadline", deadline);
+        }
+        request.body(Body::empty()).unwrap()
+    };
+
+    let response = app.call(get("/v2/kv/test", Some("50"))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    assert_eq!(response.headers()["x-error-code"], "deadline_exceeded");
+    assert!(response.headers()["server-timing"]
+        .to_str()
+        .unwrap()
+        .starts_with("deadline;dur="));
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let message = error["error"]["message"].as_str().unwrap();
+    assert!(message.contains(" in Slow read"), "{}", message);
+
+    let response = app.call(get("/hello", Some("50"))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app.call(get("/hello", Some("soon"))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // Writes aren't cut off, the caller just stops waiting for them
+    let request = Request::builder()
+        .uri("/kv/written")
+        .method("POST")
+        .header("content-type", "text/plain")
+        .header("x-request-deadline", "50")
+        .body("Hello World".into())
+        .unwrap();
+    let response = app.call(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let response = app.call(get("/kv/written", None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // The configured timeout applies without the header, and the sooner
+    // one wins
+    let state = Arc::new(RwLock::new(
+        AppState::default()
+            .with_database(Slow(MemoryDatabase::default()))
+            .with_request_timeout(Duration::from_millis(50)),
+    ));
+    let mut app = router(&state);
+    let response = app.call(get("/kv/test", None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    let response = app.call(get("/kv/test", Some("5000"))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+}
+
+/// Fails to remove `stuck`
+struct StuckRemoves(MemoryDatabase);
+
+#[async_trait]
+impl KVDatabase for StuckRemoves {
+    async fn read(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        self.0.read(key).await
+    }
+
+    async fn insert(&self, key: String, value: (String, Bytes)) -> Result<(), KVError> {
+        self.0.insert(key, value).await
+    }
+
+    async fn remove(&self, key: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        if key == "stuck" {
+            return Err(KVError::Unavailable("Backend down".to_string()));
+        }
+        self.0.remove(key).await
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, KVError> {
+        self.0.keys(prefix).await
+    }
+}
+
+#[tokio::test]
+async fn sweep_continues_past_failed_removes() {
+    let clock = MockClock::new();
+    let state = Arc::new(RwLock::new(
+        AppState::default()
+            .with_database(StuckRemoves(MemoryDatabase::default()))
+            .with_clock(clock.clone()),
+    ));
+    let mut app = router(&state);
+    for key in ["stuck", "gone"] {
+        let response = app
+            .call(
+                Request::builder()
+                    .uri(format!("/kv/{}?ttl=1", key))
+                    .method("POST")
+                    .header("content-type", "text/plain")
+                    .body("Hello World".into())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(sweep_expired(&state).await.unwrap(), 1);
+    // The entry that couldn't be removed stays hidden and is tried again
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/stuck")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(sweep_expired(&state).await.unwrap(), 0);
+}
+
+#[cfg(feature = "sled")]
+#[tokio::test]
+async fn sled_survives_reopen() {
+    use microservice_rust_workshop::SledDatabase;
+
+    let path = std::env::temp_dir().join(format!("kv-sled-{}", std::process::id()));
+    post(SledDatabase::open(&path).unwrap()).await;
+    get(SledDatabase::open(&path).unwrap()).await;
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
+#[cfg(feature = "sqlite")]
+#[tokio::test]
+async fn sqlite_survives_reopen() {
+    use microservice_rust_workshop::SqliteDatabase;
+
+    let path = std::env::temp_dir().join(format!("kv-sqlite-{}.db", std::process::id()));
+    let url = format!("sqlite://{}", path.display());
+    post(SqliteDatabase::connect(&url).await.unwrap()).await;
+    get(SqliteDatabase::connect(&url).await.unwrap()).await;
+
+    let db = SqliteDatabase::connect(&url).await.unwrap();
+    let mut writes: Vec<BatchWrite> = (0..1500)
+        .map(|i| BatchWrite::Insert {
+            key: format!("batch/{}", i),
+            value: ("text/plain".to_string(), Bytes::from(i.to_string())),
+        })
+        .collect();
+    writes.push(BatchWrite::Remove {
+        key: "test".to_string(),
+    });
+    db.write_batch(writes).await.unwrap();
+    assert_eq!(db.keys("batch/").await.unwrap().len(), 1500);
+    assert_eq!(db.read("batch/1499").await.unwrap().unwrap().1, "1499");
+    assert!(!db.contains("test").await.unwrap());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(feature = "postgres")]
+#[tokio::test]
+async fn postgres_shared_between_pools() {
+    use microservice_rust_workshop::PostgresDatabase;
+
+    // Needs a server, e.g. KV_TEST_POSTGRES_URL=postgres://kv@localhost/kv_test
+    let Ok(url) = std::env::var("KV_TEST_POSTGRES_URL") else {
+        return;
+    };
+    post(PostgresDatabase::connect(&url, 2).await.unwrap()).await;
+    get(PostgresDatabase::connect(&url, 2).await.unwrap()).await;
+}
+
+#[cfg(feature = "object-store")]
+#[tokio::test]
+async fn object_store_keeps_content_type() {
+    use microservice_rust_workshop::ObjectStoreDatabase;
+    use object_store::{memory::InMemory, path::Path, ObjectStore};
+
+    let store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+    post(ObjectStoreDatabase::new(
+        Arc::clone(&store),
+        Path::from("kv"),
+    ))
+    .await;
+    get(ObjectStoreDatabase::new(
+        Arc::clone(&store),
+        Path::from("kv"),
+    ))
+    .await;
+
+    let db = ObjectStoreDatabase::new(store, Path::from("kv"));
+    for key in ["site/index.html", "site/css/main.css", "sitemap"] {
+        let value = ("text/plain".to_string(), Bytes::from_static(b"x"));
+        db.insert(key.to_string(), value).await.unwrap();
+    }
+    let mut keys = db.keys("site/").await.unwrap();
+    keys.sort();
+    assert_eq!(keys, ["site/css/main.css", "site/index.html"]);
+
+    let writes = vec![
+        BatchWrite::Insert {
+            key: "site/about.html".to_string(),
+            value: ("text/html".to_string(), Bytes::from_static(b"x")),
+        },
+        BatchWrite::Remove {
+            key: "site/index.html".to_string(),
+        },
+        BatchWrite::Remove {
+            key: "site/missing.html".to_string(),
+        },
+    ];
+    db.write_batch(writes).await.unwrap();
+    let mut keys = db.keys("site/").await.unwrap();
+    keys.sort();
+    assert_eq!(keys, ["site/about.html", "site/css/main.css"]);
+    assert_eq!(
+        db.read("site/about.html").await.unwrap().unwrap().0,
+        "text/html"
+    );
+}
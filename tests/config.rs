@@ -0,0 +1,343 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+};
+use microservice_rust_workshop::{
+    router, BackendConfig, Config, ConfigError, Listen, MockClock, Rate, ServerOptions,
+};
+use tower::Service; // for `call`
+
+fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+    let vars: HashMap<String, String> = vars
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    move |name| vars.get(name).cloned()
+}
+
+#[test]
+fn config_file() {
+    let config = Config::from_file(Path::new("tests/fixtures/config.toml")).unwrap();
+    assert_eq!(config.server.listen, "0.0.0.0:8080".parse().unwrap());
+    assert!(matches!(config.server.admin_listen, Some(Listen::Unix(_))));
+    assert_eq!(config.shutdown_timeout(), Duration::from_secs(10));
+    assert!(matches!(
+        config.backend,
+        BackendConfig::Memory {
+            max_entries: Some(1000),
+            max_bytes: None
+        }
+    ));
+    assert_eq!(
+        config.limits.rate.writes,
+        Some(Rate {
+            per_second: 10.0,
+            burst: 50
+        })
+    );
+    assert_eq!(config.limits.rate.reads, None);
+    assert_eq!(config.auth.api_keys, ["secret"]);
+
+    // Whatever is left out has its default
+    let config = Config::default();
+    assert_eq!(config.server.listen, "127.0.0.1:3000".parse().unwrap());
+    assert!(config.server.admin_listen.is_none());
+    assert!(config.auth.api_keys.is_empty());
+}
+
+#[test]
+fn environment_overrides() {
+    let config = Config::from_file(Path::new("tests/fixtures/config.toml"))
+        .unwrap()
+        .with_env(env(&[
+            ("KV_LISTEN", "127.0.0.1:9000"),
+            ("KV_API_KEYS", "one, two,"),
+            ("KV_RATE_LIMIT_READS", "5"),
+            ("KV_FS_ROOT", "/var/lib/kv"),
+            // Only bounds the memory backend
+            ("KV_MAX_BYTES", "1024"),
+            ("KV_HOT_CACHE_BYTES", "1048576"),
+            ("KV_WRITE_BEHIND_MS", "50"),
+            ("KV_REQUEST_TIMEOUT_MS", "2000"),
+            ("KV_CLAMD_ADDR", "127.0.0.1:3310"),
+            ("KV_MAX_KEY_DEPTH", "4"),
+        ]))
+        .unwrap();
+    assert_eq!(config.server.listen, "127.0.0.1:9000".parse().unwrap());
+    assert_eq!(config.auth.api_keys, ["one", "two"]);
+    assert_eq!(config.limits.rate.reads.unwrap().burst, 5);
+    assert!(config.limits.rate.writes.is_some());
+    assert_eq!(config.limits.request_timeout_ms, Some(2000));
+    assert!(matches!(config.backend, BackendConfig::Fs { .. }));
+    assert_eq!(config.cache.hot_bytes, Some(1 << 20));
+    assert_eq!(config.cache.prefetch_keys, 1000);
+    assert_eq!(config.write_behind.max_delay_ms, Some(50));
+    assert_eq!(config.write_behind.max_writes, 500);
+    assert_eq!(
+        config.virus_scan.clamd_addr.as_deref(),
+        Some("127.0.0.1:3310")
+    );
+    assert_eq!(config.limits.max_key_depth, Some(4));
+    assert!(config.limits.flood.is_none());
+
+    let error = Config::default()
+        .with_env(env(&[("KV_RATE_LIMIT_WRITES", "fast")]))
+        .unwrap_err();
+    assert!(matches!(
+        error,
+        ConfigError::Env {
+            name: "KV_RATE_LIMIT_WRITES",
+            ..
+        }
+    ));
+}
+
+#[test]
+fn server_options_from_config() {
+    let config = Config::default()
+        .with_env(env(&[
+            ("KV_BACKLOG", "128"),
+            ("KV_TCP_NODELAY", "false"),
+            ("KV_TCP_KEEPALIVE", "0"),
+            ("KV_HTTP2_MAX_STREAMS", "0"),
+            ("KV_HTTP2_KEEPALIVE", "10"),
+        ]))
+        .unwrap();
+    let options = config.server_options();
+    assert_eq!(options.backlog, 128);
+    assert!(!options.tcp_nodelay);
+    assert_eq!(options.tcp_keepalive, None);
+    assert!(options.http1_keepalive);
+    assert_eq!(options.http2_max_concurrent_streams, None);
+    assert_eq!(
+        options.http2_keep_alive_interval,
+        Some(Duration::from_secs(10))
+    );
+
+    // Left out, they are what `ServerOptions` defaults to
+    let options = Config::default().server_options();
+    let defaults = ServerOptions::default();
+    assert_eq!(options.backlog, defaults.backlog);
+    assert_eq!(options.tcp_keepalive, defaults.tcp_keepalive);
+    assert_eq!(
+        options.http2_max_concurrent_streams,
+        defaults.http2_max_concurrent_streams
+    );
+    assert_eq!(
+        options.http2_keep_alive_timeout,
+        defaults.http2_keep_alive_timeout
+    );
+}
+
+#[test]
+fn invalid_config_file() {
+    let error = Config::from_file(Path::new("tests/fixtures/missing.toml")).unwrap_err();
+    assert!(matches!(error, ConfigError::Read { .. }));
+    // Typos don't go unnoticed
+    let error = Config::from_file(Path::new("tests/fixtures/tls-cert.pem")).unwrap_err();
+    assert!(matches!(error, ConfigError::Parse { .. }));
+    let error = toml::from_str::<Config>("[server]\nlisten_on = \"0.0.0.0:80\"").unwrap_err();
+    assert!(error.to_string().contains("listen_on"));
+}
+
+#[tokio::test]
+async fn app_state_from_config() {
+    let config = Config::from_file(Path::new("tests/fixtures/config.toml")).unwrap();
+    let state = Arc::new(RwLock::new(config.app_state().await.unwrap()));
+    let mut app = router(&state);
+
+    let request = Request::builder()
+        .uri("/kv/test")
+        .method("POST")
+        .header("content-type", "text/plain")
+        .body(Body::from("Hello World"))
+        .unwrap();
+    let response = app.call(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let request = Request::builder()
+        .uri("/kv/test")
+        .method("POST")
+        .header("content-type", "application/json")
+        .header("x-api-key", "secret")
+        .body(Body::from("{}"))
+        .unwrap();
+    let response = app.call(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+    // Backends left out of the build don't fall back to memory
+    #[cfg(not(feature = "sled"))]
+    {
+        let config = Config {
+            backend: BackendConfig::Sled {
+                path: "kv.sled".into(),
+            },
+            ..Config::default()
+        };
+        assert!(config.app_state().await.is_err());
+    }
+}
+
+#[tokio::test]
+async fn app_state_limits_from_config() {
+    let config: Config = toml::from_str(
+        r#"
+        sites = ["docs"]
+
+        [server]
+        read_only = true
+
+        [limits]
+        max_key_length = 8
+
+        [limits.flood]
+        max_writes_per_key = 1
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.limits.flood.as_ref().unwrap().cooldown, 60);
+    let state = Arc::new(RwLock::new(config.app_state().await.unwrap()));
+    let mut app = router(&state);
+    let request = |method: &str, uri: &str| {
+        Request::builder()
+            .uri(uri)
+            .method(method)
+            .header("content-type", "text/plain")
+            .body(Body::from("Hello World"))
+            .unwrap()
+    };
+
+    let response = app.call(request("POST", "/kv/test")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/admin/readonly")
+                .method("POST")
+                .header("content-type", "application/json")
+                .body(Body::from(r#"{"enabled": false}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app
+        .call(request("POST", "/kv/much-too-long"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::URI_TOO_LONG);
+    let response = app.call(request("POST", "/kv/docs%2Fa")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app.call(request("POST", "/kv/docs%2Fa")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    let response = app.call(request("GET", "/site/docs/a")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let config = Config::default()
+        .with_env(env(&[
+            ("KV_FLOOD_COOLDOWN", "5"),
+            ("KV_SITES", "docs, blog"),
+        ]))
+        .unwrap();
+    let flood = config.limits.flood.unwrap();
+    assert_eq!((flood.cooldown, flood.max_writes_per_key), (5, 100));
+    assert_eq!(config.sites, ["docs", "blog"]);
+}
+
+#[tokio::test]
+async fn state_survives_restart() {
+    let root = std::env::temp_dir().join(format!("kv-restart-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    let config = Config::default()
+        .with_env(env(&[
+            ("KV_FS_ROOT", root.to_str().unwrap()),
+            ("KV_API_KEYS", "secret"),
+        ]))
+        .unwrap();
+    let request = |method: &str, uri: &str, body: &str| {
+        Request::builder()
+            .uri(uri)
+            .method(method)
+            .header("content-type", "application/json")
+            .header("x-api-key", "secret")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+
+    let state = Arc::new(RwLock::new(config.app_state().await.unwrap()));
+    let mut app = router(&state);
+    let response = app
+        .call(request(
+            "PUT",
+            "/kv/test/acl",
+            r#"{"principals": ["alice"]}"#,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = app
+        .call(request("PUT", "/admin/holds/keys/test", ""))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let mut upload = request("POST", "/kv/note", "Hello World");
+    upload
+        .headers_mut()
+        .insert("content-md5", "sQqNsWTgdUEFt6mb5y4/5Q==".parse().unwrap());
+    let response = app.call(upload).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app
+        .call(request("POST", "/kv/brief?ttl=60", "Hello World"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let mut upload = request("POST", "/kv/secret", "Hello World");
+    upload
+        .headers_mut()
+        .insert("x-burn-after-read", "true".parse().unwrap());
+    let response = app.call(upload).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let clock = MockClock::new();
+    let state = Arc::new(RwLock::new(
+        config.app_state().await.unwrap().with_clock(clock.clone()),
+    ));
+    let mut app = router(&state);
+    let response = app.call(request("GET", "/kv/test/acl", "")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(
+        &body[..],
+        br#"{"key":"test","principals":["alice"],"prefix":false}"#
+    );
+    let response = app.call(request("GET", "/admin/holds", "")).await.unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(&body[..], br#"{"keys":["test"],"namespaces":[]}"#);
+    // The checksums verified on upload, and when it was
+    let response = app.call(request("GET", "/kv/note", "")).await.unwrap();
+    assert_eq!(
+        response.headers()["content-md5"],
+        "sQqNsWTgdUEFt6mb5y4/5Q=="
+    );
+    assert!(response.headers().contains_key("last-modified"));
+    // Still read once only
+    let response = app.call(request("GET", "/kv/secret", "")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app.call(request("GET", "/kv/secret", "")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    // And expires when it was meant to
+    let response = app.call(request("GET", "/kv/brief", "")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    clock.advance(Duration::from_secs(60));
+    let response = app.call(request("GET", "/kv/brief", "")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
@@ -21,3 +21,33 @@ async fn hello_world() {
     let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
     assert_eq!(&body[..], b"<h1>Hello Axum</h1>");
 }
+
+#[tokio::test]
+async fn deprecated_route() {
+    let state = SharedState::default();
+    let mut app = router(&state);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/hello?name=crab")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["deprecation"], "@1792108800");
+    assert_eq!(
+        response.headers()["sunset"],
+        "Fri, 16 Apr 2027 00:00:00 GMT"
+    );
+
+    let response = app
+        .call(Request::builder().uri("/").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+
+    assert!(!response.headers().contains_key("deprecation"));
+}
@@ -1,9 +1,23 @@
+use std::{
+    net::UdpSocket,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use async_trait::async_trait;
 use axum::{
-    body::Body,
+    body::{Body, Bytes},
     http::{Request, StatusCode},
 };
 
-use microservice_rust_workshop::{router, SharedState};
+use microservice_rust_workshop::{
+    router, AppState, ClamdScanner, ContentTypePolicy, ImageEncoding, ImagePolicy, KVDatabase,
+    KVError, KeyLimits, MessageTable, MockClock, RequestLimits, SharedState, StatsdMetrics,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
 use tower::Service; // for `call`
 
 #[tokio::test]
@@ -170,3 +184,1881 @@ async fn grayscale_faulty_request() {
 
     assert_eq!(response.status(), StatusCode::FORBIDDEN);
 }
+
+#[tokio::test]
+async fn grayscale_corrupt_png() {
+    let state = SharedState::default();
+    let mut app = router(&state);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/broken")
+                .method("POST")
+                .header("content-type", "image/png")
+                .body("not a png".into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/broken/grayscale")
+                .method("GET")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn content_type_policy() {
+    let state: SharedState = Arc::new(RwLock::new(
+        AppState::default()
+            .with_content_types(ContentTypePolicy::default().deny("application/x-msdownload"))
+            .with_namespace_content_types("avatars", ContentTypePolicy::default().allow("image/*")),
+    ));
+    let mut app = router(&state);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/avatars%2Fcrab")
+                .method("POST")
+                .header("content-type", "text/plain")
+                .body("Hello World".into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert_eq!(response.headers()["x-error-code"], "unsupported_media_type");
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert!(String::from_utf8(body.to_vec())
+        .unwrap()
+        .contains("image/*"));
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/tool")
+                .method("POST")
+                .header("content-type", "application/x-msdownload")
+                .body("MZ".into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/notes")
+                .method("POST")
+                .header("content-type", "text/plain")
+                .body("Hello World".into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn virus_scan_rejects_infected_upload() {
+    // Pretend to be clamd: swallow the INSTREAM upload and report a finding
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut received = Vec::new();
+        let mut buf = [0; 1024];
+        while !received.ends_with(&[0, 0, 0, 0]) {
+            let n = socket.read(&mut buf).await.unwrap();
+            received.extend_from_slice(&buf[..n]);
+        }
+        socket
+            .write_all(b"stream: Eicar-Signature FOUND\0")
+            .await
+            .unwrap();
+    });
+
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    server
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let metrics = StatsdMetrics::new(server.local_addr().unwrap(), "kv.").unwrap();
+    let state: SharedState = Arc::new(RwLock::new(
+        AppState::default()
+            .with_virus_scanner(ClamdScanner::new(addr.to_string()))
+            .with_metrics(metrics),
+    ));
+    let mut app = router(&state);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/eicar")
+                .method("POST")
+                .header("content-type", "text/plain")
+                .body("X5O!P%@AP[4\\PZX54(P^)7CC)7}$EICAR".into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let mut packet = [0; 512];
+    loop {
+        let len = server.recv(&mut packet).unwrap();
+        if &packet[..len] == b"kv.kv_virus_detections_total:1|c" {
+            break;
+        }
+    }
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/eicar")
+                .method("GET")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn virus_scan_fails_closed_when_clamd_hangs() {
+    // Accepts the connection and never answers
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut buf = [0; 1024];
+        while socket.read(&mut buf).await.unwrap() > 0 {}
+    });
+
+    let scanner = ClamdScanner::new(addr.to_string()).with_timeout(Duration::from_millis(100));
+    let state: SharedState = Arc::new(RwLock::new(AppState::default().with_virus_scanner(scanner)));
+    let mut app = router(&state);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/hung")
+                .method("POST")
+                .header("content-type", "text/plain")
+                .body("Hello".into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test]
+async fn preview_request() {
+    let state = SharedState::default();
+    let mut app = router(&state);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/main.rs")
+                .method("POST")
+                .header("content-type", "text/plain")
+                .body("fn main() {\n    println!(\"<crab>\");\n}\n".into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/main.rs/preview")
+                .method("GET")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let html = String::from_utf8(body.to_vec()).unwrap();
+    assert!(html.contains("   1 </span>"));
+    assert!(html.contains("&lt;crab&gt;"));
+}
+
+#[tokio::test]
+async fn burn_after_read() {
+    let state = SharedState::default();
+    let mut app = router(&state);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/secret")
+                .method("POST")
+                .header("content-type", "text/plain")
+                .header("x-burn-after-read", "true")
+                .body("Hello World".into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/secret")
+                .method("GET")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(&body[..], b"Hello World");
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/secret")
+                .method("GET")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn burn_after_read_through_transforms() {
+    let state = SharedState::default();
+    let mut app = router(&state);
+
+    let uploads: [(&str, &str, Body); 3] = [
+        ("main.rs", "text/plain", "fn main() {}\n".into()),
+        (
+            "crab",
+            "image/png",
+            include_bytes!("../crab-small.png")[..].into(),
+        ),
+        (
+            "batched",
+            "image/png",
+            include_bytes!("../crab-small.png")[..].into(),
+        ),
+    ];
+    for (key, content_type, body) in uploads {
+        let response = app
+            .call(
+                Request::builder()
+                    .uri(format!("/kv/{}", key))
+                    .method("POST")
+                    .header("content-type", content_type)
+                    .header("x-burn-after-read", "true")
+                    .body(body)
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    for uri in ["/kv/main.rs/preview", "/kv/crab/grayscale"] {
+        for expected in [StatusCode::OK, StatusCode::NOT_FOUND] {
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri(uri)
+                        .method("GET")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), expected, "{}", uri);
+        }
+    }
+
+    let batch = serde_json::json!({
+        "keys": ["batched"],
+        "pipeline": [{"op": "grayscale"}],
+        "target": "gray-{key}",
+    });
+    for expected in [200, 404] {
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/transform/batch")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(batch.to_string().into())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let report: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(report["results"][0]["status"], expected);
+    }
+}
+
+#[tokio::test]
+async fn generated_key() {
+    let state = SharedState::default();
+    let mut app = router(&state);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv")
+                .method("POST")
+                .header("host", "localhost:3000")
+                .header("content-type", "text/plain")
+                .body("Hello World".into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    let key = &body[r#"{"key":""#.len()..][..8];
+    assert_eq!(
+        body,
+        format!(r#"{{"key":"{key}","url":"http://localhost:3000/kv/{key}"}}"#)
+    );
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri(format!("/kv/{}", key))
+                .method("GET")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(&body[..], b"Hello World");
+}
+
+#[tokio::test]
+async fn seeded_keys() {
+    let mut keys = Vec::new();
+    for _ in 0..2 {
+        let state = Arc::new(RwLock::new(AppState::default().with_seed(42)));
+        let mut app = router(&state);
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/kv")
+                    .method("POST")
+                    .header("content-type", "text/plain")
+                    .body("Hello World".into())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        keys.push(body);
+    }
+
+    assert_eq!(keys[0], keys[1]);
+}
+
+#[tokio::test]
+async fn link_redirect() {
+    let state = SharedState::default();
+    let mut app = router(&state);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/rust")
+                .method("POST")
+                .header("content-type", "text/x-url")
+                .body("https://www.rust-lang.org/\n".into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/r/rust")
+                .method("GET")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::FOUND);
+    assert_eq!(response.headers()["location"], "https://www.rust-lang.org/");
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/r/rust/stats")
+                .method("GET")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(&body[..], br#"{"redirects":1}"#);
+}
+
+#[tokio::test]
+async fn key_limits() {
+    let state: SharedState = Arc::new(RwLock::new(
+        AppState::default()
+            .with_key_limits(KeyLimits {
+                max_length: 16,
+                max_depth: 2,
+            })
+            .with_namespace_key_limits(
+                "deep",
+                KeyLimits {
+                    max_length: 16,
+                    max_depth: 4,
+                },
+            ),
+    ));
+    let mut app = router(&state);
+
+    for (key, status) in [
+        ("a-very-long-key-indeed", StatusCode::URI_TOO_LONG),
+        ("a%2Fb%2Fc", StatusCode::UNPROCESSABLE_ENTITY),
+        ("a%2Fb", StatusCode::OK),
+        ("deep%2Fb%2Fc", StatusCode::OK),
+    ] {
+        let response = app
+            .call(
+                Request::builder()
+                    .uri(format!("/kv/{}", key))
+                    .method("POST")
+                    .header("content-type", "text/plain")
+                    .body("Hello World".into())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), status, "{}", key);
+    }
+}
+
+#[tokio::test]
+async fn reserved_namespace() {
+    let state = SharedState::default();
+    let mut app = router(&state);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/__internal%2Fapi-keys")
+                .method("POST")
+                .header("content-type", "text/plain")
+                .body("Hello World".into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/__internal%2Fapi-keys/grayscale")
+                .method("GET")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn reserved_keys_in_bodies() {
+    let state = SharedState::default();
+    let mut app = router(&state);
+
+    let mut batch = |keys: &[&str], target: &str| {
+        let batch = serde_json::json!({
+            "keys": keys,
+            "pipeline": [{"op": "grayscale"}],
+            "target": target,
+        });
+        app.call(
+            Request::builder()
+                .uri("/transform/batch")
+                .method("POST")
+                .header("content-type", "application/json")
+                .body(batch.to_string().into())
+                .unwrap(),
+        )
+    };
+
+    for target in ["__internal/features/{key}", "__int{key}"] {
+        let response = batch(&["crab"], target).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN, "{}", target);
+    }
+
+    let response = batch(&["__bucket.other/secret"], "copy-{key}")
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let report: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(report["results"][0]["status"], 403);
+}
+
+#[tokio::test]
+async fn delete_request() {
+    let state = SharedState::default();
+    let mut app = router(&state);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/test")
+                .method("POST")
+                .header("content-type", "text/plain")
+                .body("Hello World".into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    for status in [StatusCode::OK, StatusCode::NOT_FOUND] {
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/kv/test")
+                    .method("DELETE")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), status);
+    }
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/test")
+                .method("GET")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn etag_not_modified() {
+    let state = SharedState::default();
+    let mut app = router(&state);
+    let write = |body: &'static str| {
+        Request::builder()
+            .uri("/kv/test")
+            .method("POST")
+            .header("content-type", "text/plain")
+            .body(body.into())
+            .unwrap()
+    };
+    let read = |etag: Option<&str>| {
+        let mut request = Request::builder().uri("/kv/test").method("GET");
+        if let Some(etag) = etag {
+            request = request.header("if-none-match", etag);
+        }
+        request.body(Body::empty()).unwrap()
+    };
+
+    app.call(write("Hello World")).await.unwrap();
+    let response = app.call(read(None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let etag = response.headers()["etag"].to_str().unwrap().to_string();
+    assert!(etag.starts_with('"') && etag.ends_with('"'));
+
+    let response = app.call(read(Some(&etag))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(response.headers()["etag"], etag.as_str());
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert!(body.is_empty());
+
+    let weak = format!("\"other\", W/{}", etag);
+    let response = app.call(read(Some(&weak))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+    app.call(write("Hello Crab")).await.unwrap();
+    let response = app.call(read(Some(&etag))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_ne!(response.headers()["etag"], etag.as_str());
+}
+
+#[tokio::test]
+async fn last_modified() {
+    let clock = MockClock::new();
+    let state = Arc::new(RwLock::new(AppState::default().with_clock(clock.clone())));
+    let mut app = router(&state);
+    let write = || {
+        Request::builder()
+            .uri("/kv/test")
+            .method("POST")
+            .header("content-type", "text/plain")
+            .body("Hello World".into())
+            .unwrap()
+    };
+    let read = |headers: &[(&str, &str)]| {
+        let mut request = Request::builder().uri("/kv/test").method("GET");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.body(Body::empty()).unwrap()
+    };
+
+    app.call(write()).await.unwrap();
+    let response = app.call(read(&[])).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let modified = response.headers()["last-modified"]
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let response = app
+        .call(read(&[("if-modified-since", &modified)]))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(response.headers()["last-modified"], modified.as_str());
+
+    // If-None-Match wins over If-Modified-Since
+    let response = app
+        .call(read(&[
+            ("if-modified-since", &modified),
+            ("if-none-match", "\"stale\""),
+        ]))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    clock.advance(Duration::from_secs(10));
+    app.call(write()).await.unwrap();
+    let response = app
+        .call(read(&[("if-modified-since", &modified)]))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_ne!(response.headers()["last-modified"], modified.as_str());
+}
+
+#[tokio::test]
+async fn localized_errors() {
+    let state = Arc::new(RwLock::new(AppState::default().with_message_catalog(
+        MessageTable::default().with("de", "not_found", "Schlüssel nicht gefunden"),
+    )));
+    let mut app = router(&state);
+
+    for (accept_language, language, message) in [
+        (
+            Some("de-CH, en;q=0.5"),
+            Some("de"),
+            "Schlüssel nicht gefunden",
+        ),
+        (Some("fr, de;q=0.1"), Some("de"), "Schlüssel nicht gefunden"),
+        (Some("fr"), None, "Key not found"),
+        (None, None, "Key not found"),
+    ] {
+        let mut request = Request::builder().uri("/kv/missing").method("GET");
+        if let Some(accept_language) = accept_language {
+            request = request.header("accept-language", accept_language);
+        }
+        let response = app
+            .call(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()["x-error-code"], "not_found");
+        assert_eq!(
+            response
+                .headers()
+                .get("content-language")
+                .map(|value| value.to_str().unwrap()),
+            language
+        );
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(std::str::from_utf8(&body).unwrap(), message);
+    }
+}
+
+#[tokio::test]
+async fn buckets() {
+    let state = SharedState::default();
+    let mut app = router(&state);
+
+    for (uri, body) in [
+        ("/bucket/app-a/kv/config", "a"),
+        ("/bucket/app-b/kv/config", "b"),
+        ("/bucket/app-b/kv/users%2F1", "b1"),
+        ("/kv/config", "flat"),
+    ] {
+        let response = app
+            .call(
+                Request::builder()
+                    .uri(uri)
+                    .method("POST")
+                    .header("content-type", "text/plain")
+                    .body(body.into())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    for (uri, expected) in [
+        ("/bucket/app-a/kv/config", &b"a"[..]),
+        ("/bucket/app-b/kv/config", b"b"),
+        ("/kv/config", b"flat"),
+        ("/bucket/app-a/kv", br#"["config"]"#),
+        ("/bucket/app-b/kv", br#"["config","users/1"]"#),
+        ("/bucket/app-c/kv", b"[]"),
+    ] {
+        let response = app
+            .call(
+                Request::builder()
+                    .uri(uri)
+                    .method("GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], expected);
+    }
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/bucket/app-a/kv/config")
+                .method("DELETE")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    for (uri, status) in [
+        ("/bucket/app-a/kv/config", StatusCode::NOT_FOUND),
+        ("/bucket/app-b/kv/config", StatusCode::OK),
+        ("/bucket/App%20A/kv", StatusCode::BAD_REQUEST),
+        ("/kv/__bucket.app-b%2Fconfig", StatusCode::FORBIDDEN),
+    ] {
+        let response = app
+            .call(
+                Request::builder()
+                    .uri(uri)
+                    .method("GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), status);
+    }
+}
+
+/// A backend that is always down
+struct Unavailable;
+
+#[async_trait]
+impl KVDatabase for Unavailable {
+    async fn read(&self, _: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        Err(KVError::backend("connection refused"))
+    }
+
+    async fn insert(&self, _: String, _: (String, Bytes)) -> Result<(), KVError> {
+        Err(KVError::backend("connection refused"))
+    }
+
+    async fn remove(&self, _: &str) -> Result<Option<(String, Bytes)>, KVError> {
+        Err(KVError::backend("connection refused"))
+    }
+
+    async fn keys(&self, _: &str) -> Result<Vec<String>, KVError> {
+        Err(KVError::backend("connection refused"))
+    }
+}
+
+#[tokio::test]
+async fn database_errors() {
+    let state = Arc::new(RwLock::new(AppState::default().with_database(Unavailable)));
+    let mut app = router(&state);
+
+    for method in ["POST", "GET", "DELETE"] {
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/kv/test")
+                    .method(method)
+                    .header("content-type", "text/plain")
+                    .body("Hello World".into())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"Storage error: connection refused");
+    }
+}
+
+#[tokio::test]
+async fn api_versions() {
+    let state = Arc::new(RwLock::new(AppState::default().with_message_catalog(
+        MessageTable::default().with("de", "not_found", "Schlüssel nicht gefunden"),
+    )));
+    let mut app = router(&state);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/v1/kv/test")
+                .method("POST")
+                .header("content-type", "text/plain")
+                .body("Hello World".into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // v1 and the unprefixed routes answer with the raw value
+    for uri in ["/kv/test", "/v1/kv/test", "/v2/kv/test/raw"] {
+        let response = app
+            .call(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"Hello World");
+    }
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/v2/kv/test")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "application/json");
+    let etag = response.headers()["etag"].clone();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let envelope: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(envelope["key"], "test");
+    assert_eq!(envelope["content_type"], "text/plain");
+    assert_eq!(envelope["size"], 11);
+    assert_eq!(envelope["etag"], etag.to_str().unwrap());
+    assert_eq!(envelope["data"], "SGVsbG8gV29ybGQ=");
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/v2/kv/test")
+                .header("if-none-match", etag)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+    // v1 errors stay plain text, v2 errors are JSON, translated as well
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/v1/kv/missing")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(&body[..], b"Key not found");
+
+    for (accept_language, message) in [
+        (None, "Key not found"),
+        (Some("de"), "Schlüssel nicht gefunden"),
+    ] {
+        let mut request = Request::builder().uri("/v2/kv/missing");
+        if let Some(accept_language) = accept_language {
+            request = request.header("accept-language", accept_language);
+        }
+        let response = app
+            .call(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()["x-error-code"], "not_found");
+        assert_eq!(response.headers()["content-type"], "application/json");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error["error"]["code"], "not_found");
+        assert_eq!(error["error"]["message"], message);
+    }
+
+    // The prefix doesn't get around the reserved namespace
+    for uri in ["/v1/kv/__internal%2Fx", "/v2/kv/__internal%2Fx"] {
+        let response = app
+            .call(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+}
+
+#[tokio::test]
+async fn request_ids() {
+    let state = SharedState::default();
+    let mut app = router(&state);
+
+    // Generated when the client sends none, different for every request
+    let mut ids = Vec::new();
+    for _ in 0..2 {
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/hello")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let id = response.headers()["x-request-id"]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert_eq!(id.len(), 32);
+        ids.push(id);
+    }
+    assert_ne!(ids[0], ids[1]);
+
+    // Propagated from the client, and part of v2 error bodies
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/v2/kv/missing")
+                .header("x-request-id", "from-the-proxy")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(response.headers()["x-request-id"], "from-the-proxy");
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(error["error"]["request_id"], "from-the-proxy");
+
+    // v1 bodies are frozen, the ID is only in the header
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/v1/kv/missing")
+                .header("x-request-id", "from-the-proxy")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["x-request-id"], "from-the-proxy");
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(&body[..], b"Key not found");
+
+    // IDs that don't fit in a log line are replaced
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/hello")
+                .header("x-request-id", "x".repeat(200))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["x-request-id"].len(), 32);
+}
+
+#[tokio::test]
+async fn range_requests() {
+    let state = SharedState::default();
+    let mut app = router(&state);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/blob")
+                .method("POST")
+                .header("content-type", "application/octet-stream")
+                .body("0123456789".into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    for (range, status, content_range, body) in [
+        (None, StatusCode::OK, None, "0123456789"),
+        (
+            Some("bytes=2-4"),
+            StatusCode::PARTIAL_CONTENT,
+            Some("bytes 2-4/10"),
+            "234",
+        ),
+        (
+            Some("bytes=7-"),
+            StatusCode::PARTIAL_CONTENT,
+            Some("bytes 7-9/10"),
+            "789",
+        ),
+        (
+            Some("bytes=-3"),
+            StatusCode::PARTIAL_CONTENT,
+            Some("bytes 7-9/10"),
+            "789",
+        ),
+        (
+            Some("bytes=8-100"),
+            StatusCode::PARTIAL_CONTENT,
+            Some("bytes 8-9/10"),
+            "89",
+        ),
+        (
+            Some("bytes=10-"),
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            Some("bytes */10"),
+            "",
+        ),
+        (Some("bytes=0-1,4-5"), StatusCode::OK, None, "0123456789"),
+        (Some("items=0-1"), StatusCode::OK, None, "0123456789"),
+    ] {
+        let mut request = Request::builder().uri("/kv/blob");
+        if let Some(range) = range {
+            request = request.header("range", range);
+        }
+        let response = app
+            .call(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), status, "{:?}", range);
+        assert_eq!(response.headers()["accept-ranges"], "bytes");
+        assert_eq!(
+            response
+                .headers()
+                .get("content-range")
+                .map(|value| value.to_str().unwrap()),
+            content_range
+        );
+        let body_bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(std::str::from_utf8(&body_bytes).unwrap(), body);
+    }
+
+    // Only resume from a copy that's still current
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/blob")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let etag = response.headers()["etag"].to_str().unwrap().to_string();
+    for (if_range, status) in [
+        ("\"outdated\"", StatusCode::OK),
+        (etag.as_str(), StatusCode::PARTIAL_CONTENT),
+    ] {
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/kv/blob")
+                    .header("range", "bytes=0-0")
+                    .header("if-range", if_range)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), status);
+    }
+}
+
+#[tokio::test]
+async fn header_validation() {
+    let state = Arc::new(RwLock::new(AppState::default().with_request_limits(
+        RequestLimits {
+            max_body_bytes: 10,
+            max_header_bytes: 1024,
+        },
+    )));
+    let mut app = router(&state);
+
+    for (headers, status, code) in [
+        (
+            vec![("content-length", "11")],
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "body_too_large",
+        ),
+        (
+            vec![("content-length", "eleven")],
+            StatusCode::BAD_REQUEST,
+            "bad_request",
+        ),
+        (
+            vec![("transfer-encoding", "gzip, chunked")],
+            StatusCode::NOT_IMPLEMENTED,
+            "unsupported_transfer_encoding",
+        ),
+        (
+            vec![("content-type", "not a type")],
+            StatusCode::BAD_REQUEST,
+            "bad_request",
+        ),
+        (
+            vec![("x-padding", &"x".repeat(1024))],
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            "headers_too_large",
+        ),
+    ] {
+        let mut request = Request::builder().uri("/kv/test").method("POST");
+        if !headers.iter().any(|(name, _)| *name == "content-type") {
+            request = request.header("content-type", "text/plain");
+        }
+        for (name, value) in &headers {
+            request = request.header(*name, *value);
+        }
+        let response = app
+            .call(request.body("Hello".into()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), status, "{:?}", headers);
+        assert_eq!(response.headers()["x-error-code"], code);
+    }
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/test")
+                .method("POST")
+                .header("content-type", "Text/Plain ;Charset=UTF-8")
+                .header("content-length", "5")
+                .body("Hello".into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/test")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        response.headers()["content-type"],
+        "text/plain; charset=utf-8"
+    );
+}
+
+/// A body of `len` bytes sent in chunks, without a Content-Length
+fn chunked_body(len: usize) -> (Body, Vec<u8>) {
+    let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+    let chunks: Vec<Result<Vec<u8>, std::io::Error>> = data
+        .chunks(64 * 1024)
+        .map(|chunk| Ok(chunk.to_vec()))
+        .collect();
+    (Body::wrap_stream(futures::stream::iter(chunks)), data)
+}
+
+#[tokio::test]
+async fn streaming_uploads() {
+    let state = Arc::new(RwLock::new(AppState::default().with_request_limits(
+        RequestLimits {
+            max_body_bytes: 3 * 1024 * 1024,
+            ..RequestLimits::default()
+        },
+    )));
+    let mut app = router(&state);
+
+    // Larger than what's kept in memory while receiving
+    let (body, data) = chunked_body(2 * 1024 * 1024);
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/large")
+                .method("POST")
+                .header("content-type", "application/octet-stream")
+                .body(body)
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/large")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(body.len(), data.len());
+    assert!(body[..] == data[..]);
+
+    let (body, _) = chunked_body(4 * 1024 * 1024);
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/too-large")
+                .method("POST")
+                .header("content-type", "application/octet-stream")
+                .body(body)
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(response.headers()["x-error-code"], "body_too_large");
+}
+
+#[tokio::test]
+async fn upload_checksums() {
+    let state = SharedState::default();
+    let mut app = router(&state);
+    let md5 = "sQqNsWTgdUEFt6mb5y4/5Q==";
+    let sha256_hex = "a591a6d40bf420404a011733cfb7b190d62c65bf0bcda32b57b277d9ad9f146e";
+    let sha256 = "pZGm1Av0IEBKARczz7exkNYsZb8LzaMrV7J32a2fFG4=";
+
+    for (key, headers, status) in [
+        (
+            "verified",
+            vec![("content-md5", md5), ("x-checksum-sha256", sha256_hex)],
+            StatusCode::OK,
+        ),
+        (
+            "wrong-md5",
+            vec![("content-md5", "AAAAAAAAAAAAAAAAAAAAAA==")],
+            StatusCode::UNPROCESSABLE_ENTITY,
+        ),
+        (
+            "malformed-sha256",
+            vec![("x-checksum-sha256", md5)],
+            StatusCode::BAD_REQUEST,
+        ),
+    ] {
+        let mut request = Request::builder()
+            .uri(format!("/kv/{}", key))
+            .method("POST")
+            .header("content-type", "text/plain");
+        for (name, value) in headers {
+            request = request.header(name, value);
+        }
+        let response = app
+            .call(request.body("Hello World".into()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), status, "{}", key);
+    }
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/verified")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["content-md5"], md5);
+    assert_eq!(response.headers()["x-checksum-sha256"], sha256);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/wrong-md5")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn image_policy() {
+    let state = Arc::new(RwLock::new(
+        AppState::default().with_namespace_image_policy(
+            "avatars",
+            ImagePolicy {
+                encoding: ImageEncoding::Jpeg { quality: 80 },
+                max_width: 4,
+                max_height: 4,
+                ..ImagePolicy::default()
+            },
+        ),
+    ));
+    let mut app = router(&state);
+    let mut png = Vec::new();
+    image::RgbaImage::new(8, 4)
+        .write_to(
+            &mut std::io::Cursor::new(&mut png),
+            image::ImageOutputFormat::Png,
+        )
+        .unwrap();
+
+    for (key, content_type, body, status) in [
+        (
+            "avatars%2Fcrab.png",
+            "image/png",
+            png.clone(),
+            StatusCode::OK,
+        ),
+        (
+            "avatars%2Fnotes",
+            "text/plain",
+            b"notes".to_vec(),
+            StatusCode::OK,
+        ),
+        (
+            "avatars%2Fbroken.png",
+            "image/png",
+            b"not a png".to_vec(),
+            StatusCode::UNPROCESSABLE_ENTITY,
+        ),
+        ("other%2Fcrab.png", "image/png", png.clone(), StatusCode::OK),
+    ] {
+        let response = app
+            .call(
+                Request::builder()
+                    .uri(format!("/kv/{}", key))
+                    .method("POST")
+                    .header("content-type", content_type)
+                    .body(body.into())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), status, "{}", key);
+    }
+
+    for (key, content_type, dimensions) in [
+        ("avatars%2Fcrab.png", "image/jpeg", Some((4, 2))),
+        ("avatars%2Fnotes", "text/plain", None),
+        ("other%2Fcrab.png", "image/png", Some((8, 4))),
+    ] {
+        let response = app
+            .call(
+                Request::builder()
+                    .uri(format!("/kv/{}", key))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["content-type"], content_type);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        if let Some(dimensions) = dimensions {
+            let image = image::load_from_memory(&body).unwrap();
+            assert_eq!((image.width(), image.height()), dimensions);
+        }
+    }
+
+    // Transparency goes onto the background the upload asks for
+    for (background, status) in [("ff0000", StatusCode::OK), ("red", StatusCode::BAD_REQUEST)] {
+        let response = app
+            .call(
+                Request::builder()
+                    .uri(format!("/kv/avatars%2Fred.png?background={}", background))
+                    .method("POST")
+                    .header("content-type", "image/png")
+                    .body(png.clone().into())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), status, "{}", background);
+    }
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/avatars%2Fred.png")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let [r, g, b] = image::load_from_memory(&body)
+        .unwrap()
+        .to_rgb8()
+        .get_pixel(1, 1)
+        .0;
+    assert!(r > 240 && g < 16 && b < 16, "{:?}", [r, g, b]);
+}
+
+#[tokio::test]
+async fn flatten_transparency() {
+    let state = SharedState::default();
+    let mut app = router(&state);
+    // Left half transparent, right half opaque red
+    let image = image::RgbaImage::from_fn(4, 2, |x, _| match x {
+        0 | 1 => image::Rgba([0, 0, 0, 0]),
+        _ => image::Rgba([255, 0, 0, 255]),
+    });
+    let mut png = Vec::new();
+    image
+        .write_to(
+            &mut std::io::Cursor::new(&mut png),
+            image::ImageOutputFormat::Png,
+        )
+        .unwrap();
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/sprite")
+                .method("POST")
+                .header("content-type", "image/png")
+                .body(png.into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    for (query, transparent) in [("", [255, 255, 255]), ("?background=00ff00", [0, 255, 0])] {
+        let response = app
+            .call(
+                Request::builder()
+                    .uri(format!("/kv/sprite/flatten{}", query))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let image = image::load_from_memory(&body).unwrap();
+        assert!(!image.color().has_alpha());
+        let image = image.to_rgb8();
+        assert_eq!(image.get_pixel(0, 0).0, transparent);
+        assert_eq!(image.get_pixel(3, 0).0, [255, 0, 0]);
+    }
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/sprite/flatten?background=white")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+fn encode_png(image: image::RgbImage) -> Vec<u8> {
+    let mut png = Vec::new();
+    image
+        .write_to(
+            &mut std::io::Cursor::new(&mut png),
+            image::ImageOutputFormat::Png,
+        )
+        .unwrap();
+    png
+}
+
+#[tokio::test]
+async fn image_tiles() {
+    let state = SharedState::default();
+    let mut app = router(&state);
+    // Left half red, right half blue
+    let image = image::RgbImage::from_fn(600, 300, |x, _| match x {
+        0..=299 => image::Rgb([255, 0, 0]),
+        _ => image::Rgb([0, 0, 255]),
+    });
+    let post = |png: Vec<u8>| {
+        Request::builder()
+            .uri("/kv/map")
+            .method("POST")
+            .header("content-type", "image/png")
+            .body(Body::from(png))
+            .unwrap()
+    };
+    let get = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+    let response = app.call(post(encode_png(image))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = app.call(get("/kv/map/tiles")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(
+        &body[..],
+        br#"{"width":600,"height":300,"tile_size":256,"max_level":10}"#
+    );
+
+    for (uri, size, color) in [
+        ("/kv/map/tiles/10/0/0", (256, 256), [255, 0, 0]),
+        ("/kv/map/tiles/10/2/1", (88, 44), [0, 0, 255]),
+        ("/kv/map/tiles/9/1/0", (44, 150), [0, 0, 255]),
+        // Cached by now
+        ("/kv/map/tiles/10/0/0", (256, 256), [255, 0, 0]),
+    ] {
+        let response = app.call(get(uri)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "{}", uri);
+        assert_eq!(response.headers()["content-type"], "image/png");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let tile = image::load_from_memory(&body).unwrap().to_rgb8();
+        assert_eq!(tile.dimensions(), size, "{}", uri);
+        assert_eq!(tile.get_pixel(0, 0).0, color, "{}", uri);
+    }
+
+    // Down to a single pixel
+    let response = app.call(get("/kv/map/tiles/0/0/0")).await.unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let tile = image::load_from_memory(&body).unwrap();
+    assert_eq!((tile.width(), tile.height()), (1, 1));
+
+    for uri in ["/kv/map/tiles/10/3/0", "/kv/map/tiles/11/0/0"] {
+        let response = app.call(get(uri)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", uri);
+    }
+
+    // A new value doesn't get the old value's tiles
+    let green = image::RgbImage::from_pixel(600, 300, image::Rgb([0, 255, 0]));
+    let response = app.call(post(encode_png(green))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app.call(get("/kv/map/tiles/10/0/0")).await.unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let tile = image::load_from_memory(&body).unwrap().to_rgb8();
+    assert_eq!(tile.get_pixel(0, 0).0, [0, 255, 0]);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/notes")
+                .method("POST")
+                .header("content-type", "text/plain")
+                .body("Hello World".into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app.call(get("/kv/notes/tiles/0/0/0")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn smart_thumbnails() {
+    let state = SharedState::default();
+    let mut app = router(&state);
+    // Plain white, with a checkered subject near the right edge
+    let image = image::RgbImage::from_fn(300, 100, |x, y| match x {
+        220..=279 if (x / 4 + y / 4) % 2 == 0 => image::Rgb([0, 0, 0]),
+        _ => image::Rgb([255, 255, 255]),
+    });
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/portrait")
+                .method("POST")
+                .header("content-type", "image/png")
+                .body(encode_png(image).into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let mut thumbnail = |query: &'static str| {
+        let response = app.call(
+            Request::builder()
+                .uri(format!("/kv/portrait/thumbnail?{}", query))
+                .body(Body::empty())
+                .unwrap(),
+        );
+        async move {
+            let response = response.await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{}", query);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            image::load_from_memory(&body).unwrap().to_luma8()
+        }
+    };
+    let has_subject = |image: &image::GrayImage| image.pixels().any(|pixel| pixel.0[0] < 128);
+
+    // The center crop misses the subject, the smart one finds it
+    let centered = thumbnail("width=50&height=50").await;
+    assert_eq!(centered.dimensions(), (50, 50));
+    assert!(!has_subject(&centered));
+    let smart = thumbnail("width=50&height=50&gravity=smart").await;
+    assert_eq!(smart.dimensions(), (50, 50));
+    assert!(has_subject(&smart));
+    // Nothing to crop when the aspect ratio matches
+    let whole = thumbnail("width=150&height=50&gravity=smart").await;
+    assert!(has_subject(&whole));
+
+    for query in ["width=0&height=50", "width=50&height=50&gravity=left"] {
+        let response = app
+            .call(
+                Request::builder()
+                    .uri(format!("/kv/portrait/thumbnail?{}", query))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", query);
+    }
+}
+
+#[tokio::test]
+async fn transform_etags() {
+    let state = SharedState::default();
+    let mut app = router(&state);
+    let post = |color: [u8; 3]| {
+        let image = image::RgbImage::from_pixel(8, 8, image::Rgb(color));
+        Request::builder()
+            .uri("/kv/logo")
+            .method("POST")
+            .header("content-type", "image/png")
+            .body(Body::from(encode_png(image)))
+            .unwrap()
+    };
+    let get = |uri: &str, etag: Option<&str>| {
+        let request = Request::builder().uri(uri);
+        match etag {
+            Some(etag) => request.header("if-none-match", etag),
+            None => request,
+        }
+        .body(Body::empty())
+        .unwrap()
+    };
+    let response = app.call(post([255, 0, 0])).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let mut etags = Vec::new();
+    for uri in [
+        "/kv/logo/flatten",
+        "/kv/logo/flatten?background=000000",
+        "/kv/logo/grayscale",
+        "/kv/logo/thumbnail?width=4&height=4",
+        "/kv/logo/tiles/3/0/0",
+    ] {
+        let response = app.call(get(uri, None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "{}", uri);
+        let etag = response.headers()["etag"].to_str().unwrap().to_string();
+
+        let response = app.call(get(uri, Some(&etag))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED, "{}", uri);
+        assert_eq!(response.headers()["etag"], etag.as_str());
+        etags.push(etag);
+    }
+    // Every operation and parameter has its own, none is the source's
+    let response = app.call(get("/kv/logo", None)).await.unwrap();
+    etags.push(response.headers()["etag"].to_str().unwrap().to_string());
+    let mut unique = etags.clone();
+    unique.sort();
+    unique.dedup();
+    assert_eq!(unique.len(), etags.len());
+
+    // Rewriting the source invalidates its transforms
+    let response = app.call(post([0, 0, 255])).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app
+        .call(get("/kv/logo/flatten", Some(&etags[0])))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_ne!(response.headers()["etag"], etags[0].as_str());
+    let response = app
+        .call(get("/kv/logo/tiles/3/0/0", Some(&etags[4])))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn batch_transforms() {
+    let state = SharedState::default();
+    let mut app = router(&state);
+    let mut post = |uri: &str, content_type: &str, body: Body| {
+        app.call(
+            Request::builder()
+                .uri(uri)
+                .method("POST")
+                .header("content-type", content_type)
+                .body(body)
+                .unwrap(),
+        )
+    };
+    for key in ["red", "blue"] {
+        let color = if key == "red" {
+            [255, 0, 0]
+        } else {
+            [0, 0, 255]
+        };
+        let image = image::RgbImage::from_pixel(40, 20, image::Rgb(color));
+        let response = post(
+            &format!("/kv/{}", key),
+            "image/png",
+            encode_png(image).into(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let response = post("/kv/notes", "text/plain", "Hello".into())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let batch = serde_json::json!({
+        "keys": ["red", "blue", "notes", "missing"],
+        "pipeline": [
+            {"op": "grayscale"},
+            {"op": "thumbnail", "width": 10, "height": 10},
+        ],
+        "target": "thumbs-{key}",
+    });
+    let response = post(
+        "/transform/batch",
+        "application/json",
+        batch.to_string().into(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let report: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let results = report["results"].as_array().unwrap();
+    let statuses: Vec<_> = results
+        .iter()
+        .map(|result| {
+            (
+                result["target"].as_str().unwrap(),
+                result["status"].as_u64().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        statuses,
+        [
+            ("thumbs-red", 200),
+            ("thumbs-blue", 200),
+            ("thumbs-notes", 403),
+            ("thumbs-missing", 404),
+        ]
+    );
+    assert_eq!(results[3]["error"], "not_found");
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/thumbs-red")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "image/png");
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let thumbnail = image::load_from_memory(&body).unwrap();
+    assert_eq!((thumbnail.width(), thumbnail.height()), (10, 10));
+    assert!(matches!(thumbnail, image::DynamicImage::ImageLuma8(_)));
+
+    // Bad pipelines fail the whole batch before any work is done
+    for batch in [
+        serde_json::json!({"keys": ["red"], "pipeline": [], "target": "t-{key}"}),
+        serde_json::json!({"keys": ["red"], "pipeline": [{"op": "grayscale"}], "target": "t"}),
+        serde_json::json!({
+            "keys": ["red"],
+            "pipeline": [{"op": "flatten", "background": "nope"}],
+            "target": "t-{key}",
+        }),
+    ] {
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/transform/batch")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(batch.to_string().into())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", batch);
+    }
+}
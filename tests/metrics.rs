@@ -0,0 +1,204 @@
+use std::{
+    net::UdpSocket,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+};
+
+use microservice_rust_workshop::{
+    router, spawn_runtime_metrics, AppState, MockClock, SharedState, StatsdMetrics,
+};
+use tower::Service; // for `call`
+
+fn post_text() -> Request<Body> {
+    Request::builder()
+        .uri("/kv/test")
+        .method("POST")
+        .header("content-type", "text/plain")
+        .body("Hello World".into())
+        .unwrap()
+}
+
+fn get(uri: &str) -> Request<Body> {
+    Request::builder()
+        .uri(uri)
+        .method("GET")
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[tokio::test]
+async fn no_metrics_by_default() {
+    let state = SharedState::default();
+    let mut app = router(&state);
+
+    let response = app.call(get("/metrics")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn statsd_metrics() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let metrics = StatsdMetrics::new(server.local_addr().unwrap(), "kv.").unwrap();
+    let state = Arc::new(RwLock::new(AppState::default().with_metrics(metrics)));
+    let mut app = router(&state);
+
+    app.call(post_text()).await.unwrap();
+    app.call(get("/kv/missing")).await.unwrap();
+
+    let mut packet = [0; 512];
+    let mut received = Vec::new();
+    while received.len() < 2 {
+        let len = server.recv(&mut packet).unwrap();
+        let packet = String::from_utf8(packet[..len].to_vec()).unwrap();
+        // Every request is timed as well
+        if !packet.starts_with("kv.kv_http_request_duration_seconds:") {
+            received.push(packet);
+        }
+    }
+    assert_eq!(
+        received,
+        [
+            "kv.kv_writes_total:1|c",
+            "kv.kv_reads_total:1|c|#result:miss"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn deprecated_route_usage() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let metrics = StatsdMetrics::new(server.local_addr().unwrap(), "kv.").unwrap();
+    let state = Arc::new(RwLock::new(AppState::default().with_metrics(metrics)));
+    let mut app = router(&state);
+
+    app.call(get("/hello")).await.unwrap();
+
+    let mut packet = [0; 512];
+    let len = server.recv(&mut packet).unwrap();
+    assert_eq!(
+        &packet[..len],
+        b"kv.kv_deprecated_requests_total:1|c|#route:/hello"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn runtime_metrics() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let metrics = StatsdMetrics::new(server.local_addr().unwrap(), "kv.").unwrap();
+    let state = Arc::new(RwLock::new(AppState::default().with_metrics(metrics)));
+
+    spawn_runtime_metrics(&state, Duration::from_secs(60));
+
+    let mut packet = [0; 512];
+    let len = server.recv(&mut packet).unwrap();
+    assert_eq!(&packet[..len], b"kv.tokio_workers:2|g");
+}
+
+#[cfg(feature = "prometheus")]
+#[tokio::test]
+async fn prometheus_metrics() {
+    use microservice_rust_workshop::PrometheusMetrics;
+
+    let state = Arc::new(RwLock::new(
+        AppState::default().with_metrics(PrometheusMetrics::new()),
+    ));
+    let mut app = router(&state);
+
+    app.call(post_text()).await.unwrap();
+    app.call(get("/kv/test")).await.unwrap();
+    app.call(get("/kv/test")).await.unwrap();
+
+    let response = app.call(get("/metrics")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains("kv_writes_total 1\n"));
+    assert!(body.contains("kv_reads_total{result=\"hit\"} 2\n"));
+}
+
+#[cfg(feature = "prometheus")]
+#[tokio::test]
+async fn prometheus_exemplars() {
+    use microservice_rust_workshop::PrometheusMetrics;
+
+    let state = Arc::new(RwLock::new(
+        AppState::default().with_metrics(PrometheusMetrics::new()),
+    ));
+    let mut app = router(&state);
+
+    let request = Request::builder()
+        .uri("/kv/test")
+        .header(
+            "traceparent",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        )
+        .body(Body::empty())
+        .unwrap();
+    app.call(request).await.unwrap();
+    app.call(post_text()).await.unwrap();
+
+    let request = Request::builder()
+        .uri("/metrics")
+        .header("accept", "application/openmetrics-text; version=1.0.0")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.call(request).await.unwrap();
+    assert!(response.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("application/openmetrics-text"));
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.ends_with("# EOF\n"));
+    assert!(body.contains("# TYPE kv_writes counter\nkv_writes_total 1\n"));
+    let exemplars: Vec<&str> = body
+        .lines()
+        .filter(|line| line.contains("# {trace_id="))
+        .collect();
+    assert_eq!(exemplars.len(), 1);
+    assert!(exemplars[0].starts_with("kv_http_request_duration_seconds_bucket{"));
+    assert!(exemplars[0].contains("method=\"GET\""));
+    assert!(exemplars[0].contains("{trace_id=\"4bf92f3577b34da6a3ce929d0e0e4736\"}"));
+}
+
+#[tokio::test]
+async fn red_summary() {
+    let clock = MockClock::new();
+    let state = Arc::new(RwLock::new(AppState::default().with_clock(clock.clone())));
+    let mut app = router(&state);
+
+    app.call(post_text()).await.unwrap();
+    app.call(get("/kv/test")).await.unwrap();
+    app.call(get("/kv/missing")).await.unwrap();
+    clock.advance(Duration::from_secs(120));
+    app.call(get("/v2/kv/test")).await.unwrap();
+
+    let response = app.call(get("/admin/red")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let routes: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let route = |method: &str, route: &str| {
+        routes
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|summary| summary["method"] == method && summary["route"] == route)
+            .unwrap()
+            .clone()
+    };
+    let reads = route("GET", "/kv/:key");
+    // Older requests only count in the longer windows
+    assert_eq!(reads["windows"]["1m"]["requests"], 0);
+    assert_eq!(reads["windows"]["5m"]["requests"], 2);
+    assert_eq!(reads["windows"]["5m"]["errors"], 0);
+    assert_eq!(reads["windows"]["15m"]["rate"], 2.0 / 900.0);
+    let reads = route("GET", "/v2/kv/:key");
+    assert_eq!(reads["windows"]["1m"]["requests"], 1);
+    assert!(reads["windows"]["1m"]["duration_p99"].as_f64().unwrap() < 10.0);
+    assert_eq!(route("POST", "/kv/:key")["windows"]["15m"]["requests"], 1);
+}
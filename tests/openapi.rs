@@ -0,0 +1,112 @@
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+};
+use microservice_rust_workshop::{api_paths, router, SharedState};
+use serde_json::Value;
+use tower::Service; // for `call`
+
+/// Every `$ref` in `value`
+fn refs<'a>(value: &'a Value, found: &mut Vec<&'a str>) {
+    match value {
+        Value::Object(object) => {
+            for (name, value) in object {
+                match (name.as_str(), value) {
+                    ("$ref", Value::String(target)) => found.push(target),
+                    _ => refs(value, found),
+                }
+            }
+        }
+        Value::Array(values) => values.iter().for_each(|value| refs(value, found)),
+        _ => {}
+    }
+}
+
+#[tokio::test]
+async fn serves_openapi_document() {
+    let state = SharedState::default();
+    let mut app = router(&state);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/openapi.json")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "application/json");
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let spec: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(spec["openapi"], "3.0.3");
+    for path in ["/kv/{key}", "/kv/{key}/thumbnail", "/transform/batch"] {
+        assert!(spec["paths"][path].is_object(), "{} is missing", path);
+    }
+
+    // References point into the document
+    let mut found = Vec::new();
+    refs(&spec, &mut found);
+    assert!(!found.is_empty());
+    for target in found {
+        let pointer = target.strip_prefix('#').unwrap();
+        assert!(
+            spec.pointer(pointer).is_some(),
+            "{} doesn't resolve",
+            target
+        );
+    }
+
+    let response = app
+        .call(Request::builder().uri("/docs").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert!(String::from_utf8_lossy(&body).contains("openapi.json"));
+    // Served with the page rather than from a CDN
+    for (asset, content_type) in [
+        ("/docs/swagger-ui.css", "text/css"),
+        ("/docs/swagger-ui-bundle.js", "application/javascript"),
+    ] {
+        let response = app
+            .call(Request::builder().uri(asset).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], content_type);
+    }
+}
+
+#[tokio::test]
+async fn openapi_document_matches_routes() {
+    let state = SharedState::default();
+    let mut app = router(&state);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/openapi.json")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let spec: Value = serde_json::from_slice(&body).unwrap();
+    let documented = spec["paths"].as_object().unwrap();
+    let routed = api_paths();
+    for path in documented.keys() {
+        assert!(routed.contains(path), "{} isn't routed", path);
+    }
+    // v2 paths are only documented where they differ from v1
+    for path in &routed {
+        let v1 = path.strip_prefix("/v2").unwrap_or(path);
+        assert!(
+            documented.contains_key(path) || documented.contains_key(v1),
+            "{} isn't documented",
+            path
+        );
+    }
+}
@@ -0,0 +1,97 @@
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+};
+use hyper::client::conn;
+use microservice_rust_workshop::{
+    close_database, router, serve_on, spawn_expiry_sweeper, spawn_hot_key_snapshots,
+    spawn_refresh_scheduler, AppState, Listen, ServerOptions, SharedState, Shutdown,
+};
+use tokio::net::TcpStream;
+use tower::Service; // for `call`
+
+#[tokio::test]
+async fn graceful_shutdown() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let state: SharedState = Arc::new(RwLock::new(AppState::default()));
+    let shutdown = Shutdown::default();
+    let options = ServerOptions {
+        shutdown: shutdown.clone(),
+        ..ServerOptions::default()
+    };
+    let app = router(&state);
+    let server =
+        tokio::spawn(async move { serve_on(Listen::Inherited(listener), app, &options).await });
+
+    // An upload that is still coming in when the shutdown starts
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (mut sender, connection) = conn::handshake(stream).await.unwrap();
+    tokio::spawn(connection);
+    let (mut body, request_body) = Body::channel();
+    let response = tokio::spawn(
+        sender.send_request(
+            Request::builder()
+                .uri("/kv/a")
+                .method("POST")
+                .header("content-type", "text/plain")
+                .body(request_body)
+                .unwrap(),
+        ),
+    );
+    body.send_data("Hello ".into()).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    shutdown.trigger();
+    assert!(shutdown.is_triggered());
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!server.is_finished());
+    // New connections are refused
+    assert!(TcpStream::connect(addr).await.is_err());
+
+    body.send_data("World".into()).await.unwrap();
+    drop(body);
+    let response = response.await.unwrap().unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+
+    close_database(&state).await.unwrap();
+    let response = router(&state)
+        .call(Request::builder().uri("/kv/a").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(&body[..], b"Hello World");
+}
+
+#[tokio::test]
+async fn background_tasks_end_with_the_shutdown() {
+    let state = SharedState::default();
+    let shutdown = Shutdown::default();
+    let period = Duration::from_millis(10);
+    let tasks = [
+        spawn_expiry_sweeper(Arc::clone(&state), period, shutdown.clone()),
+        spawn_refresh_scheduler(Arc::clone(&state), period, shutdown.clone()),
+        spawn_hot_key_snapshots(Arc::clone(&state), period, 10, shutdown.clone()),
+    ];
+    tokio::time::sleep(period * 3).await;
+    assert!(tasks.iter().all(|task| !task.is_finished()));
+
+    shutdown.trigger();
+    for task in tasks {
+        tokio::time::timeout(Duration::from_secs(1), task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+    close_database(&state).await.unwrap();
+}
@@ -0,0 +1,122 @@
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+};
+
+use microservice_rust_workshop::{router, AppState, MockClock, SharedState};
+use tower::Service; // for `call`
+
+#[tokio::test]
+async fn static_site() {
+    let state: SharedState = Arc::new(RwLock::new(AppState::default().with_static_site("docs")));
+    let mut app = router(&state);
+
+    for (key, content_type, body) in [
+        ("docs%2Findex.html", "text/html", "<h1>Home</h1>"),
+        ("docs%2Fguide%2Findex.html", "text/html", "<h1>Guide</h1>"),
+        ("docs%2Fstyle.css", "text/css", "h1 { color: red }"),
+        ("docs%2F404.html", "text/html", "<h1>Lost</h1>"),
+    ] {
+        let response = app
+            .call(
+                Request::builder()
+                    .uri(format!("/kv/{}", key))
+                    .method("POST")
+                    .header("content-type", content_type)
+                    .body(body.into())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    for (uri, status, content_type, expected) in [
+        ("/site/docs", StatusCode::OK, "text/html", "<h1>Home</h1>"),
+        (
+            "/site/docs/guide",
+            StatusCode::OK,
+            "text/html",
+            "<h1>Guide</h1>",
+        ),
+        (
+            "/site/docs/guide/",
+            StatusCode::OK,
+            "text/html",
+            "<h1>Guide</h1>",
+        ),
+        (
+            "/site/docs/style.css",
+            StatusCode::OK,
+            "text/css",
+            "h1 { color: red }",
+        ),
+        (
+            "/site/docs/missing",
+            StatusCode::NOT_FOUND,
+            "text/html",
+            "<h1>Lost</h1>",
+        ),
+    ] {
+        let response = app
+            .call(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), status, "{}", uri);
+        assert_eq!(response.headers()["content-type"], content_type);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], expected.as_bytes());
+    }
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/site/private")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn expired_pages() {
+    let clock = MockClock::new();
+    let state: SharedState = Arc::new(RwLock::new(
+        AppState::default()
+            .with_clock(clock.clone())
+            .with_static_site("docs"),
+    ));
+    let mut app = router(&state);
+
+    let response = app
+        .call(
+            Request::builder()
+                .uri("/kv/docs%2Fnews.html?ttl=60")
+                .method("POST")
+                .header("content-type", "text/html")
+                .body("<h1>News</h1>".into())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let mut page = || {
+        app.call(
+            Request::builder()
+                .uri("/site/docs/news.html")
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+    assert_eq!(page().await.unwrap().status(), StatusCode::OK);
+    clock.advance(Duration::from_secs(60));
+    assert_eq!(page().await.unwrap().status(), StatusCode::NOT_FOUND);
+}
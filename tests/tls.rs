@@ -0,0 +1,121 @@
+use std::{fs::File, io::BufReader, net::SocketAddr, sync::Arc, time::Duration};
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+};
+use hyper::client::conn;
+use microservice_rust_workshop::{
+    redirect_to_https, router, serve_on, Listen, ServerOptions, SharedState, TlsOptions,
+};
+use tokio::net::TcpStream;
+use tokio_rustls::{
+    rustls::{crypto::ring, pki_types::ServerName, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+
+fn tls_options() -> TlsOptions {
+    TlsOptions {
+        cert_path: "tests/fixtures/tls-cert.pem".into(),
+        key_path: "tests/fixtures/tls-key.pem".into(),
+    }
+}
+
+fn connector() -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    let mut ca = BufReader::new(File::open("tests/fixtures/tls-ca.pem").unwrap());
+    for cert in rustls_pemfile::certs(&mut ca) {
+        roots.add(cert.unwrap()).unwrap();
+    }
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+/// Binds a free port on localhost
+fn listener() -> std::net::TcpListener {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    listener
+}
+
+#[tokio::test]
+async fn https() {
+    let listener = listener();
+    let addr = listener.local_addr().unwrap();
+    let state = SharedState::default();
+    let options = ServerOptions {
+        tls: Some(tls_options()),
+        ..ServerOptions::default()
+    };
+    let app = router(&state);
+    tokio::spawn(async move { serve_on(Listen::Inherited(listener), app, &options).await });
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let name = ServerName::try_from("localhost").unwrap();
+    let stream = connector().connect(name, stream).await.unwrap();
+    let (mut sender, connection) = conn::handshake(stream).await.unwrap();
+    tokio::spawn(connection);
+    let response = sender
+        .send_request(
+            Request::builder()
+                .uri("/")
+                .header("host", "localhost")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(&body[..], b"<h1>Hello Axum</h1>");
+
+    // Plain HTTP doesn't get through
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (mut sender, connection) = conn::handshake(stream).await.unwrap();
+    tokio::spawn(connection);
+    let request = Request::builder()
+        .uri("/")
+        .header("host", "localhost")
+        .body(Body::empty())
+        .unwrap();
+    assert!(sender.send_request(request).await.is_err());
+}
+
+#[tokio::test]
+async fn https_redirect() {
+    let addr: SocketAddr = listener().local_addr().unwrap();
+    tokio::spawn(redirect_to_https(addr, 8443));
+
+    // Until the listener is up
+    let mut stream = None;
+    for _ in 0..50 {
+        match TcpStream::connect(addr).await {
+            Ok(connected) => {
+                stream = Some(connected);
+                break;
+            }
+            Err(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+        }
+    }
+    let (mut sender, connection) = conn::handshake(stream.unwrap()).await.unwrap();
+    tokio::spawn(connection);
+    let response = sender
+        .send_request(
+            Request::builder()
+                .uri("/kv/a?ttl=60")
+                .header("host", "localhost:8080")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(
+        response.headers()["location"],
+        "https://localhost:8443/kv/a?ttl=60"
+    );
+}
@@ -0,0 +1,85 @@
+use std::sync::{Arc, Mutex, RwLock};
+
+use axum::{body::Body, http::Request};
+use microservice_rust_workshop::{router, AppState};
+use tower::Service; // for `call`
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id},
+    Subscriber,
+};
+use tracing_subscriber::{layer::Context, prelude::*, Layer};
+
+/// Remembers the name and the `op` and `key` fields of every new span
+#[derive(Clone, Default)]
+struct Spans(Arc<Mutex<Vec<(String, String, String)>>>);
+
+#[derive(Default)]
+struct Fields {
+    op: String,
+    key: String,
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "op" => self.op = value.to_string(),
+            "key" => self.key = value.to_string(),
+            _ => {}
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.record_str(field, &format!("{:?}", value));
+    }
+}
+
+impl<S: Subscriber> Layer<S> for Spans {
+    fn on_new_span(&self, attributes: &Attributes<'_>, _id: &Id, _ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        attributes.record(&mut fields);
+        let name = attributes.metadata().name().to_string();
+        self.0.lock().unwrap().push((name, fields.op, fields.key));
+    }
+}
+
+#[tokio::test]
+async fn spans_for_handlers_and_backends() {
+    let spans = Spans::default();
+    let _guard = tracing_subscriber::registry()
+        .with(spans.clone())
+        .set_default();
+
+    let state = Arc::new(RwLock::new(AppState::default()));
+    let mut app = router(&state);
+    app.call(
+        Request::builder()
+            .uri("/kv/crab")
+            .method("POST")
+            .header("content-type", "text/plain")
+            .body(Body::from("Hello World"))
+            .unwrap(),
+    )
+    .await
+    .unwrap();
+    app.call(
+        Request::builder()
+            .uri("/kv/crab")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await
+    .unwrap();
+
+    let spans = spans.0.lock().unwrap();
+    let has = |name: &str, op: &str| {
+        spans
+            .iter()
+            .any(|span| span.0 == name && span.1 == op && span.2 == "crab")
+    };
+    assert!(spans.iter().any(|span| span.0 == "request"));
+    assert!(has("post_kv", ""));
+    assert!(has("get_kv", ""));
+    assert!(has("kv.backend", "insert"));
+    assert!(has("kv.backend", "read_stream"));
+}